@@ -0,0 +1,67 @@
+//! The diffing and sanitisation of doc versions, without any filesystem access so that it also compiles to wasm32 and
+//! the same diffs can be rendered client side, or by other frontends. Build it for the browser with the `wasm` feature:
+//!
+//! ```sh
+//! cargo build -p update-diff --target wasm32-unknown-unknown --features wasm
+//! ```
+
+use std::io::{self, BufRead};
+
+mod sanitise;
+#[cfg(feature = "wasm")]
+mod wasm;
+
+pub use sanitise::{sanitise_fragment, HtmlSanitizer, SanitiseError};
+
+/// Diff two sanitised versions of a doc, the result is the html of the newer version with the changes marked up with
+/// `<ins>` and `<del>`
+pub fn diff(from: &str, to: &str) -> String {
+    htmldiff::htmldiff(from, to)
+}
+
+/// Read a doc body, making its site relative links absolute as it is read. Links are the values of `href` and `src`
+/// attributes starting with `/`, either quoted, protocol relative links starting with `//` are left alone
+pub fn read_with_base_url(mut reader: impl BufRead, base_url: &str) -> io::Result<String> {
+    /// read a byte into the body
+    fn next_byte(reader: &mut impl BufRead, body: &mut Vec<u8>) -> io::Result<Option<u8>> {
+        let mut byte = [0];
+        if reader.read(&mut byte)? == 0 {
+            return Ok(None);
+        }
+        body.push(byte[0]);
+        Ok(Some(byte[0]))
+    }
+
+    let mut body = vec![];
+    // each attribute name is just before an `=`, so the body is read up to each `=` to check what follows it
+    while reader.read_until(b'=', &mut body)? > 0 {
+        let name = match body.strip_suffix(b"=") {
+            Some(name) => name,
+            None => break,
+        };
+        let is_link = [&b"href"[..], b"src"]
+            .iter()
+            .any(|attribute| match name.strip_suffix(*attribute) {
+                Some(before) => before.last().map_or(false, u8::is_ascii_whitespace),
+                None => false,
+            });
+        if !is_link || !matches!(next_byte(&mut reader, &mut body)?, Some(b'"' | b'\'')) {
+            continue;
+        }
+        if next_byte(&mut reader, &mut body)? != Some(b'/') {
+            continue;
+        }
+        let mut after_slash = [0];
+        let after_slash = match reader.read(&mut after_slash)? {
+            0 => None,
+            _ => Some(after_slash[0]),
+        };
+        if after_slash != Some(b'/') {
+            body.pop();
+            body.extend_from_slice(base_url.as_bytes());
+            body.push(b'/');
+        }
+        body.extend(after_slash);
+    }
+    String::from_utf8(body).map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+}
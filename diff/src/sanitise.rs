@@ -0,0 +1,211 @@
+use std::{error::Error, fmt, io};
+
+use html5ever::{
+    serialize::{SerializeOpts, TraversalScope},
+    tendril::TendrilSink,
+    Attribute, ParseOpts,
+};
+use html5streams::{css_select, HtmlContext, HtmlPathElement, HtmlSerializer, HtmlSink};
+
+/// Selects the `<main>` of a page and drops the parts of it which change without the content changing, such as ids and
+/// the contextual sidebar, so that versions of a doc only differ where their content does
+pub struct HtmlSanitizer<InputHandle: Eq + Copy, S: HtmlSink<InputHandle>> {
+    inner: S,
+    skip_handle: Option<InputHandle>,
+    main_handle: Option<InputHandle>,
+}
+
+impl<InputHandle: Eq + Copy, S: HtmlSink<InputHandle>> HtmlSanitizer<InputHandle, S> {
+    pub fn wrap(sink: S) -> Self {
+        Self {
+            inner: sink,
+            skip_handle: None,
+            main_handle: None,
+        }
+    }
+}
+
+impl<InputHandle: Eq + Copy, S: HtmlSink<InputHandle>> HtmlSink<InputHandle> for HtmlSanitizer<InputHandle, S> {
+    type Output = S::Output;
+
+    fn append_doctype_to_document(
+        &mut self,
+        _name: &html5ever::tendril::StrTendril,
+        _public_id: &html5ever::tendril::StrTendril,
+        _system_id: &html5ever::tendril::StrTendril,
+    ) {
+    }
+
+    fn append_element(
+        &mut self,
+        mut context: HtmlContext<'_, InputHandle>,
+        element: &HtmlPathElement<'_, InputHandle>,
+    ) {
+        // select
+        if let Some(select_handle) = self.main_handle {
+            if let Some(select_index) = context
+                .iter()
+                .enumerate()
+                .find_map(|(index, elem)| (elem.handle == select_handle).then(|| index))
+            {
+                context = &context[select_index..];
+            } else {
+                // select ends
+                self.main_handle = None;
+                return;
+            }
+        }
+        if self.main_handle.is_none() && css_select!("main").is_match(element) {
+            // select starts
+            context = &[];
+            let select_handle = element.handle;
+            self.main_handle = Some(select_handle);
+        } else if self.main_handle.is_none() {
+            return;
+        }
+
+        // skip
+        if let Some(skip_handle) = self.skip_handle {
+            if context.iter().any(|elem| elem.handle == skip_handle) {
+                return;
+            } else {
+                self.skip_handle = None
+            }
+        }
+        let mut attrs: Vec<_> = element
+            .attrs
+            .iter()
+            .filter(|Attribute { name, value: _ }| !["id", "aria-labelledby", "aria-hidden"].contains(&&*name.local))
+            .cloned() // TODO : avoid cloning when not necessary
+            .collect();
+        let skip = attrs.iter().any(|Attribute { name, value }| {
+            &name.local == "class"
+                && value
+                    .split_whitespace()
+                    .any(|class| class == "gem-c-contextual-sidebar")
+        });
+        if skip {
+            self.skip_handle = Some(element.handle);
+            return;
+        }
+        attrs.sort();
+        let mut element = element.clone();
+        element.attrs = attrs.into();
+        self.inner.append_element(context, &element)
+    }
+
+    fn append_text(&mut self, context: HtmlContext<InputHandle>, text: &str) {
+        if let Some(select_handle) = self.main_handle {
+            if let Some(select_index) = context
+                .iter()
+                .enumerate()
+                .find_map(|(index, elem)| (elem.handle == select_handle).then(|| index))
+            {
+                let context = &context[select_index..];
+                if let Some(skip_handle) = self.skip_handle {
+                    if context.iter().any(|elem| elem.handle == skip_handle) {
+                        return;
+                    } else {
+                        self.skip_handle = None
+                    }
+                }
+                self.inner.append_text(context, text)
+            } else {
+                self.main_handle = None
+            }
+        }
+    }
+
+    fn append_comment(&mut self, context: HtmlContext<InputHandle>, text: &str) {
+        if let Some(select_handle) = self.main_handle {
+            if let Some(select_index) = context
+                .iter()
+                .enumerate()
+                .find_map(|(index, elem)| (elem.handle == select_handle).then(|| index))
+            {
+                let context = &context[select_index..];
+                if let Some(skip_handle) = self.skip_handle {
+                    if context.iter().any(|elem| elem.handle == skip_handle) {
+                        return;
+                    } else {
+                        self.skip_handle = None
+                    }
+                }
+                self.inner.append_comment(context, text)
+            } else {
+                self.main_handle = None
+            }
+        }
+    }
+
+    fn reset(&mut self) -> Self::Output {
+        self.skip_handle = None;
+        self.inner.reset()
+    }
+}
+
+/// Why a fragment couldn't be sanitised
+#[derive(Debug)]
+pub enum SanitiseError {
+    Read(io::Error),
+    Parse(String),
+    /// the fragment had no `<main>`, so nothing was left
+    Empty,
+}
+
+impl fmt::Display for SanitiseError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            SanitiseError::Read(err) => write!(f, "Error sanitising document {}", err),
+            SanitiseError::Parse(err) => write!(f, "Parse error {}", err),
+            SanitiseError::Empty => f.write_str("Sanitisation left nothing behind"),
+        }
+    }
+}
+
+impl Error for SanitiseError {}
+
+/// Sanitise an html fragment, written to the buffer which is cleared first
+pub fn sanitise_fragment(reader: &mut impl io::Read, mut buf: &mut Vec<u8>) -> Result<(), SanitiseError> {
+    buf.clear();
+    let opts = SerializeOpts {
+        scripting_enabled: false,
+        traversal_scope: TraversalScope::IncludeNode,
+        create_missing_parent: false,
+    };
+    let mut html_serializer = HtmlSerializer::new(&mut buf, opts);
+    let sink = HtmlSanitizer::wrap(&mut html_serializer);
+
+    let mut parse_opts = ParseOpts::default();
+    parse_opts.tree_builder.exact_errors = true;
+    let parser = html5streams::parse_fragment(sink, parse_opts);
+
+    match parser.from_utf8().read_from(reader) {
+        Err(err) => Err(SanitiseError::Read(err)),
+        Ok(Err(err)) => Err(SanitiseError::Parse(err.to_string())),
+        _ if buf.is_empty() => Err(SanitiseError::Empty),
+        _ => Ok(()),
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::{sanitise_fragment, SanitiseError};
+
+    #[test]
+    fn sanitise_outside_main() {
+        let mut buf = Vec::new();
+        let result = sanitise_fragment(&mut "<p>Not the main content</p>".as_bytes(), &mut buf);
+        assert!(matches!(result, Err(SanitiseError::Empty)));
+
+        sanitise_fragment(
+            &mut r#"<main id="content"><p>Visas</p><div class="gem-c-contextual-sidebar">Related</div></main>"#
+                .as_bytes(),
+            &mut buf,
+        )
+        .unwrap();
+        let sanitised = std::str::from_utf8(&buf).unwrap();
+        assert!(sanitised.contains("<p>Visas</p>"));
+        assert!(!sanitised.contains("id=") && !sanitised.contains("Related"));
+    }
+}
@@ -0,0 +1,24 @@
+//! The diffing exported to javascript, for rendering a diff of two raw versions of a doc in the browser
+
+use wasm_bindgen::prelude::*;
+
+/// Diff two versions of a doc as they are stored in the repo, with the site relative links made absolute to
+/// `base_url`
+#[wasm_bindgen]
+pub fn diff_versions(from: &str, to: &str, base_url: &str) -> Result<String, JsValue> {
+    let from = crate::read_with_base_url(from.as_bytes(), base_url).map_err(to_js_error)?;
+    let to = crate::read_with_base_url(to.as_bytes(), base_url).map_err(to_js_error)?;
+    Ok(crate::diff(&from, &to))
+}
+
+/// Sanitise a page's html the same way it is before it is stored in the repo
+#[wasm_bindgen]
+pub fn sanitise(html: &str) -> Result<String, JsValue> {
+    let mut buf = Vec::new();
+    crate::sanitise_fragment(&mut html.as_bytes(), &mut buf).map_err(to_js_error)?;
+    String::from_utf8(buf).map_err(to_js_error)
+}
+
+fn to_js_error(err: impl std::fmt::Display) -> JsValue {
+    JsValue::from_str(&err.to_string())
+}
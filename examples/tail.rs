@@ -0,0 +1,102 @@
+use anyhow::*;
+use clap::Parser;
+use std::{collections::HashSet, path::PathBuf, thread, time::Duration};
+
+use update_repo::{
+    doc::DocRepo,
+    filter::Filter,
+    tag::TagRepo,
+    update::{UpdateRef, UpdateRepo},
+    Url,
+};
+
+/// Follows the update tracker repo, printing the updates written to it after it starts, eg. to watch an ingesting server
+/// over SSH. The repo is checked for new updates every interval, doc versions are only filtered by their url and time
+#[derive(Parser, Debug)]
+#[clap(author, version, about, long_about = None)]
+struct Args {
+    /// The repo to follow
+    #[clap(short, long, default_value_t = String::from("repo"))]
+    repo: String,
+
+    /// Seconds between checks for new updates
+    #[clap(short, long, default_value_t = 5)]
+    interval: u64,
+
+    /// Also print new doc versions
+    #[clap(short, long)]
+    docs: bool,
+
+    /// Filter terms which reduce the output, the same as for log
+    filter: Vec<String>,
+}
+
+fn main() -> Result<()> {
+    let args = Args::parse();
+
+    let filter = Filter::parse_terms(args.filter)?;
+    eprintln!("Following {:?}", &filter);
+
+    let repo = PathBuf::from(&args.repo);
+    let tag_repo = TagRepo::new(repo.join("tag"))?;
+    let update_repo = UpdateRepo::new(repo.join("url"))?;
+    let doc_repo = DocRepo::new(repo.join("url"))?;
+    let base: Url = "https://www.gov.uk/".parse().unwrap();
+
+    // what is in the repo already isn't printed
+    let mut seen_updates: HashSet<UpdateRef> = update_repo
+        .list_all(&base)?
+        .map(|update| update.map(|update| update.update_ref().clone()))
+        .collect::<Result<_, _>>()?;
+    let mut seen_docs: HashSet<UpdateRef> = if args.docs {
+        doc_repo
+            .list_all(&base)?
+            .map(|doc| doc.map(|doc| (doc.url().clone(), *doc.timestamp()).into()))
+            .collect::<Result<_, _>>()?
+    } else {
+        HashSet::new()
+    };
+
+    loop {
+        thread::sleep(Duration::from_secs(args.interval));
+        let tagged = tagged_updates(&tag_repo, &filter)?;
+        for update in update_repo.list_all(&base)? {
+            let update = update?;
+            let update_ref = update.update_ref();
+            if seen_updates.contains(update_ref) {
+                continue;
+            }
+            if filter.filter_update_ref(update_ref) {
+                // an update is tagged after it is written, so one which isn't tagged yet is checked again next time
+                if matches!(&tagged, Some(tagged) if !tagged.contains(update_ref)) {
+                    continue;
+                }
+                println!("{}: {}", &update.timestamp(), &update.url());
+                println!("\t{}", update.change());
+            }
+            seen_updates.insert(update_ref.clone());
+        }
+        if args.docs {
+            for doc in doc_repo.list_all(&base)? {
+                let doc = doc?;
+                let doc_ref: UpdateRef = (doc.url().clone(), *doc.timestamp()).into();
+                if seen_docs.insert(doc_ref.clone()) && filter.filter_update_ref(&doc_ref) {
+                    println!("{}: {} (doc version)", doc.timestamp(), doc.url());
+                }
+            }
+        }
+    }
+}
+
+/// The updates in all of the filter's tags, `None` if it has no tags
+fn tagged_updates(tag_repo: &TagRepo, filter: &Filter) -> Result<Option<HashSet<UpdateRef>>> {
+    let mut tagged: Option<HashSet<UpdateRef>> = None;
+    for tag in &filter.tags {
+        let in_tag = tag_repo.list_updates_in_tag(tag)?.collect::<Result<HashSet<_>, _>>()?;
+        tagged = Some(match tagged {
+            Some(tagged) => tagged.intersection(&in_tag).cloned().collect(),
+            None => in_tag,
+        });
+    }
+    Ok(tagged)
+}
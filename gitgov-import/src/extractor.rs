@@ -1,7 +1,7 @@
-use std::{io, iter::empty, str::FromStr};
+use std::{io, iter::empty, path::Path};
 
 use anyhow::{bail, ensure, Context, Result};
-use chrono::{DateTime, FixedOffset, Offset, TimeZone, Timelike};
+use chrono::{DateTime, Duration, FixedOffset, Offset, TimeZone, Timelike};
 use chrono_tz::Tz;
 use git2::{Blob, Commit, Diff, Oid};
 use html5ever::serialize::{HtmlSerializer, Serialize, SerializeOpts, Serializer, TraversalScope};
@@ -10,14 +10,129 @@ use lazy_static::lazy_static;
 use scraper::Html;
 
 use url::Url;
+
+/// How a git archive of a scraped site is laid out, the defaults are those of gitgov. Update commits have a message
+/// like `9:53am, 17 February 2022: The change [Tag]`, and the files in them are the fetched docs at their path on
+/// the site
+#[derive(Debug, Clone)]
+pub struct Layout {
+    /// only commits by this author are updates, the others are skipped, all commits are updates if it is `None`
+    pub author: Option<String>,
+    /// the format of the timestamp at the start of an update commit's message
+    pub timestamp_format: String,
+    /// the time zone of the timestamps in messages
+    pub timezone: Tz,
+    /// the url which the paths of the files in the archive are relative to
+    pub base_url: Url,
+    /// an extension which is removed from file paths to get their urls, such as `.html`
+    pub strip_extension: Option<String>,
+    /// how far the timestamp in an update's message can be from the one in its doc's history, as the email and the
+    /// page are sometimes a minute or so apart
+    pub timestamp_tolerance: Duration,
+}
+
+impl Default for Layout {
+    fn default() -> Self {
+        Self {
+            author: Some("info@gov.uk".to_owned()),
+            timestamp_format: "%I:%M%p, %d %B %Y".to_owned(),
+            timezone: chrono_tz::Europe::London,
+            base_url: "https://www.gov.uk/".parse().unwrap(),
+            strip_extension: None,
+            timestamp_tolerance: Duration::minutes(5),
+        }
+    }
+}
+
+impl Layout {
+    /// The default layout with any of it replaced by `GITGOV_AUTHOR` (empty for all commits),
+    /// `GITGOV_TIMESTAMP_FORMAT`, `GITGOV_TIMEZONE`, `GITGOV_BASE_URL`, `GITGOV_STRIP_EXTENSION` and
+    /// `GITGOV_TIMESTAMP_TOLERANCE_MINUTES`
+    pub fn from_env() -> Result<Self> {
+        let mut layout = Self::default();
+        if let Ok(author) = dotenv::var("GITGOV_AUTHOR") {
+            layout.author = Some(author).filter(|author| !author.is_empty());
+        }
+        if let Ok(timestamp_format) = dotenv::var("GITGOV_TIMESTAMP_FORMAT") {
+            layout.timestamp_format = timestamp_format;
+        }
+        if let Ok(timezone) = dotenv::var("GITGOV_TIMEZONE") {
+            layout.timezone = timezone
+                .parse()
+                .map_err(anyhow::Error::msg)
+                .context("GITGOV_TIMEZONE")?;
+        }
+        if let Ok(base_url) = dotenv::var("GITGOV_BASE_URL") {
+            layout.base_url = base_url.parse().context("GITGOV_BASE_URL")?;
+        }
+        if let Ok(strip_extension) = dotenv::var("GITGOV_STRIP_EXTENSION") {
+            layout.strip_extension = Some(strip_extension).filter(|extension| !extension.is_empty());
+        }
+        if let Ok(tolerance) = dotenv::var("GITGOV_TIMESTAMP_TOLERANCE_MINUTES") {
+            let minutes = tolerance.parse().context("GITGOV_TIMESTAMP_TOLERANCE_MINUTES")?;
+            layout.timestamp_tolerance = Duration::minutes(minutes);
+        }
+        Ok(layout)
+    }
+
+    /// Whether a commit is of an update, rather than some other change to the archive
+    pub fn is_update_commit(&self, commit: &Commit) -> bool {
+        match &self.author {
+            Some(author) => commit.author().email() == Some(author.as_str()),
+            None => true,
+        }
+    }
+
+    /// The url of the doc at a path in the archive
+    pub fn url_for_path(&self, path: &Path) -> Result<Url> {
+        let path = path.to_str().context("path isn't utf-8")?;
+        let path = match &self.strip_extension {
+            Some(extension) => path.strip_suffix(extension.as_str()).unwrap_or(path),
+            None => path,
+        };
+        Ok(self.base_url.join(path)?)
+    }
+
+    /// How much the timestamp in an update's message differs from the one in its doc's history, to the minute, if it
+    /// does. It is an error if they differ by more than the tolerance
+    pub fn timestamp_discrepancy(
+        &self,
+        message_ts: DateTime<Tz>,
+        history_ts: DateTime<FixedOffset>,
+    ) -> Result<Option<Duration>> {
+        let discrepancy = history_ts.with_second(0).unwrap().signed_duration_since(message_ts);
+        ensure!(
+            discrepancy.num_seconds().abs() <= self.timestamp_tolerance.num_seconds(),
+            "expected {} to be within {} minutes of {}",
+            history_ts,
+            self.timestamp_tolerance.num_minutes(),
+            message_ts
+        );
+        Ok(Some(discrepancy).filter(|discrepancy| *discrepancy != Duration::zero()))
+    }
+
+    /// The timestamp at the start of a commit message
+    fn parse_timestamp(&self, message: &str) -> Result<DateTime<Tz>> {
+        let date = message.split(": ").next().unwrap();
+        self.timezone
+            .datetime_from_str(date, &self.timestamp_format)
+            .context("parsing timestamp")
+    }
+}
+
 pub struct Extractor<'r> {
     repo: &'r git2::Repository,
     commit: &'r git2::Commit<'r>,
+    layout: &'r Layout,
 }
 
 impl<'r> Extractor<'r> {
-    pub fn new(repo: &'r git2::Repository, commit: &'r git2::Commit<'r>) -> Self {
-        Extractor { repo, commit }
+    pub fn new(repo: &'r git2::Repository, commit: &'r git2::Commit<'r>, layout: &'r Layout) -> Self {
+        Extractor { repo, commit, layout }
+    }
+
+    pub fn layout(&self) -> &Layout {
+        self.layout
     }
 
     pub fn diff(&self) -> Result<Diff> {
@@ -68,7 +183,7 @@ impl<'r> Extractor<'r> {
                 DocExtractor::Blob(blob)
             };
 
-            let url = Url::from_str(&format!("https://www.gov.uk/{}", path.to_str().unwrap()))?;
+            let url = self.layout.url_for_path(&path)?;
             v.push((url, content));
         }
         Ok((v, skip_delete))
@@ -161,19 +276,12 @@ impl<'r> Extractor<'r> {
             bail!("Too many files in commit {}", self.commit.id());
         }
         let path = files[0].new_file().path().unwrap();
-        let url = Url::from_str(&format!("https://www.gov.uk/{}", path.to_str().unwrap()))?;
-        Ok(url)
+        self.layout.url_for_path(path)
     }
 
     /// timestamp of update
     pub fn updated_at(&self) -> Result<DateTime<Tz>> {
-        let date = self.commit.message().unwrap().split(": ").next().unwrap();
-        // println!("date{}", date);
-        const DATE_FORMAT: &str = "%I:%M%p, %d %B %Y";
-        let local_ts = chrono_tz::Europe::London
-            .datetime_from_str(date, DATE_FORMAT)
-            .context("parsing timestamp")?;
-        Ok(local_ts)
+        self.layout.parse_timestamp(self.commit.message().unwrap())
     }
 
     /// timestamp of retrieval
@@ -319,6 +427,64 @@ impl<Wr: Write> Serializer for NormalizingHtmlSerializer<Wr> {
     }
 }
 
+#[test]
+fn test_layout() {
+    let layout = Layout::default();
+    assert_eq!(
+        layout
+            .url_for_path(Path::new("foreign-travel-advice/burundi"))
+            .unwrap()
+            .as_str(),
+        "https://www.gov.uk/foreign-travel-advice/burundi"
+    );
+    assert_eq!(
+        layout
+            .parse_timestamp("9:53am, 17 February 2022: The change [Travel]")
+            .unwrap()
+            .to_rfc3339(),
+        "2022-02-17T09:53:00+00:00"
+    );
+
+    let layout = Layout {
+        author: None,
+        timestamp_format: "%Y-%m-%d %H:%M".to_owned(),
+        timezone: chrono_tz::Europe::Paris,
+        base_url: "https://www.example.fr/archive/".parse().unwrap(),
+        strip_extension: Some(".html".to_owned()),
+        timestamp_tolerance: Duration::minutes(5),
+    };
+    assert_eq!(
+        layout.url_for_path(Path::new("a/page.html")).unwrap().as_str(),
+        "https://www.example.fr/archive/a/page"
+    );
+    assert_eq!(
+        layout
+            .parse_timestamp("2022-02-17 10:53: The change")
+            .unwrap()
+            .to_rfc3339(),
+        "2022-02-17T10:53:00+01:00"
+    );
+
+    // the history can be a few minutes off the message
+    let message_ts = layout.parse_timestamp("2022-02-17 10:53: The change").unwrap();
+    let history_ts = |ts: &str| DateTime::parse_from_rfc3339(ts).unwrap();
+    assert_eq!(
+        layout
+            .timestamp_discrepancy(message_ts, history_ts("2022-02-17T09:53:30+00:00"))
+            .unwrap(),
+        None
+    );
+    assert_eq!(
+        layout
+            .timestamp_discrepancy(message_ts, history_ts("2022-02-17T09:52:00+00:00"))
+            .unwrap(),
+        Some(Duration::minutes(-1))
+    );
+    assert!(layout
+        .timestamp_discrepancy(message_ts, history_ts("2022-02-17T10:00:00+00:00"))
+        .is_err());
+}
+
 #[test]
 fn test_normalise_html() {
     assert_eq!(
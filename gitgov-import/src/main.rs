@@ -1,24 +1,35 @@
 use std::{
+    env,
     fs::remove_dir_all,
     io::{self, Read, Write},
     iter::successors,
     ops::AddAssign,
+    path::PathBuf,
     str::from_utf8,
+    time::Instant,
 };
 
-use anyhow::{ensure, format_err, Context, Result};
-use extractor::Extractor;
+use anyhow::{format_err, Context, Result};
+use extractor::{Extractor, Layout};
 use git2::Repository;
+use report::{CommitOutcome, ImportReport};
+use serde_json::{json, Value};
 use update_repo::{
     doc::{DocEvent, DocRepo},
+    progress::{Progress, Verbosity},
+    repository::Durability,
     tag::TagRepo,
-    update::UpdateRepo,
+    update::{ChangeNormalizer, UpdateConflict, UpdateRepo, UpdateValidation},
     Url,
 };
 
 mod extractor;
+mod report;
 
+/// Imports the history of a gitgov repo, configured with environment variables. Takes `--quiet` or `--verbose`. A
+/// report of the import is written to `GITGOV_REPORT`, or `import-report.json` in the base repo
 fn main() -> Result<()> {
+    let (verbosity, _args) = Verbosity::from_args(env::args().skip(1));
     let base_repo: &str = &dotenv::var("BASE_REPO")?;
     let tag_repo_base = &format!("{}/tag", base_repo);
     let url_repo_base: &str = &format!("{}/url", base_repo);
@@ -28,30 +39,77 @@ fn main() -> Result<()> {
     let repo = Repository::open(dotenv::var("GITGOV_REPO")?)?;
     let reference = repo.find_reference(&dotenv::var("GITGOV_REF")?)?;
     let last_commit = reference.peel_to_commit()?;
+    let layout = Layout::from_env()?;
+    let report_path = dotenv::var("GITGOV_REPORT")
+        .map(PathBuf::from)
+        .unwrap_or_else(|_| PathBuf::from(base_repo).join("import-report.json"));
 
-    let mut doc_repo = DocRepo::new(url_repo_base)?;
-    let mut tag_repo = TagRepo::new(tag_repo_base)?;
-    let mut update_repo = UpdateRepo::new(url_repo_base)?;
+    // an import which is interrupted is run again from the start, so it isn't worth syncing each write, and the docs are
+    // deduplicated once at the end rather than comparing each write with its neighbours
+    let mut doc_repo = DocRepo::new(url_repo_base)?
+        .with_durability(Durability::None)
+        .with_bulk_mode();
+    let mut tag_repo = TagRepo::new(tag_repo_base)?.with_durability(Durability::None);
+    // historic updates are imported as they were, even if they wouldn't pass validation now, and the same update in
+    // several commits is often only reformatted
+    let mut update_repo = UpdateRepo::new(url_repo_base)?
+        .with_durability(Durability::None)
+        .with_validation(UpdateValidation::none())
+        .with_change_normalizer(ChangeNormalizer::lenient());
 
     let mut update_imports_skipped = 0;
     let mut updates_imported = 0;
+    let mut update_conflicts = 0;
+    let mut timestamp_discrepancies = 0;
     let mut doc_stats = DocImportStats::new();
+    let mut report = ImportReport::default();
+    let progress = Progress::new(None, verbosity);
 
     for commit in successors(Some(last_commit), |commit| commit.parents().next()) {
-        if commit.author().email().unwrap() == "info@gov.uk" {
-            let extractor = Extractor::new(&repo, &commit);
-            doc_stats += import_docs_from_commit(&extractor, &mut doc_repo)
+        let started = Instant::now();
+        if layout.is_update_commit(&commit) {
+            let extractor = Extractor::new(&repo, &commit, &layout);
+            let commit_doc_stats = import_docs_from_commit(&extractor, &mut doc_repo, &progress)
                 .context(format!("Importing docs from {}", commit.id()))?;
-            if let Err(e) = import_update_from_commit(&extractor, &mut tag_repo, &mut update_repo)
-                .context(format!("Importing tag from {}", commit.id()))
-            {
-                println!("Error importing tag : {:? }\n", e);
-                update_imports_skipped += 1;
-            } else {
-                updates_imported += 1;
-            }
+            doc_stats += commit_doc_stats;
+            let imported = import_update_from_commit(&extractor, &mut tag_repo, &mut update_repo, &progress)
+                .context(format!("Importing tag from {}", commit.id()));
+            let (outcome, timestamp_discrepancy) = match imported {
+                Err(e) => {
+                    progress.warn(format!("Error importing tag : {:?}", e));
+                    update_imports_skipped += 1;
+                    (CommitOutcome::Error(format!("{:?}", e)), None)
+                }
+                Ok(UpdateImport {
+                    imported,
+                    timestamp_discrepancy,
+                }) => {
+                    if imported {
+                        updates_imported += 1;
+                    } else {
+                        update_conflicts += 1;
+                    }
+                    if timestamp_discrepancy.is_some() {
+                        timestamp_discrepancies += 1;
+                    }
+                    let outcome = if imported {
+                        CommitOutcome::Imported
+                    } else {
+                        CommitOutcome::Conflict
+                    };
+                    (outcome, timestamp_discrepancy)
+                }
+            };
+            report.record(
+                &commit,
+                outcome,
+                Some(commit_doc_stats),
+                timestamp_discrepancy,
+                started.elapsed(),
+            );
         } else {
-            println!("Non-update commit : {}", commit.message().unwrap());
+            progress.log(format!("Non-update commit : {}", commit.message().unwrap()));
+            report.record(&commit, CommitOutcome::NotAnUpdate, None, None, started.elapsed());
         }
 
         let commit_date = chrono::TimeZone::timestamp(
@@ -61,35 +119,63 @@ fn main() -> Result<()> {
         )
         .date();
 
-        print!(
-            "{}: Imported: {} docs: {} new, {} updated, {} deleted, {} updates. {} skipped updates. {} deleted docs\r",
-            commit_date,
-            doc_stats.docs_imported,
-            doc_stats.events_new,
-            doc_stats.events_updated,
-            doc_stats.events_deleted,
-            updates_imported,
-            update_imports_skipped,
-            doc_stats.skip_deleted,
-        );
-        io::stdout().flush().unwrap();
+        progress.inc(1);
+        progress.set_message(format!(
+            "{}: {} docs, {} updates, {} skipped updates",
+            commit_date, doc_stats.docs_imported, updates_imported, update_imports_skipped,
+        ));
     }
-    println!("{} docs imported", doc_stats.docs_imported);
+    progress.set_message("removing duplicate doc versions");
+    let compacted = doc_repo.compact_all(&layout.base_url.clone().into())?;
+    let elapsed = progress.finish();
+
+    println!("Imported in {}s", elapsed.as_secs());
+    println!(
+        "{} docs imported: {} new, {} updated, {} deleted",
+        doc_stats.docs_imported, doc_stats.events_new, doc_stats.events_updated, doc_stats.events_deleted
+    );
     println!("{} updates imported", updates_imported);
     println!("{} errors importing updates", update_imports_skipped);
+    println!("{} updates conflicting with an update already imported", update_conflicts);
+    println!(
+        "{} updates whose message and doc history timestamps differ",
+        timestamp_discrepancies
+    );
     println!("{} deleted docs skipped", doc_stats.skip_deleted);
+    println!("{} duplicate doc versions removed", compacted.versions_removed);
 
+    report.write(
+        &report_path,
+        json!({
+            "duration_ms": elapsed.as_millis() as u64,
+            "docs": doc_stats.to_json(),
+            "updates_imported": updates_imported,
+            "update_errors": update_imports_skipped,
+            "update_conflicts": update_conflicts,
+            "timestamp_discrepancies": timestamp_discrepancies,
+            "duplicate_doc_versions_removed": compacted.versions_removed,
+        }),
+    )?;
+    println!("Report written to {:?}", report_path);
     Ok(())
 }
 
-/// Import a tag into the tag repo from the commit. If the commit only has one file it is easy, but if it has more, we need to find which of the files matches the update in the commit
+/// The outcome of importing the update of a commit
+struct UpdateImport {
+    /// false if an update with a different change was already imported at the same time, which is kept
+    imported: bool,
+    /// how far the timestamp in the doc's history was from the one in the message, if they differ
+    timestamp_discrepancy: Option<chrono::Duration>,
+}
+
+/// Import a tag into the tag repo from the commit. If the commit only has one file it is easy, but if it has more, we need to find which of the files matches the update in the commit.
+/// The update is imported at the timestamp in the doc's history, if that is within the tolerance of the one in the message
 fn import_update_from_commit(
     extractor: &Extractor,
     tag_repo: &mut TagRepo,
     update_repo: &mut UpdateRepo,
-) -> Result<()> {
-    use chrono::Timelike;
-
+    progress: &Progress,
+) -> Result<UpdateImport> {
     let ts1 = extractor.updated_at()?;
     let change = extractor.message()?;
     let tag = extractor.tag().unwrap_or("Unknown");
@@ -99,32 +185,55 @@ fn import_update_from_commit(
         .context("Finding the main doc version in the update")?;
     let url: Url = url.into();
 
-    ensure!(
-        ts1 == ts2.with_second(0).unwrap(),
-        "expected {} == {}",
-        ts1,
-        ts2.with_second(0).unwrap()
-    );
+    let timestamp_discrepancy = extractor.layout().timestamp_discrepancy(ts1, ts2)?;
+    if let Some(discrepancy) = timestamp_discrepancy {
+        progress.log(format!(
+            "Update of {} at {} is {} minutes from its message",
+            url,
+            ts2,
+            discrepancy.num_minutes()
+        ));
+    }
 
     let _tag = tag_repo
         .tag_update(tag.to_owned(), (url.clone(), ts2).into())
         .context("Tagging update in repo")?;
-    let _update = update_repo
-        .ensure(url, ts2, &change)
-        .context("Creating update in repo")?;
-    Ok(())
+    match update_repo.ensure(url, ts2, &change) {
+        Ok(_update) => Ok(UpdateImport {
+            imported: true,
+            timestamp_discrepancy,
+        }),
+        Err(err) => match UpdateConflict::from_error(&err) {
+            Some(conflict) => {
+                progress.log(format!(
+                    "Conflicting change for {}, keeping {:?} rather than {:?}",
+                    conflict.existing, conflict.existing.change(), conflict.attempted.change()
+                ));
+                Ok(UpdateImport {
+                    imported: false,
+                    timestamp_discrepancy,
+                })
+            }
+            None => Err(err).context("Creating update in repo"),
+        },
+    }
 }
 
-fn import_docs_from_commit(extractor: &Extractor, doc_repo: &mut DocRepo) -> Result<DocImportStats> {
+fn import_docs_from_commit(
+    extractor: &Extractor,
+    doc_repo: &mut DocRepo,
+    progress: &Progress,
+) -> Result<DocImportStats> {
     let mut docs_imported = 0;
     let mut events_new = 0;
     let mut events_updated = 0;
     let mut events_deleted = 0;
     let ts = extractor.retrieved_at();
     let (doc_versions, skip_deleted) = extractor.doc_versions().context("loading doc versions")?;
+    let mut write_avoidance_buffer = Vec::new();
     for (url, content) in doc_versions {
         let url: Url = url.into();
-        match doc_repo.create(url.clone(), ts) {
+        match doc_repo.create(url.clone(), ts, &mut write_avoidance_buffer) {
             Ok(mut writer) => {
                 writer.write_all(content.as_bytes())?;
                 let update = writer.done()?;
@@ -133,6 +242,8 @@ fn import_docs_from_commit(extractor: &Extractor, doc_repo: &mut DocRepo) -> Res
                         DocEvent::Created { .. } => events_new += 1,
                         DocEvent::Updated { .. } => events_updated += 1,
                         DocEvent::Deleted { .. } => events_deleted += 1,
+                        // removals, withdrawals and first versions are marked separately rather than by writing a version
+                        _ => {}
                     }
                 }
                 docs_imported += 1;
@@ -143,7 +254,7 @@ fn import_docs_from_commit(extractor: &Extractor, doc_repo: &mut DocRepo) -> Res
                 let mut existing_data: Vec<u8> = vec![];
                 doc_repo.open(&existing)?.read_to_end(&mut existing_data)?;
                 if existing_data == content.as_bytes() {
-                    println!("Doc version already exists {}", &existing);
+                    progress.log(format!("Doc version already exists {}", &existing));
                     Ok(())
                 } else {
                     let diff = prettydiff::diff_lines(from_utf8(&existing_data)?, content.as_str());
@@ -167,6 +278,7 @@ fn import_docs_from_commit(extractor: &Extractor, doc_repo: &mut DocRepo) -> Res
     })
 }
 
+#[derive(Clone, Copy)]
 struct DocImportStats {
     docs_imported: u16,
     skip_deleted: u16,
@@ -185,6 +297,16 @@ impl DocImportStats {
             events_deleted: 0,
         }
     }
+
+    fn to_json(&self) -> Value {
+        json!({
+            "imported": self.docs_imported,
+            "skipped_deleted": self.skip_deleted,
+            "new": self.events_new,
+            "updated": self.events_updated,
+            "deleted": self.events_deleted,
+        })
+    }
 }
 
 impl AddAssign for DocImportStats {
@@ -0,0 +1,66 @@
+//! The report written at the end of an import, a JSON object with the outcome of each commit and the totals, so that
+//! the quality of imports can be compared across runs and changes to the extraction heuristics checked
+
+use std::{fs, path::Path, time::Duration};
+
+use anyhow::{Context, Result};
+use git2::Commit;
+use serde_json::{json, Value};
+
+use crate::DocImportStats;
+
+/// What was done with a commit
+pub enum CommitOutcome {
+    /// the commit isn't by the author of updates
+    NotAnUpdate,
+    Imported,
+    /// an update with a different change was already imported at the same time, which was kept
+    Conflict,
+    /// the update couldn't be imported, its docs may have been
+    Error(String),
+}
+
+#[derive(Default)]
+pub struct ImportReport {
+    commits: Vec<Value>,
+}
+
+impl ImportReport {
+    pub fn record(
+        &mut self,
+        commit: &Commit,
+        outcome: CommitOutcome,
+        docs: Option<DocImportStats>,
+        timestamp_discrepancy: Option<chrono::Duration>,
+        duration: Duration,
+    ) {
+        let (outcome, error) = match outcome {
+            CommitOutcome::NotAnUpdate => ("not-an-update", None),
+            CommitOutcome::Imported => ("imported", None),
+            CommitOutcome::Conflict => ("conflict", None),
+            CommitOutcome::Error(error) => ("error", Some(error)),
+        };
+        let mut record = json!({
+            "commit": commit.id().to_string(),
+            "time": commit.time().seconds(),
+            "outcome": outcome,
+            "duration_ms": duration.as_millis() as u64,
+        });
+        if let Some(error) = error {
+            record["error"] = json!(error);
+        }
+        if let Some(docs) = docs {
+            record["docs"] = docs.to_json();
+        }
+        if let Some(discrepancy) = timestamp_discrepancy {
+            record["timestamp_discrepancy_minutes"] = json!(discrepancy.num_minutes());
+        }
+        self.commits.push(record);
+    }
+
+    /// Write the report with the totals of the import
+    pub fn write(self, path: &Path, mut totals: Value) -> Result<()> {
+        totals["commits"] = Value::Array(self.commits);
+        fs::write(path, serde_json::to_string_pretty(&totals)?).context(format!("Writing report to {:?}", path))
+    }
+}
@@ -0,0 +1,218 @@
+//! Python bindings for reading an update tracker repo, for analysing the archive from notebooks. Build and install the
+//! module into the current virtualenv with `maturin develop` in this directory, then:
+//!
+//! ```python
+//! import update_repo_py
+//! repo = update_repo_py.Repo("repo")
+//! for update in repo.list_updates("https://www.gov.uk/guidance/", "#brexit 2022-01.."):
+//!     versions = repo.list_versions(update["url"])
+//! ```
+//!
+//! The bindings aren't in the workspace's default members, they are tested with `cargo test -p update-repo-py`
+
+use std::{
+    collections::HashSet,
+    io::{self, Read},
+    path::PathBuf,
+};
+
+use pyo3::{
+    exceptions::PyValueError,
+    prelude::*,
+    types::{PyBytes, PyDict},
+};
+use update_repo::{
+    doc::{DocRepo, DocumentVersion},
+    filter::Filter,
+    tag::{Tag, TagRepo},
+    update::{Update, UpdateRef, UpdateRepo},
+    Url,
+};
+
+/// A repo opened for reading, the timestamps passed in and returned are RFC 3339 strings
+#[pyclass]
+struct Repo {
+    update_repo: UpdateRepo,
+    doc_repo: DocRepo,
+    tag_repo: TagRepo,
+}
+
+#[pymethods]
+impl Repo {
+    #[new]
+    fn new(path: PathBuf) -> PyResult<Self> {
+        Ok(Self {
+            update_repo: UpdateRepo::new(path.join("url"))?,
+            doc_repo: DocRepo::new(path.join("url"))?,
+            tag_repo: TagRepo::new(path.join("tag"))?,
+        })
+    }
+
+    /// The updates to urls starting with a prefix, in url and then timestamp order, as dicts of their `url`,
+    /// `timestamp`, `change` and `category`, which is `None` for updates stored without one. `filter` takes the filter
+    /// terms of the `log` example, eg. `#brexit 2022-01..`
+    #[pyo3(signature = (prefix = "https://www.gov.uk/", filter = None))]
+    fn list_updates(&self, py: Python, prefix: &str, filter: Option<&str>) -> PyResult<Vec<PyObject>> {
+        let filter: Filter = match filter {
+            Some(filter) => filter.parse().map_err(value_error)?,
+            None => Filter::default(),
+        };
+        let tagged = self.tagged_updates(&filter.tags)?;
+        let mut updates = vec![];
+        for update in self.update_repo.list_all(&parse_url(prefix)?)? {
+            let update = update?;
+            if filter.filter_update_ref(update.update_ref())
+                && !matches!(&tagged, Some(tagged) if !tagged.contains(update.update_ref()))
+            {
+                updates.push(update_dict(py, &update)?);
+            }
+        }
+        Ok(updates)
+    }
+
+    /// The versions of the doc at a url, newest first, as dicts of their `url` and `timestamp`
+    fn list_versions(&self, py: Python, url: &str) -> PyResult<Vec<PyObject>> {
+        let mut versions = vec![];
+        for version in self.doc_repo.list_versions(parse_url(url)?)? {
+            versions.push(version_dict(py, &version?)?);
+        }
+        Ok(versions)
+    }
+
+    /// The content of the version of the doc at a url at a timestamp
+    fn read_doc(&self, py: Python, url: &str, timestamp: &str) -> PyResult<PyObject> {
+        let version = self
+            .doc_repo
+            .ensure_version(parse_url(url)?, parse_timestamp(timestamp)?)?;
+        let mut content = vec![];
+        self.doc_repo.open(&version)?.read_to_end(&mut content)?;
+        Ok(PyBytes::new(py, &content).into())
+    }
+
+    /// The names of all the tags
+    fn list_tags(&self) -> PyResult<Vec<String>> {
+        Ok(self.tag_repo.list_tags()?.map(|tag| tag.name().to_owned()).collect())
+    }
+
+    /// The updates in a tag, as dicts of their `url` and `timestamp`
+    fn list_updates_in_tag(&self, py: Python, tag: &str) -> PyResult<Vec<PyObject>> {
+        self.updates_in_tag(&Tag::new(tag.to_owned()))?
+            .iter()
+            .map(|update_ref| Ok(update_ref_dict(py, update_ref)?.into()))
+            .collect()
+    }
+}
+
+impl Repo {
+    /// The updates in all of the tags, `None` if there are no tags
+    fn tagged_updates(&self, tags: &[Tag]) -> PyResult<Option<HashSet<UpdateRef>>> {
+        let mut tagged: Option<HashSet<UpdateRef>> = None;
+        for tag in tags {
+            let in_tag: HashSet<_> = self.updates_in_tag(tag)?.into_iter().collect();
+            tagged = Some(match tagged {
+                Some(tagged) => tagged.intersection(&in_tag).cloned().collect(),
+                None => in_tag,
+            });
+        }
+        Ok(tagged)
+    }
+
+    /// The updates in a tag, none if there is no such tag
+    fn updates_in_tag(&self, tag: &Tag) -> PyResult<Vec<UpdateRef>> {
+        match self.tag_repo.list_updates_in_tag(tag) {
+            Ok(updates) => updates.collect::<Result<_, _>>().map_err(value_error),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(vec![]),
+            Err(err) => Err(err.into()),
+        }
+    }
+}
+
+fn value_error(err: impl std::fmt::Display) -> PyErr {
+    PyValueError::new_err(err.to_string())
+}
+
+fn parse_url(url: &str) -> PyResult<Url> {
+    url.parse()
+        .map_err(|err| PyValueError::new_err(format!("Invalid url {:?} : {}", url, err)))
+}
+
+fn parse_timestamp(timestamp: &str) -> PyResult<chrono::DateTime<chrono::FixedOffset>> {
+    chrono::DateTime::parse_from_rfc3339(timestamp)
+        .map_err(|err| PyValueError::new_err(format!("Invalid timestamp {:?} : {}", timestamp, err)))
+}
+
+fn update_ref_dict<'py>(py: Python<'py>, update_ref: &UpdateRef) -> PyResult<&'py PyDict> {
+    let dict = PyDict::new(py);
+    dict.set_item("url", update_ref.url.as_str())?;
+    dict.set_item("timestamp", update_ref.timestamp.to_rfc3339())?;
+    Ok(dict)
+}
+
+fn update_dict(py: Python, update: &Update) -> PyResult<PyObject> {
+    let dict = update_ref_dict(py, update.update_ref())?;
+    dict.set_item("change", update.change())?;
+    dict.set_item("category", update.fields().category.as_deref())?;
+    Ok(dict.into())
+}
+
+fn version_dict(py: Python, version: &DocumentVersion) -> PyResult<PyObject> {
+    let dict = PyDict::new(py);
+    dict.set_item("url", version.url().as_str())?;
+    dict.set_item("timestamp", version.timestamp().to_rfc3339())?;
+    Ok(dict.into())
+}
+
+#[pymodule]
+fn update_repo_py(_py: Python, m: &PyModule) -> PyResult<()> {
+    m.add_class::<Repo>()
+}
+
+#[cfg(test)]
+mod test {
+    use std::path::Path;
+
+    use pyo3::{
+        prelude::*,
+        types::{IntoPyDict, PyModule},
+    };
+    use update_repo::{check::init_repo, tag::TagRepo, update::UpdateRepo};
+
+    #[test]
+    fn test_module_smoke() {
+        let base = Path::new("tmp/test_module_smoke");
+        let _ = std::fs::remove_dir_all(base);
+        init_repo(base).unwrap();
+        let update = UpdateRepo::new(base.join("url"))
+            .unwrap()
+            .create(
+                "https://www.gov.uk/guidance/a".parse().unwrap(),
+                "2022-02-17T09:00:00+00:00".parse().unwrap(),
+                "Updated",
+            )
+            .unwrap()
+            .into_inner();
+        TagRepo::new(base.join("tag"))
+            .unwrap()
+            .tag_update("brexit".to_owned(), update.update_ref().clone())
+            .unwrap();
+
+        pyo3::prepare_freethreaded_python();
+        Python::with_gil(|py| {
+            let module = PyModule::new(py, "update_repo_py").unwrap();
+            super::update_repo_py(py, module).unwrap();
+            let locals = [("update_repo_py", module)].into_py_dict(py);
+            py.run(
+                r##"
+repo = update_repo_py.Repo("tmp/test_module_smoke")
+updates = repo.list_updates("https://www.gov.uk/", "#brexit")
+assert [(u["url"], u["change"]) for u in updates] == [("https://www.gov.uk/guidance/a", "Updated")], updates
+assert repo.list_tags() == ["brexit"]
+assert repo.list_updates("https://www.gov.uk/", "#other") == []
+"##,
+                None,
+                Some(locals),
+            )
+            .unwrap();
+        });
+    }
+}
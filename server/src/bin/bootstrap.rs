@@ -0,0 +1,39 @@
+use std::{
+    env,
+    io::{self, BufRead},
+    path::PathBuf,
+};
+
+use anyhow::Result;
+use update_tracker::ingress::{bootstrap, fetch::UreqFetcher};
+use url::Url;
+
+/// Starts tracking pages with the change history published on them, `bootstrap REPO URL...` bootstraps each url, or the
+/// urls read a line at a time from stdin when none are given. `bootstrap REPO --prefix URL` bootstraps the page at url
+/// and the pages under it which it links to
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let repo_path = PathBuf::from(args.next().expect("no repo path"));
+    let args: Vec<String> = args.collect();
+    let urls: Vec<Url> = match args.as_slice() {
+        [flag, prefix] if flag == "--prefix" => bootstrap::pages_under(&prefix.parse()?, &UreqFetcher)?,
+        [] => io::stdin()
+            .lock()
+            .lines()
+            .filter(|line| !matches!(line, Ok(line) if line.trim().is_empty()))
+            .map(|line| Ok(line?.trim().parse()?))
+            .collect::<Result<_>>()?,
+        urls => urls.iter().map(|url| url.parse()).collect::<Result<_, _>>()?,
+    };
+    println!("Bootstrapping {} pages", urls.len());
+
+    let report = bootstrap::run(&repo_path, &urls, &UreqFetcher)?;
+    println!(
+        "Bootstrapped {} pages, wrote {} updates from their history ({} were already written) and {} docs",
+        report.pages, report.updates, report.existing_updates, report.docs
+    );
+    for url in &report.failed {
+        println!("Failed : {}", url);
+    }
+    Ok(())
+}
@@ -0,0 +1,24 @@
+use std::{env, path::PathBuf, process};
+
+use anyhow::Result;
+use update_tracker::ingress::{self, fetch::UreqFetcher};
+
+/// Processes emails in the outbox again, `reprocess REPO EMAIL...` takes the paths of the emails in the outbox, as
+/// shown as the source of their updates, eg. `updates/2022-02-17T09:53:57.eml`. What is already in the repo is left
+/// alone, so it can be run again until it succeeds
+fn main() -> Result<()> {
+    let mut args = env::args().skip(1);
+    let repo_path = PathBuf::from(args.next().expect("no repo path"));
+    let sources: Vec<String> = args.collect();
+    if sources.is_empty() {
+        eprintln!("usage: reprocess REPO EMAIL...");
+        process::exit(2);
+    }
+
+    let failed = ingress::reprocess(&repo_path, &sources, &UreqFetcher)?;
+    println!("Reprocessed {} emails, {} failed", sources.len(), failed);
+    if failed > 0 {
+        process::exit(1);
+    }
+    Ok(())
+}
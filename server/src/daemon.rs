@@ -0,0 +1,100 @@
+//! Running as a service under systemd or another supervisor, with a pid file, a readiness notification, log files which
+//! can be rotated and config reloading on `SIGHUP`
+
+use std::{
+    env,
+    fs::{self, OpenOptions},
+    io,
+    os::unix::io::AsRawFd,
+    path::PathBuf,
+    process, thread,
+};
+
+use signal_hook::{
+    consts::{SIGHUP, SIGINT, SIGTERM},
+    iterator::Signals,
+};
+
+/// A file containing the pid of the server, removed when the server is stopped by a signal
+pub struct PidFile(PathBuf);
+
+impl PidFile {
+    /// Configured with `PID_FILE`, there is no pid file if it isn't set
+    pub fn from_env() -> io::Result<Option<Self>> {
+        let path = match dotenv::var("PID_FILE") {
+            Ok(path) => PathBuf::from(path),
+            Err(_) => return Ok(None),
+        };
+        fs::write(&path, format!("{}\n", process::id()))?;
+        Ok(Some(Self(path)))
+    }
+}
+
+impl Drop for PidFile {
+    fn drop(&mut self) {
+        let _ = fs::remove_file(&self.0);
+    }
+}
+
+/// Redirect stdout and stderr to the end of `LOG_FILE` if it is set, reopening it if it was already open so that a
+/// rotated log file is replaced
+pub fn reopen_log() -> io::Result<()> {
+    let path = match dotenv::var("LOG_FILE") {
+        Ok(path) => path,
+        Err(_) => return Ok(()),
+    };
+    let file = OpenOptions::new().create(true).append(true).open(path)?;
+    for fd in [libc::STDOUT_FILENO, libc::STDERR_FILENO] {
+        // safe as both fds are open for the life of the process and dup2 replaces the target atomically
+        if unsafe { libc::dup2(file.as_raw_fd(), fd) } < 0 {
+            return Err(io::Error::last_os_error());
+        }
+    }
+    Ok(())
+}
+
+/// Tell systemd that the server is ready, this does nothing if it wasn't started by systemd with `Type=notify`
+pub fn notify_ready() {
+    if let Err(err) = sd_notify::notify(false, &[sd_notify::NotifyState::Ready]) {
+        println!("Failed notifying readiness : {}", err);
+    }
+}
+
+/// Handle signals on a new thread, `SIGHUP` re-reads `.env`, reopens the log and calls `reload`, `SIGINT` and `SIGTERM`
+/// remove the pid file and exit
+pub fn handle_signals(pid_file: Option<PidFile>, reload: impl Fn() + Send + 'static) -> io::Result<()> {
+    let mut signals = Signals::new(&[SIGHUP, SIGINT, SIGTERM])?;
+    thread::spawn(move || {
+        for signal in signals.forever() {
+            if signal != SIGHUP {
+                let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Stopping]);
+                drop(pid_file);
+                process::exit(0);
+            }
+            let _ = sd_notify::notify(false, &[sd_notify::NotifyState::Reloading]);
+            reload_env();
+            if let Err(err) = reopen_log() {
+                println!("Failed reopening log : {}", err);
+            }
+            reload();
+            println!("Reloaded config");
+            notify_ready();
+        }
+    });
+    Ok(())
+}
+
+/// Set the variables in `.env` again, overriding the values they were set to before
+fn reload_env() {
+    match dotenv::dotenv_iter() {
+        Ok(vars) => {
+            for var in vars {
+                match var {
+                    Ok((key, value)) => env::set_var(key, value),
+                    Err(err) => println!("Failed reading .env : {}", err),
+                }
+            }
+        }
+        Err(err) => println!("Not reloading .env : {}", err),
+    }
+}
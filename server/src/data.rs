@@ -1,34 +1,93 @@
 use std::{
-    cmp::Reverse,
-    collections::{BTreeMap, HashSet},
-    io::{self, Read},
-    ops::Deref,
-    path::Path,
-    sync::Arc,
-    time::Instant,
+    collections::{btree_map, BTreeMap, BTreeSet, HashMap, HashSet},
+    fs,
+    io::{self, BufRead, Read},
+    ops::{Bound, Deref},
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Arc, Mutex, RwLock,
+    },
+    thread,
+    time::{Duration, Instant},
 };
 
-use chrono::{DateTime, FixedOffset};
-use htmldiff::htmldiff;
-use qp_trie::Trie;
+use chrono::{DateTime, FixedOffset, NaiveDate};
+use rayon::prelude::*;
 use update_repo::{
-    doc::{DocRepo, DocumentVersion},
-    tag::{Tag, TagRepo},
-    update::{Update, UpdateRef, UpdateRepo},
+    doc::{DiffRepo, DocEvent, DocRepo, DocumentVersion, FirstVersion, StoredContent, WriteStats},
+    filter::Filter,
+    repository::EventSink,
+    tag::{Tag, TagEvent, TagRepo},
+    update::{Update, UpdateEvent, UpdateRef, UpdateRefByTimestamp, UpdateRefByUrl, UpdateRepo},
     Url,
 };
 
-type TimestampSubIndex = BTreeMap<DateTime<FixedOffset>, (Arc<Update>, HashSet<Arc<Tag>>)>;
+use crate::{
+    display_rules::DisplayRules,
+    search::{self, BodyIndex, ChangeIndex, Query},
+    url_index::{TimestampSubIndex, UrlIndex},
+    web::DIFF_VERSION,
+    webhooks::Webhooks,
+};
 
+/// The updates and their indexes. It is cloned for the second copy kept by [`SharedData`], the updates and the repos
+/// are shared between the copies
+#[derive(Clone)]
 pub struct Data {
     /// When some data was last changed
     updated_at: Instant,
-    doc_repo: DocRepo,
-    /// All updates in ascending timestamp order
-    updates: Vec<Arc<Update>>,
+    update_repo: Arc<UpdateRepo>,
+    doc_repo: Arc<DocRepo>,
+    /// the diffs between neighbouring doc versions which have been precomputed
+    diff_repo: Arc<DiffRepo>,
+    /// All updates in ascending timestamp and then url order
+    by_timestamp: BTreeMap<UpdateRefByTimestamp, Arc<Update>>,
+    /// All updates in ascending url and then timestamp order
+    by_url: BTreeMap<UpdateRefByUrl<UpdateRef>, Arc<Update>>,
     /// all updates in url and then timestamp order with tags
-    index: Trie<Url, TimestampSubIndex>,
+    index: UrlIndex,
+    /// all updates indexed by the words in their change description
+    change_index: ChangeIndex,
+    /// the latest version of each doc with updates indexed by the words in its text, if the bodies are searchable
+    body_index: Option<BodyIndex>,
     all_tags: Vec<String>,
+    /// the updates in each tag by its name, in timestamp order, for finding the neighbours of an update in a tag
+    by_tag: HashMap<String, BTreeSet<UpdateRefByTimestamp>>,
+    /// counts of updates by [`section`] of the site, for filtering by section
+    sections: BTreeMap<String, usize>,
+    /// the number of urls with updates in each section, and the timestamp of the section's earliest update
+    section_urls: HashMap<String, (usize, DateTime<FixedOffset>)>,
+    /// urls of pages which have been removed from the site, with when they were found to be removed
+    removed: HashMap<Url, DateTime<FixedOffset>>,
+    /// urls whose latest version has a withdrawn notice, with the timestamp of that version and when it was withdrawn
+    withdrawn: HashMap<Url, (DateTime<FixedOffset>, DateTime<FixedOffset>)>,
+    /// the timestamp of the first stored version of each doc and how it was captured, where that was recorded
+    first_versions: HashMap<Url, (DateTime<FixedOffset>, FirstVersion)>,
+    /// urls hidden by an admin from the public listings, search and feeds, their updates and docs are kept
+    hidden: HashSet<Url>,
+    /// the file the hidden urls are kept in, with a url per line
+    hidden_path: PathBuf,
+    /// which urls are displayed, set from the config
+    display_rules: DisplayRules,
+    /// the timestamp of the latest version of each doc with updates, for finding docs which are no longer being captured
+    last_versions: HashMap<Url, DateTime<FixedOffset>>,
+    /// the timestamp of the earliest version of any doc under each url path, keyed by the path without a trailing slash,
+    /// so that browsing can tell which paths existed at a time without walking the repo
+    earliest_versions: HashMap<String, DateTime<FixedOffset>>,
+    /// the docs with the most stored and when they were listed, they are only listed again after `LARGEST_DOCS_TTL`
+    largest_docs: Arc<Mutex<Option<(Instant, Arc<Vec<(Url, u64)>>)>>>,
+    /// the titles of the doc versions which have been shown, a version doesn't change once it is written so they are
+    /// kept rather than the doc being read again for each view
+    titles: Arc<Mutex<HashMap<(Url, DateTime<FixedOffset>), Option<String>>>>,
+    /// counts of updates by UTC day, kept up to date as updates are added for the dashboard
+    daily_activity: BTreeMap<NaiveDate, DayActivity>,
+    /// number of urls which have updates
+    url_count: usize,
+    /// updates by their short id
+    short_ids: HashMap<String, UpdateRef>,
+    /// callbacks subscribed to the updates of single docs
+    webhooks: Arc<Webhooks>,
 }
 
 impl Data {
@@ -36,32 +95,111 @@ impl Data {
         let update_repo = UpdateRepo::new(repo_base.join("url")).unwrap();
         let doc_repo = DocRepo::new(repo_base.join("url")).unwrap();
 
-        let updates: Vec<_> = vec![];
-        let index: Trie<_, BTreeMap<_, _>> = Trie::new();
-
         let tag_repo = TagRepo::new(repo_base.join("tag")).unwrap();
         let all_tags = vec![];
 
         let mut this = Self {
             updated_at: Instant::now(),
-            doc_repo,
-            updates,
-            index,
+            // the one listed from is borrowed while the updates are indexed
+            update_repo: Arc::new(UpdateRepo::new(repo_base.join("url")).unwrap()),
+            doc_repo: Arc::new(doc_repo),
+            diff_repo: Arc::new(DiffRepo::new(repo_base.join("url"), DIFF_VERSION).unwrap()),
+            by_timestamp: BTreeMap::new(),
+            by_url: BTreeMap::new(),
+            index: UrlIndex::default(),
+            change_index: ChangeIndex::default(),
+            body_index: None,
             all_tags,
+            by_tag: HashMap::new(),
+            sections: BTreeMap::new(),
+            section_urls: HashMap::new(),
+            removed: HashMap::new(),
+            withdrawn: HashMap::new(),
+            first_versions: HashMap::new(),
+            last_versions: HashMap::new(),
+            earliest_versions: HashMap::new(),
+            hidden: load_hidden(&repo_base.join("hidden")).unwrap(),
+            hidden_path: repo_base.join("hidden"),
+            display_rules: DisplayRules::default(),
+            largest_docs: Arc::default(),
+            titles: Arc::default(),
+            daily_activity: BTreeMap::new(),
+            url_count: 0,
+            short_ids: HashMap::new(),
+            webhooks: Arc::new(Webhooks::load(repo_base.join("webhooks")).unwrap()),
         };
 
-        for update in update_repo.list_all(&"https://www.gov.uk/".parse().unwrap()).unwrap() {
-            let update = update.unwrap();
-            this.append_update(update);
+        // reading the updates is most of the time spent loading, the top level directories are read in parallel and then
+        // indexed in turn
+        let base: Url = "https://www.gov.uk/".parse().unwrap();
+        let shards: Vec<Vec<Update>> = update_repo
+            .child_urls(&base)
+            .unwrap()
+            .par_iter()
+            .map(|url| update_repo.list_all(url).unwrap().map(Result::unwrap).collect())
+            .collect();
+        let base_updates = update_repo.list_updates(base).unwrap().map(Result::unwrap);
+        for update in shards.into_iter().flatten().chain(base_updates) {
+            this.index_update(update);
+        }
+
+        for removed in this.doc_repo.list_removed(&"https://www.gov.uk/".parse().unwrap()).unwrap() {
+            let (last_version, removed_at) = removed.unwrap();
+            this.removed.insert(last_version.url().clone(), removed_at);
+        }
+        for withdrawn in this.doc_repo.list_withdrawn(&"https://www.gov.uk/".parse().unwrap()).unwrap() {
+            let (latest, withdrawn_at) = withdrawn.unwrap();
+            this.mark_withdrawn(&latest, withdrawn_at);
+        }
+        // matched up with the timestamps of the docs' first versions once those are listed
+        let mut first_versions: HashMap<Url, FirstVersion> = this
+            .doc_repo
+            .list_first_versions(&"https://www.gov.uk/".parse().unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        // each doc's versions are listed from its own directory, so they are also read in parallel
+        let urls: Vec<Url> = this.index.urls().cloned().collect();
+        let doc_repo = &this.doc_repo;
+        this.last_versions = urls
+            .into_par_iter()
+            .filter_map(|url| {
+                let latest = match doc_repo.list_versions(url.clone()).ok()?.next()? {
+                    Ok(latest) => latest,
+                    Err(err) => {
+                        eprintln!("Skipping the versions of {} which can't be listed : {}", url, err);
+                        return None;
+                    }
+                };
+                Some((url, *latest.timestamp()))
+            })
+            .collect();
+
+        // only the names of the versions are listed, the top level directories in parallel as with the updates
+        let base: Url = "https://www.gov.uk/".parse().unwrap();
+        let doc_repo = &this.doc_repo;
+        let mut earliest: Vec<HashMap<Url, DateTime<FixedOffset>>> = doc_repo
+            .child_urls(&base)
+            .unwrap_or_default()
+            .par_iter()
+            .filter_map(|url| Some(earliest_versions(doc_repo.list_all(url).ok()?)))
+            .collect();
+        if let Ok(versions) = doc_repo.list_versions(base) {
+            earliest.push(earliest_versions(versions));
+        }
+        for (url, timestamp) in earliest.into_iter().flatten() {
+            this.note_version(&url, timestamp);
+            if let Some(first) = first_versions.remove(&url) {
+                this.first_versions.insert(url, (timestamp, first));
+            }
         }
-        this.updates.sort_by_key(|u| u.timestamp().to_owned());
 
         for tag in tag_repo.list_tags().unwrap() {
             println!("Tag {}", tag.name());
             this.all_tags.push(tag.name().to_owned());
             let tag = Arc::new(tag);
-            for ur in tag_repo.list_updates_in_tag(&tag).unwrap() {
-                let ur = ur.unwrap();
+            let report = |err| eprintln!("Skipping invalid line in tag {} : {}", tag.name(), err);
+            for ur in tag_repo.list_valid_updates_in_tag(&tag, report).unwrap() {
                 this.add_tag(ur, tag.clone());
             }
         }
@@ -69,14 +207,66 @@ impl Data {
         this
     }
 
-    /// Notifies that a new update has been stored
+    /// Index the text of the latest version of each doc with updates, so that updates can be searched for by it. The
+    /// docs are read in parallel, and then kept indexed as new versions are fetched
+    pub fn index_bodies(&mut self) {
+        let doc_repo = &self.doc_repo;
+        let texts: Vec<(Url, String)> = self
+            .index
+            .urls()
+            .cloned()
+            .collect::<Vec<_>>()
+            .into_par_iter()
+            .filter_map(|url| {
+                let text = latest_doc_text(doc_repo, &url)?;
+                Some((url, text))
+            })
+            .collect();
+        let mut body_index = BodyIndex::default();
+        for (url, text) in texts {
+            body_index.insert(url, &text);
+        }
+        self.body_index = Some(body_index);
+    }
+
+    /// Whether updates can be searched for by the text of their docs
+    pub fn has_body_index(&self) -> bool {
+        self.body_index.is_some()
+    }
+
+    /// Notifies that a new update has been stored, its subscribers are notified by the [`DataUpdater`] as this is
+    /// applied to each copy of the data
     pub fn append_update(&mut self, update: Update) {
+        self.index_update(update);
+    }
+
+    /// Add an update to all the indexes
+    fn index_update(&mut self, update: Update) {
         let update = Arc::new(update);
-        self.updates.push(update.clone());
-        self.index
-            .entry(update.url().clone())
-            .or_insert_with(Default::default)
-            .insert(*update.timestamp(), (update, HashSet::with_capacity(2)));
+        self.by_timestamp
+            .insert(UpdateRefByTimestamp(update.update_ref().clone()), update.clone());
+        self.by_url
+            .insert(UpdateRefByUrl(update.update_ref().clone()), update.clone());
+        self.change_index.insert(update.clone());
+        let section = section(update.url());
+        let day = self.daily_activity.entry(update.timestamp().naive_utc().date()).or_default();
+        day.updates += 1;
+        *day.sections.entry(section.clone()).or_default() += 1;
+        *self.sections.entry(section.clone()).or_default() += 1;
+        self.short_ids
+            .entry(update.update_ref().short_id())
+            .or_insert_with(|| update.update_ref().clone());
+        let (section_urls, oldest) = self
+            .section_urls
+            .entry(section)
+            .or_insert((0, *update.timestamp()));
+        if update.timestamp() < oldest {
+            *oldest = *update.timestamp();
+        }
+        if self.index.insert(update.clone()) {
+            self.url_count += 1;
+            *section_urls += 1;
+        }
         self.updated_at = Instant::now();
     }
 
@@ -85,39 +275,460 @@ impl Data {
             .index
             .get_mut(&ur.url)
             .expect("no tag entry for url")
-            .get_mut(&ur.timestamp)
+            .get_mut(&(ur.timestamp, ur.seq))
             .expect("no tag entry for timestamp");
+        self.by_tag
+            .entry(tag.name().to_owned())
+            .or_default()
+            .insert(UpdateRefByTimestamp(ur));
         tags.insert(tag);
     }
 
-    pub fn list_updates(&self, base: &Url, tag: Option<Tag>) -> Box<dyn Iterator<Item = &Update> + '_> {
+    /// Notifies that an update has been removed from a tag
+    pub fn remove_tag(&mut self, ur: &UpdateRef, tag: &Tag) {
+        if let Some((_update, tags)) = self
+            .index
+            .get_mut(&ur.url)
+            .and_then(|updates| updates.get_mut(&(ur.timestamp, ur.seq)))
+        {
+            tags.retain(|tagged| **tagged != *tag);
+            self.untag(ur, tag.name());
+            self.updated_at = Instant::now();
+        }
+    }
+
+    fn untag(&mut self, ur: &UpdateRef, tag_name: &str) {
+        if let Some(tagged) = self.by_tag.get_mut(tag_name) {
+            tagged.remove(&UpdateRefByTimestamp(ur.clone()));
+            if tagged.is_empty() {
+                self.by_tag.remove(tag_name);
+            }
+        }
+    }
+
+    /// Notifies that an update has been deleted, removing it from all the indexes. The earliest update of its section
+    /// isn't looked for again, so the section's coverage is still from when it was
+    pub fn remove_update(&mut self, ur: &UpdateRef) {
+        let update = match self.by_timestamp.remove(&UpdateRefByTimestamp(ur.clone())) {
+            Some(update) => update,
+            None => return,
+        };
+        self.by_url.remove(&UpdateRefByUrl(ur.clone()));
+        self.change_index.remove(ur);
+        let section = section(update.url());
+        let date = update.timestamp().naive_utc().date();
+        if let Some(day) = self.daily_activity.get_mut(&date) {
+            day.updates -= 1;
+            if let Some(count) = day.sections.get_mut(&section) {
+                *count -= 1;
+                if *count == 0 {
+                    day.sections.remove(&section);
+                }
+            }
+            if day.updates == 0 {
+                self.daily_activity.remove(&date);
+            }
+        }
+        if let Some(count) = self.sections.get_mut(&section) {
+            *count -= 1;
+            if *count == 0 {
+                self.sections.remove(&section);
+            }
+        }
+        if self.short_ids.get(&ur.short_id()) == Some(ur) {
+            self.short_ids.remove(&ur.short_id());
+        }
+        let tag_names: Vec<String> = self
+            .index
+            .get(&ur.url)
+            .and_then(|updates| updates.get(&(ur.timestamp, ur.seq)))
+            .map(|(_, tags)| tags.iter().map(|tag| tag.name().to_owned()).collect())
+            .unwrap_or_default();
+        for tag_name in tag_names {
+            self.untag(ur, &tag_name);
+        }
+        if self.index.remove(ur) {
+            self.url_count -= 1;
+            if let Some((section_urls, _)) = self.section_urls.get_mut(&section) {
+                *section_urls -= 1;
+                if *section_urls == 0 {
+                    self.section_urls.remove(&section);
+                }
+            }
+        }
+        self.updated_at = Instant::now();
+    }
+
+    /// Notifies that the page at a url has been found to be removed
+    pub fn mark_removed(&mut self, url: Url, removed_at: DateTime<FixedOffset>) {
+        self.removed.insert(url, removed_at);
+        self.updated_at = Instant::now();
+    }
+
+    /// Notifies that a version of a doc has a withdrawn notice
+    pub fn mark_withdrawn(&mut self, doc: &DocumentVersion, withdrawn_at: DateTime<FixedOffset>) {
+        self.withdrawn.insert(doc.url().clone(), (*doc.timestamp(), withdrawn_at));
+        self.updated_at = Instant::now();
+    }
+
+    /// Notifies how the first version of a doc was captured
+    pub fn mark_first_version(&mut self, doc: &DocumentVersion, first: FirstVersion) {
+        self.first_versions.insert(doc.url().clone(), (*doc.timestamp(), first));
+        self.updated_at = Instant::now();
+    }
+
+    /// Notifies that a new version of a doc has been stored, so it is no longer removed or withdrawn if it was before. The
+    /// body index is given the `words` of the version, as split by [`search::body_words`], if it is the latest
+    pub fn mark_fetched(&mut self, url: &Url, timestamp: DateTime<FixedOffset>, words: Option<&[String]>) {
+        self.note_version(url, timestamp);
+        let latest = self.last_versions.entry(url.clone()).or_insert(timestamp);
+        let is_latest = *latest <= timestamp;
+        if is_latest {
+            *latest = timestamp;
+        }
+        if let (true, Some(body_index), Some(words)) = (is_latest, &mut self.body_index, words) {
+            if self.index.get(url).is_some() {
+                body_index.insert_words(url.clone(), words.to_vec());
+                self.updated_at = Instant::now();
+            }
+        }
+        if matches!(self.removed.get(url), Some(removed_at) if *removed_at < timestamp) {
+            self.removed.remove(url);
+            self.updated_at = Instant::now();
+        }
+        if matches!(self.withdrawn.get(url), Some((version, _)) if *version < timestamp) {
+            self.withdrawn.remove(url);
+            self.updated_at = Instant::now();
+        }
+    }
+
+    /// Notifies that a version of a doc has been deleted, if it was the latest, the latest of those left is read from
+    /// the repo along with when it was withdrawn
+    pub fn mark_version_deleted(&mut self, doc: &DocumentVersion) {
+        let url = doc.url();
+        if matches!(self.withdrawn.get(url), Some((version, _)) if version == doc.timestamp()) {
+            self.withdrawn.remove(url);
+            self.updated_at = Instant::now();
+        }
+        if self.last_versions.get(url) != Some(doc.timestamp()) {
+            return;
+        }
+        let latest = self
+            .doc_repo
+            .list_versions(url.clone())
+            .ok()
+            .and_then(|mut versions| versions.next())
+            .and_then(Result::ok);
+        match latest {
+            Some(latest) => {
+                self.last_versions.insert(url.clone(), *latest.timestamp());
+                if let Ok(Some(withdrawn_at)) = self.doc_repo.withdrawn_at(&latest) {
+                    self.withdrawn.insert(url.clone(), (*latest.timestamp(), withdrawn_at));
+                }
+            }
+            None => {
+                self.last_versions.remove(url);
+            }
+        }
+        if self.index.get(url).is_some() {
+            if let (Some(body_index), Some(text)) = (&mut self.body_index, latest_doc_text(&self.doc_repo, url)) {
+                body_index.insert(url.clone(), &text);
+            }
+        }
+        self.updated_at = Instant::now();
+    }
+
+    /// Keep the earliest version under each path above a doc up to date with a version of it
+    fn note_version(&mut self, url: &Url, timestamp: DateTime<FixedOffset>) {
+        for path in url_paths(url) {
+            match self.earliest_versions.get_mut(path) {
+                Some(earliest) if *earliest <= timestamp => {}
+                Some(earliest) => *earliest = timestamp,
+                None => {
+                    self.earliest_versions.insert(path.to_owned(), timestamp);
+                }
+            }
+        }
+    }
+
+    /// The timestamp of the latest version of each doc with updates
+    pub fn latest_versions(&self) -> impl Iterator<Item = (&Url, &DateTime<FixedOffset>)> {
+        self.last_versions.iter()
+    }
+
+    /// The docs with updates whose latest version is from before a time, with the timestamp of that version, oldest
+    /// first. Pages which have been removed from the site aren't expected to have new versions so they aren't listed
+    pub fn stale_docs(&self, before: DateTime<FixedOffset>) -> Vec<(&Url, DateTime<FixedOffset>)> {
+        let mut stale: Vec<_> = self
+            .last_versions
+            .iter()
+            .filter(|(url, latest)| {
+                **latest < before
+                    && self.index.get(url).is_some()
+                    && !self.removed.contains_key(*url)
+                    && self.is_displayed(url)
+            })
+            .map(|(url, latest)| (url, *latest))
+            .collect();
+        stale.sort_by(|(url_a, a), (url_b, b)| a.cmp(b).then_with(|| url_a.cmp(url_b)));
+        stale
+    }
+
+    /// Hide a url from the public listings, search and feeds, returns false if it was already hidden
+    pub fn hide(&mut self, url: Url) -> io::Result<bool> {
+        if !self.hidden.insert(url) {
+            return Ok(false);
+        }
+        self.save_hidden()?;
+        self.updated_at = Instant::now();
+        Ok(true)
+    }
+
+    /// Show a hidden url in the public listings again, returns false if it wasn't hidden
+    pub fn unhide(&mut self, url: &Url) -> io::Result<bool> {
+        if !self.hidden.remove(url) {
+            return Ok(false);
+        }
+        self.save_hidden()?;
+        self.updated_at = Instant::now();
+        Ok(true)
+    }
+
+    pub fn is_hidden(&self, url: &Url) -> bool {
+        self.hidden.contains(url)
+    }
+
+    /// Replace the rules of which urls are displayed
+    pub fn set_display_rules(&mut self, display_rules: DisplayRules) {
+        self.display_rules = display_rules;
+        self.updated_at = Instant::now();
+    }
+
+    /// Whether a url is in the public listings, search and feeds, it isn't if it is hidden or the display rules deny it
+    pub fn is_displayed(&self, url: &Url) -> bool {
+        !self.hidden.contains(url) && self.display_rules.displays(url)
+    }
+
+    fn save_hidden(&self) -> io::Result<()> {
+        let mut urls: Vec<&str> = self.hidden.iter().map(Url::as_str).collect();
+        urls.sort_unstable();
+        let mut contents = urls.join("\n");
+        contents.push('\n');
+        let tmp_path = self.hidden_path.with_extension("saving");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(tmp_path, &self.hidden_path)
+    }
+
+    /// When the page at a url was removed from the site, if it has been
+    pub fn removed_at(&self, url: &Url) -> Option<DateTime<FixedOffset>> {
+        self.removed.get(url).copied()
+    }
+
+    /// When the doc at a url was withdrawn, if its latest version has a withdrawn notice
+    pub fn withdrawn_at(&self, url: &Url) -> Option<DateTime<FixedOffset>> {
+        self.withdrawn.get(url).map(|(_, withdrawn_at)| *withdrawn_at)
+    }
+
+    /// How the first version of an update's doc was captured, if that was recorded and the update's diff starts at it,
+    /// which is the diff of the latest update before the first version
+    pub fn first_version(&self, update: &Update) -> Option<FirstVersion> {
+        let (first_timestamp, first) = self.first_versions.get(update.url())?;
+        let (&key, _) = self.get_updates(update.url())?.range(..(*first_timestamp, 0)).next_back()?;
+        (key == (*update.timestamp(), update.update_ref().seq)).then(|| *first)
+    }
+
+    /// The updates matching a filter, in [`newest_first`] order, optionally in a section of the site and with a change
+    /// description matching a query, then they are ordered by relevance first
+    pub fn list_updates(
+        &self,
+        filter: &Filter,
+        section: Option<&str>,
+        change: Option<&Query>,
+        body: Option<&Query>,
+    ) -> Box<dyn Iterator<Item = &Update> + '_> {
+        let base = filter
+            .url_prefix
+            .clone()
+            .unwrap_or_else(|| "https://www.gov.uk/".parse().unwrap());
+        // on the whole site a section is also a prefix, so only the updates under it need to be filtered
+        let section_base: Option<Url> = section
+            .filter(|_| base.as_str().trim_end_matches('/') == "https://www.gov.uk")
+            .and_then(|section| format!("https://www.gov.uk{}", section).parse().ok());
+        let base = section_base.unwrap_or(base);
+        let section = section.map(str::to_owned);
+        let filter = filter.clone();
+        // without a body index nothing matches a body query
+        let body_matches: Option<HashSet<&Url>> =
+            body.map(|body| self.body_index.as_ref().map(|index| index.search(body)).unwrap_or_default());
         let match_tag_and_change = move |u: &&Update| {
-            if let Some(tag) = &tag {
-                if !self.get_tags(u.update_ref()).contains(tag) {
+            if !self.is_displayed(u.url()) || !self.matches_filter(u, &filter) {
+                return false;
+            }
+            if matches!(&body_matches, Some(body_matches) if !body_matches.contains(u.url())) {
+                return false;
+            }
+            if let Some(section) = &section {
+                if !in_section(u.url(), section) {
                     return false;
                 }
             }
             true
         };
 
-        if base.as_str() == "https://www.gov.uk" {
-            let iter = self.updates.iter().rev().map(Deref::deref);
+        if let Some(change) = change {
+            let iter = self.change_index.search(change).into_iter();
+            Box::new(
+                iter.filter(move |u| u.url().as_str().starts_with(base.as_str()))
+                    .filter(match_tag_and_change),
+            )
+        } else if base.as_str() == "https://www.gov.uk" {
+            let iter = self.by_timestamp.values().rev().map(|update| &**update);
             Box::new(iter.filter(match_tag_and_change))
         } else {
-            let mut filtered: Vec<_> = self
-                .index
-                .iter_prefix(base)
-                .flat_map(|(_, map)| map.iter().map(|(_, (update, _))| update))
-                .collect();
-            filtered.sort_by_key(|update| Reverse(update.timestamp()));
-            Box::new(filtered.into_iter().map(Deref::deref).filter(match_tag_and_change))
+            let iter = self.index.newest_first(&base);
+            Box::new(iter.filter(match_tag_and_change))
         }
     }
 
+    /// The displayed update before an update in time which matches a filter, as the updates lists order them
+    pub fn previous_update(&self, update_ref: &UpdateRef, filter: &Filter) -> Option<&Update> {
+        self.neighbour(update_ref, filter, Neighbour::Previous)
+    }
+
+    /// The displayed update after an update in time which matches a filter, as the updates lists order them
+    pub fn next_update(&self, update_ref: &UpdateRef, filter: &Filter) -> Option<&Update> {
+        self.neighbour(update_ref, filter, Neighbour::Next)
+    }
+
+    /// The nearest displayed update to an update in time which matches a filter. It is looked for in whichever has the
+    /// fewest updates of the filter's least used tag, the updates under its url prefix and all of the updates, so that
+    /// a selective filter doesn't have every update up to its next match looked through
+    fn neighbour(&self, update_ref: &UpdateRef, filter: &Filter, direction: Neighbour) -> Option<&Update> {
+        let at = UpdateRefByTimestamp(update_ref.clone());
+        let range = match direction {
+            Neighbour::Previous => (Bound::Unbounded, Bound::Excluded(&at)),
+            Neighbour::Next => (Bound::Excluded(&at), Bound::Unbounded),
+        };
+        let matches = |update: &&Update| self.is_displayed(update.url()) && self.matches_filter(update, filter);
+        let tagged = filter
+            .tags
+            .iter()
+            .map(|tag| self.by_tag.get(tag.name()))
+            .min_by_key(|tagged| tagged.map_or(0, BTreeSet::len));
+        let prefixed = filter
+            .url_prefix
+            .as_ref()
+            .map(|prefix| (prefix, self.index.count(prefix.as_str())));
+
+        match (tagged, prefixed) {
+            // a tag which no update has matches nothing
+            (Some(None), _) => None,
+            (Some(Some(tagged)), prefixed) if prefixed.map_or(true, |(_, count)| tagged.len() <= count) => {
+                let updates = tagged
+                    .range::<UpdateRefByTimestamp, _>(range)
+                    .filter_map(|ur| self.by_timestamp.get(ur))
+                    .map(|update| &**update);
+                direction.find(updates, matches)
+            }
+            (_, Some((prefix, count))) if count < self.by_timestamp.len() => {
+                // the nearest match of each url, and then the nearest of those, in the order of `by_timestamp`
+                fn key(update: &Update) -> (&DateTime<FixedOffset>, &Url, u32) {
+                    (update.timestamp(), update.url(), update.update_ref().seq)
+                }
+                let at = (&update_ref.timestamp, &update_ref.url, update_ref.seq);
+                let nearest = self.index.prefixed(prefix).filter_map(|updates| match direction {
+                    Neighbour::Previous => updates
+                        .range(..=(update_ref.timestamp, u32::MAX))
+                        .map(|(_, (update, _))| &**update)
+                        .filter(|update| key(update) < at)
+                        .rfind(matches),
+                    Neighbour::Next => updates
+                        .range((update_ref.timestamp, 0)..)
+                        .map(|(_, (update, _))| &**update)
+                        .filter(|update| key(update) > at)
+                        .find(matches),
+                });
+                match direction {
+                    Neighbour::Previous => nearest.max_by(|a, b| key(a).cmp(&key(b))),
+                    Neighbour::Next => nearest.min_by(|a, b| key(a).cmp(&key(b))),
+                }
+            }
+            _ => {
+                let updates = self
+                    .by_timestamp
+                    .range::<UpdateRefByTimestamp, _>(range)
+                    .map(|(_, update)| &**update);
+                direction.find(updates, matches)
+            }
+        }
+    }
+
+    /// Whether an update matches a filter, including its tags
+    pub fn matches_filter(&self, update: &Update, filter: &Filter) -> bool {
+        filter.filter_update_ref(update.update_ref())
+            && filter
+                .tags
+                .iter()
+                .all(|tag| self.get_tags(update.update_ref()).contains(tag))
+    }
+
+    /// Find an update by its [`UpdateRef::short_id`]
+    pub fn get_short_id(&self, short_id: &str) -> Option<&UpdateRef> {
+        self.short_ids.get(short_id)
+    }
+
+    /// Updates in timestamp and then url order, starting after an update, by its url and seq, or at a timestamp
+    pub fn updates_after(
+        &self,
+        timestamp: &DateTime<FixedOffset>,
+        after: Option<(&Url, u32)>,
+    ) -> impl Iterator<Item = &Update> {
+        let start = match after {
+            Some((url, seq)) => Bound::Excluded(UpdateRefByTimestamp(UpdateRef {
+                url: url.clone(),
+                timestamp: *timestamp,
+                seq,
+            })),
+            // every url is under the root of the site, so it is first of the urls at a timestamp
+            None => Bound::Included(UpdateRefByTimestamp(UpdateRef {
+                url: "https://www.gov.uk/".parse().unwrap(),
+                timestamp: *timestamp,
+                seq: 0,
+            })),
+        };
+        self.by_timestamp
+            .range((start, Bound::Unbounded))
+            .map(|(_, update)| &**update)
+    }
+
+    /// Updates to urls starting with a prefix, in url and then timestamp order
+    pub fn updates_by_url(&self, prefix: &Url) -> impl Iterator<Item = &Update> {
+        let start = UpdateRefByUrl(UpdateRef {
+            url: prefix.clone(),
+            timestamp: chrono::MIN_DATETIME.into(),
+            seq: 0,
+        });
+        let prefix = prefix.as_str().to_owned();
+        self.by_url
+            .range(start..)
+            .map(|(_, update)| &**update)
+            .take_while(move |update| update.url().as_str().starts_with(&prefix))
+    }
+
     pub fn get_updates(&self, url: &Url) -> Option<&TimestampSubIndex> {
         self.index.get(url)
     }
 
+    /// The number of updates to urls starting with a prefix
+    pub fn count_updates(&self, prefix: &Url) -> usize {
+        self.index.count(prefix.as_str())
+    }
+
+    /// The paths one segment on from a url's path which have updates, with the number of updates under each
+    pub fn child_paths(&self, url: &Url) -> impl Iterator<Item = (&str, usize)> {
+        self.index.children(url)
+    }
+
     pub(crate) fn get_doc_version(&self, url: &Url, timestamp: DateTime<FixedOffset>) -> io::Result<DocumentVersion> {
         self.doc_repo.ensure_version(url.to_owned(), timestamp)
     }
@@ -129,40 +740,579 @@ impl Data {
             .map(|iter| iter.filter_map(Result::ok))
     }
 
-    pub fn read_doc_to_string(&self, doc: &DocumentVersion) -> DocBody {
+    /// The diff between neighbouring versions of a doc, if it has been precomputed
+    pub fn precomputed_diff(&self, from: &DocumentVersion, to: &DocumentVersion) -> io::Result<Option<String>> {
+        self.diff_repo.read(from, to)
+    }
+
+    pub fn read_doc(&self, doc: &DocumentVersion) -> io::Result<StoredContent> {
+        self.doc_repo.read(doc)
+    }
+
+    pub fn webhooks(&self) -> &Webhooks {
+        &self.webhooks
+    }
+
+    /// Where an update came from, such as the path of its email in the outbox
+    pub fn update_source(&self, ur: &UpdateRef) -> io::Result<String> {
+        self.update_repo.get_source(ur)
+    }
+
+    pub fn read_doc_to_string(&self, doc: &DocumentVersion) -> io::Result<DocBody> {
         let mut body = String::new();
-        self.doc_repo.open(doc).unwrap().read_to_string(&mut body).unwrap();
-        DocBody(body)
+        self.doc_repo.open(doc)?.read_to_string(&mut body)?;
+        Ok(DocBody(body))
+    }
+
+    /// Read a doc with its site relative links made absolute, the links are rewritten as it is read so that only the
+    /// rewritten doc is held in memory
+    pub fn read_doc_with_base_url(&self, doc: &DocumentVersion, base_url: &str) -> io::Result<DocBody> {
+        DocBody::read_with_base_url(io::BufReader::new(self.doc_repo.open(doc)?), base_url)
     }
 
     pub fn get_tags(&self, ur: &UpdateRef) -> &HashSet<Arc<Tag>> {
-        &self.index.get(&ur.url).unwrap().get(&ur.timestamp).unwrap().1
+        &self.index.get(&ur.url).unwrap().get(&(ur.timestamp, ur.seq)).unwrap().1
     }
 
     pub fn all_tags(&self) -> impl Iterator<Item = &String> {
         self.all_tags.iter()
     }
 
+    /// The sections of the site which have updates, in order, with how many updates each has
+    pub fn all_sections(&self) -> impl Iterator<Item = (&str, usize)> {
+        self.sections.iter().map(|(section, count)| (section.as_str(), *count))
+    }
+
+    /// What is tracked of each section of the site which has updates, in order, counting the recent updates from a day
+    pub fn coverage(&self, since: NaiveDate) -> Vec<SectionCoverage<'_>> {
+        let mut recent: HashMap<&str, usize> = HashMap::new();
+        for (_, activity) in self.daily_activity(since) {
+            for (section, count) in &activity.sections {
+                *recent.entry(section.as_str()).or_default() += count;
+            }
+        }
+        self.sections
+            .iter()
+            .filter_map(|(section, updates)| {
+                let (urls, oldest) = self.section_urls.get(section)?;
+                Some(SectionCoverage {
+                    section,
+                    urls: *urls,
+                    updates: *updates,
+                    recent_updates: recent.get(section.as_str()).copied().unwrap_or_default(),
+                    oldest: *oldest,
+                })
+            })
+            .collect()
+    }
+
     pub fn updated_at(&self) -> Instant {
         self.updated_at
     }
+
+    /// Update counts for each UTC day from `since`, oldest first
+    pub fn daily_activity(&self, since: NaiveDate) -> btree_map::Range<'_, NaiveDate, DayActivity> {
+        self.daily_activity.range(since..)
+    }
+
+    /// The urls updated since a time, with their latest update, ordered by how many updates they have had in total
+    pub fn most_updated_since(&self, since: DateTime<FixedOffset>) -> Vec<(&Update, usize)> {
+        let mut seen = HashSet::new();
+        let mut urls: Vec<(&Update, usize)> = vec![];
+        for update in self.by_timestamp.values().rev().take_while(|u| *u.timestamp() >= since) {
+            if self.is_displayed(update.url()) && seen.insert(update.url()) {
+                urls.push((update, self.index.get(update.url()).map_or(0, BTreeMap::len)));
+            }
+        }
+        urls.sort_by_key(|(update, count)| (std::cmp::Reverse(*count), std::cmp::Reverse(*update.timestamp())));
+        urls
+    }
+
+    pub fn latest_update(&self) -> Option<&Update> {
+        self.by_timestamp.values().next_back().map(|update| &**update)
+    }
+
+    /// How many doc versions have been written, and how many of those weren't stored as they were duplicates
+    pub fn write_stats(&self) -> io::Result<WriteStats> {
+        self.doc_repo.write_stats()
+    }
+
+    /// The docs with the most bytes stored, largest first. Listing them reads through the whole doc repo, so the list is
+    /// kept for a while
+    pub fn largest_docs(&self) -> io::Result<Arc<Vec<(Url, u64)>>> {
+        let mut cached = self.largest_docs.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        if let Some((listed_at, largest)) = &*cached {
+            if listed_at.elapsed() < LARGEST_DOCS_TTL {
+                return Ok(largest.clone());
+            }
+        }
+        let largest = Arc::new(
+            self.doc_repo
+                .largest_docs(&"https://www.gov.uk/".parse().unwrap(), LARGEST_DOCS_LENGTH)?,
+        );
+        *cached = Some((Instant::now(), largest.clone()));
+        Ok(largest)
+    }
+
+    /// The title of a doc version, the version is only read the first time
+    pub fn doc_title(&self, doc: &DocumentVersion) -> Option<String> {
+        let key = (doc.url().clone(), *doc.timestamp());
+        if let Some(title) = self.titles.lock().unwrap_or_else(|poisoned| poisoned.into_inner()).get(&key) {
+            return title.clone();
+        }
+        // not locked while reading, a version read twice at once gets the same title, and a failed read is tried again
+        let title = self.read_doc_to_string(doc).ok()?.title();
+        self.titles
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .insert(key, title.clone());
+        title
+    }
+
+    /// The version of the doc at a url at a time, and the urls one path segment under it which had docs then, or urls
+    /// under them with docs, with their versions then. Whether the urls under a child had docs then is told from the
+    /// earliest versions kept in memory, so only the url's own directory and its children's are read. Urls which aren't
+    /// displayed are left out, and aren't found themselves
+    pub fn tree_at(
+        &self,
+        url: &Url,
+        at: DateTime<FixedOffset>,
+    ) -> io::Result<(Option<DocumentVersion>, Vec<(Url, Option<DocumentVersion>)>)> {
+        if !self.is_displayed(url) {
+            return Err(io::ErrorKind::NotFound.into());
+        }
+        let version = self.doc_repo.version_at(url, at)?;
+        let mut children = vec![];
+        for child in self.doc_repo.child_urls(url)? {
+            if !self.is_displayed(&child) {
+                continue;
+            }
+            let child_version = self.doc_repo.version_at(&child, at)?;
+            let existed = || {
+                self.earliest_versions
+                    .get(child.as_str().trim_end_matches('/'))
+                    .map_or(false, |earliest| *earliest <= at)
+            };
+            if child_version.is_some() || existed() {
+                children.push((child, child_version));
+            }
+        }
+        Ok((version, children))
+    }
+
+    pub fn update_count(&self) -> usize {
+        self.by_timestamp.len()
+    }
+
+    pub fn url_count(&self) -> usize {
+        self.url_count
+    }
+}
+
+/// The number of updates on a day, in total and by section of the site
+#[derive(Clone, Default)]
+pub struct DayActivity {
+    pub updates: usize,
+    pub sections: HashMap<String, usize>,
+}
+
+/// What is tracked of a section of the site
+#[derive(Debug, PartialEq)]
+pub struct SectionCoverage<'d> {
+    pub section: &'d str,
+    /// the number of urls in the section with updates
+    pub urls: usize,
+    pub updates: usize,
+    /// the number of updates since the day asked about
+    pub recent_updates: usize,
+    /// the timestamp of the section's earliest update
+    pub oldest: DateTime<FixedOffset>,
+}
+
+/// The section of the site a url is in, its first path segment, or first two under `/government` as that is shared by most publications
+pub(crate) fn section(url: &Url) -> String {
+    let mut segments = url.path_segments().into_iter().flatten().filter(|s| !s.is_empty());
+    match segments.next() {
+        Some("government") => match segments.next() {
+            Some(second) => format!("/government/{}", second),
+            None => "/government".to_owned(),
+        },
+        Some(first) => format!("/{}", first),
+        None => "/".to_owned(),
+    }
+}
+
+/// Whether a url is in a section of the site, without allocating its section
+fn in_section(url: &Url, section: &str) -> bool {
+    let path = url.path().trim_end_matches('/');
+    if section == "/" {
+        return path.is_empty();
+    }
+    match path.strip_prefix(section) {
+        Some("") => true,
+        // `/government` is only the section of urls which aren't in one of its subsections
+        Some(rest) => rest.starts_with('/') && section != "/government",
+        None => false,
+    }
+}
+
+/// The text of the latest version of a doc without its markup, if it has a version and it is text
+fn latest_doc_text(doc_repo: &DocRepo, url: &Url) -> Option<String> {
+    let latest = doc_repo.list_versions(url.clone()).ok()?.next()?.ok()?;
+    doc_text(doc_repo, &latest)
+}
+
+fn doc_text(doc_repo: &DocRepo, doc: &DocumentVersion) -> Option<String> {
+    let content = doc_repo.read(doc).ok()?;
+    Some(html_text(content.as_str().ok()?))
+}
+
+/// The earliest of the versions of each doc
+fn earliest_versions(versions: impl Iterator<Item = io::Result<DocumentVersion>>) -> HashMap<Url, DateTime<FixedOffset>> {
+    let mut earliest = HashMap::new();
+    for version in versions.flatten() {
+        let timestamp = earliest.entry(version.url().clone()).or_insert(*version.timestamp());
+        if version.timestamp() < timestamp {
+            *timestamp = *version.timestamp();
+        }
+    }
+    earliest
+}
+
+/// A url's path and each of the paths above it, from the origin, without trailing slashes
+fn url_paths(url: &Url) -> impl Iterator<Item = &str> {
+    let url = url.as_str().trim_end_matches('/');
+    let path_start = url
+        .find("://")
+        .and_then(|scheme_end| url[scheme_end + 3..].find('/').map(|host_len| scheme_end + 3 + host_len))
+        .unwrap_or(url.len());
+    url[path_start..]
+        .match_indices('/')
+        .map(move |(index, _)| &url[..path_start + index])
+        .chain(std::iter::once(url))
+}
+
+/// The text of html outside of its tags
+fn html_text(html: &str) -> String {
+    let mut text = String::with_capacity(html.len());
+    let mut in_tag = false;
+    for c in html.chars() {
+        match c {
+            '<' => in_tag = true,
+            '>' => {
+                in_tag = false;
+                // words either side of a tag, such as in neighbouring paragraphs, aren't joined
+                text.push(' ');
+            }
+            c if !in_tag => text.push(c),
+            _ => {}
+        }
+    }
+    text
+}
+
+/// The order of `Data::by_timestamp`, the url breaks ties so that the order is stable
+fn update_order(update: &Update) -> (&DateTime<FixedOffset>, &Url) {
+    (update.timestamp(), update.url())
+}
+
+/// Which of the neighbours of an update in time to find
+#[derive(Clone, Copy)]
+enum Neighbour {
+    Previous,
+    Next,
+}
+
+impl Neighbour {
+    /// The nearest of updates in timestamp order which matches
+    fn find<'a>(
+        self,
+        mut updates: impl DoubleEndedIterator<Item = &'a Update>,
+        matches: impl FnMut(&&'a Update) -> bool,
+    ) -> Option<&'a Update> {
+        match self {
+            Neighbour::Previous => updates.rfind(matches),
+            Neighbour::Next => updates.find(matches),
+        }
+    }
+}
+
+/// The order of the updates lists, newest first and then by url in reverse, the reverse of `Data::by_timestamp`. Updates
+/// sent in the same email share a timestamp, without the url they could swap places between loads and paging through
+/// them would skip or repeat some
+pub fn newest_first(a: &Update, b: &Update) -> std::cmp::Ordering {
+    update_order(b).cmp(&update_order(a))
+}
+
+/// The number of docs listed by [`Data::largest_docs`]
+const LARGEST_DOCS_LENGTH: usize = 20;
+/// How long the list of the largest docs is kept before it is listed again
+const LARGEST_DOCS_TTL: Duration = Duration::from_secs(60 * 60);
+
+/// The data shared by the readers and the writers. Readers take a snapshot of the data, which no write changes, so a
+/// reader is never held up by a write. There are two copies of the data, a write is applied to the copy readers don't
+/// have and then that copy is swapped in for new snapshots. Once the last snapshot of the other copy is dropped, the
+/// write is applied to it too, ready for the next write. The updates and the repos are shared by the copies, so it is
+/// the indexes which take twice the memory
+pub struct SharedData {
+    /// the copy snapshots are taken of, the lock is only held to clone or to replace it
+    current: RwLock<Arc<Data>>,
+    /// the copy the next write is applied to, writers hold this for the whole of a write
+    next: Mutex<Arc<Data>>,
+}
+
+impl SharedData {
+    /// How long a writer waits between checks for the last snapshot of a copy to be dropped
+    const SNAPSHOT_POLL: Duration = Duration::from_millis(1);
+
+    pub fn new(data: Data) -> Self {
+        Self {
+            current: RwLock::new(Arc::new(data.clone())),
+            next: Mutex::new(Arc::new(data)),
+        }
+    }
+
+    /// The data as it is now, later writes don't change it. Drop it once read, a writer waits for it before it can
+    /// write the next copy
+    pub fn snapshot(&self) -> Arc<Data> {
+        self.current.read().unwrap().clone()
+    }
+
+    /// Apply a write to the data, returning what the write returned for the first copy. The write is applied to each
+    /// copy, so it must do the same to each and anything it does outside of the data, such as saving a file, must be
+    /// safe to do twice
+    pub fn write<R>(&self, write: impl Fn(&mut Data) -> R) -> R {
+        let waiting = Instant::now();
+        let mut next = self.next.lock().unwrap();
+        let writing = Instant::now();
+        let result = write(Arc::get_mut(&mut next).expect("next copy of the data has a snapshot"));
+        let previous = std::mem::replace(&mut *self.current.write().unwrap(), next.clone());
+        *next = previous;
+        record_write_lock(writing - waiting, writing.elapsed());
+        // no more snapshots are taken of the previous copy, so once the last is dropped it is only held here
+        loop {
+            if let Some(data) = Arc::get_mut(&mut next) {
+                write(data);
+                break;
+            }
+            thread::sleep(Self::SNAPSHOT_POLL);
+        }
+        result
+    }
+}
+
+/// Keeps shared data up to date with the writes made to repos. The events of the writes are kept until the writer
+/// flushes them, such as after each email, and then applied in one write of the data, so that the copies of the data
+/// aren't swapped for each event in a burst of emails
+pub struct DataUpdater {
+    data: Arc<SharedData>,
+    pending: Mutex<Vec<DataEvent>>,
+}
+
+/// A write to apply to the data
+enum DataEvent {
+    Update(Update),
+    Removed(Url, DateTime<FixedOffset>),
+    Withdrawn(DocumentVersion, DateTime<FixedOffset>),
+    First(DocumentVersion, FirstVersion),
+    /// with the words of the version if the data has a body index, split out before the data is written
+    Fetched(Url, DateTime<FixedOffset>, Option<Vec<String>>),
+    Tagged(UpdateRef, Arc<Tag>),
+    Untagged(UpdateRef, Tag),
+    Deleted(UpdateRef),
+    VersionDeleted(DocumentVersion),
+}
+
+impl DataEvent {
+    fn apply(&self, data: &mut Data) {
+        match self {
+            DataEvent::Update(update) => data.append_update(update.clone()),
+            DataEvent::Removed(url, timestamp) => data.mark_removed(url.clone(), *timestamp),
+            DataEvent::Withdrawn(doc, timestamp) => data.mark_withdrawn(doc, *timestamp),
+            DataEvent::First(doc, first) => data.mark_first_version(doc, *first),
+            DataEvent::Fetched(url, timestamp, words) => data.mark_fetched(url, *timestamp, words.as_deref()),
+            DataEvent::Tagged(update_ref, tag) => data.add_tag(update_ref.clone(), tag.clone()),
+            DataEvent::Untagged(update_ref, tag) => data.remove_tag(update_ref, tag),
+            DataEvent::Deleted(update_ref) => data.remove_update(update_ref),
+            DataEvent::VersionDeleted(doc) => data.mark_version_deleted(doc),
+        }
+    }
+}
+
+impl DataUpdater {
+    /// The most events kept before they are applied without waiting for a flush
+    const BATCH_LIMIT: usize = 100;
+
+    pub fn new(data: Arc<SharedData>) -> Self {
+        Self {
+            data,
+            pending: Mutex::default(),
+        }
+    }
+
+    /// Apply the events kept since the last flush to the data, and notify the subscribers of the new updates
+    pub fn flush(&self) {
+        let events = std::mem::take(&mut *self.pending.lock().unwrap());
+        if events.is_empty() {
+            return;
+        }
+        let webhooks = self.data.snapshot().webhooks.clone();
+        for event in &events {
+            if let DataEvent::Update(update) = event {
+                webhooks.notify(update);
+            }
+        }
+        self.data.write(|data| {
+            for event in &events {
+                event.apply(data);
+            }
+        });
+    }
+
+    fn push(&self, event: DataEvent) {
+        let mut pending = self.pending.lock().unwrap();
+        pending.push(event);
+        if pending.len() >= Self::BATCH_LIMIT {
+            drop(pending);
+            self.flush();
+        }
+    }
 }
 
+impl Drop for DataUpdater {
+    fn drop(&mut self) {
+        self.flush();
+    }
+}
+
+impl EventSink for DataUpdater {
+    fn on_update(&self, update: &Update, event: &UpdateEvent) {
+        match event {
+            UpdateEvent::Added { .. } => self.push(DataEvent::Update(update.clone())),
+            UpdateEvent::Deleted { .. } => self.push(DataEvent::Deleted(update.update_ref().clone())),
+            UpdateEvent::New { .. } => {}
+        }
+    }
+
+    fn on_doc(&self, doc: &DocumentVersion, event: &DocEvent) {
+        match event {
+            DocEvent::Removed { url, timestamp } => self.push(DataEvent::Removed(url.clone(), *timestamp)),
+            DocEvent::Withdrawn { timestamp, .. } => self.push(DataEvent::Withdrawn(doc.clone(), *timestamp)),
+            DocEvent::First { first, .. } => self.push(DataEvent::First(doc.clone(), *first)),
+            DocEvent::Updated { url, timestamp } => {
+                // the doc has only just been written, so reading it back is quick
+                let data = self.data.snapshot();
+                let words = data
+                    .body_index
+                    .as_ref()
+                    .and_then(|_| doc_text(&data.doc_repo, doc))
+                    .map(|text| search::body_words(&text));
+                self.push(DataEvent::Fetched(url.clone(), *timestamp, words))
+            }
+            DocEvent::Deleted { .. } => self.push(DataEvent::VersionDeleted(doc.clone())),
+            _ => {}
+        }
+    }
+
+    fn on_tag(&self, _tag: &Tag, event: &TagEvent) {
+        match event {
+            TagEvent::UpdateTagged { tag, update_ref } => {
+                self.push(DataEvent::Tagged(update_ref.clone(), Arc::new(tag.clone())))
+            }
+            TagEvent::UpdateUntagged { tag, update_ref } => {
+                self.push(DataEvent::Untagged(update_ref.clone(), tag.clone()))
+            }
+            TagEvent::TagCreated { .. } => {}
+        }
+    }
+}
+
+/// The number of writes of the data by the updaters
+static LOCK_WRITES: AtomicU64 = AtomicU64::new(0);
+/// The total and the longest time the updaters waited for another write to finish, in microseconds
+static LOCK_WAIT_MICROS: AtomicU64 = AtomicU64::new(0);
+static LOCK_WAIT_MAX_MICROS: AtomicU64 = AtomicU64::new(0);
+/// The total and the longest time the updaters took to write a copy of the data, before readers see the write, in
+/// microseconds
+static LOCK_HELD_MICROS: AtomicU64 = AtomicU64::new(0);
+static LOCK_HELD_MAX_MICROS: AtomicU64 = AtomicU64::new(0);
+
+fn record_write_lock(wait: Duration, held: Duration) {
+    let (wait, held) = (wait.as_micros() as u64, held.as_micros() as u64);
+    LOCK_WRITES.fetch_add(1, Ordering::Relaxed);
+    LOCK_WAIT_MICROS.fetch_add(wait, Ordering::Relaxed);
+    LOCK_WAIT_MAX_MICROS.fetch_max(wait, Ordering::Relaxed);
+    LOCK_HELD_MICROS.fetch_add(held, Ordering::Relaxed);
+    LOCK_HELD_MAX_MICROS.fetch_max(held, Ordering::Relaxed);
+}
+
+/// How long the updaters' writes of the data have taken since the server started
+#[derive(Debug, Default, PartialEq)]
+pub struct WriteLockStats {
+    pub writes: u64,
+    pub wait_micros: u64,
+    pub max_wait_micros: u64,
+    pub held_micros: u64,
+    pub max_held_micros: u64,
+}
+
+pub fn write_lock_stats() -> WriteLockStats {
+    WriteLockStats {
+        writes: LOCK_WRITES.load(Ordering::Relaxed),
+        wait_micros: LOCK_WAIT_MICROS.load(Ordering::Relaxed),
+        max_wait_micros: LOCK_WAIT_MAX_MICROS.load(Ordering::Relaxed),
+        held_micros: LOCK_HELD_MICROS.load(Ordering::Relaxed),
+        max_held_micros: LOCK_HELD_MAX_MICROS.load(Ordering::Relaxed),
+    }
+}
+
+#[derive(Default)]
 pub struct DocBody(String);
 
 impl DocBody {
     pub fn diff(&self, other: &Self) -> String {
-        htmldiff(&self.0, &other.0)
+        update_diff::diff(&self.0, &other.0)
     }
 
     pub fn with_base_url(self, base_url: &str) -> Self {
-        let replace = format!("href=\"{}/", base_url);
-        DocBody(self.0.replace("href=\"/", &replace))
+        Self::read_with_base_url(self.0.as_bytes(), base_url).expect("rewriting valid utf-8")
+    }
+
+    /// Read a doc body, making its site relative links absolute as it is read, see [`update_diff::read_with_base_url`]
+    pub fn read_with_base_url(reader: impl BufRead, base_url: &str) -> io::Result<Self> {
+        update_diff::read_with_base_url(reader, base_url).map(DocBody)
     }
 
     pub fn into_inner(self) -> String {
         self.0
     }
+
+    /// The text of the doc's first heading, which on GOV.UK is its title
+    pub fn title(&self) -> Option<String> {
+        let start = self.0.find("<h1")?;
+        let start = start + self.0[start..].find('>')? + 1;
+        let end = start + self.0[start..].find("</h1>")?;
+        let mut title = String::new();
+        let mut in_tag = false;
+        for c in self.0[start..end].chars() {
+            match c {
+                '<' => in_tag = true,
+                '>' => in_tag = false,
+                c if !in_tag => title.push(c),
+                _ => {}
+            }
+        }
+        let title = title
+            .split_whitespace()
+            .collect::<Vec<_>>()
+            .join(" ")
+            .replace("&lt;", "<")
+            .replace("&gt;", ">")
+            .replace("&quot;", "\"")
+            .replace("&#39;", "'")
+            .replace("&amp;", "&");
+        (!title.is_empty()).then(|| title)
+    }
 }
 
 impl Deref for DocBody {
@@ -172,3 +1322,445 @@ impl Deref for DocBody {
         &self.0
     }
 }
+
+/// The urls in the file of hidden urls, there are none if it doesn't exist
+fn load_hidden(path: &Path) -> io::Result<HashSet<Url>> {
+    let mut hidden = HashSet::new();
+    match fs::read_to_string(path) {
+        Ok(contents) => {
+            for line in contents.lines().filter(|line| !line.is_empty()) {
+                match line.parse() {
+                    Ok(url) => {
+                        hidden.insert(url);
+                    }
+                    Err(_) => println!("Ignoring invalid hidden url {:?}", line),
+                }
+            }
+        }
+        Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+        Err(err) => return Err(err),
+    }
+    Ok(hidden)
+}
+
+#[test]
+fn test_section() {
+    let section = |url: &str| section(&url.parse().unwrap());
+    assert_eq!(section("https://www.gov.uk/foreign-travel-advice/france"), "/foreign-travel-advice");
+    assert_eq!(section("https://www.gov.uk/government/publications/a-report"), "/government/publications");
+    assert_eq!(section("https://www.gov.uk/government"), "/government");
+    assert_eq!(section("https://www.gov.uk/"), "/");
+}
+
+#[test]
+fn test_in_section() {
+    for url in [
+        "https://www.gov.uk/foreign-travel-advice/france",
+        "https://www.gov.uk/foreign-travel-advice",
+        "https://www.gov.uk/government/publications/a-report",
+        "https://www.gov.uk/government",
+        "https://www.gov.uk/guidance-for-tourists",
+        "https://www.gov.uk/",
+    ] {
+        let url: Url = url.parse().unwrap();
+        for candidate in ["/", "/foreign-travel-advice", "/government", "/government/publications", "/guidance"] {
+            assert_eq!(in_section(&url, candidate), candidate == section(&url), "{} in {}", url, candidate);
+        }
+    }
+}
+
+#[test]
+fn test_tree_at() {
+    let base = Path::new("tmp/test_tree_at");
+    let _ = std::fs::remove_dir_all(base);
+    TagRepo::new(base.join("tag")).unwrap();
+    let doc_repo = DocRepo::new(base.join("url")).unwrap();
+    let mut write_avoidance_buffer = Vec::new();
+    let mut write = |url: &str, timestamp: &str| {
+        let mut write = doc_repo
+            .create(url.parse().unwrap(), timestamp.parse().unwrap(), &mut write_avoidance_buffer)
+            .unwrap();
+        io::Write::write_all(&mut write, format!("<p>{}</p>", timestamp).as_bytes()).unwrap();
+        write.done().unwrap();
+    };
+    write("https://www.gov.uk/guidance/travel/abroad", "2022-02-17T09:00:00+00:00");
+    write("https://www.gov.uk/news/a", "2022-02-18T09:00:00+00:00");
+    let mut data = Data::load(base);
+    let children = |data: &Data, url: &str, at: &str| -> Vec<String> {
+        let (_, children) = data.tree_at(&url.parse().unwrap(), at.parse().unwrap()).unwrap();
+        children.into_iter().map(|(url, _)| url.path().to_owned()).collect()
+    };
+    assert_eq!(children(&data, "https://www.gov.uk/", "2022-02-17T12:00:00+00:00"), ["/guidance"]);
+    assert_eq!(
+        children(&data, "https://www.gov.uk/", "2022-02-18T12:00:00+00:00"),
+        ["/guidance", "/news"]
+    );
+    assert!(children(&data, "https://www.gov.uk/", "2022-02-16T12:00:00+00:00").is_empty());
+    // versions fetched after loading count too
+    write("https://www.gov.uk/news/b", "2022-02-15T09:00:00+00:00");
+    data.mark_fetched(
+        &"https://www.gov.uk/news/b".parse().unwrap(),
+        "2022-02-15T09:00:00+00:00".parse().unwrap(),
+        None,
+    );
+    assert_eq!(children(&data, "https://www.gov.uk/news/", "2022-02-16T12:00:00+00:00"), ["/news/b"]);
+    // hidden urls aren't browsed
+    data.hide("https://www.gov.uk/news/b".parse().unwrap()).unwrap();
+    assert!(children(&data, "https://www.gov.uk/news/", "2022-02-16T12:00:00+00:00").is_empty());
+    let hidden = data.tree_at(&"https://www.gov.uk/news/b".parse().unwrap(), "2022-02-16T12:00:00+00:00".parse().unwrap());
+    assert_eq!(hidden.unwrap_err().kind(), io::ErrorKind::NotFound);
+}
+
+#[test]
+fn test_first_version_labels() {
+    let base = Path::new("tmp/test_first_version_labels");
+    let _ = std::fs::remove_dir_all(base);
+    let update_repo = UpdateRepo::new(base.join("url")).unwrap();
+    let doc_repo = DocRepo::new(base.join("url")).unwrap();
+    TagRepo::new(base.join("tag")).unwrap();
+    let url: Url = "https://www.gov.uk/guidance/a".parse().unwrap();
+    let create = |timestamp: &str| {
+        update_repo
+            .create(url.clone(), timestamp.parse().unwrap(), "Updated")
+            .unwrap()
+            .into_inner()
+    };
+    // the doc was only captured after its second update
+    let earlier = create("2022-02-16T09:00:00+00:00");
+    let captured = create("2022-02-17T09:00:00+00:00");
+    let mut write_avoidance_buffer = Vec::new();
+    let mut write = doc_repo
+        .create(url.clone(), "2022-02-17T09:05:00+00:00".parse().unwrap(), &mut write_avoidance_buffer)
+        .unwrap();
+    io::Write::write_all(&mut write, b"<p>a</p>").unwrap();
+    let first = write.done().unwrap().into_inner();
+    doc_repo.mark_first_version(&first, FirstVersion::Captured).unwrap();
+    let later = create("2022-02-18T09:00:00+00:00");
+
+    let data = Data::load(base);
+    assert_eq!(data.first_version(&earlier), None);
+    assert_eq!(data.first_version(&captured), Some(FirstVersion::Captured));
+    assert_eq!(data.first_version(&later), None);
+}
+
+#[test]
+fn test_neighbours_in_selective_filters() {
+    let base = Path::new("tmp/test_neighbours_in_selective_filters");
+    let _ = std::fs::remove_dir_all(base);
+    let update_repo = UpdateRepo::new(base.join("url")).unwrap();
+    TagRepo::new(base.join("tag")).unwrap();
+    let mut data = Data::load(base);
+    let at = |page: &str, day: u32| UpdateRef {
+        url: format!("https://www.gov.uk/guidance/{}", page).parse().unwrap(),
+        timestamp: format!("2022-02-{:02}T09:00:00+00:00", day).parse().unwrap(),
+        seq: 0,
+    };
+    // many updates which don't match between those which do, and some at the same time as them
+    for day in 1..=20 {
+        for page in ["a", "b", "c"] {
+            let UpdateRef { url, timestamp, .. } = at(page, day);
+            data.append_update(update_repo.create(url, timestamp, "Updated").unwrap().into_inner());
+        }
+    }
+    let brexit = Arc::new(Tag::new("brexit".to_owned()));
+    data.add_tag(at("b", 3), brexit.clone());
+    data.add_tag(at("a", 10), brexit.clone());
+    data.add_tag(at("c", 10), brexit.clone());
+    fn refs(update: Option<&Update>) -> Option<String> {
+        update.map(|update| format!("{} {}", update.timestamp().format("%d"), update.url().path()))
+    }
+    let filter = |terms: &[&str]| Filter::parse_terms(terms).unwrap();
+
+    let tagged = filter(&["#brexit"]);
+    assert_eq!(refs(data.next_update(&at("b", 3), &tagged)).as_deref(), Some("10 /guidance/a"));
+    assert_eq!(refs(data.next_update(&at("a", 10), &tagged)).as_deref(), Some("10 /guidance/c"));
+    assert_eq!(refs(data.previous_update(&at("a", 10), &tagged)).as_deref(), Some("03 /guidance/b"));
+    assert_eq!(refs(data.next_update(&at("c", 10), &tagged)), None);
+    // from an update which isn't in the tag, as when the filter is carried from a page of another
+    assert_eq!(refs(data.previous_update(&at("a", 5), &tagged)).as_deref(), Some("03 /guidance/b"));
+    assert_eq!(refs(data.next_update(&at("a", 5), &tagged)).as_deref(), Some("10 /guidance/a"));
+    assert_eq!(refs(data.next_update(&at("a", 5), &filter(&["#unused"]))), None);
+
+    // the updates to one page, ordered by url among those at the same time
+    let page = filter(&["https://www.gov.uk/guidance/b"]);
+    assert_eq!(refs(data.previous_update(&at("a", 5), &page)).as_deref(), Some("04 /guidance/b"));
+    assert_eq!(refs(data.next_update(&at("a", 5), &page)).as_deref(), Some("05 /guidance/b"));
+    assert_eq!(refs(data.previous_update(&at("c", 5), &page)).as_deref(), Some("05 /guidance/b"));
+    assert_eq!(refs(data.next_update(&at("b", 20), &page)), None);
+    let both = filter(&["#brexit", "https://www.gov.uk/guidance/c"]);
+    assert_eq!(refs(data.next_update(&at("b", 3), &both)).as_deref(), Some("10 /guidance/c"));
+
+    // untagged and deleted updates are left out
+    data.remove_tag(&at("a", 10), &brexit);
+    assert_eq!(refs(data.next_update(&at("b", 3), &tagged)).as_deref(), Some("10 /guidance/c"));
+    data.remove_update(&at("c", 10));
+    assert_eq!(refs(data.next_update(&at("b", 3), &tagged)), None);
+}
+
+#[test]
+fn test_update_order_indexes() {
+    let base = Path::new("tmp/test_update_order_indexes");
+    let _ = std::fs::remove_dir_all(base);
+    let update_repo = UpdateRepo::new(base.join("url")).unwrap();
+    TagRepo::new(base.join("tag")).unwrap();
+    let create = |url: &str, timestamp: &str| {
+        update_repo
+            .create(url.parse().unwrap(), timestamp.parse().unwrap(), "Updated")
+            .unwrap()
+            .into_inner()
+    };
+    create("https://www.gov.uk/guidance/b", "2022-02-17T09:00:00+00:00");
+    create("https://www.gov.uk/guidance/a", "2022-02-18T09:00:00+00:00");
+    let mut data = Data::load(base);
+    // appended out of order, and at the same time as a loaded update
+    data.append_update(create("https://www.gov.uk/guidance/c", "2022-02-18T09:00:00+00:00"));
+    data.append_update(create("https://www.gov.uk/news/a", "2022-02-16T09:00:00+00:00"));
+
+    fn refs<'a>(updates: impl Iterator<Item = &'a Update>) -> Vec<String> {
+        updates
+            .map(|update| format!("{} {}", update.timestamp().format("%d"), update.url().path()))
+            .collect()
+    }
+    let at: DateTime<FixedOffset> = "2022-02-18T09:00:00+00:00".parse().unwrap();
+    assert_eq!(
+        refs(data.updates_after(&chrono::MIN_DATETIME.into(), None)),
+        ["16 /news/a", "17 /guidance/b", "18 /guidance/a", "18 /guidance/c"]
+    );
+    assert_eq!(
+        refs(data.updates_after(&at, None)),
+        ["18 /guidance/a", "18 /guidance/c"]
+    );
+    assert_eq!(
+        refs(data.updates_after(&at, Some((&"https://www.gov.uk/guidance/a".parse().unwrap(), 0)))),
+        ["18 /guidance/c"]
+    );
+    assert_eq!(
+        refs(data.list_updates(&Filter::default(), None, None, None)),
+        ["18 /guidance/c", "18 /guidance/a", "17 /guidance/b", "16 /news/a"]
+    );
+    assert_eq!(
+        refs(data.updates_by_url(&"https://www.gov.uk/guidance/".parse().unwrap())),
+        ["18 /guidance/a", "17 /guidance/b", "18 /guidance/c"]
+    );
+    let guidance_a = UpdateRef {
+        url: "https://www.gov.uk/guidance/a".parse().unwrap(),
+        timestamp: at,
+        seq: 0,
+    };
+    assert_eq!(
+        refs(data.previous_update(&guidance_a, &Filter::default()).into_iter()),
+        ["17 /guidance/b"]
+    );
+    assert_eq!(
+        refs(data.next_update(&guidance_a, &Filter::default()).into_iter()),
+        ["18 /guidance/c"]
+    );
+    let news = Filter::parse_terms(["https://www.gov.uk/news"]).unwrap();
+    assert_eq!(
+        refs(data.previous_update(&guidance_a, &news).into_iter()),
+        ["16 /news/a"]
+    );
+    assert!(data.next_update(&guidance_a, &news).is_none());
+    assert_eq!(data.latest_update().unwrap().url().path(), "/guidance/c");
+    assert_eq!(data.update_count(), 4);
+
+    let coverage = data.coverage("2022-02-17".parse().unwrap());
+    assert_eq!(
+        coverage,
+        [
+            SectionCoverage {
+                section: "/guidance",
+                urls: 3,
+                updates: 3,
+                recent_updates: 3,
+                oldest: "2022-02-17T09:00:00+00:00".parse().unwrap(),
+            },
+            SectionCoverage {
+                section: "/news",
+                urls: 1,
+                updates: 1,
+                recent_updates: 0,
+                oldest: "2022-02-16T09:00:00+00:00".parse().unwrap(),
+            },
+        ]
+    );
+}
+
+#[test]
+fn test_hidden_urls() {
+    let base = Path::new("tmp/test_hidden_urls");
+    let _ = std::fs::remove_dir_all(base);
+    let update_repo = UpdateRepo::new(base.join("url")).unwrap();
+    TagRepo::new(base.join("tag")).unwrap();
+    for (url, timestamp) in [
+        ("https://www.gov.uk/guidance/a", "2022-02-17T09:00:00+00:00"),
+        ("https://www.gov.uk/guidance/typo", "2022-02-18T09:00:00+00:00"),
+    ] {
+        update_repo
+            .create(url.parse().unwrap(), timestamp.parse().unwrap(), "Updated")
+            .unwrap();
+    }
+    let typo: Url = "https://www.gov.uk/guidance/typo".parse().unwrap();
+    let listed = |data: &Data| {
+        data.list_updates(&Filter::default(), None, None, None)
+            .map(|update| update.url().path().to_owned())
+            .collect::<Vec<_>>()
+    };
+
+    let mut data = Data::load(base);
+    assert!(data.hide(typo.clone()).unwrap());
+    assert!(!data.hide(typo.clone()).unwrap());
+    assert_eq!(listed(&data), ["/guidance/a"]);
+    // the updates are kept
+    assert!(data.get_updates(&typo).is_some());
+
+    // hidden urls are kept in the repo
+    let mut data = Data::load(base);
+    assert!(data.is_hidden(&typo));
+    assert_eq!(listed(&data), ["/guidance/a"]);
+    assert!(data.unhide(&typo).unwrap());
+    assert!(!data.unhide(&typo).unwrap());
+    assert_eq!(listed(&Data::load(base)), ["/guidance/typo", "/guidance/a"]);
+
+    data.set_display_rules(DisplayRules::parse("deny:/guidance/ allow:/guidance/t"));
+    assert_eq!(listed(&data), ["/guidance/typo"]);
+}
+
+#[test]
+fn test_data_updater_applies_writes_when_flushed() {
+    let base = Path::new("tmp/test_data_updater_applies_writes_when_flushed");
+    let _ = std::fs::remove_dir_all(base);
+    TagRepo::new(base.join("tag")).unwrap();
+    let data = Arc::new(SharedData::new(Data::load(base)));
+    let updater = Arc::new(DataUpdater::new(data.clone()));
+    let update_repo = UpdateRepo::new(base.join("url")).unwrap().with_event_sink(updater.clone());
+    let writes = write_lock_stats().writes;
+    for timestamp in ["2022-02-17T09:00:00+00:00", "2022-02-18T09:00:00+00:00"] {
+        update_repo
+            .create(
+                "https://www.gov.uk/guidance/a".parse().unwrap(),
+                timestamp.parse().unwrap(),
+                "Updated",
+            )
+            .unwrap();
+    }
+    let before = data.snapshot();
+    assert_eq!(before.update_count(), 0);
+    // the write is seen by new snapshots while the one taken before it is unchanged, the flush returns once that is
+    // dropped and the write is applied to its copy too
+    let flushing = thread::spawn(move || updater.flush());
+    while data.snapshot().update_count() != 2 {
+        thread::sleep(Duration::from_millis(1));
+    }
+    assert_eq!(before.update_count(), 0);
+    assert!(!flushing.is_finished());
+    drop(before);
+    flushing.join().unwrap();
+    assert!(write_lock_stats().writes > writes);
+    // each write swaps the copies, both have had every write applied
+    let url: Url = "https://www.gov.uk/guidance/a".parse().unwrap();
+    let removed_at = "2022-02-19T09:00:00+00:00".parse().unwrap();
+    data.write(|data| data.mark_removed(url.clone(), removed_at));
+    for _ in 0..2 {
+        assert_eq!(data.snapshot().update_count(), 2);
+        assert_eq!(data.snapshot().removed_at(&url), Some(removed_at));
+        data.write(|_| {});
+    }
+}
+
+#[test]
+fn test_deletes_are_applied() {
+    use update_repo::{doc::content::DocContent, tracker::Tracker};
+
+    let base = Path::new("tmp/test_deletes_are_applied");
+    let _ = std::fs::remove_dir_all(base);
+    TagRepo::new(base.join("tag")).unwrap();
+    let data = Arc::new(SharedData::new(Data::load(base)));
+    let updater = Arc::new(DataUpdater::new(data.clone()));
+    let tracker = Tracker::new(base).unwrap().with_event_sink(updater.clone());
+    let url: Url = "https://www.gov.uk/guidance/a".parse().unwrap();
+    let capture = |url: &str, timestamp: &str, body: &str, change: Option<&str>| {
+        let content = DocContent::Other(body.as_bytes().to_vec());
+        tracker
+            .capture(url.parse().unwrap(), timestamp.parse().unwrap(), Some(&content), change, ["visas"])
+            .unwrap()
+    };
+    let good = capture(url.as_str(), "2022-02-17T09:00:00+00:00", "<main>Visas</main>", Some("Added visas"));
+    // as if from a mis-parsed email
+    let bogus = capture("https://www.gov.uk/news/b", "2022-02-18T09:00:00+00:00", "<main>News</main>", Some("Bogus"));
+    let bogus_version = capture(url.as_str(), "2022-02-18T09:00:00+00:00", "<main>News</main>", None);
+    updater.flush();
+    assert_eq!(data.snapshot().update_count(), 2);
+
+    tracker.delete_update(bogus.update.unwrap().update_ref()).unwrap();
+    tracker.doc_repo().delete_version(&bogus_version.doc.unwrap()).unwrap();
+    updater.flush();
+    let good_update = good.update.unwrap();
+    // both copies have had the deletes applied
+    for _ in 0..2 {
+        let snapshot = data.snapshot();
+        assert_eq!(snapshot.update_count(), 1);
+        assert_eq!(snapshot.url_count(), 1);
+        assert_eq!(snapshot.count_updates(&"https://www.gov.uk/".parse().unwrap()), 1);
+        assert_eq!(snapshot.all_sections().collect::<Vec<_>>(), [("/guidance", 1)]);
+        assert_eq!(snapshot.latest_update(), Some(&good_update));
+        assert_eq!(snapshot.get_tags(good_update.update_ref()).len(), 1);
+        assert_eq!(
+            snapshot.latest_versions().collect::<HashMap<_, _>>()[&url],
+            good.doc.as_ref().unwrap().timestamp()
+        );
+        drop(snapshot);
+        data.write(|_| {});
+    }
+}
+
+#[test]
+fn test_html_text() {
+    assert_eq!(
+        html_text(r#"<main><h1 class="title">Visas</h1><p>Apply <a href="/apply">online</a></p></main>"#)
+            .split_whitespace()
+            .collect::<Vec<_>>(),
+        ["Visas", "Apply", "online"]
+    );
+}
+
+#[test]
+fn test_with_base_url() {
+    let body = concat!(
+        r#"<main><a href="/guidance">Guidance</a> <a class=x href='/travel'>Travel</a> <img src="/image.png">"#,
+        r#"<a href="//example.org/x">Elsewhere</a> <a href="https://www.gov.uk/abroad">Abroad</a>"#,
+        r#" <a data-href="/not-a-link">Not</a> <p>x=1</p> <a href="/">Home</a> <a href=""#,
+    );
+    let expected = concat!(
+        r#"<main><a href="https://www.gov.uk/guidance">Guidance</a> <a class=x href='https://www.gov.uk/travel'>Travel</a> <img src="https://www.gov.uk/image.png">"#,
+        r#"<a href="//example.org/x">Elsewhere</a> <a href="https://www.gov.uk/abroad">Abroad</a>"#,
+        r#" <a data-href="/not-a-link">Not</a> <p>x=1</p> <a href="https://www.gov.uk/">Home</a> <a href=""#,
+    );
+    assert_eq!(
+        DocBody(body.to_owned())
+            .with_base_url("https://www.gov.uk")
+            .into_inner(),
+        expected
+    );
+    // the links are found across the reader's buffer boundaries
+    for capacity in 1..8 {
+        let reader = io::BufReader::with_capacity(capacity, body.as_bytes());
+        assert_eq!(
+            DocBody::read_with_base_url(reader, "https://www.gov.uk")
+                .unwrap()
+                .into_inner(),
+            expected
+        );
+    }
+}
+
+#[test]
+fn test_doc_title() {
+    let title = |body: &str| DocBody(body.to_owned()).title();
+    assert_eq!(
+        title("<main><h1 class=\"gem-c-title__text\">\n  Travel <span>abroad</span> &amp; return\n</h1><h1>Other</h1></main>"),
+        Some("Travel abroad & return".to_owned())
+    );
+    assert_eq!(title("<main><h1> </h1></main>"), None);
+    assert_eq!(title("<main><p>No heading</p></main>"), None);
+}
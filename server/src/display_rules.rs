@@ -0,0 +1,105 @@
+//! Rules for which urls are displayed in the public listings, search, feeds and APIs, such as to leave the binaries
+//! under `/government/uploads/` out of the updates list. The rules are set in `DISPLAY_RULES`, separated by whitespace,
+//! each `allow:` or `deny:` followed by a pattern of the start of a url's path where `*` matches any characters:
+//!
+//! ```text
+//! DISPLAY_RULES="deny:/government/uploads/ allow:/government/uploads/*/travel-advice-"
+//! ```
+//!
+//! The rule with the longest pattern matching a url's path decides whether it is displayed, and urls which no rule
+//! matches are displayed
+
+use update_repo::Url;
+
+#[derive(Debug, Default, Clone)]
+pub struct DisplayRules {
+    rules: Vec<Rule>,
+}
+
+#[derive(Debug, Clone, PartialEq)]
+struct Rule {
+    allow: bool,
+    pattern: String,
+}
+
+impl DisplayRules {
+    /// The rules set in `DISPLAY_RULES`, invalid rules are skipped
+    pub fn from_env() -> Self {
+        dotenv::var("DISPLAY_RULES").map_or_else(|_| Self::default(), |rules| Self::parse(&rules))
+    }
+
+    pub fn parse(rules: &str) -> Self {
+        let rules = rules
+            .split_whitespace()
+            .filter_map(|rule| {
+                let parsed = match rule.split_once(':') {
+                    Some(("allow", pattern)) => Some(Rule::new(true, pattern)),
+                    Some(("deny", pattern)) => Some(Rule::new(false, pattern)),
+                    _ => None,
+                };
+                if parsed.is_none() {
+                    println!("Ignoring invalid display rule {:?}", rule);
+                }
+                parsed
+            })
+            .collect();
+        Self { rules }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.rules.is_empty()
+    }
+
+    /// Whether a url is displayed
+    pub fn displays(&self, url: &Url) -> bool {
+        let path = url.path();
+        self.rules
+            .iter()
+            .filter(|rule| rule.matches(path))
+            .max_by_key(|rule| rule.pattern.len())
+            .map_or(true, |rule| rule.allow)
+    }
+}
+
+impl Rule {
+    fn new(allow: bool, pattern: &str) -> Self {
+        Self {
+            allow,
+            pattern: pattern.to_owned(),
+        }
+    }
+
+    /// Whether the pattern matches the start of a path
+    fn matches(&self, path: &str) -> bool {
+        let mut parts = self.pattern.split('*');
+        let mut rest = match path.strip_prefix(parts.next().unwrap_or_default()) {
+            Some(rest) => rest,
+            None => return false,
+        };
+        for part in parts {
+            match rest.find(part) {
+                Some(index) => rest = &rest[index + part.len()..],
+                None => return false,
+            }
+        }
+        true
+    }
+}
+
+#[test]
+fn test_display_rules() {
+    let rules = DisplayRules::parse("deny:/government/uploads/ allow:/government/uploads/*/travel-advice- nonsense");
+    let displays = |url: &str| rules.displays(&format!("https://www.gov.uk{}", url).parse().unwrap());
+    assert!(displays("/foreign-travel-advice/france"));
+    assert!(displays("/government/publications/a-report"));
+    assert!(!displays(
+        "/government/uploads/system/uploads/attachment_data/file/1/report.pdf"
+    ));
+    assert!(displays(
+        "/government/uploads/system/uploads/attachment_data/file/2/travel-advice-france.pdf"
+    ));
+    assert!(displays("/government/uploads"));
+
+    assert!(DisplayRules::parse("").is_empty());
+    assert!(DisplayRules::default().displays(&"https://www.gov.uk/government/uploads/x.pdf".parse().unwrap()));
+}
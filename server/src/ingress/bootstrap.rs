@@ -0,0 +1,187 @@
+//! Starting to track pages which have no updates yet. GOV.UK publishes the change history of each page on the page, so
+//! the updates from before the page was tracked can be written from it, along with the current version of the page
+
+use std::{collections::BTreeSet, path::Path};
+
+use anyhow::{Context, Result};
+use update_repo::check;
+use url::Url;
+
+use super::{fetch::Fetcher, FetchDocs, NewRepoWriter};
+
+/// What a bootstrap wrote to the repo
+#[derive(Debug, Default, PartialEq, Eq)]
+pub struct Report {
+    pub pages: usize,
+    /// updates written from the pages' change histories
+    pub updates: usize,
+    /// updates in the pages' change histories which were already in the repo
+    pub existing_updates: usize,
+    /// versions of the pages and their attachments which were fetched
+    pub docs: usize,
+    /// pages which couldn't be fetched, or whose history couldn't be written
+    pub failed: Vec<Url>,
+}
+
+/// Bootstrap the tracking of each of `urls` in the repo at `new_repo_path`, writing the updates in their change history
+/// and capturing their current content. It writes to the repo without updating a running server, which shows the
+/// updates once it is restarted
+pub fn run(new_repo_path: &Path, urls: &[Url], fetcher: &dyn Fetcher) -> Result<Report> {
+    check::init_repo(new_repo_path).context("Initialising repo")?;
+    let writer = NewRepoWriter::new(new_repo_path, None)?;
+    let mut report = Report::default();
+    for url in urls {
+        match bootstrap_page(&writer, url, fetcher, &mut report) {
+            Ok(()) => report.pages += 1,
+            Err(err) => {
+                println!("Bootstrapping {} failed : {}", url, err);
+                report.failed.push(url.clone());
+            }
+        }
+    }
+    Ok(report)
+}
+
+fn bootstrap_page(writer: &NewRepoWriter, url: &Url, fetcher: &dyn Fetcher, report: &mut Report) -> Result<()> {
+    let mut fetch = FetchDocs::fetch(url.clone(), fetcher);
+    for res in &mut fetch {
+        let (path, content) = res?;
+
+        let mut doc_url = url.clone();
+        doc_url.set_path(path.to_str().unwrap());
+        // only the page itself has a change history, its attachments are fetched after it
+        if doc_url == *url {
+            for history_update in content.history().unwrap_or_default() {
+                if writer.write_history_update(url, (*history_update.date()).into(), history_update.summary())? {
+                    report.updates += 1;
+                } else {
+                    report.existing_updates += 1;
+                }
+            }
+        }
+        let ts = chrono::Utc::now();
+        writer
+            .write_doc(doc_url, ts.into(), &content)
+            .context("Writing to doc repo")?;
+        report.docs += 1;
+    }
+    if fetch.removed.contains(url) {
+        anyhow::bail!("The page has been removed");
+    }
+    Ok(())
+}
+
+/// The pages on GOV.UK linked to from the page at `prefix` which are under it, to bootstrap a section of the site from
+/// its index page. The page itself is first
+pub fn pages_under(prefix: &Url, fetcher: &dyn Fetcher) -> Result<Vec<Url>> {
+    let doc = fetcher
+        .fetch(prefix)?
+        .with_context(|| format!("{} has been removed", prefix))?;
+    let html = String::from_utf8_lossy(doc.content.as_bytes());
+    let prefix_path = prefix.path().trim_end_matches('/');
+    let mut pages = BTreeSet::new();
+    for href in html.split("href=\"").skip(1).filter_map(|rest| rest.split('"').next()) {
+        if let Ok(mut page) = prefix.join(href) {
+            page.set_fragment(None);
+            page.set_query(None);
+            let path = page.path();
+            if page.host_str() == Some("www.gov.uk")
+                && path.starts_with(prefix_path)
+                && path[prefix_path.len()..].starts_with('/')
+                && path.len() > prefix_path.len() + 1
+            {
+                pages.insert(page);
+            }
+        }
+    }
+    Ok(std::iter::once(prefix.clone()).chain(pages).collect())
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use anyhow::Result;
+    use chrono::{DateTime, Utc};
+    use update_repo::{
+        doc::{
+            content::{Doc, DocContent, DocUpdate},
+            DocRepo,
+        },
+        update::UpdateRepo,
+    };
+    use url::Url;
+
+    use super::{pages_under, run, Report};
+    use crate::ingress::fetch::Fetcher;
+
+    /// Serves a section index page linking to its pages, each of which has a change history of two updates
+    struct HistoryFetcher;
+
+    impl Fetcher for HistoryFetcher {
+        fn fetch(&self, url: &Url) -> Result<Option<Doc>> {
+            let date = |date: &str| date.parse::<DateTime<Utc>>().unwrap();
+            let content = if url.path() == "/guidance" {
+                DocContent::DiffableHtml(
+                    r#"<main><a href="/guidance/travel-abroad">Travel</a> <a href="/guidance/travel-home#top">Home</a> <a href="/guidance">Guidance</a> <a href="/news/travel">News</a> <a href="https://example.org/guidance/elsewhere">Elsewhere</a></main>"#.to_owned(),
+                    vec![],
+                    vec![],
+                    None,
+                )
+            } else {
+                DocContent::DiffableHtml(
+                    format!("<main><h1>{}</h1></main>", url.path()),
+                    vec![],
+                    vec![
+                        DocUpdate::new(date("2022-02-17T09:30:00Z"), "Added visa requirements"),
+                        DocUpdate::new(date("2021-01-01T00:00:00Z"), "First published."),
+                    ],
+                    None,
+                )
+            };
+            Ok(Some(Doc {
+                url: url.clone(),
+                content,
+            }))
+        }
+    }
+
+    #[test]
+    fn test_bootstrap() {
+        let base = std::path::Path::new("tmp/test_bootstrap");
+        let _ = fs::remove_dir_all(base);
+        fs::create_dir_all(base.join("url").join("www.gov.uk")).unwrap();
+
+        let prefix: Url = "https://www.gov.uk/guidance".parse().unwrap();
+        let pages = pages_under(&prefix, &HistoryFetcher).unwrap();
+        let travel_abroad: Url = "https://www.gov.uk/guidance/travel-abroad".parse().unwrap();
+        let travel_home: Url = "https://www.gov.uk/guidance/travel-home".parse().unwrap();
+        assert_eq!(pages, [prefix, travel_abroad.clone(), travel_home]);
+
+        let report = run(base, &pages[1..], &HistoryFetcher).unwrap();
+        assert_eq!(
+            report,
+            Report {
+                pages: 2,
+                updates: 4,
+                existing_updates: 0,
+                docs: 2,
+                failed: vec![],
+            }
+        );
+        let update_repo = UpdateRepo::new(base.join("url")).unwrap();
+        let update = update_repo
+            .get_update(
+                travel_abroad.clone().into(),
+                "2022-02-17T09:30:00+00:00".parse().unwrap(),
+            )
+            .unwrap();
+        assert_eq!(update.change(), "Added visa requirements");
+        let doc_repo = DocRepo::new(base.join("url")).unwrap();
+        assert_eq!(doc_repo.list_versions(travel_abroad.clone().into()).unwrap().count(), 1);
+
+        // bootstrapping again only fetches the pages
+        let report = run(base, &pages[1..2], &HistoryFetcher).unwrap();
+        assert_eq!((report.updates, report.existing_updates), (0, 2));
+    }
+}
@@ -0,0 +1,89 @@
+//! Polling the GOV.UK Content API for the change history of tracked pages, so that updates whose email was lost are
+//! still written. The pages are polled on a [`CrawlSchedule`](super::crawl::CrawlSchedule) of their own, and only the
+//! changes newer than a page's latest update are written, the older history of a page is left to a bootstrap
+
+use anyhow::{format_err, Context, Result};
+use chrono::{DateTime, FixedOffset, Timelike};
+use url::Url;
+
+/// The url of a page's content item in the Content API
+pub fn content_url(url: &Url) -> Url {
+    let mut content_url = url.clone();
+    content_url.set_path(&format!("/api/content{}", url.path()));
+    content_url.set_query(None);
+    content_url.set_fragment(None);
+    content_url
+}
+
+/// The change history of a page from the Content API, oldest first, `None` if the page has no content item
+pub fn fetch_change_history(url: &Url) -> Result<Option<Vec<(DateTime<FixedOffset>, String)>>> {
+    let content_url = content_url(url);
+    println!("retrieving content item : {}", content_url);
+    let response = match ureq::get(content_url.as_str())
+        .set("User-Agent", "GovDiffBot/0.1; +https://govdiff.njk.onl")
+        .call()
+    {
+        Ok(response) => response,
+        Err(ureq::Error::Status(404 | 410, _)) => return Ok(None),
+        err => err.context("Error retrieving content item")?,
+    };
+    let body = response.into_string().context("Reading content item")?;
+    parse_change_history(&body).map(Some)
+}
+
+/// The change history in a content item. The timestamps are truncated to the minute, as those in update emails are, so
+/// that a change which was also emailed is written once
+fn parse_change_history(content_item: &str) -> Result<Vec<(DateTime<FixedOffset>, String)>> {
+    let content_item: serde_json::Value = serde_json::from_str(content_item)?;
+    let mut history = vec![];
+    for change in content_item["details"]["change_history"]
+        .as_array()
+        .into_iter()
+        .flatten()
+    {
+        let field = |name: &str| {
+            change[name]
+                .as_str()
+                .ok_or_else(|| format_err!("Change without {}", name))
+        };
+        let timestamp = DateTime::parse_from_rfc3339(field("public_timestamp")?)?;
+        let timestamp = timestamp.with_second(0).and_then(|ts| ts.with_nanosecond(0)).unwrap();
+        history.push((timestamp, field("note")?.to_owned()));
+    }
+    history.sort_by_key(|(timestamp, _)| *timestamp);
+    Ok(history)
+}
+
+#[test]
+fn test_change_history() {
+    assert_eq!(
+        content_url(
+            &"https://www.gov.uk/foreign-travel-advice/france?x=1#history"
+                .parse()
+                .unwrap()
+        )
+        .as_str(),
+        "https://www.gov.uk/api/content/foreign-travel-advice/france"
+    );
+
+    let content_item = r#"{
+        "base_path": "/foreign-travel-advice/france",
+        "details": {
+            "change_history": [
+                {"public_timestamp": "2022-02-18T10:31:52.000+00:00", "note": "Entry requirements updated"},
+                {"public_timestamp": "2022-02-17T09:53:07Z", "note": "First published"}
+            ]
+        }
+    }"#;
+    let history = parse_change_history(content_item).unwrap();
+    let at = |timestamp| DateTime::parse_from_rfc3339(timestamp).unwrap();
+    assert_eq!(
+        history,
+        [
+            (at("2022-02-17T09:53:00Z"), "First published".to_owned()),
+            (at("2022-02-18T10:31:00Z"), "Entry requirements updated".to_owned()),
+        ]
+    );
+    assert_eq!(parse_change_history(r#"{"details": {}}"#).unwrap(), []);
+    assert!(parse_change_history(r#"{"details": {"change_history": [{"note": "No timestamp"}]}}"#).is_err());
+}
@@ -0,0 +1,242 @@
+//! Refetching the pages in sections of the site on a schedule, so that changes made without an update email are still
+//! captured. A version is only stored when the content has changed, so a crawl of unchanged pages writes nothing
+
+use std::{
+    collections::{HashMap, VecDeque},
+    fs, io,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::{Duration, Instant},
+};
+
+use anyhow::{format_err, Context, Result};
+use chrono::{DateTime, Utc};
+use url::Url;
+
+/// How often the pages under each url prefix are crawled.
+///
+/// The schedule is a text file with a prefix per line, `prefix interval`, where the interval is a number of minutes,
+/// hours or days (eg. `30m`, `6h`, `1d`). Blank lines and lines starting with `#` are ignored. A prefix crawls the
+/// tracked pages at its url and below it in the url's path.
+#[derive(Debug, Default, PartialEq)]
+pub struct CrawlSchedule {
+    prefixes: Vec<(Url, Duration)>,
+}
+
+impl CrawlSchedule {
+    pub fn load(path: impl AsRef<Path>) -> Result<Self> {
+        fs::read_to_string(path)?.parse()
+    }
+}
+
+impl FromStr for CrawlSchedule {
+    type Err = anyhow::Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let mut prefixes = Vec::new();
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let schedule = line
+                .split_once(char::is_whitespace)
+                .and_then(|(prefix, interval)| Some((prefix.parse().ok()?, parse_interval(interval.trim())?)));
+            match schedule {
+                Some(schedule) => prefixes.push(schedule),
+                None => {
+                    return Err(format_err!(
+                        "Invalid crawl schedule on line {} : expected `prefix interval`",
+                        index + 1
+                    ))
+                }
+            }
+        }
+        Ok(Self { prefixes })
+    }
+}
+
+/// An interval of minutes, hours or days, eg. `30m`
+fn parse_interval(interval: &str) -> Option<Duration> {
+    let unit = match interval.chars().last()? {
+        'm' => 60,
+        'h' => 60 * 60,
+        'd' => 24 * 60 * 60,
+        _ => return None,
+    };
+    let count: u64 = interval[..interval.len() - 1].parse().ok().filter(|count| *count > 0)?;
+    Some(Duration::from_secs(count * unit))
+}
+
+/// Works through the crawls of a [`CrawlSchedule`] as they are due, a page at a time. When each prefix is next due is
+/// kept in a file so that a restart doesn't start every crawl again
+pub struct Crawler {
+    schedule: CrawlSchedule,
+    /// the file the next run of each prefix is kept in, a line per prefix of the prefix and the time
+    state_path: PathBuf,
+    next_runs: HashMap<Url, DateTime<Utc>>,
+    /// pages of due crawls which haven't been fetched yet
+    queue: VecDeque<Url>,
+    /// the least time between fetches, so that a crawl doesn't hammer the site
+    delay: Duration,
+    last_fetch: Option<Instant>,
+}
+
+impl Crawler {
+    /// Start crawling on a schedule, with the next runs kept in the file at `state_path`. Prefixes which haven't been
+    /// crawled before are due straight away
+    pub fn load(schedule: CrawlSchedule, state_path: PathBuf, delay: Duration) -> Result<Self> {
+        let mut next_runs = HashMap::new();
+        match fs::read_to_string(&state_path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let parsed = line
+                        .split_once(' ')
+                        .and_then(|(prefix, next_run)| Some((prefix.parse().ok()?, next_run.parse().ok()?)));
+                    match parsed {
+                        Some((prefix, next_run)) => {
+                            next_runs.insert(prefix, next_run);
+                        }
+                        None => println!("Ignoring invalid crawl state {:?}", line),
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err).context(format!("Reading crawl state {:?}", state_path)),
+        }
+        Ok(Self {
+            schedule,
+            state_path,
+            next_runs,
+            queue: VecDeque::new(),
+            delay,
+            last_fetch: None,
+        })
+    }
+
+    /// Queue the pages of the prefixes due to be crawled at `now`, listed by `pages_under`, and schedule their next runs
+    pub fn queue_due(
+        &mut self,
+        now: DateTime<Utc>,
+        mut pages_under: impl FnMut(&Url) -> io::Result<Vec<Url>>,
+    ) -> io::Result<()> {
+        let mut scheduled = false;
+        for (prefix, interval) in &self.schedule.prefixes {
+            if matches!(self.next_runs.get(prefix), Some(next_run) if *next_run > now) {
+                continue;
+            }
+            let pages = pages_under(prefix)?;
+            println!("Crawling {} pages under {}", pages.len(), prefix);
+            for page in pages {
+                if !self.queue.contains(&page) {
+                    self.queue.push_back(page);
+                }
+            }
+            let interval = chrono::Duration::from_std(*interval).unwrap_or_else(|_| chrono::Duration::days(1));
+            self.next_runs.insert(prefix.clone(), now + interval);
+            scheduled = true;
+        }
+        if scheduled {
+            self.save()?;
+        }
+        Ok(())
+    }
+
+    /// The next page to fetch, if there is one and it has been long enough since the last
+    pub fn next_page(&mut self, now: Instant) -> Option<Url> {
+        if matches!(self.last_fetch, Some(last_fetch) if now < last_fetch + self.delay) {
+            return None;
+        }
+        let page = self.queue.pop_front()?;
+        self.last_fetch = Some(now);
+        Some(page)
+    }
+
+    fn save(&self) -> io::Result<()> {
+        let mut contents = String::new();
+        for (prefix, next_run) in &self.next_runs {
+            contents.push_str(&format!("{} {}\n", prefix, next_run.to_rfc3339()));
+        }
+        let tmp_path = self.state_path.with_extension("saving");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(tmp_path, &self.state_path)
+    }
+}
+
+#[test]
+fn test_crawl_schedule() {
+    let schedule: CrawlSchedule = "
+        # travel advice changes often
+        https://www.gov.uk/foreign-travel-advice 30m
+        https://www.gov.uk/guidance/immigration-rules 1d
+    "
+    .parse()
+    .unwrap();
+    assert_eq!(
+        schedule.prefixes,
+        [
+            (
+                "https://www.gov.uk/foreign-travel-advice".parse().unwrap(),
+                Duration::from_secs(30 * 60)
+            ),
+            (
+                "https://www.gov.uk/guidance/immigration-rules".parse().unwrap(),
+                Duration::from_secs(24 * 60 * 60)
+            ),
+        ]
+    );
+    assert_eq!(
+        "\nhttps://www.gov.uk/guidance 2w"
+            .parse::<CrawlSchedule>()
+            .unwrap_err()
+            .to_string(),
+        "Invalid crawl schedule on line 2 : expected `prefix interval`"
+    );
+    assert!("https://www.gov.uk/guidance 0m".parse::<CrawlSchedule>().is_err());
+    assert!("https://www.gov.uk/guidance".parse::<CrawlSchedule>().is_err());
+}
+
+#[test]
+fn test_crawler() {
+    let state_path = PathBuf::from("tmp/test_crawler");
+    let _ = fs::remove_file(&state_path);
+    fs::create_dir_all(state_path.parent().unwrap()).unwrap();
+    let schedule = || "https://www.gov.uk/foreign-travel-advice 1h".parse().unwrap();
+    let pages_under = |prefix: &Url| {
+        Ok(["france", "spain"]
+            .iter()
+            .map(|country| prefix.join(&format!("foreign-travel-advice/{}", country)).unwrap())
+            .collect())
+    };
+    let start: DateTime<Utc> = "2022-02-17T09:00:00Z".parse().unwrap();
+    let delay = Duration::from_secs(5);
+
+    let mut crawler = Crawler::load(schedule(), state_path.clone(), delay).unwrap();
+    crawler.queue_due(start, pages_under).unwrap();
+    // queued again when already queued, the pages aren't crawled twice
+    crawler
+        .queue_due(start + chrono::Duration::hours(1), pages_under)
+        .unwrap();
+    let fetched_at = Instant::now();
+    assert_eq!(
+        crawler.next_page(fetched_at).unwrap().as_str(),
+        "https://www.gov.uk/foreign-travel-advice/france"
+    );
+    assert_eq!(crawler.next_page(fetched_at + Duration::from_secs(1)), None);
+    assert_eq!(
+        crawler.next_page(fetched_at + delay).unwrap().as_str(),
+        "https://www.gov.uk/foreign-travel-advice/spain"
+    );
+    assert_eq!(crawler.next_page(fetched_at + delay * 2), None);
+
+    // the next run is kept across restarts
+    let mut crawler = Crawler::load(schedule(), state_path, delay).unwrap();
+    crawler
+        .queue_due(start + chrono::Duration::minutes(119), pages_under)
+        .unwrap();
+    assert_eq!(crawler.next_page(fetched_at), None);
+    crawler
+        .queue_due(start + chrono::Duration::minutes(120), pages_under)
+        .unwrap();
+    assert!(crawler.next_page(fetched_at).is_some());
+}
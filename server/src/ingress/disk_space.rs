@@ -0,0 +1,128 @@
+//! Pausing ingress while the repo's volume is low on space, so that writes aren't left failing part way with IO errors.
+//! While paused, emails wait in the inbox and refetches wait in their queue, and they are processed once there is space
+//! again
+
+use std::{
+    ffi::CString,
+    io, mem,
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::atomic::{AtomicBool, AtomicU64, Ordering},
+    time::{Duration, Instant},
+};
+
+/// Whether ingress is paused for lack of space, for reporting on the status
+static LOW: AtomicBool = AtomicBool::new(false);
+/// The free space found by the last check, in bytes
+static FREE_BYTES: AtomicU64 = AtomicU64::new(u64::MAX);
+
+/// Whether ingress is paused as the repo's volume is low on space
+pub fn is_low() -> bool {
+    LOW.load(Ordering::Relaxed)
+}
+
+/// The free space on the repo's volume at the last check, if it has been checked
+pub fn last_free_bytes() -> Option<u64> {
+    Some(FREE_BYTES.load(Ordering::Relaxed)).filter(|free| *free != u64::MAX)
+}
+
+/// The space available to unprivileged users on the volume of a path, in bytes
+pub fn free_bytes(path: &Path) -> io::Result<u64> {
+    let stat = statvfs(path)?;
+    #[allow(clippy::unnecessary_cast)]
+    Ok(stat.f_bavail as u64 * stat.f_frsize as u64)
+}
+
+/// Whether the volume of a path is mounted read only
+pub fn is_read_only(path: &Path) -> io::Result<bool> {
+    Ok(statvfs(path)?.f_flag & libc::ST_RDONLY != 0)
+}
+
+fn statvfs(path: &Path) -> io::Result<libc::statvfs> {
+    let path =
+        CString::new(path.as_os_str().as_bytes()).map_err(|err| io::Error::new(io::ErrorKind::InvalidInput, err))?;
+    // safe as the path is nul terminated and the struct is only read once statvfs has filled it in
+    unsafe {
+        let mut stat: libc::statvfs = mem::zeroed();
+        if libc::statvfs(path.as_ptr(), &mut stat) != 0 {
+            return Err(io::Error::last_os_error());
+        }
+        Ok(stat)
+    }
+}
+
+/// Checks the free space on the repo's volume from time to time
+pub struct DiskSpace {
+    path: PathBuf,
+    /// ingress is paused when there is less free space than this
+    min_free_bytes: u64,
+    last_check: Option<Instant>,
+}
+
+impl DiskSpace {
+    const CHECK_INTERVAL: Duration = Duration::from_secs(60);
+
+    /// Checks the volume of the repo for the space set in `MIN_FREE_MB` (default 200), `0` disables the check
+    pub fn from_env(repo: &Path) -> Option<Self> {
+        let min_free_mb: u64 = dotenv::var("MIN_FREE_MB")
+            .ok()
+            .and_then(|mb| mb.parse().ok())
+            .unwrap_or(200);
+        (min_free_mb > 0).then(|| Self {
+            path: repo.to_owned(),
+            min_free_bytes: min_free_mb * 1024 * 1024,
+            last_check: None,
+        })
+    }
+
+    /// Whether ingress can write, the space is only checked again once `CHECK_INTERVAL` has passed since the last check.
+    /// A failed check allows writes, as a write is more likely to show what is wrong
+    pub fn writes_allowed(&mut self, now: Instant) -> bool {
+        if matches!(self.last_check, Some(last_check) if now.duration_since(last_check) < Self::CHECK_INTERVAL) {
+            return !is_low();
+        }
+        self.last_check = Some(now);
+        let free = match free_bytes(&self.path) {
+            Ok(free) => free,
+            Err(err) => {
+                println!("Checking free space on {:?} failed : {}", self.path, err);
+                return true;
+            }
+        };
+        FREE_BYTES.store(free, Ordering::Relaxed);
+        let low = free < self.min_free_bytes;
+        if low != LOW.swap(low, Ordering::Relaxed) {
+            if low {
+                println!(
+                    "ALERT : Pausing ingress, {} MiB free on {:?}",
+                    free / 1024 / 1024,
+                    self.path
+                );
+            } else {
+                println!("Resuming ingress, {} MiB free on {:?}", free / 1024 / 1024, self.path);
+            }
+        }
+        !low
+    }
+}
+
+#[test]
+fn test_free_bytes() {
+    assert!(free_bytes(Path::new(".")).unwrap() > 0);
+    assert!(free_bytes(Path::new("does/not/exist")).is_err());
+    assert!(!is_read_only(Path::new(".")).unwrap());
+
+    let mut disk_space = DiskSpace {
+        path: PathBuf::from("."),
+        min_free_bytes: u64::MAX,
+        last_check: None,
+    };
+    let now = Instant::now();
+    assert!(!disk_space.writes_allowed(now));
+    assert!(is_low());
+    // not checked again until the interval has passed
+    disk_space.min_free_bytes = 0;
+    assert!(!disk_space.writes_allowed(now + Duration::from_secs(1)));
+    assert!(disk_space.writes_allowed(now + DiskSpace::CHECK_INTERVAL));
+    assert!(!is_low());
+}
@@ -0,0 +1,219 @@
+//! Fetching docs from gov.uk, through a [`Fetcher`] so that the network can be replaced
+
+use std::{
+    fs,
+    io::{self, Read, Write},
+    path::{Path, PathBuf},
+};
+
+use anyhow::{format_err, Context, Result};
+use update_repo::doc::content::{Doc, DocContent};
+use ureq::get;
+use url::Url;
+
+/// Fetches docs from gov.uk
+pub trait Fetcher {
+    /// Fetch the doc at a url, `None` if it has been removed
+    fn fetch(&self, url: &Url) -> Result<Option<Doc>>;
+}
+
+/// Fetches docs over http
+pub struct UreqFetcher;
+
+impl Fetcher for UreqFetcher {
+    fn fetch(&self, url: &Url) -> Result<Option<Doc>> {
+        retrieve_doc(url)
+    }
+}
+
+/// Fetches docs over http and records the responses in a dir so that they can be replayed with a [`ReplayFetcher`]
+pub struct RecordingFetcher {
+    dir: PathBuf,
+}
+
+impl RecordingFetcher {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+}
+
+impl Fetcher for RecordingFetcher {
+    fn fetch(&self, url: &Url) -> Result<Option<Doc>> {
+        println!("retrieving url : {}", url);
+        let response = Response::get(url)?;
+        let path = recording_path(&self.dir, url);
+        response
+            .write_to(&path)
+            .context(format!("Recording response to {:?}", &path))?;
+        response.into_doc(url)
+    }
+}
+
+/// Fetches docs from responses recorded by a [`RecordingFetcher`], without using the network
+pub struct ReplayFetcher {
+    dir: PathBuf,
+}
+
+impl ReplayFetcher {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        Self { dir: dir.into() }
+    }
+
+    /// Whether there is a recorded response for the url
+    pub fn has_recording(&self, url: &Url) -> bool {
+        recording_path(&self.dir, url).exists()
+    }
+}
+
+impl Fetcher for ReplayFetcher {
+    fn fetch(&self, url: &Url) -> Result<Option<Doc>> {
+        let path = recording_path(&self.dir, url);
+        Response::read_from(&path)
+            .context(format!("Reading recorded response for {} from {:?}", url, &path))?
+            .into_doc(url)
+    }
+}
+
+/// Replays recorded responses from a dir, recording them all again when refreshing. A missing recording is an error
+/// otherwise, so that tests don't depend on the network without it being asked for
+pub struct CassetteFetcher {
+    replay: ReplayFetcher,
+    record: RecordingFetcher,
+    refresh: bool,
+}
+
+impl CassetteFetcher {
+    pub fn new(dir: impl Into<PathBuf>, refresh: bool) -> Self {
+        let dir = dir.into();
+        Self {
+            replay: ReplayFetcher::new(dir.clone()),
+            record: RecordingFetcher::new(dir),
+            refresh,
+        }
+    }
+
+    /// Records all of the responses again if `REFRESH_CASSETTES` is set
+    pub fn from_env(dir: impl Into<PathBuf>) -> Self {
+        Self::new(dir, std::env::var_os("REFRESH_CASSETTES").is_some())
+    }
+}
+
+impl Fetcher for CassetteFetcher {
+    fn fetch(&self, url: &Url) -> Result<Option<Doc>> {
+        if self.refresh {
+            self.record.fetch(url)
+        } else if self.replay.has_recording(url) {
+            self.replay.fetch(url)
+        } else {
+            Err(format_err!(
+                "No recorded response for {} in {:?}, set REFRESH_CASSETTES to record it",
+                url,
+                &self.replay.dir
+            ))
+        }
+    }
+}
+
+pub fn retrieve_doc(url: &Url) -> Result<Option<Doc>> {
+    println!("retrieving url : {}", url);
+    Response::get(url)?.into_doc(url)
+}
+
+/// A response to a request for a doc, before it is parsed
+enum Response {
+    /// the doc has been intentionally removed
+    Gone,
+    Found { content_type: String, body: Vec<u8> },
+}
+
+impl Response {
+    fn get(url: &Url) -> Result<Self> {
+        let response = match get(url.as_str())
+            .set("User-Agent", "GovDiffBot/0.1; +https://govdiff.njk.onl")
+            .call()
+        {
+            Ok(response) => response,
+            Err(ureq::Error::Status(410, _)) => return Ok(Response::Gone), /* other responses could indicate that a retry should happen or that we have a programming issue, but 410 really means that we're requesting the intended document but it has been intentionally removed */
+            err => err.context("Error retrieving")?,
+        };
+        let content_type = response.content_type().to_owned();
+        let mut body = vec![];
+        response
+            .into_reader()
+            .read_to_end(&mut body)
+            .map_err(|err| format_err!("Error retrieving doc : {}, url : {}", &err, &url))?;
+        Ok(Response::Found { content_type, body })
+    }
+
+    fn into_doc(self, url: &Url) -> Result<Option<Doc>> {
+        match self {
+            Response::Gone => Ok(None),
+            Response::Found { content_type, body } if content_type == "text/html" => Ok(Some(Doc {
+                content: DocContent::html(&mut body.as_slice(), Some(url)).map_err(|e| format_err!("Problem {}", e))?,
+                url: url.to_owned(),
+            })),
+            Response::Found { content_type: _, body } => Ok(Some(Doc {
+                url: url.to_owned(),
+                content: DocContent::Other(body),
+            })),
+        }
+    }
+
+    /// Recorded as the content type, or `410` if gone, on the first line followed by the body
+    fn write_to(&self, path: &Path) -> io::Result<()> {
+        fs::create_dir_all(path.parent().unwrap())?;
+        let mut file = fs::File::create(path)?;
+        match self {
+            Response::Gone => file.write_all(b"410\n")?,
+            Response::Found { content_type, body } => {
+                writeln!(file, "{}", content_type)?;
+                file.write_all(body)?;
+            }
+        }
+        file.flush()
+    }
+
+    fn read_from(path: &Path) -> io::Result<Self> {
+        let recording = fs::read(path)?;
+        let (content_type, body) = match recording.iter().position(|&b| b == b'\n') {
+            Some(split) => (&recording[..split], &recording[split + 1..]),
+            None => return Err(io::Error::new(io::ErrorKind::InvalidData, "Recording has no content type")),
+        };
+        if content_type == b"410" {
+            return Ok(Response::Gone);
+        }
+        Ok(Response::Found {
+            content_type: String::from_utf8_lossy(content_type).into_owned(),
+            body: body.to_vec(),
+        })
+    }
+}
+
+/// Where a response for a url is recorded, following the url's path under its host
+fn recording_path(dir: &Path, url: &Url) -> PathBuf {
+    dir.join(url.host_str().unwrap_or("local"))
+        .join(format!("{}.response", url.path().trim_start_matches('/')))
+}
+
+#[test]
+fn test_replay_recorded_responses() {
+    let dir = Path::new("tmp/test_replay_recorded_responses");
+    let _ = fs::remove_dir_all(dir);
+    let attachment: Url = "https://www.gov.uk/government/uploads/attachment.csv".parse().unwrap();
+    let removed: Url = "https://www.gov.uk/guidance/removed".parse().unwrap();
+    Response::Found {
+        content_type: "text/csv".to_owned(),
+        body: b"a,b\n1,2\n".to_vec(),
+    }
+    .write_to(&recording_path(dir, &attachment))
+    .unwrap();
+    Response::Gone.write_to(&recording_path(dir, &removed)).unwrap();
+
+    let fetcher = ReplayFetcher::new(dir);
+    let doc = fetcher.fetch(&attachment).unwrap().unwrap();
+    assert_eq!(doc.url, attachment);
+    assert_eq!(doc.content, DocContent::Other(b"a,b\n1,2\n".to_vec()));
+    assert!(fetcher.fetch(&removed).unwrap().is_none());
+    assert!(!fetcher.has_recording(&"https://www.gov.uk/guidance/other".parse().unwrap()));
+    assert!(fetcher.fetch(&"https://www.gov.uk/guidance/other".parse().unwrap()).is_err());
+}
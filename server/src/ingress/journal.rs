@@ -0,0 +1,273 @@
+//! A journal of the recently processed update emails, with what was done for each change in them, so that an operator
+//! can check that a change made it into the repo. Only the last [`Journal::CAPACITY`] emails are kept, in a file of a
+//! JSON object per line which the web server reads
+
+use std::{
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    sync::{Arc, Mutex},
+};
+
+use chrono::{DateTime, Utc};
+use serde_json::{json, Value};
+use update_repo::{
+    doc::{DocEvent, DocumentVersion},
+    repository::EventSink,
+    tag::{Tag, TagEvent},
+    update::{Update, UpdateEvent},
+};
+
+/// The journal file, appends replace the file so that readers only see whole journals
+pub struct Journal {
+    path: PathBuf,
+}
+
+/// A processed email
+#[derive(Debug, Clone, PartialEq)]
+pub struct EmailRecord {
+    /// the path of the email in the outbox
+    pub email: String,
+    pub processed_at: DateTime<Utc>,
+    pub duration_ms: u64,
+    /// whether all the changes were handled and the email was moved to the outbox
+    pub ok: bool,
+    pub changes: Vec<ChangeRecord>,
+}
+
+/// A change extracted from an email and what handling it did
+#[derive(Debug, Clone, PartialEq)]
+pub struct ChangeRecord {
+    pub url: String,
+    pub updated_at: String,
+    pub change: String,
+    /// the urls fetched for the change, the doc and its attachments
+    pub fetched: Vec<String>,
+    /// the events of the writes to the repo
+    pub events: Vec<String>,
+    pub duration_ms: u64,
+    pub error: Option<String>,
+}
+
+impl Journal {
+    /// The number of emails kept in the journal
+    pub const CAPACITY: usize = 200;
+
+    pub fn new(path: impl Into<PathBuf>) -> Self {
+        Self { path: path.into() }
+    }
+
+    /// The journal set in `INGRESS_JOURNAL`, or `ingress-journal` in the repo
+    pub fn from_env(repo: &Path) -> Self {
+        Self::new(
+            dotenv::var("INGRESS_JOURNAL")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| repo.join("ingress-journal")),
+        )
+    }
+
+    /// Add a processed email, dropping the oldest once there are more than [`Self::CAPACITY`]
+    pub fn append(&self, record: &EmailRecord) -> io::Result<()> {
+        let mut lines: Vec<String> = match fs::read_to_string(&self.path) {
+            Ok(journal) => journal.lines().map(str::to_owned).collect(),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => vec![],
+            Err(err) => return Err(err),
+        };
+        lines.push(record.to_json().to_string());
+        let skip = lines.len().saturating_sub(Self::CAPACITY);
+
+        let tmp_path = self.path.with_extension("tmp");
+        let mut file = fs::File::create(&tmp_path)?;
+        for line in &lines[skip..] {
+            writeln!(file, "{}", line)?;
+        }
+        file.sync_all()?;
+        fs::rename(tmp_path, &self.path)
+    }
+
+    /// The processed emails, most recent first, lines which can't be read are skipped
+    pub fn recent(&self) -> io::Result<Vec<EmailRecord>> {
+        let journal = match fs::read_to_string(&self.path) {
+            Ok(journal) => journal,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(vec![]),
+            Err(err) => return Err(err),
+        };
+        Ok(journal
+            .lines()
+            .rev()
+            .filter_map(|line| EmailRecord::from_json(&serde_json::from_str(line).ok()?))
+            .collect())
+    }
+}
+
+impl EmailRecord {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "email": self.email,
+            "processed_at": self.processed_at.to_rfc3339(),
+            "duration_ms": self.duration_ms,
+            "ok": self.ok,
+            "changes": self.changes.iter().map(ChangeRecord::to_json).collect::<Vec<_>>(),
+        })
+    }
+
+    fn from_json(json: &Value) -> Option<Self> {
+        Some(Self {
+            email: json["email"].as_str()?.to_owned(),
+            processed_at: json["processed_at"].as_str()?.parse().ok()?,
+            duration_ms: json["duration_ms"].as_u64()?,
+            ok: json["ok"].as_bool()?,
+            changes: json["changes"]
+                .as_array()?
+                .iter()
+                .map(ChangeRecord::from_json)
+                .collect::<Option<_>>()?,
+        })
+    }
+}
+
+impl ChangeRecord {
+    pub fn to_json(&self) -> Value {
+        json!({
+            "url": self.url,
+            "updated_at": self.updated_at,
+            "change": self.change,
+            "fetched": self.fetched,
+            "events": self.events,
+            "duration_ms": self.duration_ms,
+            "error": self.error,
+        })
+    }
+
+    fn from_json(json: &Value) -> Option<Self> {
+        let strings = |value: &Value| -> Option<Vec<String>> {
+            value
+                .as_array()?
+                .iter()
+                .map(|value| value.as_str().map(str::to_owned))
+                .collect()
+        };
+        Some(Self {
+            url: json["url"].as_str()?.to_owned(),
+            updated_at: json["updated_at"].as_str()?.to_owned(),
+            change: json["change"].as_str()?.to_owned(),
+            fetched: strings(&json["fetched"])?,
+            events: strings(&json["events"])?,
+            duration_ms: json["duration_ms"].as_u64()?,
+            error: json["error"].as_str().map(str::to_owned),
+        })
+    }
+}
+
+/// Describes the events of the writes for the journal, passing them on to another sink. Events are only recorded
+/// between a start and a take, so that writes which aren't journalled, such as crawls, aren't kept
+pub struct RecordingSink {
+    inner: Option<Arc<dyn EventSink>>,
+    events: Mutex<Option<Vec<String>>>,
+}
+
+impl RecordingSink {
+    pub fn new(inner: Option<Arc<dyn EventSink>>) -> Self {
+        Self {
+            inner,
+            events: Mutex::new(None),
+        }
+    }
+
+    /// Start recording events
+    pub fn start(&self) {
+        *self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) = Some(vec![]);
+    }
+
+    /// The events recorded since the start, recording stops until the next start
+    pub fn take(&self) -> Vec<String> {
+        self.events
+            .lock()
+            .unwrap_or_else(|poisoned| poisoned.into_inner())
+            .take()
+            .unwrap_or_default()
+    }
+
+    fn record(&self, event: impl FnOnce() -> String) {
+        if let Some(events) = &mut *self.events.lock().unwrap_or_else(|poisoned| poisoned.into_inner()) {
+            events.push(event());
+        }
+    }
+}
+
+impl EventSink for RecordingSink {
+    fn on_update(&self, update: &Update, event: &UpdateEvent) {
+        self.record(|| match event {
+            UpdateEvent::Added { url, timestamp } => format!("Update added {} {}", url, timestamp.to_rfc3339()),
+            UpdateEvent::New { url, .. } => format!("Newest update of {}", url),
+            UpdateEvent::Deleted { url, timestamp } => format!("Update deleted {} {}", url, timestamp.to_rfc3339()),
+        });
+        if let Some(inner) = &self.inner {
+            inner.on_update(update, event);
+        }
+    }
+
+    fn on_doc(&self, doc: &DocumentVersion, event: &DocEvent) {
+        self.record(|| match event {
+            DocEvent::Created { url } => format!("Doc created {}", url),
+            DocEvent::Updated { url, timestamp } => format!("Doc version stored {} {}", url, timestamp.to_rfc3339()),
+            DocEvent::Deleted { url, timestamp } => format!("Doc version replaced {} {}", url, timestamp.to_rfc3339()),
+            DocEvent::Removed { url, timestamp } => format!("Doc removed {} {}", url, timestamp.to_rfc3339()),
+            DocEvent::Withdrawn { url, timestamp } => format!("Doc withdrawn {} {}", url, timestamp.to_rfc3339()),
+            DocEvent::First { url, first } => format!("{} {}", first.label(), url),
+        });
+        if let Some(inner) = &self.inner {
+            inner.on_doc(doc, event);
+        }
+    }
+
+    fn on_tag(&self, tag: &Tag, event: &TagEvent) {
+        self.record(|| match event {
+            TagEvent::UpdateTagged { tag, update_ref } => format!("Tagged {} {}", tag.name(), update_ref.url),
+            TagEvent::TagCreated { tag } => format!("Tag created {}", tag.name()),
+            TagEvent::UpdateUntagged { tag, update_ref } => format!("Untagged {} {}", tag.name(), update_ref.url),
+        });
+        if let Some(inner) = &self.inner {
+            inner.on_tag(tag, event);
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::{ChangeRecord, EmailRecord, Journal};
+
+    #[test]
+    fn test_journal_keeps_recent_emails() {
+        let path = "tmp/test_journal_keeps_recent_emails";
+        fs::create_dir_all("tmp").unwrap();
+        let _ = fs::remove_file(path);
+        let journal = Journal::new(path);
+        assert_eq!(journal.recent().unwrap(), []);
+
+        let record = |email: usize| EmailRecord {
+            email: format!("updates/{}.eml", email),
+            processed_at: "2022-02-17T09:53:57Z".parse().unwrap(),
+            duration_ms: 120,
+            ok: true,
+            changes: vec![ChangeRecord {
+                url: "https://www.gov.uk/foreign-travel-advice/burundi".to_owned(),
+                updated_at: "9:53am, 17 February 2022".to_owned(),
+                change: "Updated entry requirements".to_owned(),
+                fetched: vec!["https://www.gov.uk/foreign-travel-advice/burundi".to_owned()],
+                events: vec!["Doc created https://www.gov.uk/foreign-travel-advice/burundi".to_owned()],
+                duration_ms: 100,
+                error: None,
+            }],
+        };
+        for email in 0..Journal::CAPACITY + 2 {
+            journal.append(&record(email)).unwrap();
+        }
+        let recent = journal.recent().unwrap();
+        assert_eq!(recent.len(), Journal::CAPACITY);
+        assert_eq!(recent[0], record(Journal::CAPACITY + 1));
+        assert_eq!(recent.last().unwrap().email, "updates/2.eml");
+    }
+}
@@ -0,0 +1,135 @@
+//! Fetching update emails from an IMAP mailbox, in place of an MTA delivering them to the inbox directory. New emails are
+//! downloaded into a directory of the inbox, from where they are processed as any other email, and are only marked as
+//! seen once they have been written there, so an email isn't lost if the server stops part way
+
+use std::{
+    fs,
+    net::TcpStream,
+    path::{Path, PathBuf},
+    time::{Duration, Instant},
+};
+
+use anyhow::{format_err, Context, Result};
+use native_tls::{TlsConnector, TlsStream};
+
+type Session = imap::Session<TlsStream<TcpStream>>;
+
+pub struct Mailbox {
+    host: String,
+    port: u16,
+    user: String,
+    password: String,
+    /// the mailbox on the server the emails are in
+    mailbox: String,
+    /// the IMAP search for the emails to download, they are marked as seen once they are downloaded
+    search: String,
+    poll_interval: Duration,
+    last_poll: Option<Instant>,
+}
+
+impl Mailbox {
+    /// The directory of the inbox which the emails are downloaded into
+    pub const INBOX_DIR: &'static str = "imap";
+
+    /// The mailbox set by `IMAP_HOST`, `IMAP_PORT` (default 993), `IMAP_USER` and `IMAP_PASSWORD`, if `IMAP_HOST` is set.
+    /// `IMAP_MAILBOX` (default `INBOX`) is polled every `IMAP_POLL_SECS` (default 60) for the unseen emails matching
+    /// `IMAP_SEARCH` (default `UNSEEN FROM "gov.uk"`)
+    pub fn from_env() -> Result<Option<Self>> {
+        let host = match dotenv::var("IMAP_HOST") {
+            Ok(host) => host,
+            Err(_) => return Ok(None),
+        };
+        let port = dotenv::var("IMAP_PORT")
+            .ok()
+            .map(|port| port.parse())
+            .transpose()
+            .context("Invalid IMAP_PORT")?
+            .unwrap_or(993);
+        let poll_secs = dotenv::var("IMAP_POLL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(60);
+        Ok(Some(Self {
+            host,
+            port,
+            user: dotenv::var("IMAP_USER").context("IMAP_USER must be set with IMAP_HOST")?,
+            password: dotenv::var("IMAP_PASSWORD").context("IMAP_PASSWORD must be set with IMAP_HOST")?,
+            mailbox: dotenv::var("IMAP_MAILBOX").unwrap_or_else(|_| "INBOX".to_owned()),
+            search: dotenv::var("IMAP_SEARCH").unwrap_or_else(|_| r#"UNSEEN FROM "gov.uk""#.to_owned()),
+            poll_interval: Duration::from_secs(poll_secs),
+            last_poll: None,
+        }))
+    }
+
+    /// Download the new emails into the inbox if a poll is due, returns the number downloaded
+    pub fn poll_due(&mut self, now: Instant, inbox: &Path) -> Result<usize> {
+        if matches!(self.last_poll, Some(last_poll) if now.duration_since(last_poll) < self.poll_interval) {
+            return Ok(0);
+        }
+        self.last_poll = Some(now);
+        let mut session = self.connect()?;
+        let result = self.download(&mut session, &inbox.join(Self::INBOX_DIR));
+        // the emails downloaded are seen already, so a failure logging out doesn't matter
+        let _ = session.logout();
+        result
+    }
+
+    fn connect(&self) -> Result<Session> {
+        let tls = TlsConnector::builder().build()?;
+        let client = imap::connect((self.host.as_str(), self.port), &self.host, &tls)
+            .context(format!("Connecting to {}:{}", self.host, self.port))?;
+        let mut session = client
+            .login(&self.user, &self.password)
+            .map_err(|(err, _)| format_err!("Logging in to {} : {}", self.host, err))?;
+        session
+            .select(&self.mailbox)
+            .context(format!("Selecting mailbox {}", self.mailbox))?;
+        Ok(session)
+    }
+
+    fn download(&self, session: &mut Session, dir: &Path) -> Result<usize> {
+        let mut uids: Vec<u32> = session.uid_search(&self.search)?.into_iter().collect();
+        uids.sort_unstable();
+        fs::create_dir_all(dir)?;
+        let mut count = 0;
+        for uid in uids {
+            let messages = session.uid_fetch(uid.to_string(), "BODY.PEEK[]")?;
+            let body = match messages.iter().next().and_then(|message| message.body()) {
+                Some(body) => body,
+                None => {
+                    println!("IMAP email {} has no body, skipping it", uid);
+                    continue;
+                }
+            };
+            let path = spool(dir, uid, body)?;
+            println!("Downloaded IMAP email {} to {:?}", uid, path);
+            session.uid_store(uid.to_string(), r"+FLAGS (\Seen)")?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+/// Write an email into a directory of the inbox. It is written beside it first, as anything in the directory may be
+/// processed as soon as it is there
+fn spool(dir: &Path, uid: u32, body: &[u8]) -> Result<PathBuf> {
+    let name = format!("{}-{}.eml", chrono::Utc::now().format("%Y-%m-%dT%H:%M:%S"), uid);
+    let tmp_path = dir.with_extension("downloading");
+    fs::write(&tmp_path, body)?;
+    let path = dir.join(name);
+    fs::rename(&tmp_path, &path)?;
+    Ok(path)
+}
+
+#[test]
+fn test_spool() {
+    let dir = PathBuf::from("tmp/test_spool/imap");
+    let _ = fs::remove_dir_all(dir.parent().unwrap());
+    fs::create_dir_all(&dir).unwrap();
+    let path = spool(&dir, 42, b"Subject: Update").unwrap();
+    assert!(path.to_str().unwrap().ends_with("-42.eml"));
+    assert_eq!(fs::read(&path).unwrap(), b"Subject: Update");
+    // only the email is left in the directory for the processing to find
+    assert_eq!(fs::read_dir(&dir).unwrap().count(), 1);
+    assert_eq!(fs::read_dir(dir.parent().unwrap()).unwrap().count(), 1);
+}
@@ -1,29 +1,45 @@
 use anyhow::{format_err, Context, Result};
-use chrono::{Offset, TimeZone, Utc};
+use chrono::{DateTime, FixedOffset, Offset, TimeZone, Utc};
 use std::{
-    cell::RefCell,
-    io::{self, copy, Write},
-    sync::{Arc, RwLock},
+    io,
+    sync::{
+        mpsc::{Receiver, Sender},
+        Arc,
+    },
 };
 use update_repo::{
-    doc::{
-        content::{Doc, DocContent},
-        DocEvent, DocRepo,
-    },
-    tag::{TagEvent, TagRepo},
-    update::UpdateRepo,
+    check,
+    doc::content::DocContent,
+    repository::{Durability, EventSink},
+    tag::{TagMapping, TagRules},
+    tracker::Tracker,
+    update::{ChangeNormalizer, UpdateConflict, UpdateFields, UpdateRef},
 };
-use ureq::get;
 use url::Url;
 
+pub mod bootstrap;
+pub mod content_api;
+pub mod crawl;
+pub mod disk_space;
 pub mod email_update;
+pub mod fetch;
 pub mod git;
+pub mod journal;
+pub mod mailbox;
+pub mod replicate;
+pub mod watchdog;
 
 use self::{
+    crawl::{CrawlSchedule, Crawler},
+    disk_space::DiskSpace,
     email_update::GovUkChange,
+    fetch::{Fetcher, UreqFetcher},
     git::{GitRepoTransaction, GitRepoWriter},
+    journal::{ChangeRecord, EmailRecord, Journal, RecordingSink},
+    mailbox::Mailbox,
+    watchdog::Watchdog,
 };
-use crate::data::Data;
+use crate::data::{DataUpdater, SharedData};
 use dotenv::dotenv;
 use file_locker::FileLock;
 
@@ -33,12 +49,33 @@ use std::{
     io::Read,
     path::{Path, PathBuf},
     thread,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
-pub fn run(new_repo_path: &Path, data: Arc<RwLock<Data>>) -> Result<()> {
+/// A request from the admin routes, handled by ingress as it makes all of the writes to the repo
+pub enum AdminRequest {
+    /// fetch a page now
+    Refetch(Url),
+    /// delete a bogus update or doc version, replying with what was deleted
+    Retract(Retraction, Sender<Result<String, String>>),
+}
+
+/// An update or doc version to delete, such as one written from a mis-parsed email
+pub enum Retraction {
+    Update(UpdateRef),
+    DocVersion(Url, DateTime<FixedOffset>),
+}
+
+pub fn run(new_repo_path: &Path, data: Arc<SharedData>, admin_queue: Receiver<AdminRequest>) -> Result<()> {
     let _ = dotenv();
-    let govuk_emails_inbox = dotenv::var("INBOX")?;
+    check::init_repo(new_repo_path).context("Initialising repo")?;
+    let mut mailbox = Mailbox::from_env()?;
+    // emails fetched from a mailbox are put in an inbox in the repo unless it is set
+    let govuk_emails_inbox = match (dotenv::var("INBOX"), &mailbox) {
+        (Ok(inbox), _) => inbox,
+        (Err(_), Some(_)) => new_repo_path.join("inbox").to_str().context("Inbox path")?.to_owned(),
+        (Err(err), None) => return Err(err).context("INBOX must be set unless IMAP_HOST is"),
+    };
     let outbox_dir = dotenv::var("OUTBOX")
         .ok()
         .and_then(|s| s.parse().ok())
@@ -58,6 +95,43 @@ pub fn run(new_repo_path: &Path, data: Arc<RwLock<Data>>) -> Result<()> {
 
     println!("Watching inbox {} for updates", &govuk_emails_inbox);
 
+    let mut crawler = match dotenv::var("CRAWL_SCHEDULE") {
+        Ok(path) => {
+            let schedule = CrawlSchedule::load(&path).context(format!("Loading crawl schedule {}", path))?;
+            let state_path = dotenv::var("CRAWL_STATE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| new_repo_path.join("crawl-state"));
+            let delay = dotenv::var("CRAWL_DELAY_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .unwrap_or(5);
+            Some(Crawler::load(schedule, state_path, Duration::from_secs(delay))?)
+        }
+        Err(_) => None,
+    };
+
+    // polls the content api on a schedule of its own, a page at a time as the crawls do
+    let mut content_poller = match dotenv::var("CONTENT_API_SCHEDULE") {
+        Ok(path) => {
+            let schedule = CrawlSchedule::load(&path).context(format!("Loading content api schedule {}", path))?;
+            let state_path = dotenv::var("CONTENT_API_STATE")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| new_repo_path.join("content-api-state"));
+            let delay = dotenv::var("CONTENT_API_DELAY_SECS")
+                .ok()
+                .and_then(|secs| secs.parse().ok())
+                .unwrap_or(5);
+            Some(Crawler::load(schedule, state_path, Duration::from_secs(delay))?)
+        }
+        Err(_) => None,
+    };
+
+    let mut disk_space = DiskSpace::from_env(new_repo_path);
+    let mut watchdog = Watchdog::from_env(Utc::now());
+    // updates are counted in the data as they are written
+    let watched_data = data.clone();
+    let mut update_count = watched_data.snapshot().update_count();
+
     let mut update_email_processor = UpdateEmailProcessor::new(
         govuk_emails_inbox.as_ref(),
         &outbox_dir,
@@ -65,26 +139,122 @@ pub fn run(new_repo_path: &Path, data: Arc<RwLock<Data>>) -> Result<()> {
         git_repo_path.as_ref(),
         git_reference,
         new_repo_path,
-        &data,
+        data,
+        &UreqFetcher,
     )?;
     loop {
-        let count = update_email_processor
-            .process_updates()
-            .expect("the processing fails, the repo may be unclean");
+        // while paused for space the emails wait in the inbox and the refetches in their queue
+        let writes_allowed = disk_space
+            .as_mut()
+            .map_or(true, |disk_space| disk_space.writes_allowed(Instant::now()));
+        if let (true, Some(mailbox)) = (writes_allowed, &mut mailbox) {
+            if let Err(err) = mailbox.poll_due(Instant::now(), govuk_emails_inbox.as_ref()) {
+                println!("Fetching emails from the mailbox failed : {:#}", err);
+            }
+        }
+        let count = if writes_allowed {
+            update_email_processor
+                .process_updates()
+                .expect("the processing fails, the repo may be unclean")
+        } else {
+            0
+        };
         if count > 0 {
             println!("Processed {} update emails, pushing", count);
             git::push(&git_repo_path).unwrap_or_else(|err| println!("Push failed : {}", err));
         }
+        if let Some(watchdog) = &mut watchdog {
+            let now = Utc::now();
+            if count > 0 {
+                watchdog.email_processed(now);
+            }
+            let new_count = watched_data.snapshot().update_count();
+            if new_count != update_count {
+                update_count = new_count;
+                watchdog.update_written(now);
+            }
+            watchdog.check(now);
+        }
+        if !writes_allowed {
+            thread::sleep(Duration::from_secs(1));
+            continue;
+        }
+        for request in admin_queue.try_iter() {
+            match request {
+                AdminRequest::Refetch(url) => {
+                    println!("Refetching {}", &url);
+                    if let Err(err) = update_email_processor.refetch(&url) {
+                        println!("Refetch of {} failed : {}", &url, err);
+                    }
+                }
+                AdminRequest::Retract(retraction, reply) => {
+                    let result = update_email_processor.retract(&retraction);
+                    match &result {
+                        Ok(deleted) => println!("{}", deleted),
+                        Err(err) => println!("Retraction failed : {:#}", err),
+                    }
+                    // the admin may have given up waiting
+                    let _ = reply.send(result.map_err(|err| format!("{:#}", err)));
+                }
+            }
+        }
+        if let Some(crawler) = &mut crawler {
+            let new = &update_email_processor.new;
+            if let Err(err) = crawler.queue_due(Utc::now(), |prefix| new.tracked_urls(prefix)) {
+                println!("Listing pages to crawl failed : {}", err);
+            }
+            if let Some(url) = crawler.next_page(Instant::now()) {
+                if let Err(err) = update_email_processor.refetch(&url) {
+                    println!("Crawl of {} failed : {}", &url, err);
+                }
+            }
+        }
+        if let Some(content_poller) = &mut content_poller {
+            let new = &update_email_processor.new;
+            if let Err(err) = content_poller.queue_due(Utc::now(), |prefix| new.tracked_urls(prefix)) {
+                println!("Listing pages to poll failed : {}", err);
+            }
+            if let Some(url) = content_poller.next_page(Instant::now()) {
+                if let Err(err) = update_email_processor.poll_content_api(&url) {
+                    println!("Content api poll of {} failed : {:#}", &url, err);
+                }
+            }
+        }
         thread::sleep(Duration::from_secs(1));
     }
 }
 
+/// Process emails from the outbox again, `sources` are their paths in the outbox as recorded on their updates. Everything
+/// is written with `ensure` semantics, so what is already in the repo is left alone, and an update which conflicts with
+/// the one already written is logged and the written one kept, for recovering from bugs which were fixed after the emails
+/// were first processed. Returns the number of emails which failed, including those whose updates couldn't be written
+pub fn reprocess(new_repo_path: &Path, sources: &[String], fetcher: &dyn Fetcher) -> Result<usize> {
+    let _ = dotenv();
+    let outbox_dir = dotenv::var("OUTBOX")
+        .ok()
+        .and_then(|s| s.parse().ok())
+        .unwrap_or_else(|| new_repo_path.join("outbox"));
+    let processor = UpdateEmailProcessor::reprocessor(&outbox_dir, new_repo_path, fetcher)?;
+    let mut failed = 0;
+    for source in sources {
+        println!("Reprocessing {}", source);
+        if !processor.reprocess(source)? {
+            eprintln!("Failed reprocessing {}", source);
+            failed += 1;
+        }
+    }
+    Ok(failed)
+}
+
 struct UpdateEmailProcessor<'a> {
     in_dir: &'a Path,
     out_dir: &'a Path,
     work_dir: &'a Path,
-    git: GitRepoWriter<'a>,
-    new: NewRepoWriter<'a>,
+    /// the git mirror, which isn't written when reprocessing
+    git: Option<GitRepoWriter<'a>>,
+    new: NewRepoWriter,
+    fetcher: &'a dyn Fetcher,
+    journal: Journal,
 }
 
 impl<'a> UpdateEmailProcessor<'a> {
@@ -95,14 +265,31 @@ impl<'a> UpdateEmailProcessor<'a> {
         git_repo: &'a Path,
         git_reference: &'a str,
         new_repo: &Path,
-        data: &'a RwLock<Data>,
+        data: Arc<SharedData>,
+        fetcher: &'a dyn Fetcher,
     ) -> Result<Self> {
         Ok(Self {
             in_dir,
             out_dir,
             work_dir,
-            git: GitRepoWriter::new(git_repo, git_reference)?,
-            new: NewRepoWriter::new(new_repo, data)?,
+            git: Some(GitRepoWriter::new(git_repo, git_reference)?),
+            new: NewRepoWriter::new(new_repo, Some(data))?,
+            fetcher,
+            journal: Journal::from_env(new_repo),
+        })
+    }
+
+    /// Reprocesses emails in the outbox, everything is written with `ensure` so that whatever is already in the repo is
+    /// left alone. The git mirror isn't written as its history can only be added to
+    fn reprocessor(out_dir: &'a Path, new_repo: &Path, fetcher: &'a dyn Fetcher) -> Result<Self> {
+        Ok(Self {
+            in_dir: out_dir,
+            out_dir,
+            work_dir: out_dir,
+            git: None,
+            new: NewRepoWriter::new(new_repo, None)?.ensuring(),
+            fetcher,
+            journal: Journal::from_env(new_repo),
         })
     }
 
@@ -114,13 +301,13 @@ impl<'a> UpdateEmailProcessor<'a> {
                 for email in fs::read_dir(to_inbox.path())? {
                     let email = email?;
                     println!("Processing {:?}", email);
-                    if !(self
-                        .process_email_update_file(to_inbox.file_name(), &email)
-                        .context(format!(
-                            "Failed processing {}",
-                            email.path().to_str().unwrap_or_default()
-                        ))?)
-                    {
+                    let processed = self.process_email_update_file(to_inbox.file_name(), &email);
+                    // the writes for an email are served together, once it has been processed
+                    self.new.flush_data();
+                    if !(processed.context(format!(
+                        "Failed processing {}",
+                        email.path().to_str().unwrap_or_default()
+                    ))?) {
                         eprintln!(
                             "Non-fatal failure processing {}",
                             email.path().to_str().unwrap_or_default()
@@ -152,32 +339,90 @@ impl<'a> UpdateEmailProcessor<'a> {
             lock.file.read_to_end(&mut bytes).context("Reading email file")?;
             bytes
         };
+        // where the email will be once it is moved to the outbox, relative to the outbox
+        let source = Path::new(to_dir_name.as_ref()).join(dir_entry.file_name());
+        let source = source.to_str().context("Email path is not UTF-8")?;
+        let git = self.git.as_ref().context("Processing without a git repo")?;
+        let mut git_transaction = git.start_transaction()?;
+        if !self.handle_email(source, data, Some(&mut git_transaction))? {
+            return Ok(false);
+        }
+        // successfully handled, 'commit' the new commits by updating the reference and then move email to outbox
+        git_transaction.commit(&format!("Added updates from {:?}", dir_entry.path()))?;
+        let done_path = self.out_dir.join(&to_dir_name).join(dir_entry.file_name());
+        fs::create_dir_all(done_path.parent().unwrap()).context("Creating outbox dir")?;
+        fs::rename(&working_path, &done_path).context(format!(
+            "Renaming file {} to {}",
+            working_path.to_str().unwrap_or_default(),
+            &done_path.to_str().unwrap_or_default()
+        ))?;
+        Ok(true)
+    }
+
+    /// Process an email in the outbox again, `source` is its path in the outbox as recorded on its updates. Returns
+    /// false if a change in it failed, the changes before it are still written
+    fn reprocess(&self, source: &str) -> Result<bool> {
+        let path = self.out_dir.join(source);
+        let data = fs::read(&path).context(format!("Reading email file {}", path.to_str().unwrap_or_default()))?;
+        self.handle_email(source, data, None)
+    }
+
+    /// Write the changes in an email, and journal it. Returns false if it couldn't be parsed or a change failed
+    fn handle_email(
+        &self,
+        source: &str,
+        data: Vec<u8>,
+        mut git_transaction: Option<&mut GitRepoTransaction>,
+    ) -> Result<bool> {
+        let started = Instant::now();
+        let mut record = EmailRecord {
+            email: source.to_owned(),
+            processed_at: Utc::now(),
+            duration_ms: 0,
+            ok: false,
+            changes: vec![],
+        };
         let updates = match GovUkChange::from_eml(&String::from_utf8(data)?) {
             Ok(updates) => updates,
             Err(err) => {
                 eprintln!("Error parsing email: {:?}", &err);
+                self.journal(record, started);
                 return Ok(false);
             }
         };
-        let mut git_transaction = self.git.start_transaction()?;
         for change in &updates {
-            if let Err(err) = self.handle_change(change, &mut git_transaction) {
+            let change_started = Instant::now();
+            let mut fetched = vec![];
+            self.new.events.start();
+            let result = self.handle_change(change, source, git_transaction.as_deref_mut(), &mut fetched);
+            record.changes.push(ChangeRecord {
+                url: change.url.to_string(),
+                updated_at: change.updated_at.clone(),
+                change: change.change.clone(),
+                fetched,
+                events: self.new.events.take(),
+                duration_ms: change_started.elapsed().as_millis() as u64,
+                error: result.as_ref().err().map(|err| format!("{:?}", err)),
+            });
+            if let Err(err) = result {
                 eprintln!("Error processing change: {:?}: {:?}", change, &err);
+                self.journal(record, started);
                 return Ok(false);
             }
         }
-        // successfully handled, 'commit' the new commits by updating the reference and then move email to outbox
-        git_transaction.commit(&format!("Added updates from {:?}", dir_entry.path()))?;
-        let done_path = self.out_dir.join(&to_dir_name).join(dir_entry.file_name());
-        fs::create_dir_all(done_path.parent().unwrap()).context("Creating outbox dir")?;
-        fs::rename(&working_path, &done_path).context(format!(
-            "Renaming file {} to {}",
-            working_path.to_str().unwrap_or_default(),
-            &done_path.to_str().unwrap_or_default()
-        ))?;
+        record.ok = true;
+        self.journal(record, started);
         Ok(true)
     }
 
+    /// Add a processed email to the journal, the email has been processed whether or not this fails
+    fn journal(&self, mut record: EmailRecord, started: Instant) {
+        record.duration_ms = started.elapsed().as_millis() as u64;
+        if let Err(err) = self.journal.append(&record) {
+            eprintln!("Error writing to ingress journal : {}", err);
+        }
+    }
+
     fn handle_change<'repo>(
         &'repo self,
         GovUkChange {
@@ -186,19 +431,27 @@ impl<'a> UpdateEmailProcessor<'a> {
             updated_at,
             category,
         }: &GovUkChange,
-        git_transaction: &mut GitRepoTransaction,
+        source: &str,
+        git_transaction: Option<&mut GitRepoTransaction>,
+        fetched: &mut Vec<String>,
     ) -> Result<()> {
-        if let Err(err) = self.new.write_update(url, updated_at, change, category.as_deref()) {
+        if let Err(err) = self.new.write_update(url, updated_at, change, category.as_deref(), source) {
+            // writing the updates is what reprocessing is for, so it fails the change, while ingress still fetches the docs
+            if self.new.ensure {
+                return Err(err.context("Writing to update repo"));
+            }
             println!("Error writing to update repo {}", err);
         }
 
-        let mut commit_builder = git_transaction.start_change()?;
+        let mut commit_builder = git_transaction.map(GitRepoTransaction::start_change).transpose()?;
 
-        for res in FetchDocs::fetch(url.clone()) {
+        let mut fetch = FetchDocs::fetch(url.clone(), self.fetcher);
+        for res in &mut fetch {
             let (path, content) = res?;
 
             let mut url = url.clone();
             url.set_path(path.to_str().unwrap());
+            fetched.push(url.to_string());
             let ts = Utc::now();
             let ts = ts.with_timezone(&ts.offset().fix());
             if let Err(err) = self.new.write_doc(url, ts, &content) {
@@ -209,33 +462,109 @@ impl<'a> UpdateEmailProcessor<'a> {
             if content.is_html() {
                 assert!(path.set_extension("html"));
             }
-            commit_builder.add_doc(&path, &content)?;
+            if let Some(commit_builder) = &mut commit_builder {
+                commit_builder.add_doc(&path, &content)?;
+            }
+        }
+        for removed in fetch.removed {
+            if let Err(err) = self.new.mark_removed(removed) {
+                println!("Error writing removal to doc repo {}", err)
+            }
         }
 
-        commit_builder.commit_update(updated_at, change, category.as_deref())?;
+        if let Some(commit_builder) = commit_builder {
+            commit_builder.commit_update(updated_at, change, category.as_deref())?;
+        }
+        Ok(())
+    }
+
+    /// Fetch a url and its attachments outside of an update email, the doc repo only stores a new version if the content changed
+    fn refetch(&self, url: &Url) -> Result<()> {
+        let result = self.refetch_docs(url);
+        self.new.flush_data();
+        result
+    }
+
+    /// Delete a bogus update or doc version, returning a description of what was deleted
+    fn retract(&self, retraction: &Retraction) -> Result<String> {
+        let result = self.new.retract(retraction);
+        self.new.flush_data();
+        result
+    }
+
+    /// Write the changes in the Content API's change history of a page which are newer than its latest update, and fetch
+    /// the page if there are any
+    fn poll_content_api(&self, url: &Url) -> Result<()> {
+        let written = self.write_content_api_history(url);
+        self.new.flush_data();
+        if written? > 0 {
+            self.refetch(url)?;
+        }
+        Ok(())
+    }
+
+    /// Returns the number of updates written
+    fn write_content_api_history(&self, url: &Url) -> Result<usize> {
+        let history = match content_api::fetch_change_history(url)? {
+            Some(history) => history,
+            None => return Ok(0),
+        };
+        let latest = self.new.latest_update(url)?;
+        let mut written = 0;
+        for (timestamp, change) in history {
+            let is_new = latest.map_or(true, |latest| timestamp > latest);
+            if is_new && self.new.write_history_update(url, timestamp, &change)? {
+                println!("Wrote update from the content api to {}", url);
+                written += 1;
+            }
+        }
+        Ok(written)
+    }
+
+    fn refetch_docs(&self, url: &Url) -> Result<()> {
+        let mut fetch = FetchDocs::fetch(url.clone(), self.fetcher);
+        for res in &mut fetch {
+            let (path, content) = res?;
+
+            let mut url = url.clone();
+            url.set_path(path.to_str().unwrap());
+            let ts = Utc::now();
+            let ts = ts.with_timezone(&ts.offset().fix());
+            self.new.write_doc(url, ts, &content).context("Writing to doc repo")?;
+        }
+        for removed in fetch.removed {
+            self.new.mark_removed(removed).context("Writing removal to doc repo")?;
+        }
         Ok(())
     }
 }
 
-struct FetchDocs {
+struct FetchDocs<'f> {
     urls: VecDeque<Url>,
+    fetcher: &'f dyn Fetcher,
+    /// urls which responded that they have been removed
+    removed: Vec<Url>,
 }
 
-impl FetchDocs {
-    fn fetch(url: Url) -> FetchDocs {
+impl<'f> FetchDocs<'f> {
+    fn fetch(url: Url, fetcher: &'f dyn Fetcher) -> Self {
         let mut urls = VecDeque::new();
         urls.push_back(url);
-        Self { urls }
+        Self {
+            urls,
+            fetcher,
+            removed: vec![],
+        }
     }
 
     fn fetch_doc(&mut self, url: Url) -> Result<Option<(PathBuf, DocContent)>> {
-        if let Some(doc) = retrieve_doc(&url).or_else(|err| {
+        if let Some(doc) = self.fetcher.fetch(&url).or_else(|err| {
             println!(
                 "Request for {} failed with {}, waiting {:?} once and retrying",
                 &url, err, RETRY_DELAY
             );
             thread::sleep(RETRY_DELAY);
-            retrieve_doc(&url)
+            self.fetcher.fetch(&url)
         })? {
             self.urls
                 .extend(doc.content.attachments().unwrap_or_default().iter().cloned());
@@ -243,6 +572,8 @@ impl FetchDocs {
             println!("Writing doc to : {}", path.to_str().unwrap());
             Ok(Some((path, doc.content)))
         } else {
+            println!("Doc has been removed : {}", &url);
+            self.removed.push(url);
             Ok(None)
         }
     }
@@ -250,7 +581,7 @@ impl FetchDocs {
 
 const RETRY_DELAY: Duration = Duration::from_secs(60);
 
-impl Iterator for FetchDocs {
+impl Iterator for FetchDocs<'_> {
     type Item = Result<(PathBuf, DocContent)>;
 
     fn next(&mut self) -> Option<Self::Item> {
@@ -268,183 +599,414 @@ impl Iterator for FetchDocs {
     }
 }
 
-pub fn retrieve_doc(url: &Url) -> Result<Option<Doc>> {
-    println!("retrieving url : {}", url);
-    let response = match get(url.as_str())
-        .set("User-Agent", "GovDiffBot/0.1; +https://govdiff.njk.onl")
-        .call()
-    {
-        Ok(response) => response,
-        Err(ureq::Error::Status(410, _)) => return Ok(None), /* other responses could indicate that a retry should happen or that we have a programming issue, but 410 really means that we're requesting the intended document but it has been intentionally removed */
-        err => err.context("Error retrieving")?,
-    };
-
-    if response.content_type() == "text/html" {
-        let mut content = response.into_reader();
-        let doc = Doc {
-            content: DocContent::html(&mut content, Some(url)).map_err(|e| format_err!("Problem {}", e))?,
-            url: url.to_owned(),
-        };
-
-        Ok(Some(doc))
-    } else {
-        let mut reader = response.into_reader();
-        let mut buf = vec![];
-        copy(&mut reader, &mut buf)
-            .map_err(|err| format_err!("Error retrieving attachment : {}, url : {}", &err, &url))?;
-        Ok(Some(Doc {
-            url: url.to_owned(),
-            content: DocContent::Other(buf),
-        }))
+/// How writes to the repo are made durable, set by `DURABILITY`, each write is synced by default
+pub(crate) fn durability() -> Result<Durability> {
+    match dotenv::var("DURABILITY") {
+        Ok(durability) => durability.parse().map_err(|err: String| format_err!(err)),
+        Err(_) => Ok(Durability::default()),
     }
 }
 
-struct NewRepoWriter<'a> {
-    update_repo: UpdateRepo,
-    doc_repo: DocRepo,
-    tag_repo: TagRepo,
-    data: &'a RwLock<Data>,
-    write_avoidance_buffer: RefCell<Vec<u8>>,
+struct NewRepoWriter {
+    tracker: Tracker,
+    /// the events of the writes, recorded for the ingress journal
+    events: Arc<RecordingSink>,
+    /// keeps the data up to date with the writes, once they are flushed
+    data_updater: Option<Arc<DataUpdater>>,
+    /// maps email categories onto tags
+    tag_mapping: TagMapping,
+    /// tags updates with our own tags
+    tag_rules: TagRules,
+    /// whether writes are ensured, for reprocessing
+    ensure: bool,
 }
-impl<'a> NewRepoWriter<'a> {
-    fn new(new_repo: &Path, data: &'a RwLock<Data>) -> Result<Self> {
-        let update_repo = UpdateRepo::new(new_repo.join("url"))?;
-        let doc_repo = DocRepo::new(new_repo.join("url"))?;
-        let tag_repo = TagRepo::new(new_repo.join("tag"))?;
+impl NewRepoWriter {
+    /// Writes to the repo at `new_repo`, keeping `data` up to date with the writes if there is any
+    fn new(new_repo: &Path, data: Option<Arc<SharedData>>) -> Result<Self> {
+        let mut tracker = Tracker::new(new_repo)?.with_durability(durability()?);
+        if let Some(minutes) = dotenv::var("DOC_COALESCE_MINUTES").ok().and_then(|m| m.parse().ok()) {
+            tracker = tracker.with_doc_coalesce_window(chrono::Duration::minutes(minutes));
+        }
+        let compression = dotenv::var("DOC_COMPRESSION").map_or(false, |c| !matches!(c.as_str(), "" | "0" | "false"));
+        if compression {
+            // older versions of the tracker use repos of earlier versions, and would read compressed versions as they are
+            let version = check::repo_version(new_repo)?;
+            if version < 3 {
+                return Err(format_err!(
+                    "DOC_COMPRESSION needs a repo of version 3, the repo is version {}, migrate it with compress_docs",
+                    version
+                ));
+            }
+            tracker = tracker.with_doc_compression();
+        }
+        let data_updater = data.map(|data| Arc::new(DataUpdater::new(data)));
+        let events = Arc::new(RecordingSink::new(
+            data_updater.clone().map(|updater| updater as Arc<dyn EventSink>),
+        ));
+        tracker = tracker.with_event_sink(events.clone());
+        let tag_mapping = match dotenv::var("TAG_MAPPING") {
+            Ok(path) => TagMapping::load(&path).context(format!("Loading tag mapping {}", path))?,
+            Err(_) => TagMapping::default(),
+        };
+        let tag_rules = match dotenv::var("TAG_RULES") {
+            Ok(path) => TagRules::load(&path).context(format!("Loading tag rules {}", path))?,
+            Err(_) => TagRules::default(),
+        };
         Ok(Self {
-            update_repo,
-            doc_repo,
-            tag_repo,
-            data,
-            write_avoidance_buffer: RefCell::new(Vec::new()),
+            tracker,
+            events,
+            data_updater,
+            tag_mapping,
+            tag_rules,
+            ensure: false,
         })
     }
 
-    fn write_update(&self, url: &Url, updated_at: &str, change: &str, category: Option<&str>) -> Result<()> {
+    /// Delete an update along with its lines in the tags, or a doc version along with its withdrawn marker, the events
+    /// keep the data consistent once flushed
+    fn retract(&self, retraction: &Retraction) -> Result<String> {
+        match retraction {
+            Retraction::Update(update_ref) => {
+                let update = self.tracker.delete_update(update_ref)?;
+                Ok(format!(
+                    "Deleted update {} {} : {}",
+                    update.url(),
+                    update.timestamp().to_rfc3339(),
+                    update.change()
+                ))
+            }
+            Retraction::DocVersion(url, timestamp) => {
+                let version = self.tracker.doc_repo().ensure_version(url.clone(), *timestamp)?;
+                self.tracker.doc_repo().delete_version(&version)?;
+                Ok(format!(
+                    "Deleted doc version {} {}",
+                    version.url(),
+                    version.timestamp().to_rfc3339()
+                ))
+            }
+        }
+    }
+
+    /// Apply the writes made since the last flush to the data, so that they are served
+    fn flush_data(&self) {
+        if let Some(data_updater) = &self.data_updater {
+            data_updater.flush();
+        }
+    }
+
+    /// Ensure the writes rather than only adding to the repo, see [`Tracker::ensure`]. A fix to parsing can change the
+    /// whitespace or punctuation of a change, so those differences aren't conflicts
+    fn ensuring(self) -> Self {
+        Self {
+            tracker: self.tracker.with_change_normalizer(ChangeNormalizer::lenient()),
+            ensure: true,
+            ..self
+        }
+    }
+
+    /// Write an update from an email, `source` is the path of the email in the outbox
+    fn write_update(
+        &self,
+        url: &Url,
+        updated_at: &str,
+        change: &str,
+        category: Option<&str>,
+        source: &str,
+    ) -> Result<()> {
         const DATE_FORMAT: &str = "%I:%M%p, %d %B %Y";
         if let Ok(ts) = chrono_tz::Europe::London
             .datetime_from_str(updated_at, DATE_FORMAT)
             .context("parsing timestamp")
         {
             let ts = ts.with_timezone(&ts.offset().fix());
-
-            let update_res = self.update_repo.create(url.clone().into(), ts, change).map(|update| {
+            let category_tag = self.tag_mapping.canonical(category.unwrap_or("unknown"));
+            // a rule can give the category's tag too, which is only written once
+            let rule_tags = self.tag_rules.tags(url.as_str(), change).filter(|tag| *tag != category_tag);
+            let tags = std::iter::once(category_tag).chain(rule_tags);
+
+            let fields = UpdateFields {
+                category: category.map(str::to_owned),
+                source: Some(source.to_owned()),
+                ..UpdateFields::default()
+            };
+
+            let capture = if self.ensure {
+                match self
+                    .tracker
+                    .ensure_with_fields(url.clone().into(), ts, None, change, fields, tags)
+                {
+                    Ok(capture) => capture,
+                    Err(err) => match UpdateConflict::from_error(&err) {
+                        // the email was changed by a fix to parsing, the update already written is kept
+                        Some(conflict) => {
+                            println!("Update conflicts with the update repo, keeping it : {}", conflict);
+                            return Ok(());
+                        }
+                        None => return Err(err.into()),
+                    },
+                }
+            } else {
+                self.tracker
+                    .capture_with_fields(url.clone().into(), ts, None, change, fields, tags)?
+            };
+            let update_ref = match &capture.update {
+                Some(update) => update.update_ref().clone(),
+                // the update already in the repo, which needn't be the first to the url at the timestamp
+                None => self
+                    .tracker
+                    .update_repo()
+                    .ensure(url.clone().into(), ts, change)?
+                    .update_ref()
+                    .clone(),
+            };
+            if capture.update.is_some() {
                 println!("Wrote update to update repo");
-                if let Ok(mut data) = self.data.write() {
-                    data.append_update(update.into_inner());
+            } else if self.ensure {
+                // the tags were ensured with the update, and the source of an update from an earlier email is kept
+                if let Err(err) = self.tracker.update_repo().get_source(&update_ref) {
+                    if err.kind() != io::ErrorKind::NotFound {
+                        return Err(err.into());
+                    }
+                    self.tracker.update_repo().set_source(&update_ref, source)?;
                 }
-            });
-
-            if update_res.is_ok() || update_res.as_ref().unwrap_err().kind() == io::ErrorKind::AlreadyExists {
-                self.tag_repo
-                    .tag_update(
-                        category.unwrap_or("unknown").to_owned(),
-                        (url.to_owned().into(), ts).into(),
-                    )
-                    .map(|tag| {
-                        for e in tag.into_events() {
-                            self.handle_tag_event(e);
-                        }
-                    })?;
+                println!("Update was already in update repo");
+            } else {
+                // the update was in an earlier email, which can be in another category
+                self.tracker.tag_repo().tag_update(category_tag.to_owned(), update_ref)?;
+                println!("Update was already in update repo");
             }
-            update_res?;
         }
         Ok(())
     }
 
-    fn write_doc(
-        &self,
-        url: Url,
-        ts: chrono::DateTime<chrono::FixedOffset>,
-        content: impl AsRef<[u8]>,
-    ) -> io::Result<()> {
-        self.doc_repo
-            .create(url.into(), ts, &mut *self.write_avoidance_buffer.borrow_mut())
-            .and_then(|mut doc| doc.write_all(content.as_ref()).and_then(|_| doc.done()))
-            .map(|doc| {
-                println!("Wrote doc to doc repo");
-                for e in doc.into_events() {
-                    self.handle_doc_event(e);
-                }
-            })
+    /// Write an update from the change history published on a page, returns false if it was already written
+    fn write_history_update(&self, url: &Url, ts: chrono::DateTime<chrono::FixedOffset>, change: &str) -> Result<bool> {
+        let tags = self.tag_rules.tags(url.as_str(), change);
+        let capture = self.tracker.capture(url.clone().into(), ts, None, Some(change), tags)?;
+        Ok(capture.update.is_some())
     }
 
-    pub(crate) fn handle_tag_event(&self, e: TagEvent) {
-        match e {
-            TagEvent::UpdateTagged { tag, update_ref } => {
-                if let Ok(mut data) = self.data.write() {
-                    data.add_tag(update_ref, Arc::new(tag));
-                }
+    fn write_doc(&self, url: Url, ts: chrono::DateTime<chrono::FixedOffset>, content: &DocContent) -> io::Result<()> {
+        self.tracker
+            .capture(url.into(), ts, Some(content), None, None::<String>)?;
+        println!("Wrote doc to doc repo");
+        Ok(())
+    }
+
+    /// The time of the latest update to a url, if it has any
+    fn latest_update(&self, url: &Url) -> io::Result<Option<chrono::DateTime<chrono::FixedOffset>>> {
+        let updates = match self.tracker.update_repo().list_updates(url.clone().into()) {
+            Ok(updates) => updates,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        let mut latest = None;
+        for update in updates {
+            let timestamp = *update?.timestamp();
+            if latest.map_or(true, |latest| timestamp > latest) {
+                latest = Some(timestamp);
             }
-            TagEvent::TagCreated { tag: _ } => {}
         }
+        Ok(latest)
     }
 
-    pub(crate) fn handle_doc_event(&self, e: DocEvent) {
-        match e {
-            DocEvent::Created { url: _ } => {}
-            DocEvent::Updated { url: _, timestamp: _ } => {}
-            DocEvent::Deleted { url: _, timestamp: _ } => {}
+    /// The urls with updates at a url and below it in its path
+    fn tracked_urls(&self, prefix: &Url) -> io::Result<Vec<Url>> {
+        let mut urls: Vec<Url> = vec![];
+        for update in self.tracker.update_repo().list_all(&prefix.clone().into())? {
+            let update = update?;
+            let url: &Url = update.url();
+            // an url's updates are listed together
+            if urls.last() != Some(url) {
+                urls.push(url.clone());
+            }
+        }
+        Ok(urls)
+    }
+
+    /// Mark the page at a url as removed, if a version of it has been stored
+    fn mark_removed(&self, url: Url) -> io::Result<()> {
+        let ts = Utc::now();
+        match self
+            .tracker
+            .doc_repo()
+            .mark_removed(url.into(), ts.with_timezone(&ts.offset().fix()))
+        {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(()),
+            result => result.map(|_| println!("Wrote removal to doc repo")),
         }
     }
 }
 
 #[cfg(test)]
 mod test {
-    // use super::UpdateEmailProcessor;
-    // use super::{email_update::GovUkChange, git::CommitBuilder};
-    // use anyhow::Result;
-    // use git2::{Repository, Signature};
-    // use std::path::Path;
-    // use update_repo::doc::DocRepo;
-    // use update_repo::tag::TagRepo;
-    // use update_repo::update::UpdateRepo;
-
-    // #[test]
-    // fn test_obtain_changes() -> Result<()> {
-    //     const REPO_DIR: &str = "tests/tmp/test_obtain_changes";
-    //     let _ = std::fs::remove_dir_all(REPO_DIR);
-    //     let repo = Repository::init_bare(REPO_DIR)?;
-    //     let test_sig = Signature::now("name", "email")?;
-    //     CommitBuilder::new(&repo, None)?.commit(&test_sig, &test_sig, "initial commit")?;
-    //     // let oid = repo.treebuilder(None)?.write()?;
-    //     // let tree = repo.find_tree(oid)?;
-    //     // repo.commit(Some(GIT_REF), &test_sig, &test_sig, "initial commit", &tree, &[])?;
-    //     let update_email_processor = UpdateEmailProcessor {
-    //         in_dir: "".as_ref(),
-    //         out_dir: "".as_ref(),
-    //         git_repo: repo,
-    //         git_reference: "",
-    //         update_repo: UpdateRepo::new("testtemprepo")?,
-    //         doc_repo: DocRepo::new("testtemprepo")?,
-    //         tag_repo: TagRepo::new("testtemprepo")?,
-    //     };
-    //     let commit = update_email_processor.handle_change(
-    //         &GovUkChange {
-    //             url: "https://www.gov.uk/government/consultations/bus-services-act-2017-bus-open-data".parse()?,
-    //             change: "testing the stuff".to_owned(),
-    //             updated_at: "some time".to_owned(),
-    //             category: Some("Test Category".to_owned()),
-    //         },
-    //         None,
-    //     )?;
-    //     update_email_processor
-    //         .git_repo
-    //         .reference("refs/heads/main", commit.id(), false, "log_message")?;
-
-    //     assert_eq!(commit.message(), Some("some time: testing the stuff [Test Category]"));
-    //     assert_eq!(
-    //         commit
-    //             .tree()?
-    //             .get_path(Path::new(
-    //                 "government/consultations/bus-services-act-2017-bus-open-data.html"
-    //             ))?
-    //             .to_object(&update_email_processor.git_repo)?
-    //             .as_blob()
-    //             .unwrap()
-    //             .size(),
-    //         20929
-    //     );
-    //     Ok(())
-    // }
+    use std::{
+        cell::RefCell,
+        fs,
+        path::Path,
+        sync::Arc,
+    };
+
+    use anyhow::Result;
+    use git2::{Repository, Signature};
+    use update_repo::{
+        doc::{
+            content::{Doc, DocContent},
+            DocRepo,
+        },
+        tag::{Tag, TagRepo},
+        update::UpdateRepo,
+    };
+    use url::Url;
+
+    use super::{fetch::Fetcher, git::CommitBuilder, journal::Journal, UpdateEmailProcessor};
+    use crate::data::{Data, SharedData};
+
+    /// Serves a small html doc for every url and records which were requested
+    #[derive(Default)]
+    struct FixtureFetcher {
+        fetched: RefCell<Vec<Url>>,
+    }
+
+    impl Fetcher for FixtureFetcher {
+        fn fetch(&self, url: &Url) -> Result<Option<Doc>> {
+            self.fetched.borrow_mut().push(url.clone());
+            Ok(Some(Doc {
+                url: url.clone(),
+                content: DocContent::DiffableHtml(format!("<main><h1>{}</h1></main>", url.path()), vec![], vec![], None),
+            }))
+        }
+    }
+
+    #[test]
+    fn test_process_update_emails() {
+        const EMAILS: [&str; 2] = ["2022-02-17T09:53:57.eml", "2022-02-17T09:55:47.eml"];
+        const GIT_REF: &str = "refs/heads/main";
+        let base = Path::new("tmp/test_process_update_emails");
+        let _ = fs::remove_dir_all(base);
+        let (in_dir, out_dir, work_dir, git_dir, new_repo) = (
+            base.join("inbox"),
+            base.join("outbox"),
+            base.join("work"),
+            base.join("git"),
+            base.join("repo"),
+        );
+
+        fs::create_dir_all(in_dir.join("updates")).unwrap();
+        for email in EMAILS {
+            fs::copy(Path::new("tests/emails").join(email), in_dir.join("updates").join(email)).unwrap();
+        }
+        let git_repo = Repository::init_bare(&git_dir).unwrap();
+        let sig = Signature::now("name", "email").unwrap();
+        let initial_commit = CommitBuilder::new(&git_repo, None)
+            .unwrap()
+            .commit(&sig, &sig, "initial commit")
+            .unwrap();
+        git_repo.reference(GIT_REF, initial_commit.id(), false, "initial commit").unwrap();
+        // data is loaded from the gov.uk dir, so it needs to exist
+        fs::create_dir_all(new_repo.join("url").join("www.gov.uk")).unwrap();
+        let data = Arc::new(SharedData::new(Data::load(&new_repo)));
+        let fetcher = FixtureFetcher::default();
+
+        let mut processor = UpdateEmailProcessor::new(
+            &in_dir,
+            &out_dir,
+            &work_dir,
+            &git_dir,
+            GIT_REF,
+            &new_repo,
+            data.clone(),
+            &fetcher,
+        )
+        .unwrap();
+        assert_eq!(processor.process_updates().unwrap(), 2);
+
+        let guinea_bissau: Url = "https://www.gov.uk/foreign-travel-advice/guinea-bissau".parse().unwrap();
+        let burundi: Url = "https://www.gov.uk/foreign-travel-advice/burundi".parse().unwrap();
+        let mut fetched = fetcher.fetched.take();
+        fetched.sort();
+        assert_eq!(fetched, [burundi.clone(), guinea_bissau.clone()]);
+
+        // emails are moved to the outbox
+        for email in EMAILS {
+            assert!(out_dir.join("updates").join(email).exists());
+            assert!(!in_dir.join("updates").join(email).exists());
+        }
+
+        // and journalled with what was done for their changes
+        let journal = Journal::from_env(&new_repo).recent().unwrap();
+        assert_eq!(journal.len(), 2);
+        assert!(journal.iter().all(|email| email.ok && email.changes.iter().all(|change| !change.events.is_empty())));
+        let mut journal_fetched: Vec<_> = journal
+            .iter()
+            .flat_map(|email| email.changes.iter().flat_map(|change| change.fetched.clone()))
+            .collect();
+        journal_fetched.sort();
+        assert_eq!(journal_fetched, [burundi.to_string(), guinea_bissau.to_string()]);
+
+        // updates, docs and tags are written to the repo
+        let update_repo = UpdateRepo::new(new_repo.join("url")).unwrap();
+        let update = update_repo
+            .get_update(guinea_bissau.clone().into(), "2022-02-17T09:53:00+00:00".parse().unwrap())
+            .unwrap();
+        assert!(update.change().starts_with("The FCDO no longer advises"));
+        assert_eq!(update.fields().category.as_deref(), Some("Guidance and regulation"));
+        // with the path of its email in the outbox
+        assert_eq!(
+            update_repo.get_source(update.update_ref()).unwrap(),
+            "updates/2022-02-17T09:53:57.eml"
+        );
+        let doc_repo = DocRepo::new(new_repo.join("url")).unwrap();
+        assert_eq!(doc_repo.list_versions(burundi.clone().into()).unwrap().count(), 1);
+        let tag_repo = TagRepo::new(new_repo.join("tag")).unwrap();
+        assert_eq!(
+            tag_repo
+                .list_updates_in_tag(&Tag::new("Guidance and regulation".to_owned()))
+                .unwrap()
+                .count(),
+            2
+        );
+
+        // the events from the writes update the in memory data
+        let data = data.snapshot();
+        let updates = data.get_updates(&burundi.clone().into()).unwrap();
+        assert_eq!(updates.len(), 1);
+        let (_, tags) = updates.values().next().unwrap();
+        assert_eq!(
+            tags.iter().map(|tag| tag.name()).collect::<Vec<_>>(),
+            ["Guidance and regulation"]
+        );
+
+        // each update is committed to git with its doc
+        let head = git_repo.find_reference(GIT_REF).unwrap().peel_to_commit().unwrap();
+        let previous = head.parent(0).unwrap();
+        assert_eq!(previous.parent_id(0).unwrap(), initial_commit.id());
+        let mut messages = [head.message().unwrap(), previous.message().unwrap()];
+        messages.sort_unstable();
+        assert!(messages[0].starts_with("9:53am, 17 February 2022: The FCDO"));
+        assert!(messages[1].starts_with("9:55am, 17 February 2022: The FCDO"));
+        for path in ["foreign-travel-advice/burundi.html", "foreign-travel-advice/guinea-bissau.html"] {
+            assert!(head.tree().unwrap().get_path(Path::new(path)).is_ok());
+        }
+
+        // reprocessing an email leaves what is already in the repo, and git, alone
+        let reprocessor = UpdateEmailProcessor::reprocessor(&out_dir, &new_repo, &fetcher).unwrap();
+        assert!(reprocessor.reprocess("updates/2022-02-17T09:53:57.eml").unwrap());
+        assert_eq!(fetcher.fetched.take().len(), 1);
+        assert_eq!(doc_repo.list_versions(guinea_bissau.clone().into()).unwrap().count(), 1);
+        assert_eq!(
+            tag_repo
+                .list_updates_in_tag(&Tag::new("Guidance and regulation".to_owned()))
+                .unwrap()
+                .count(),
+            2
+        );
+        assert_eq!(
+            git_repo.find_reference(GIT_REF).unwrap().peel_to_commit().unwrap().id(),
+            head.id()
+        );
+        let journal = Journal::from_env(&new_repo).recent().unwrap();
+        assert_eq!(journal[0].email, "updates/2022-02-17T09:53:57.eml");
+        assert!(journal[0].ok);
+    }
 }
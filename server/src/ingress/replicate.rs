@@ -0,0 +1,202 @@
+//! Mirrors another tracker instance by tailing its export API, as a hot standby or a copy for research
+
+use std::{
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::Arc,
+    thread,
+    time::Duration,
+};
+
+use anyhow::{format_err, Context, Result};
+use chrono::{DateTime, FixedOffset};
+use update_repo::{
+    check,
+    doc::DocRepo,
+    tag::TagRepo,
+    update::{UpdateConflict, UpdateRepo, UpdateValidation},
+    Url,
+};
+
+use crate::data::{DataUpdater, SharedData};
+
+/// Tail the export of the instance at `source` (eg. `https://govdiff.njk.onl`), writing everything it exports into the
+/// repo. The cursor is saved in the repo so that a restarted mirror carries on where it left off
+pub fn run(new_repo_path: &Path, data: Arc<SharedData>, source: &str) -> Result<()> {
+    let poll_interval = Duration::from_secs(
+        dotenv::var("REPLICATE_POLL_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(60),
+    );
+    check::init_repo(new_repo_path).context("Initialising repo")?;
+    let mut replicator = Replicator::new(new_repo_path, data, source.trim_end_matches('/'))?;
+    println!("Replicating from {}", source);
+    loop {
+        match replicator.replicate_page() {
+            Ok(0) => thread::sleep(poll_interval),
+            Ok(count) => println!("Replicated {} updates", count),
+            Err(err) => {
+                println!("Replication failed, retrying after {:?} : {:?}", poll_interval, err);
+                thread::sleep(poll_interval);
+            }
+        }
+    }
+}
+
+struct Replicator<'s> {
+    source: &'s str,
+    update_repo: UpdateRepo,
+    doc_repo: DocRepo,
+    tag_repo: TagRepo,
+    /// the writes of a page are applied to the data together once it has been replicated
+    data_updater: Arc<DataUpdater>,
+    cursor_path: PathBuf,
+    cursor: Option<String>,
+    write_avoidance_buffer: Vec<u8>,
+}
+
+impl<'s> Replicator<'s> {
+    fn new(new_repo: &Path, data: Arc<SharedData>, source: &'s str) -> Result<Self> {
+        let event_sink = Arc::new(DataUpdater::new(data));
+        let cursor_path = new_repo.join("replication-cursor");
+        let cursor = match fs::read_to_string(&cursor_path) {
+            Ok(cursor) => Some(cursor.trim().to_owned()),
+            Err(err) if err.kind() == std::io::ErrorKind::NotFound => None,
+            Err(err) => return Err(err).context("Reading replication cursor"),
+        };
+        let durability = super::durability()?;
+        Ok(Self {
+            source,
+            // the source has already validated its updates
+            update_repo: UpdateRepo::new(new_repo.join("url"))?
+                .with_validation(UpdateValidation::none())
+                .with_durability(durability)
+                .with_event_sink(event_sink.clone()),
+            doc_repo: DocRepo::new(new_repo.join("url"))?
+                .with_durability(durability)
+                .with_event_sink(event_sink.clone()),
+            tag_repo: TagRepo::new(new_repo.join("tag"))?
+                .with_durability(durability)
+                .with_event_sink(event_sink.clone()),
+            data_updater: event_sink,
+            cursor_path,
+            cursor,
+            write_avoidance_buffer: Vec::new(),
+        })
+    }
+
+    /// Replicate the next page of the export, returns the number of updates in it
+    fn replicate_page(&mut self) -> Result<usize> {
+        let mut request = ureq::get(&format!("{}/api/v1/updates/export", self.source))
+            .query("include", "tags,doc_versions")
+            .set("User-Agent", "GovDiffMirror/0.1");
+        if let Some(cursor) = &self.cursor {
+            request = request.query("since", cursor);
+        }
+        let response = request.call().context("Requesting export")?;
+        let next_cursor = response.header("X-Next-Cursor").map(str::to_owned);
+        let body = response.into_string().context("Reading export")?;
+
+        let count = self.replicate_updates(&body)?;
+        if let Some(next_cursor) = next_cursor {
+            fs::write(&self.cursor_path, &next_cursor).context("Writing replication cursor")?;
+            self.cursor = Some(next_cursor);
+        }
+        Ok(count)
+    }
+
+    /// Replicate a page of the export, a page is replicated again if the mirror stopped before saving its cursor
+    fn replicate_updates(&mut self, page: &str) -> Result<usize> {
+        let mut count = 0;
+        let replicated: Result<()> = page.lines().filter(|line| !line.is_empty()).try_for_each(|line| {
+            self.replicate_update(line).with_context(|| format!("Replicating {}", line))?;
+            count += 1;
+            Ok(())
+        });
+        self.data_updater.flush();
+        replicated?;
+        Ok(count)
+    }
+
+    fn replicate_update(&mut self, line: &str) -> Result<()> {
+        let exported: serde_json::Value = serde_json::from_str(line)?;
+        let field = |name: &str| exported[name].as_str().ok_or_else(|| format_err!("Missing {}", name));
+        let url: Url = field("url")?.parse()?;
+        let timestamp: DateTime<FixedOffset> = field("timestamp")?.parse()?;
+
+        // docs first, so that the update doesn't appear on the mirror before the diff it shows
+        for doc_version in exported["doc_versions"].as_array().into_iter().flatten() {
+            let doc_timestamp: DateTime<FixedOffset> = doc_version
+                .as_str()
+                .ok_or_else(|| format_err!("Invalid doc version"))?
+                .parse()?;
+            if self.doc_repo.ensure_version(url.clone(), doc_timestamp).is_err() {
+                self.replicate_doc_version(&url, doc_timestamp)?;
+            }
+        }
+
+        let change = field("change")?;
+        // ensure can't tell a later update at the same timestamp from a reworded first one, but the source numbered it
+        let is_later = exported["seq"].as_u64().unwrap_or(0) > 0;
+        let update = match self.update_repo.ensure(url.clone(), timestamp, change) {
+            Err(err) if is_later && UpdateConflict::from_error(&err).is_some() => {
+                self.update_repo.create(url.clone(), timestamp, change)?
+            }
+            result => result?,
+        };
+        // tagged on every pass, as the mirror could have stopped between writing the update and tagging it
+        let update_ref = update.update_ref().clone();
+        for tag in exported["tags"].as_array().into_iter().flatten().filter_map(|tag| tag.as_str()) {
+            self.tag_repo.ensure_tagged(tag.to_owned(), update_ref.clone())?;
+        }
+        Ok(())
+    }
+
+    fn replicate_doc_version(&mut self, url: &Url, timestamp: DateTime<FixedOffset>) -> Result<()> {
+        let response = ureq::get(&format!(
+            "{}/api/v1/docs/{}/{}",
+            self.source,
+            timestamp.to_rfc3339(),
+            url.as_str().trim_start_matches("https://")
+        ))
+        .set("User-Agent", "GovDiffMirror/0.1")
+        .call()
+        .context("Requesting doc version")?;
+        let mut content = vec![];
+        response.into_reader().read_to_end(&mut content)?;
+        let mut write = self
+            .doc_repo
+            .create(url.clone(), timestamp, &mut self.write_avoidance_buffer)?;
+        write.write_all(&content)?;
+        write.done()?;
+        Ok(())
+    }
+}
+
+#[test]
+fn test_replicating_a_page_again() {
+    let base = Path::new("tmp/test_replicating_a_page_again");
+    let _ = fs::remove_dir_all(base);
+    check::init_repo(base).unwrap();
+    let data = Arc::new(SharedData::new(crate::data::Data::load(base)));
+    let mut replicator = Replicator::new(base, data.clone(), "https://govdiff.example").unwrap();
+    let page = concat!(
+        r#"{"url":"https://www.gov.uk/guidance/a","timestamp":"2022-02-17T09:00:00+00:00","change":"Updated","tags":["brexit"],"doc_versions":[]}"#,
+        "\n",
+        r#"{"url":"https://www.gov.uk/guidance/b","timestamp":"2022-02-17T09:05:00+00:00","change":"Updated","tags":["brexit","visas"],"doc_versions":[]}"#,
+        "\n",
+    );
+    // as if the mirror had stopped after writing the second update but before tagging it
+    let url = "https://www.gov.uk/guidance/b".parse().unwrap();
+    let timestamp = "2022-02-17T09:05:00+00:00".parse().unwrap();
+    replicator.update_repo.create(url, timestamp, "Updated").unwrap();
+
+    assert_eq!(replicator.replicate_updates(page).unwrap(), 2);
+    assert_eq!(replicator.replicate_updates(page).unwrap(), 2);
+    let tagged = |tag: &str| replicator.tag_repo.list_updates_in_tag(tag).unwrap().count();
+    assert_eq!(tagged("brexit"), 2);
+    assert_eq!(tagged("visas"), 1);
+    assert_eq!(data.snapshot().update_count(), 2);
+}
@@ -0,0 +1,187 @@
+//! Alerting when ingestion stalls, when no email has been processed or no update has been written for a while during
+//! working hours, as happens when the disk is full or the mail forwarding has broken. Update emails are only sent during
+//! the working day, so quiet nights and weekends don't alert
+
+use std::{
+    sync::atomic::{AtomicBool, Ordering},
+    time::Duration,
+};
+
+use chrono::{DateTime, Datelike, TimeZone, Timelike, Utc, Weekday};
+use chrono_tz::Tz;
+
+/// Whether ingestion is stalled, for reporting alongside the other stats
+static STALLED: AtomicBool = AtomicBool::new(false);
+
+/// Whether the watchdog has found ingestion to be stalled, and it hasn't resumed since
+pub fn is_stalled() -> bool {
+    STALLED.load(Ordering::Relaxed)
+}
+
+pub struct Watchdog {
+    /// how long there can be no activity during working hours before alerting
+    stall_after: chrono::Duration,
+    /// the hours of weekdays when emails are expected, from the start hour up to the end hour
+    working_hours: (u32, u32),
+    tz: Tz,
+    /// posted a JSON message when ingestion stalls and when it resumes
+    notify_url: Option<url::Url>,
+    last_email: DateTime<Utc>,
+    last_update: DateTime<Utc>,
+    alerted: bool,
+}
+
+impl Watchdog {
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// The watchdog configured with `STALL_ALERT_HOURS`, the hours without activity before alerting, it is disabled when
+    /// that isn't set. `STALL_ALERT_WORKING_HOURS` are the hours of weekdays in `DISPLAY_TZ` when emails are expected
+    /// (default `9-18`), and alerts are also posted to `STALL_ALERT_URL` if it is set
+    pub fn from_env(now: DateTime<Utc>) -> Option<Self> {
+        let stall_after = dotenv::var("STALL_ALERT_HOURS").ok()?.parse().ok()?;
+        let working_hours = dotenv::var("STALL_ALERT_WORKING_HOURS")
+            .ok()
+            .and_then(|hours| parse_hours(&hours))
+            .unwrap_or((9, 18));
+        let tz = dotenv::var("DISPLAY_TZ")
+            .ok()
+            .and_then(|tz| tz.parse().ok())
+            .unwrap_or(chrono_tz::Europe::London);
+        let notify_url = dotenv::var("STALL_ALERT_URL").ok().and_then(|url| url.parse().ok());
+        Some(Self::new(
+            chrono::Duration::hours(stall_after),
+            working_hours,
+            tz,
+            notify_url,
+            now,
+        ))
+    }
+
+    fn new(
+        stall_after: chrono::Duration,
+        working_hours: (u32, u32),
+        tz: Tz,
+        notify_url: Option<url::Url>,
+        now: DateTime<Utc>,
+    ) -> Self {
+        Self {
+            stall_after,
+            working_hours,
+            tz,
+            notify_url,
+            last_email: now,
+            last_update: now,
+            alerted: false,
+        }
+    }
+
+    pub fn email_processed(&mut self, at: DateTime<Utc>) {
+        self.last_email = at;
+    }
+
+    pub fn update_written(&mut self, at: DateTime<Utc>) {
+        self.last_update = at;
+    }
+
+    /// Alert if ingestion has stalled, or that it has resumed after an alert
+    pub fn check(&mut self, now: DateTime<Utc>) {
+        match (self.stall(now), self.alerted) {
+            (Some(stall), false) => {
+                self.alerted = true;
+                STALLED.store(true, Ordering::Relaxed);
+                self.alert(&format!("Ingestion has stalled, {}", stall));
+            }
+            (None, true) => {
+                self.alerted = false;
+                STALLED.store(false, Ordering::Relaxed);
+                self.alert("Ingestion has resumed");
+            }
+            _ => {}
+        }
+    }
+
+    /// What hasn't happened for too long, if ingestion is stalled. Once stalled it stays stalled out of working hours,
+    /// until there is activity again
+    fn stall(&self, now: DateTime<Utc>) -> Option<String> {
+        let since = |last: DateTime<Utc>| now - last >= self.stall_after;
+        let stall = if since(self.last_email) {
+            format!("no email has been processed since {}", self.last_email.to_rfc3339())
+        } else if since(self.last_update) {
+            format!("no update has been written since {}", self.last_update.to_rfc3339())
+        } else {
+            return None;
+        };
+        (self.alerted || self.is_working_hours(now)).then(|| stall)
+    }
+
+    fn is_working_hours(&self, now: DateTime<Utc>) -> bool {
+        let local = self.tz.from_utc_datetime(&now.naive_utc());
+        let (start, end) = self.working_hours;
+        !matches!(local.weekday(), Weekday::Sat | Weekday::Sun) && (start..end).contains(&local.hour())
+    }
+
+    fn alert(&self, message: &str) {
+        println!("ALERT : {}", message);
+        if let Some(notify_url) = &self.notify_url {
+            let body = serde_json::json!({ "text": message }).to_string();
+            let result = ureq::post(notify_url.as_str())
+                .timeout(Self::TIMEOUT)
+                .set("Content-Type", "application/json")
+                .send_string(&body);
+            if let Err(err) = result {
+                println!("Stall alert to {} failed : {}", notify_url, err);
+            }
+        }
+    }
+}
+
+/// A range of hours, eg. `9-18`
+fn parse_hours(hours: &str) -> Option<(u32, u32)> {
+    let (start, end) = hours.split_once('-')?;
+    let (start, end) = (start.trim().parse().ok()?, end.trim().parse().ok()?);
+    (start < end && end <= 24).then(|| (start, end))
+}
+
+#[test]
+fn test_stall_during_working_hours() {
+    let at = |timestamp: &str| -> DateTime<Utc> { timestamp.parse().unwrap() };
+    // a Friday
+    let start = at("2022-02-18T08:00:00Z");
+    let mut watchdog = Watchdog::new(
+        chrono::Duration::hours(4),
+        (9, 18),
+        chrono_tz::Europe::London,
+        None,
+        start,
+    );
+    assert_eq!(watchdog.stall(at("2022-02-18T11:00:00Z")), None);
+    assert_eq!(
+        watchdog.stall(at("2022-02-18T12:00:00Z")).as_deref(),
+        Some("no email has been processed since 2022-02-18T08:00:00+00:00")
+    );
+
+    watchdog.email_processed(at("2022-02-18T12:00:00Z"));
+    assert_eq!(
+        watchdog.stall(at("2022-02-18T12:00:00Z")).as_deref(),
+        Some("no update has been written since 2022-02-18T08:00:00+00:00")
+    );
+    watchdog.update_written(at("2022-02-18T12:00:00Z"));
+    assert_eq!(watchdog.stall(at("2022-02-18T12:00:00Z")), None);
+
+    // not in the evening or at the weekend
+    assert_eq!(watchdog.stall(at("2022-02-18T20:00:00Z")), None);
+    assert_eq!(watchdog.stall(at("2022-02-19T12:00:00Z")), None);
+    assert!(watchdog.stall(at("2022-02-21T09:00:00Z")).is_some());
+
+    // an alert isn't resolved by the end of the day
+    watchdog.check(at("2022-02-21T09:00:00Z"));
+    assert!(is_stalled());
+    assert!(watchdog.stall(at("2022-02-21T20:00:00Z")).is_some());
+    watchdog.email_processed(at("2022-02-21T20:00:00Z"));
+    watchdog.update_written(at("2022-02-21T20:00:00Z"));
+    watchdog.check(at("2022-02-21T20:00:00Z"));
+    assert!(!is_stalled());
+
+    assert_eq!(parse_hours("8-20"), Some((8, 20)));
+    assert_eq!(parse_hours("20-8"), None);
+}
@@ -1,3 +1,24 @@
+use std::path::Path;
+
+pub mod daemon;
 pub mod data;
+pub mod display_rules;
 pub mod ingress;
+pub mod search;
+pub mod url_index;
 pub mod web;
+pub mod webhooks;
+
+/// Whether the server is configured with `READ_ONLY` to serve an existing repo without writing anything to it, ingress,
+/// admin actions and diff cache writes are all disabled. It is also read only when the repo in `NEW_REPO` is on a read
+/// only filesystem, such as a snapshot
+pub fn read_only() -> bool {
+    dotenv::var("READ_ONLY").map_or(false, |read_only| !matches!(read_only.as_str(), "" | "0" | "false"))
+        || dotenv::var("NEW_REPO").map_or(false, |repo| is_read_only_fs(Path::new(&repo)))
+}
+
+/// Whether a path is on a filesystem mounted read only, a path which can't be checked is taken to be writable as writing
+/// to it will show what is wrong
+pub fn is_read_only_fs(path: &Path) -> bool {
+    ingress::disk_space::is_read_only(path).unwrap_or(false)
+}
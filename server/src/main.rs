@@ -3,27 +3,65 @@
 static ALLOC: dhat::Alloc = dhat::Alloc;
 
 use std::{
-    sync::{Arc, RwLock},
+    sync::{mpsc, Arc, Mutex},
     thread,
 };
 
-use update_tracker::{data::Data, ingress, web};
+use update_repo::check::check_repo_or_exit;
+use update_tracker::{
+    daemon,
+    data::{Data, SharedData},
+    ingress, read_only, web,
+};
 
 fn main() {
     #[cfg(feature = "dhat-heap")]
     let profiler = dhat::Profiler::builder().file_name("dhat-heap-setup.json").build();
 
-    let new_repo_path = dotenv::var("NEW_REPO").unwrap();
+    if let Err(err) = daemon::reopen_log() {
+        eprintln!("Can't open LOG_FILE : {}", err);
+        std::process::exit(1);
+    }
+    let new_repo_path = dotenv::var("NEW_REPO").unwrap_or_else(|_| {
+        eprintln!("NEW_REPO must be set to the path of the repo");
+        std::process::exit(1);
+    });
+    check_repo_or_exit(&new_repo_path);
+    let pid_file = daemon::PidFile::from_env().unwrap_or_else(|err| {
+        eprintln!("Can't write PID_FILE : {}", err);
+        std::process::exit(1);
+    });
     println!("Loading data");
 
-    let data = Arc::new(RwLock::new(Data::load(new_repo_path.as_ref())));
+    let mut data = Data::load(new_repo_path.as_ref());
+    if dotenv::var("BODY_SEARCH").map_or(false, |body_search| !matches!(body_search.as_str(), "" | "0" | "false")) {
+        println!("Indexing doc bodies");
+        data.index_bodies();
+    }
+    let data = Arc::new(SharedData::new(data));
     let data2 = data.clone();
+    let (admin_sender, admin_receiver) = mpsc::channel();
 
-    thread::spawn(move || {
-        if let Err(err) = ingress::run(new_repo_path.as_ref(), data2) {
-            println!("Ingress failed : {} {:?}", err, err);
+    if read_only() {
+        println!("Read only, ingress is disabled");
+        // refetches and retractions fail as there is nothing to receive them
+        drop(admin_receiver);
+    } else {
+        if dotenv::var("PRECOMPUTE_DIFFS").map_or(false, |precompute| !matches!(precompute.as_str(), "" | "0" | "false")) {
+            let precomputer = web::precompute::Precomputer::from_env(new_repo_path.as_ref(), data.clone())
+                .expect("opening the repo to precompute diffs");
+            thread::spawn(move || precomputer.run());
         }
-    });
+        thread::spawn(move || {
+            let result = match dotenv::var("REPLICATE_FROM") {
+                Ok(source) => ingress::replicate::run(new_repo_path.as_ref(), data2, &source),
+                Err(_) => ingress::run(new_repo_path.as_ref(), data2, admin_receiver),
+            };
+            if let Err(err) = result {
+                println!("Ingress failed : {} {:?}", err, err);
+            }
+        });
+    }
 
     #[cfg(feature = "dhat-heap")]
     drop(profiler);
@@ -42,5 +80,10 @@ fn main() {
         std::process::exit(0);
     });
 
-    web::listen(dotenv::var("LISTEN_ADDR").as_deref().unwrap_or("127.0.0.1:8080"), data);
+    let app = Arc::new(web::App::new(data, Mutex::new(admin_sender)));
+    let reload_app = app.clone();
+    daemon::handle_signals(pid_file, move || reload_app.reload_config()).expect("handling signals");
+    daemon::notify_ready();
+
+    web::listen(dotenv::var("LISTEN_ADDR").as_deref().unwrap_or("127.0.0.1:8080"), app);
 }
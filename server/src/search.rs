@@ -0,0 +1,470 @@
+//! Searching updates by their change descriptions, and docs by the text of their latest versions
+
+use std::{
+    collections::{btree_map, BTreeMap, BTreeSet, HashMap, HashSet},
+    convert::Infallible,
+    mem,
+    ops::{Bound, Range},
+    str::FromStr,
+    sync::Arc,
+};
+
+use update_repo::{
+    update::{Update, UpdateRef},
+    Url,
+};
+
+/// An inverted index of the words in update change descriptions, supporting word, prefix and phrase queries
+#[derive(Clone, Default)]
+pub struct ChangeIndex {
+    /// indexed updates, their index in this is their document id
+    docs: Vec<Arc<Update>>,
+    /// each word to the (document id, word position) of each of its occurences, in insertion order
+    postings: BTreeMap<String, Vec<(u32, u32)>>,
+}
+
+impl ChangeIndex {
+    /// Add an update to the index
+    pub fn insert(&mut self, update: Arc<Update>) {
+        let doc = self.docs.len() as u32;
+        for (position, word) in words(update.change()).enumerate() {
+            self.postings.entry(word).or_default().push((doc, position as u32));
+        }
+        self.docs.push(update);
+    }
+
+    /// Remove an update from the index, its document id isn't reused. Updates are removed rarely enough to look through
+    /// every word
+    pub fn remove(&mut self, update_ref: &UpdateRef) {
+        // the last, in case it was removed and added again
+        let doc = match self.docs.iter().rposition(|update| update.update_ref() == update_ref) {
+            Some(doc) => doc as u32,
+            None => return,
+        };
+        self.postings.retain(|_, postings| {
+            postings.retain(|(posting_doc, _)| *posting_doc != doc);
+            !postings.is_empty()
+        });
+    }
+
+    /// Updates matching all the terms of the query, the most relevant first and then from newest to oldest. Relevance is the number of times the terms match
+    pub fn search(&self, query: &Query) -> Vec<&Update> {
+        // matching documents and their relevance
+        let mut matches: Option<BTreeMap<u32, usize>> = None;
+        for term in &query.terms {
+            let term_matches = self.term_matches(term);
+            matches = Some(match matches {
+                Some(mut matches) => {
+                    matches.retain(|doc, relevance| {
+                        if let Some(positions) = term_matches.get(doc) {
+                            *relevance += positions.len();
+                            true
+                        } else {
+                            false
+                        }
+                    });
+                    matches
+                }
+                None => term_matches
+                    .into_iter()
+                    .map(|(doc, positions)| (doc, positions.len()))
+                    .collect(),
+            });
+        }
+        let mut results: Vec<(usize, &Update)> = matches
+            .unwrap_or_default()
+            .into_iter()
+            .map(|(doc, relevance)| (relevance, &*self.docs[doc as usize]))
+            .collect();
+        results.sort_by(|(a_relevance, a), (b_relevance, b)| {
+            b_relevance
+                .cmp(a_relevance)
+                .then_with(|| crate::data::newest_first(a, b))
+        });
+        results.into_iter().map(|(_, update)| update).collect()
+    }
+
+    /// The documents matching a term with the positions at which the match starts in each
+    fn term_matches(&self, term: &QueryTerm) -> BTreeMap<u32, Vec<u32>> {
+        match term {
+            QueryTerm::Word(word) => collect_positions(self.postings.get(word)),
+            QueryTerm::Prefix(prefix) => collect_positions(
+                self.postings
+                    .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+                    .take_while(|(word, _)| word.starts_with(prefix.as_str()))
+                    .map(|(_, postings)| postings),
+            ),
+            QueryTerm::Phrase(words) => {
+                let mut words = words.iter().enumerate();
+                let mut starts = match words.next() {
+                    Some((_, word)) => collect_positions(self.postings.get(word)),
+                    None => return BTreeMap::new(),
+                };
+                for (offset, word) in words {
+                    let next = collect_positions(self.postings.get(word));
+                    starts.retain(|doc, positions| {
+                        if let Some(next_positions) = next.get(doc) {
+                            positions.retain(|position| next_positions.contains(&(position + offset as u32)));
+                            !positions.is_empty()
+                        } else {
+                            false
+                        }
+                    });
+                }
+                starts
+            }
+        }
+    }
+}
+
+/// An index of the words in the latest version of each doc. Only which docs have a word is kept, not where it is in
+/// them, as the docs are so much longer than change descriptions, so a phrase matches the docs with all of its words
+#[derive(Clone, Default)]
+pub struct BodyIndex {
+    /// indexed docs, their index in this is their document id
+    urls: Vec<Url>,
+    ids: HashMap<Url, u32>,
+    /// each word to the documents it is in
+    postings: BTreeMap<String, BTreeSet<u32>>,
+    /// the words of each document, so that a reindexed document is removed from only the postings of its words
+    doc_words: Vec<Vec<String>>,
+}
+
+impl BodyIndex {
+    /// Index the text of a doc, replacing the text it was indexed with before
+    pub fn insert(&mut self, url: Url, text: &str) {
+        self.insert_words(url, body_words(text));
+    }
+
+    /// Index a doc by its words as split by [`body_words`], replacing the words it was indexed with before. The words
+    /// can be split from the text before the index is written, so that writing it is quick
+    pub fn insert_words(&mut self, url: Url, words: Vec<String>) {
+        let doc = match self.ids.get(&url) {
+            Some(&doc) => {
+                for word in mem::take(&mut self.doc_words[doc as usize]) {
+                    if let btree_map::Entry::Occupied(mut docs) = self.postings.entry(word) {
+                        docs.get_mut().remove(&doc);
+                        if docs.get().is_empty() {
+                            docs.remove();
+                        }
+                    }
+                }
+                doc
+            }
+            None => {
+                let doc = self.urls.len() as u32;
+                self.ids.insert(url.clone(), doc);
+                self.urls.push(url);
+                self.doc_words.push(vec![]);
+                doc
+            }
+        };
+        for word in &words {
+            self.postings.entry(word.clone()).or_default().insert(doc);
+        }
+        self.doc_words[doc as usize] = words;
+    }
+
+    /// The docs with all of the words of the query
+    pub fn search(&self, query: &Query) -> HashSet<&Url> {
+        let mut matches: Option<BTreeSet<u32>> = None;
+        for term in &query.terms {
+            let term_matches = self.term_matches(term);
+            matches = Some(match matches {
+                Some(matches) => matches.intersection(&term_matches).copied().collect(),
+                None => term_matches,
+            });
+        }
+        matches
+            .unwrap_or_default()
+            .into_iter()
+            .map(|doc| &self.urls[doc as usize])
+            .collect()
+    }
+
+    fn term_matches(&self, term: &QueryTerm) -> BTreeSet<u32> {
+        match term {
+            QueryTerm::Word(word) => self.postings.get(word).cloned().unwrap_or_default(),
+            QueryTerm::Prefix(prefix) => self
+                .postings
+                .range::<str, _>((Bound::Included(prefix.as_str()), Bound::Unbounded))
+                .take_while(|(word, _)| word.starts_with(prefix.as_str()))
+                .flat_map(|(_, docs)| docs.iter().copied())
+                .collect(),
+            QueryTerm::Phrase(words) => words
+                .iter()
+                .map(|word| self.term_matches(&QueryTerm::Word(word.clone())))
+                .reduce(|matches, word_matches| matches.intersection(&word_matches).copied().collect())
+                .unwrap_or_default(),
+        }
+    }
+}
+
+fn collect_positions<'p>(postings: impl IntoIterator<Item = &'p Vec<(u32, u32)>>) -> BTreeMap<u32, Vec<u32>> {
+    let mut positions: BTreeMap<u32, Vec<u32>> = BTreeMap::new();
+    for &(doc, position) in postings.into_iter().flatten() {
+        positions.entry(doc).or_default().push(position);
+    }
+    positions
+}
+
+/// The distinct words of a doc's text, in order, as a [`BodyIndex`] indexes them
+pub fn body_words(text: &str) -> Vec<String> {
+    let words: BTreeSet<String> = words(text).collect();
+    words.into_iter().collect()
+}
+
+/// Splits text into lowercase words, ignoring punctuation
+fn words(text: &str) -> impl Iterator<Item = String> + '_ {
+    word_spans(text).map(|(_, word)| word)
+}
+
+/// Splits text into lowercase words along with the range of the text they came from
+fn word_spans(text: &str) -> impl Iterator<Item = (Range<usize>, String)> + '_ {
+    text.split(|c: char| !c.is_alphanumeric())
+        .filter(|word| !word.is_empty())
+        .map(move |word| {
+            let start = word.as_ptr() as usize - text.as_ptr() as usize;
+            (start..start + word.len(), word.to_lowercase())
+        })
+}
+
+/// A parsed search query, all of the terms need to match. Words in double quotes are matched as a phrase and a word ending in `*` matches as a prefix
+#[derive(Debug, PartialEq, Eq)]
+pub struct Query {
+    terms: Vec<QueryTerm>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum QueryTerm {
+    Word(String),
+    Prefix(String),
+    Phrase(Vec<String>),
+}
+
+impl Query {
+    pub fn parse(query: &str) -> Self {
+        let mut terms = vec![];
+        for (i, part) in query.split('"').enumerate() {
+            if i % 2 == 1 {
+                // inside quotes
+                let mut phrase: Vec<String> = words(part).collect();
+                match phrase.len() {
+                    0 => {}
+                    1 => terms.push(QueryTerm::Word(phrase.remove(0))),
+                    _ => terms.push(QueryTerm::Phrase(phrase)),
+                }
+                continue;
+            }
+            for token in part.split_whitespace() {
+                let is_prefix = token.ends_with('*');
+                let mut token_words: Vec<String> = words(token).collect();
+                let last = token_words.pop();
+                terms.extend(token_words.into_iter().map(QueryTerm::Word));
+                match last {
+                    Some(last) if is_prefix => terms.push(QueryTerm::Prefix(last)),
+                    Some(last) => terms.push(QueryTerm::Word(last)),
+                    None => {}
+                }
+            }
+        }
+        Self { terms }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.terms.is_empty()
+    }
+
+    /// Wrap the words in the text which match this query in `<mark>`
+    pub fn highlight(&self, text: &str) -> String {
+        let spans: Vec<_> = word_spans(text).collect();
+        let mut marked = vec![false; spans.len()];
+        for term in &self.terms {
+            match term {
+                QueryTerm::Word(word) => {
+                    for (i, (_, span_word)) in spans.iter().enumerate() {
+                        marked[i] |= span_word == word;
+                    }
+                }
+                QueryTerm::Prefix(prefix) => {
+                    for (i, (_, span_word)) in spans.iter().enumerate() {
+                        marked[i] |= span_word.starts_with(prefix.as_str());
+                    }
+                }
+                QueryTerm::Phrase(words) => {
+                    for (i, window) in spans.windows(words.len()).enumerate() {
+                        if window.iter().zip(words).all(|((_, span_word), word)| span_word == word) {
+                            marked[i..i + words.len()].iter_mut().for_each(|marked| *marked = true);
+                        }
+                    }
+                }
+            }
+        }
+
+        let mut highlighted = String::with_capacity(text.len());
+        let mut written_to = 0;
+        for ((range, _), marked) in spans.into_iter().zip(marked) {
+            if marked {
+                highlighted.push_str(&text[written_to..range.start]);
+                highlighted.push_str("<mark>");
+                highlighted.push_str(&text[range.clone()]);
+                highlighted.push_str("</mark>");
+                written_to = range.end;
+            }
+        }
+        highlighted.push_str(&text[written_to..]);
+        highlighted
+    }
+}
+
+impl FromStr for Query {
+    type Err = Infallible;
+
+    fn from_str(query: &str) -> Result<Self, Self::Err> {
+        Ok(Self::parse(query))
+    }
+}
+
+#[test]
+fn test_query_parse() {
+    assert_eq!(
+        Query::parse(r#"Travel advice* "Covid-19 risks" x"#),
+        Query {
+            terms: vec![
+                QueryTerm::Word("travel".to_owned()),
+                QueryTerm::Prefix("advice".to_owned()),
+                QueryTerm::Phrase(vec!["covid".to_owned(), "19".to_owned(), "risks".to_owned()]),
+                QueryTerm::Word("x".to_owned()),
+            ]
+        }
+    );
+    assert!(Query::parse(r#" "" * "#).is_empty());
+}
+
+#[test]
+fn test_search() {
+    use update_repo::update::UpdateRepo;
+
+    let _ = std::fs::remove_dir_all("tmp/search");
+    let repo = UpdateRepo::new("tmp/search").unwrap();
+    let mut index = ChangeIndex::default();
+    for (timestamp, change) in [
+        ("2022-02-17T09:52:00+00:00", "Travel and travel insurance advice"),
+        ("2022-02-17T09:53:00+00:00", "Travel advice updated for COVID-19 risks"),
+        ("2022-02-17T09:54:00+00:00", "Updated the travel advisory"),
+        ("2022-02-17T09:55:00+00:00", "Advice on travel updated"),
+    ] {
+        let update = repo
+            .create(
+                "https://www.gov.uk/foreign-travel-advice".parse().unwrap(),
+                timestamp.parse().unwrap(),
+                change,
+            )
+            .unwrap();
+        index.insert(Arc::new(update.into_inner()));
+    }
+    let search = |query| -> Vec<String> {
+        index
+            .search(&Query::parse(query))
+            .into_iter()
+            .map(|update| update.change().to_owned())
+            .collect()
+    };
+
+    assert_eq!(
+        search("travel updated"),
+        [
+            "Advice on travel updated",
+            "Updated the travel advisory",
+            "Travel advice updated for COVID-19 risks"
+        ]
+    );
+    assert_eq!(
+        search("advice travel"),
+        [
+            "Travel and travel insurance advice",
+            "Advice on travel updated",
+            "Travel advice updated for COVID-19 risks"
+        ]
+    );
+    assert_eq!(search("advi*").len(), 4);
+    assert_eq!(search(r#""travel advice""#), ["Travel advice updated for COVID-19 risks"]);
+    assert!(search("brexit").is_empty());
+
+    // equally relevant updates at the same time are in url order, reversed
+    for url in ["https://www.gov.uk/visas/b", "https://www.gov.uk/visas/c", "https://www.gov.uk/visas/a"] {
+        let update = repo
+            .create(url.parse().unwrap(), "2022-02-17T09:56:00+00:00".parse().unwrap(), "Visa fees")
+            .unwrap();
+        index.insert(Arc::new(update.into_inner()));
+    }
+    let urls: Vec<_> = index
+        .search(&Query::parse("visa"))
+        .into_iter()
+        .map(|update| update.url().as_str().to_owned())
+        .collect();
+    assert_eq!(
+        urls,
+        [
+            "https://www.gov.uk/visas/c",
+            "https://www.gov.uk/visas/b",
+            "https://www.gov.uk/visas/a"
+        ]
+    );
+
+    index.remove(&UpdateRef {
+        url: "https://www.gov.uk/visas/b".parse().unwrap(),
+        timestamp: "2022-02-17T09:56:00+00:00".parse().unwrap(),
+        seq: 0,
+    });
+    assert_eq!(index.search(&Query::parse("visa")).len(), 2);
+    assert!(index
+        .search(&Query::parse("fees"))
+        .iter()
+        .all(|update| update.url().as_str() != "https://www.gov.uk/visas/b"));
+}
+
+#[test]
+fn test_body_search() {
+    let mut index = BodyIndex::default();
+    let url = |path: &str| -> Url { format!("https://www.gov.uk{}", path).parse().unwrap() };
+    index.insert(url("/a"), "Apply for a visa before you travel");
+    index.insert(url("/b"), "Travel insurance is advised");
+    index.insert(url("/c"), "Fees for a visa");
+    let search = |index: &BodyIndex, query| -> Vec<String> {
+        let mut paths: Vec<_> = index
+            .search(&Query::parse(query))
+            .into_iter()
+            .map(|url| url.path().to_owned())
+            .collect();
+        paths.sort();
+        paths
+    };
+
+    assert_eq!(search(&index, "visa"), ["/a", "/c"]);
+    assert_eq!(search(&index, "travel visa"), ["/a"]);
+    assert_eq!(search(&index, "advi*"), ["/b"]);
+    assert_eq!(search(&index, r#""visa before""#), ["/a"]);
+    assert!(search(&index, "brexit").is_empty());
+
+    // a new version replaces the text of the last
+    index.insert(url("/a"), "Apply for a passport");
+    assert_eq!(search(&index, "visa"), ["/c"]);
+    assert_eq!(search(&index, "passport"), ["/a"]);
+    assert!(search(&index, "before").is_empty());
+    // words only the replaced text had are dropped
+    assert!(!index.postings.contains_key("before"));
+
+    index.insert_words(url("/c"), body_words("Fees for a passport, fees"));
+    assert!(search(&index, "visa").is_empty());
+    assert_eq!(search(&index, "passport"), ["/a", "/c"]);
+    assert!(!index.postings.contains_key("visa"));
+}
+
+#[test]
+fn test_highlight() {
+    assert_eq!(
+        Query::parse(r#"advi* "covid 19" the"#).highlight("Updated the travel advisory, Advice on COVID-19 and 19"),
+        "Updated <mark>the</mark> travel <mark>advisory</mark>, <mark>Advice</mark> on <mark>COVID</mark>-<mark>19</mark> and 19"
+    );
+}
@@ -0,0 +1,364 @@
+//! The index of updates by url, for listing and counting the updates under a url prefix
+
+use std::{
+    collections::{btree_map, BTreeMap, BinaryHeap, HashSet},
+    ops::Bound,
+    sync::Arc,
+};
+
+use chrono::{DateTime, FixedOffset};
+use qp_trie::Trie;
+use update_repo::{
+    tag::Tag,
+    update::{Update, UpdateRef},
+    Url,
+};
+
+/// The updates to a url with their tags, by timestamp and then seq
+pub type TimestampSubIndex = BTreeMap<(DateTime<FixedOffset>, u32), (Arc<Update>, HashSet<Arc<Tag>>)>;
+
+/// Updates in url and then timestamp order with their tags. Alongside the trie of urls, the number of updates under each
+/// path is kept up to date as updates are added, so that the updates under a prefix can be counted without visiting them
+#[derive(Clone, Default)]
+pub struct UrlIndex {
+    urls: Trie<Url, TimestampSubIndex>,
+    counts: PathCounts,
+}
+
+/// The number of updates to urls starting with a path, and to those starting with each longer path one segment on. The
+/// top level is keyed by the urls' origins, then by each segment of their paths
+#[derive(Clone, Default)]
+struct PathCounts {
+    total: usize,
+    children: BTreeMap<Box<str>, PathCounts>,
+}
+
+impl UrlIndex {
+    /// Add an update, returns whether it is the first update to its url
+    pub fn insert(&mut self, update: Arc<Update>) -> bool {
+        let updates = self.urls.entry(update.url().clone()).or_insert_with(Default::default);
+        let first = updates.is_empty();
+        let key = (*update.timestamp(), update.update_ref().seq);
+        let replaced = updates.insert(key, (update.clone(), HashSet::with_capacity(2)));
+        if replaced.is_none() {
+            let mut counts = &mut self.counts;
+            counts.total += 1;
+            for component in components(update.url().as_str()) {
+                counts = counts.children.entry(component.into()).or_default();
+                counts.total += 1;
+            }
+        }
+        first
+    }
+
+    /// Remove an update, returns whether it was the last update to its url
+    pub fn remove(&mut self, update_ref: &UpdateRef) -> bool {
+        let updates = match self.urls.get_mut(&update_ref.url) {
+            Some(updates) => updates,
+            None => return false,
+        };
+        if updates.remove(&(update_ref.timestamp, update_ref.seq)).is_none() {
+            return false;
+        }
+        let last = updates.is_empty();
+        if last {
+            self.urls.remove(&update_ref.url);
+        }
+        self.counts.remove(components(update_ref.url.as_str()));
+        last
+    }
+
+    /// All the urls which have updates
+    pub fn urls(&self) -> impl Iterator<Item = &Url> {
+        self.urls.iter().map(|(url, _)| url)
+    }
+
+    pub fn get(&self, url: &Url) -> Option<&TimestampSubIndex> {
+        self.urls.get(url)
+    }
+
+    pub fn get_mut(&mut self, url: &Url) -> Option<&mut TimestampSubIndex> {
+        self.urls.get_mut(url)
+    }
+
+    /// The updates to urls starting with a prefix, from newest to oldest
+    pub fn newest_first(&self, prefix: &Url) -> NewestFirst<'_> {
+        NewestFirst::new(self.prefixed(prefix))
+    }
+
+    /// The updates to each url starting with a prefix
+    pub fn prefixed(&self, prefix: &Url) -> impl Iterator<Item = &TimestampSubIndex> {
+        self.urls.iter_prefix(prefix).map(|(_, updates)| updates)
+    }
+
+    /// The number of updates to urls starting with a prefix
+    pub fn count(&self, prefix: &str) -> usize {
+        let mut components: Vec<_> = components(prefix).collect();
+        // the last component can be part of a segment, so it counts all the segments it starts
+        let partial = components.pop().unwrap_or_default();
+        let mut counts = &self.counts;
+        for component in components {
+            match counts.children.get(component) {
+                Some(child) => counts = child,
+                None => return 0,
+            }
+        }
+        counts
+            .children
+            .range::<str, _>((Bound::Included(partial), Bound::Unbounded))
+            .take_while(|(segment, _)| segment.starts_with(partial))
+            .map(|(_, child)| child.total)
+            .sum()
+    }
+
+    /// The paths one segment on from a url's path which have updates, with the number of updates under each, in segment
+    /// order
+    pub fn children(&self, url: &Url) -> impl Iterator<Item = (&str, usize)> {
+        let mut counts = Some(&self.counts);
+        for component in components(url.as_str().trim_end_matches('/')) {
+            counts = counts.and_then(|counts| counts.children.get(component));
+        }
+        counts
+            .into_iter()
+            .flat_map(|counts| counts.children.iter())
+            .filter(|(segment, _)| !segment.is_empty())
+            .map(|(segment, child)| (&**segment, child.total))
+    }
+}
+
+impl PathCounts {
+    /// Uncount an update under a path, the longer paths left without updates are removed so that they aren't listed
+    fn remove<'c>(&mut self, mut components: impl Iterator<Item = &'c str>) {
+        self.total -= 1;
+        if let Some(component) = components.next() {
+            if let Some(child) = self.children.get_mut(component) {
+                child.remove(components);
+                if child.total == 0 {
+                    self.children.remove(component);
+                }
+            }
+        }
+    }
+}
+
+/// A url or url prefix split into its origin and then the segments of its path, the last may be empty
+fn components(url: &str) -> impl Iterator<Item = &str> {
+    let path_start = url.find("://").and_then(|scheme_end| {
+        url[scheme_end + 3..]
+            .find('/')
+            .map(|host_len| scheme_end + 3 + host_len)
+    });
+    let (origin, path) = match path_start {
+        Some(path_start) => (&url[..path_start], Some(&url[path_start + 1..])),
+        None => (url, None),
+    };
+    std::iter::once(origin).chain(path.into_iter().flat_map(|path| path.split('/')))
+}
+
+/// Lazily merges the per url indexes into one iterator of updates in [`crate::data::newest_first`] order, so that a page of updates under a prefix doesn't need every match collected and sorted first
+pub struct NewestFirst<'a> {
+    sources: Vec<std::iter::Rev<btree_map::Values<'a, (DateTime<FixedOffset>, u32), (Arc<Update>, HashSet<Arc<Tag>>)>>>,
+    /// the timestamp and url of the next update from each source which has one, newest and then the last url at the top
+    heads: BinaryHeap<(DateTime<FixedOffset>, &'a Url, usize)>,
+    /// the next update from each source
+    pending: Vec<Option<&'a Update>>,
+}
+
+impl<'a> NewestFirst<'a> {
+    fn new(indexes: impl Iterator<Item = &'a TimestampSubIndex>) -> Self {
+        let mut sources: Vec<_> = indexes.map(|index| index.values().rev()).collect();
+        let mut heads = BinaryHeap::with_capacity(sources.len());
+        let mut pending = Vec::with_capacity(sources.len());
+        for (source_index, source) in sources.iter_mut().enumerate() {
+            let next = source.next().map(|(update, _)| &**update);
+            if let Some(update) = next {
+                heads.push((*update.timestamp(), update.url(), source_index));
+            }
+            pending.push(next);
+        }
+        Self {
+            sources,
+            heads,
+            pending,
+        }
+    }
+}
+
+impl<'a> Iterator for NewestFirst<'a> {
+    type Item = &'a Update;
+
+    fn next(&mut self) -> Option<Self::Item> {
+        let (_, _, source_index) = self.heads.pop()?;
+        let update = self.pending[source_index].take();
+        if let Some((next, _)) = self.sources[source_index].next() {
+            self.heads.push((*next.timestamp(), next.url(), source_index));
+            self.pending[source_index] = Some(&**next);
+        }
+        update
+    }
+}
+
+#[cfg(test)]
+fn test_index(name: &str, updates: impl Iterator<Item = (String, DateTime<FixedOffset>)>) -> UrlIndex {
+    let path = format!("tmp/{}", name);
+    let _ = std::fs::remove_dir_all(&path);
+    let repo = update_repo::update::UpdateRepo::new(path).unwrap();
+    let mut index = UrlIndex::default();
+    for (url, timestamp) in updates {
+        let update = repo.create(url.parse().unwrap(), timestamp, "Updated").unwrap();
+        index.insert(Arc::new(update.into_inner()));
+    }
+    index
+}
+
+#[test]
+fn test_url_index() {
+    let mut index = test_index(
+        "test_url_index",
+        [
+            ("https://www.gov.uk/guidance/travel-abroad", "2022-02-17T09:00:00+00:00"),
+            ("https://www.gov.uk/guidance/travel-abroad", "2022-02-18T09:00:00+00:00"),
+            (
+                "https://www.gov.uk/guidance/travel-insurance",
+                "2022-02-17T10:00:00+00:00",
+            ),
+            ("https://www.gov.uk/guidance", "2022-02-16T09:00:00+00:00"),
+            ("https://www.gov.uk/guidance-for-tourists", "2022-02-19T09:00:00+00:00"),
+            (
+                "https://www.gov.uk/government/publications/a-report",
+                "2022-02-15T09:00:00+00:00",
+            ),
+        ]
+        .iter()
+        .map(|(url, timestamp)| (url.to_string(), timestamp.parse().unwrap())),
+    );
+    assert_eq!(index.count("https://www.gov.uk/"), 6);
+    assert_eq!(index.count("https://www.gov.uk/guidance"), 5);
+    assert_eq!(index.count("https://www.gov.uk/guidance/"), 3);
+    assert_eq!(index.count("https://www.gov.uk/guidance/travel-"), 3);
+    assert_eq!(index.count("https://www.gov.uk/guidance/travel-abroad"), 2);
+    assert_eq!(index.count("https://www.gov.uk/gov"), 1);
+    assert_eq!(index.count("https://www.gov.uk/news/"), 0);
+    assert_eq!(index.count("https://www.gov.uk/news/an-article"), 0);
+
+    let children = |url: &str| index.children(&url.parse().unwrap()).collect::<Vec<_>>();
+    assert_eq!(
+        children("https://www.gov.uk/"),
+        [("government", 1), ("guidance", 4), ("guidance-for-tourists", 1)]
+    );
+    assert_eq!(
+        children("https://www.gov.uk/guidance/"),
+        [("travel-abroad", 2), ("travel-insurance", 1)]
+    );
+    assert!(children("https://www.gov.uk/news").is_empty());
+
+    let newest_first = index
+        .newest_first(&"https://www.gov.uk/guidance".parse().unwrap())
+        .map(|update| update.timestamp().to_rfc3339())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        newest_first,
+        [
+            "2022-02-19T09:00:00+00:00",
+            "2022-02-18T09:00:00+00:00",
+            "2022-02-17T10:00:00+00:00",
+            "2022-02-17T09:00:00+00:00",
+            "2022-02-16T09:00:00+00:00",
+        ]
+    );
+
+    let update_ref = |url: &str, timestamp: &str| UpdateRef {
+        url: url.parse().unwrap(),
+        timestamp: timestamp.parse().unwrap(),
+        seq: 0,
+    };
+    assert!(!index.remove(&update_ref(
+        "https://www.gov.uk/guidance/travel-abroad",
+        "2022-02-18T09:00:00+00:00"
+    )));
+    assert!(index.remove(&update_ref(
+        "https://www.gov.uk/guidance/travel-insurance",
+        "2022-02-17T10:00:00+00:00"
+    )));
+    assert!(!index.remove(&update_ref("https://www.gov.uk/news", "2022-02-17T10:00:00+00:00")));
+    assert_eq!(index.count("https://www.gov.uk/"), 4);
+    assert_eq!(index.count("https://www.gov.uk/guidance/"), 1);
+    assert!(index.get(&"https://www.gov.uk/guidance/travel-insurance".parse().unwrap()).is_none());
+    assert_eq!(
+        index.children(&"https://www.gov.uk/guidance/".parse().unwrap()).collect::<Vec<_>>(),
+        [("travel-abroad", 1)]
+    );
+}
+
+#[test]
+fn test_newest_first_ties() {
+    // updates from the same email share a timestamp, they are ordered by url
+    let index = test_index(
+        "test_newest_first_ties",
+        [
+            "https://www.gov.uk/guidance/b",
+            "https://www.gov.uk/guidance/c",
+            "https://www.gov.uk/guidance/a",
+            "https://www.gov.uk/guidance/b/d",
+        ]
+        .iter()
+        .map(|url| (url.to_string(), "2022-02-17T09:00:00+00:00".parse().unwrap())),
+    );
+    let newest_first = index
+        .newest_first(&"https://www.gov.uk/guidance".parse().unwrap())
+        .collect::<Vec<_>>();
+    assert_eq!(
+        newest_first.iter().map(|update| update.url().as_str()).collect::<Vec<_>>(),
+        [
+            "https://www.gov.uk/guidance/c",
+            "https://www.gov.uk/guidance/b/d",
+            "https://www.gov.uk/guidance/b",
+            "https://www.gov.uk/guidance/a",
+        ]
+    );
+    let mut sorted = newest_first.clone();
+    sorted.sort_by(|a, b| crate::data::newest_first(a, b));
+    assert_eq!(sorted, newest_first);
+}
+
+/// Compares counting and paging the updates under a prefix with the index to doing it with just the trie, run with
+/// `cargo test --release bench_url_index -- --ignored --nocapture`
+#[test]
+#[ignore]
+fn bench_url_index() {
+    use std::time::Instant;
+
+    let start: DateTime<FixedOffset> = "2022-02-17T09:00:00+00:00".parse().unwrap();
+    let index = test_index(
+        "bench_url_index",
+        (0..20_000).map(|n| {
+            (
+                format!("https://www.gov.uk/section-{}/doc-{}", n % 20, n % 5_000),
+                start + chrono::Duration::minutes(n),
+            )
+        }),
+    );
+    let prefix: Url = "https://www.gov.uk/section-1".parse().unwrap();
+    let time = |name: &str, f: &dyn Fn() -> usize| {
+        let started = Instant::now();
+        let mut result = 0;
+        for _ in 0..100 {
+            result = f();
+        }
+        println!("{:<24} {:>10?} ({})", name, started.elapsed() / 100, result);
+    };
+    time("trie count", &|| {
+        index.urls.iter_prefix(&prefix).map(|(_, updates)| updates.len()).sum()
+    });
+    time("index count", &|| index.count(prefix.as_str()));
+    time("trie first page", &|| {
+        let mut updates: Vec<_> = index
+            .urls
+            .iter_prefix(&prefix)
+            .flat_map(|(_, updates)| updates.values())
+            .collect();
+        updates.sort_by_key(|(update, _)| std::cmp::Reverse(*update.timestamp()));
+        updates.into_iter().take(200).count()
+    });
+    time("index first page", &|| index.newest_first(&prefix).take(200).count());
+}
@@ -0,0 +1,120 @@
+use std::{
+    cell::Cell,
+    fs::{self, File, OpenOptions},
+    io::{self, Write},
+    path::PathBuf,
+    sync::Mutex,
+};
+
+/// An access log of JSON lines, separate from the application log on stderr. The file is rotated once it reaches
+/// `MAX_BYTES`, keeping `KEEP` rotated files named with a number suffix, `.1` being the most recent.
+pub struct AccessLog {
+    path: PathBuf,
+    /// the open file and its size, opened on the first write after startup or rotation
+    file: Mutex<Option<(File, u64)>>,
+}
+
+/// What is logged about each request
+pub struct Entry<'a> {
+    pub timestamp: &'a str,
+    pub request_id: &'a str,
+    pub ip: &'a str,
+    pub method: &'a str,
+    pub url: &'a str,
+    pub status: u16,
+    pub duration_ms: u64,
+    pub referrer: &'a str,
+    pub user_agent: &'a str,
+}
+
+impl AccessLog {
+    const MAX_BYTES: u64 = 64 * 1024 * 1024;
+    const KEEP: usize = 5;
+
+    /// Configured with `ACCESS_LOG`, requests are logged to stderr if it isn't set
+    pub fn from_env() -> Option<Self> {
+        dotenv::var("ACCESS_LOG").ok().map(|path| Self {
+            path: path.into(),
+            file: Mutex::default(),
+        })
+    }
+
+    /// Log a request with the route and cache hit recorded while it was handled
+    pub fn log(&self, entry: &Entry) -> io::Result<()> {
+        let mut line = serde_json::json!({
+            "ts": entry.timestamp,
+            "request_id": entry.request_id,
+            "ip": entry.ip,
+            "method": entry.method,
+            "url": entry.url,
+            "route": current_route(),
+            "status": entry.status,
+            "duration_ms": entry.duration_ms,
+            "cache_hit": is_cache_hit(),
+            "referrer": entry.referrer,
+            "user_agent": entry.user_agent,
+        })
+        .to_string();
+        line.push('\n');
+
+        let mut file = self.file.lock().unwrap();
+        if matches!(&*file, Some((_, size)) if *size + line.len() as u64 > Self::MAX_BYTES) {
+            *file = None;
+            self.rotate()?;
+        }
+        if file.is_none() {
+            let open = OpenOptions::new().create(true).append(true).open(&self.path)?;
+            let size = open.metadata()?.len();
+            *file = Some((open, size));
+        }
+        let (file, size) = file.as_mut().unwrap();
+        file.write_all(line.as_bytes())?;
+        *size += line.len() as u64;
+        Ok(())
+    }
+
+    fn rotate(&self) -> io::Result<()> {
+        let rotated = |n: usize| {
+            let mut path = self.path.clone().into_os_string();
+            path.push(format!(".{}", n));
+            PathBuf::from(path)
+        };
+        for n in (1..Self::KEEP).rev() {
+            if rotated(n).exists() {
+                fs::rename(rotated(n), rotated(n + 1))?;
+            }
+        }
+        fs::rename(&self.path, rotated(1))
+    }
+}
+
+thread_local! {
+    /// The template of the route which matched the request being handled on this thread
+    static CURRENT_ROUTE: Cell<Option<&'static str>> = Cell::new(None);
+    /// Whether the request being handled on this thread was served from a cache
+    static CACHE_HIT: Cell<bool> = Cell::new(false);
+}
+
+/// Clear what was recorded about the previous request handled on this thread
+pub fn start_request() {
+    CURRENT_ROUTE.with(|route| route.set(None));
+    CACHE_HIT.with(|hit| hit.set(false));
+}
+
+/// Record the route which matched the current request, called by the `route!` macro
+pub fn set_route(template: &'static str) {
+    CURRENT_ROUTE.with(|route| route.set(Some(template)));
+}
+
+pub fn current_route() -> Option<&'static str> {
+    CURRENT_ROUTE.with(Cell::get)
+}
+
+/// Record that the current request was served from a cache
+pub fn mark_cache_hit() {
+    CACHE_HIT.with(|hit| hit.set(true));
+}
+
+fn is_cache_hit() -> bool {
+    CACHE_HIT.with(Cell::get)
+}
@@ -0,0 +1,233 @@
+//! Collapsing the unchanged parts of a diff, so that a small change to a long doc isn't lost among the text around it
+
+use std::{fmt::Write, ops::Range};
+
+use super::share::change_counts;
+
+/// Runs of at least this many unchanged blocks are collapsed
+const MIN_COLLAPSED: usize = 3;
+/// The unchanged blocks left either side of a change, for context
+const CONTEXT: usize = 1;
+
+const VOID_ELEMENTS: &[&str] = &[
+    "area", "base", "br", "col", "embed", "hr", "img", "input", "link", "meta", "source", "track", "wbr",
+];
+/// Elements which are collapsed when they are unchanged, the rest of the diff is left as it is
+const BLOCK_ELEMENTS: &[&str] = &[
+    "address",
+    "article",
+    "aside",
+    "blockquote",
+    "details",
+    "div",
+    "dl",
+    "figure",
+    "footer",
+    "h1",
+    "h2",
+    "h3",
+    "h4",
+    "h5",
+    "h6",
+    "header",
+    "hr",
+    "nav",
+    "ol",
+    "p",
+    "pre",
+    "section",
+    "table",
+    "ul",
+];
+/// Elements whose children can't be wrapped in a `<details>`
+const LIST_ELEMENTS: &[&str] = &["dl", "ol", "select", "table", "tbody", "tfoot", "thead", "tr", "ul"];
+
+/// Collapse runs of unchanged blocks in a diff into `<details>` elements which can be expanded to show them. Changed
+/// blocks are collapsed within, so that a change deep in a doc still has the unchanged text around it collapsed
+pub fn collapse_unchanged(diff: &str) -> String {
+    let mut collapsed = String::with_capacity(diff.len());
+    collapse_into(diff, None, &mut collapsed);
+    collapsed
+}
+
+fn collapse_into(html: &str, parent: Option<&str>, out: &mut String) {
+    let blocks = match blocks(html) {
+        Some(blocks) => blocks,
+        // not html this can make sense of, so it is left as it is
+        None => return out.push_str(html),
+    };
+    let can_collapse = !matches!(parent, Some(parent) if is_one_of(parent, LIST_ELEMENTS));
+    let collapsible: Vec<bool> = blocks
+        .iter()
+        .map(|block| {
+            can_collapse
+                && matches!(block.name, Some(name) if is_one_of(name, BLOCK_ELEMENTS))
+                && change_counts(&html[block.outer.clone()]) == (0, 0)
+        })
+        .collect();
+
+    let mut written = 0;
+    let mut index = 0;
+    while index < blocks.len() {
+        if collapsible[index] {
+            let run_end = index
+                + collapsible[index..]
+                    .iter()
+                    .take_while(|collapsible| **collapsible)
+                    .count();
+            let start = if index > 0 { index + CONTEXT } else { index };
+            let end = if run_end < blocks.len() {
+                run_end.saturating_sub(CONTEXT)
+            } else {
+                run_end
+            };
+            if end >= start + MIN_COLLAPSED {
+                let range = blocks[start].outer.start..blocks[end - 1].outer.end;
+                out.push_str(&html[written..range.start]);
+                let _ = write!(
+                    out,
+                    r#"<details class="unchanged"><summary>… {} unchanged paragraphs …</summary>{}</details>"#,
+                    end - start,
+                    &html[range.clone()],
+                );
+                written = range.end;
+            }
+            index = run_end;
+        } else {
+            let block = &blocks[index];
+            if let (Some(name), Some(inner)) = (block.name, &block.inner) {
+                if change_counts(&html[inner.clone()]) != (0, 0) {
+                    out.push_str(&html[written..inner.start]);
+                    collapse_into(&html[inner.clone()], Some(name), out);
+                    written = inner.end;
+                }
+            }
+            index += 1;
+        }
+    }
+    out.push_str(&html[written..]);
+}
+
+/// An element or some text at the top level of some html
+#[derive(Debug, PartialEq)]
+struct Block<'a> {
+    /// the element's tag name, text has none
+    name: Option<&'a str>,
+    outer: Range<usize>,
+    /// the element's content, void elements and text have none
+    inner: Option<Range<usize>>,
+}
+
+/// The top level elements and text of some html, whitespace between elements and comments are left out. `None` if the
+/// html isn't balanced
+fn blocks(html: &str) -> Option<Vec<Block<'_>>> {
+    let mut blocks = vec![];
+    let mut depth = 0usize;
+    // the start, content start and name of the top level element being read
+    let mut open = None;
+    let mut pos = 0;
+    while pos < html.len() {
+        let tag_start = html[pos..].find('<').map_or(html.len(), |offset| pos + offset);
+        if depth == 0 && !html[pos..tag_start].trim().is_empty() {
+            blocks.push(Block {
+                name: None,
+                outer: pos..tag_start,
+                inner: None,
+            });
+        }
+        if tag_start == html.len() {
+            break;
+        }
+        if html[tag_start..].starts_with("<!--") {
+            pos = tag_start + html[tag_start..].find("-->")? + 3;
+            continue;
+        }
+        let tag_end = tag_start + tag_len(&html[tag_start..])?;
+        let tag = &html[tag_start..tag_end];
+        pos = tag_end;
+        if tag.starts_with("</") {
+            depth = depth.checked_sub(1)?;
+            if depth == 0 {
+                let (start, inner_start, name) = open.take()?;
+                blocks.push(Block {
+                    name: Some(name),
+                    outer: start..tag_end,
+                    inner: Some(inner_start..tag_start),
+                });
+            }
+        } else if !tag.starts_with("<!") && !tag.starts_with("<?") {
+            let name = tag[1..]
+                .split(|c: char| c.is_ascii_whitespace() || c == '/' || c == '>')
+                .next()
+                .unwrap_or_default();
+            if tag.ends_with("/>") || is_one_of(name, VOID_ELEMENTS) {
+                if depth == 0 {
+                    blocks.push(Block {
+                        name: Some(name),
+                        outer: tag_start..tag_end,
+                        inner: None,
+                    });
+                }
+            } else {
+                if depth == 0 {
+                    open = Some((tag_start, tag_end, name));
+                }
+                depth += 1;
+            }
+        }
+    }
+    if depth == 0 {
+        Some(blocks)
+    } else {
+        None
+    }
+}
+
+/// The length of the tag at the start of some html, up to its `>` outside of any quoted attribute values
+fn tag_len(html: &str) -> Option<usize> {
+    let mut quote = None;
+    for (index, c) in html.char_indices() {
+        match (quote, c) {
+            (None, '>') => return Some(index + 1),
+            (None, '"' | '\'') => quote = Some(c),
+            (Some(open), c) if c == open => quote = None,
+            _ => {}
+        }
+    }
+    None
+}
+
+fn is_one_of(name: &str, names: &[&str]) -> bool {
+    names.iter().any(|candidate| candidate.eq_ignore_ascii_case(name))
+}
+
+#[test]
+fn test_collapse_unchanged() {
+    let paragraphs = |range: Range<usize>| range.map(|n| format!("<p>Paragraph {}</p>", n)).collect::<String>();
+    let diff = format!(
+        r#"<main><div class="govspeak">{}<p>Paragraph <ins>new</ins></p>{}<p data-diff-node="del">Gone</p>{}</div></main>"#,
+        paragraphs(0..5),
+        paragraphs(5..8),
+        paragraphs(8..10),
+    );
+    assert_eq!(
+        collapse_unchanged(&diff),
+        format!(
+            r#"<main><div class="govspeak"><details class="unchanged"><summary>… 4 unchanged paragraphs …</summary>{}</details>{}<p>Paragraph <ins>new</ins></p>{}<p data-diff-node="del">Gone</p>{}</div></main>"#,
+            paragraphs(0..4),
+            paragraphs(4..5),
+            // too few to collapse once the context either side is left
+            paragraphs(5..8),
+            paragraphs(8..10),
+        )
+    );
+
+    // list items can't be wrapped, and inline elements aren't collapsed
+    let list = format!("<ol>{}<li><del>x</del></li></ol>", "<li>Item</li>".repeat(5));
+    assert_eq!(collapse_unchanged(&list), list);
+    let inline = format!("<p>{}<ins>x</ins></p>", "<a href='/x>y'>Link</a> ".repeat(5));
+    assert_eq!(collapse_unchanged(&inline), inline);
+    // unbalanced html is left as it is
+    let unbalanced = format!("{}<p><ins>x</ins>", paragraphs(0..5));
+    assert_eq!(collapse_unchanged(&unbalanced), unbalanced);
+}
@@ -0,0 +1,236 @@
+//! The cache of computed diffs, either a `cacache` directory or, for deployments without a good place on disk for it,
+//! an in memory cache which drops the least recently used diffs once it is full
+
+use std::{
+    collections::{BTreeMap, HashMap},
+    fs, io,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::is_read_only_fs;
+
+use super::error::{log_internal_error, Error};
+
+/// The version of the diffs, which is part of their keys so that the diffs cached before a change to the diff or to the
+/// sanitiser aren't served after it. Bump it with any change which changes the diffs
+pub const DIFF_VERSION: u32 = 1;
+/// The file in a disk cache with the version of the diffs in it, the cache is cleared when it is of another version
+const VERSION_FILE: &str = "diff-version";
+
+pub enum DiffCache {
+    Disk {
+        path: PathBuf,
+        /// diffs are read from the cache but never written to it, in read only mode or when the cache is on a read only
+        /// filesystem
+        read_only: bool,
+    },
+    Memory(Mutex<MemoryCache>),
+}
+
+impl DiffCache {
+    /// The cache set by `DIFFCACHE`, the path of a directory, or `memory` for a cache of up to `DIFFCACHE_MEMORY_MB`
+    /// (default 64) in memory. There is no cache if it isn't set. A disk cache isn't written to in read only mode
+    pub fn from_env(read_only: bool) -> Option<Self> {
+        let cache = dotenv::var("DIFFCACHE").ok().filter(|cache| !cache.is_empty())?;
+        if cache == "memory" {
+            let megabytes: usize = dotenv::var("DIFFCACHE_MEMORY_MB")
+                .ok()
+                .and_then(|mb| mb.parse().ok())
+                .unwrap_or(64);
+            return Some(Self::memory(megabytes * 1024 * 1024));
+        }
+        let path = PathBuf::from(cache);
+        let read_only = read_only || is_read_only_fs(&path);
+        Some(Self::disk(path, read_only))
+    }
+
+    /// The cache in a directory, a writable cache of diffs of an earlier version is cleared
+    pub fn disk(path: PathBuf, read_only: bool) -> Self {
+        if !read_only {
+            if let Err(err) = clear_outdated(&path) {
+                log_internal_error(format!("Clearing the outdated diff cache {:?} failed : {}", path, err));
+            }
+        }
+        Self::Disk { path, read_only }
+    }
+
+    pub fn memory(capacity_bytes: usize) -> Self {
+        Self::Memory(Mutex::new(MemoryCache {
+            capacity_bytes,
+            ..MemoryCache::default()
+        }))
+    }
+
+    /// Whether diffs are written to the cache
+    pub fn is_writable(&self) -> bool {
+        !matches!(self, Self::Disk { read_only: true, .. })
+    }
+
+    /// A diff from the cache, if it has been cached
+    pub fn read(&self, key: &str) -> Result<Option<String>, Error> {
+        let key = &versioned(key);
+        match self {
+            Self::Disk { path, .. } => match cacache::read_sync(path, key) {
+                Ok(from_cache) => Ok(String::from_utf8(from_cache).ok()),
+                Err(cacache::Error::EntryNotFound(_, _)) => Ok(None),
+                Err(err) => Err(err.into()),
+            },
+            Self::Memory(cache) => Ok(cache.lock().unwrap().get(key)),
+        }
+    }
+
+    /// Add a diff to the cache, the cache is only an optimisation so errors are logged rather than returned
+    pub fn write(&self, key: &str, diff: &str) {
+        let key = &versioned(key);
+        match self {
+            Self::Disk { read_only: true, .. } => {}
+            Self::Disk { path, .. } => {
+                if let Err(err) = cacache::write_sync(path, key, diff) {
+                    log_internal_error(Error::from(err).to_string());
+                }
+            }
+            Self::Memory(cache) => cache.lock().unwrap().insert(key, diff),
+        }
+    }
+
+    /// Remove a diff which couldn't be read from the cache, so that it is replaced
+    pub fn remove(&self, key: &str) {
+        let key = &versioned(key);
+        match self {
+            Self::Disk { read_only: true, .. } => {}
+            Self::Disk { path, .. } => {
+                if let Err(err) = cacache::remove_sync(path, key) {
+                    log_internal_error(Error::from(err).to_string());
+                }
+            }
+            Self::Memory(cache) => cache.lock().unwrap().remove(key),
+        }
+    }
+}
+
+fn versioned(key: &str) -> String {
+    format!("v{}:{}", DIFF_VERSION, key)
+}
+
+/// Clear a cache which has diffs of another version than `DIFF_VERSION`, or of no version as they were before they had
+/// one. Their keys can't match anymore, this reclaims their space
+fn clear_outdated(path: &Path) -> io::Result<()> {
+    let version_path = path.join(VERSION_FILE);
+    let version = match fs::read_to_string(&version_path) {
+        Ok(version) => Some(version.trim().to_owned()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => None,
+        Err(err) => return Err(err),
+    };
+    if version.as_deref() == Some(&DIFF_VERSION.to_string()) {
+        return Ok(());
+    }
+    if path.exists() {
+        println!("Clearing the diff cache {:?} of version {:?}", path, version);
+        // the clear removes each entry of the cache as a dir, so it fails on the version file unless it is removed first
+        match fs::remove_file(&version_path) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        cacache::clear_sync(path).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
+    }
+    fs::create_dir_all(path)?;
+    fs::write(version_path, format!("{}\n", DIFF_VERSION))
+}
+
+/// Diffs by their keys, with the order they were last used in
+#[derive(Default)]
+pub struct MemoryCache {
+    capacity_bytes: usize,
+    size_bytes: usize,
+    /// each diff with the tick it was last used at
+    diffs: HashMap<String, (String, u64)>,
+    /// the keys by the tick they were last used at, the first is the least recently used
+    used: BTreeMap<u64, String>,
+    tick: u64,
+}
+
+impl MemoryCache {
+    fn get(&mut self, key: &str) -> Option<String> {
+        self.tick += 1;
+        let (diff, used_at) = self.diffs.get_mut(key)?;
+        let key = self.used.remove(used_at).unwrap();
+        *used_at = self.tick;
+        self.used.insert(self.tick, key);
+        Some(diff.clone())
+    }
+
+    fn insert(&mut self, key: &str, diff: &str) {
+        self.remove(key);
+        // a diff larger than the whole cache would only empty it
+        if diff.len() > self.capacity_bytes {
+            return;
+        }
+        while self.size_bytes + diff.len() > self.capacity_bytes {
+            let (_, oldest) = self.used.iter().next().expect("cache is empty but full");
+            let oldest = oldest.clone();
+            self.remove(&oldest);
+        }
+        self.tick += 1;
+        self.size_bytes += diff.len();
+        self.diffs.insert(key.to_owned(), (diff.to_owned(), self.tick));
+        self.used.insert(self.tick, key.to_owned());
+    }
+
+    fn remove(&mut self, key: &str) {
+        if let Some((diff, used_at)) = self.diffs.remove(key) {
+            self.size_bytes -= diff.len();
+            self.used.remove(&used_at);
+        }
+    }
+}
+
+#[test]
+fn test_outdated_disk_cache_is_cleared() {
+    let path = PathBuf::from("tmp/test_outdated_disk_cache_is_cleared");
+    let _ = fs::remove_dir_all(&path);
+    let cache = DiffCache::disk(path.clone(), false);
+    cache.write("/diff/a", "diff a");
+    assert_eq!(cache.read("/diff/a").unwrap().as_deref(), Some("diff a"));
+    assert_eq!(
+        DiffCache::disk(path.clone(), false).read("/diff/a").unwrap().as_deref(),
+        Some("diff a")
+    );
+
+    // a cache written before the diffs changed
+    fs::write(path.join(VERSION_FILE), "0\n").unwrap();
+    // it isn't cleared without writing
+    let read_only = DiffCache::disk(path.clone(), true);
+    assert!(cacache::read_sync(&path, versioned("/diff/a")).is_ok());
+    assert!(!read_only.is_writable());
+    DiffCache::disk(path.clone(), false);
+    assert!(cacache::read_sync(&path, versioned("/diff/a")).is_err());
+    assert_eq!(
+        fs::read_to_string(path.join(VERSION_FILE)).unwrap(),
+        format!("{}\n", DIFF_VERSION)
+    );
+}
+
+#[test]
+fn test_memory_cache_drops_least_recently_used() {
+    let cache = DiffCache::memory(10);
+    cache.write("a", "aaaa");
+    cache.write("b", "bbbb");
+    assert_eq!(cache.read("a").unwrap().as_deref(), Some("aaaa"));
+    // b is dropped as a was used since
+    cache.write("c", "cccc");
+    assert_eq!(cache.read("b").unwrap(), None);
+    assert_eq!(cache.read("a").unwrap().as_deref(), Some("aaaa"));
+    assert_eq!(cache.read("c").unwrap().as_deref(), Some("cccc"));
+
+    // replacing a diff frees its space
+    cache.write("c", "cc");
+    cache.write("d", "dddd");
+    assert_eq!(cache.read("a").unwrap().as_deref(), Some("aaaa"));
+    cache.remove("a");
+    assert_eq!(cache.read("a").unwrap(), None);
+
+    cache.write("e", "too big for the cache");
+    assert_eq!(cache.read("e").unwrap(), None);
+    assert_eq!(cache.read("d").unwrap().as_deref(), Some("dddd"));
+}
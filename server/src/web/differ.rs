@@ -0,0 +1,83 @@
+use std::{
+    collections::HashSet,
+    sync::{mpsc, Arc, Mutex},
+    thread,
+    time::Duration,
+};
+
+use crate::{data::DocBody, read_only};
+
+use super::{diff_cache::DiffCache, error::log_internal_error};
+
+/// Computes diffs on their own threads so that a request can stop waiting for a slow one, a diff which takes too long is
+/// still finished in the background and written to the cache so that the next request for it is served from there.
+pub struct Differ {
+    /// how long a request waits for a diff, there is no timeout without a cache as the finished diff would be lost
+    timeout: Option<Duration>,
+    /// the cache keys of the diffs being computed
+    in_progress: Arc<Mutex<HashSet<String>>>,
+    cache: Option<Arc<DiffCache>>,
+}
+
+impl Differ {
+    /// Configured with `DIFF_TIMEOUT_SECS`, defaulting to 10 seconds, `0` waits for every diff to finish, and with the
+    /// cache set by `DIFFCACHE`. Nothing is written to a cache on disk in read only mode, or when it is on a read only
+    /// filesystem
+    pub fn from_env() -> Self {
+        let timeout = dotenv::var("DIFF_TIMEOUT_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(10);
+        Self {
+            timeout: (timeout > 0).then(|| Duration::from_secs(timeout)),
+            in_progress: Arc::default(),
+            cache: DiffCache::from_env(read_only()).map(Arc::new),
+        }
+    }
+
+    /// A diff from the cache, if there is a cache and the diff is in it. The cache is only an optimisation, so errors
+    /// reading it are logged and the diff is removed so that it is replaced
+    pub fn cached(&self, key: &str) -> Option<String> {
+        let cache = self.cache.as_ref()?;
+        cache.read(key).unwrap_or_else(|err| {
+            log_internal_error(err.to_string());
+            cache.remove(key);
+            None
+        })
+    }
+
+    /// Diff two docs, writing the result to the cache if `cache` and there is one. Returns `None` if the diff didn't
+    /// finish before the timeout, it will be in the cache once it has
+    pub fn diff(&self, cache: bool, key: &str, from: DocBody, to: DocBody) -> Option<String> {
+        let cache = self
+            .cache
+            .clone()
+            .filter(|diff_cache| cache && diff_cache.is_writable());
+        let (cache, timeout) = match (cache, self.timeout) {
+            (Some(cache), Some(timeout)) => (cache, timeout),
+            (cache, _) => {
+                let diff = from.diff(&to);
+                if let Some(cache) = cache {
+                    cache.write(key, &diff);
+                }
+                return Some(diff);
+            }
+        };
+
+        if !self.in_progress.lock().unwrap().insert(key.to_owned()) {
+            // another request is already waiting on it
+            return None;
+        }
+        let (sender, receiver) = mpsc::channel();
+        let in_progress = self.in_progress.clone();
+        let key = key.to_owned();
+        thread::spawn(move || {
+            let diff = from.diff(&to);
+            cache.write(&key, &diff);
+            in_progress.lock().unwrap().remove(&key);
+            // the request may have stopped waiting
+            let _ = sender.send(diff);
+        });
+        receiver.recv_timeout(timeout).ok()
+    }
+}
@@ -1,4 +1,4 @@
-use std::io;
+use std::{cell::RefCell, collections::VecDeque, fmt, io, sync::Mutex};
 
 use rouille::Response;
 
@@ -6,16 +6,141 @@ use rouille::Response;
 pub enum Error {
     NotFound(&'static str),
     InvalidRequest,
+    /// A named request parameter couldn't be parsed
+    InvalidParam(&'static str),
+    Unauthorized,
+    /// A part of the service which the request depends on isn't running
+    Unavailable(&'static str),
+    /// The server is in read only mode and the request would change something
+    ReadOnly,
+    /// Reading or writing the diff cache failed
+    Cache(cacache::Error),
     InternalServer,
 }
 
+impl Error {
+    fn status_code(&self) -> u16 {
+        match self {
+            Error::NotFound(_) => 404,
+            Error::InvalidRequest | Error::InvalidParam(_) => 400,
+            Error::Unauthorized => 401,
+            Error::Unavailable(_) | Error::ReadOnly => 503,
+            Error::Cache(_) | Error::InternalServer => 500,
+        }
+    }
+}
+
+impl fmt::Display for Error {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            Error::NotFound(name) => write!(f, "{} not found", name),
+            Error::InvalidRequest => f.write_str("Invalid request"),
+            Error::InvalidParam(name) => write!(f, "Invalid {} parameter", name),
+            Error::Unauthorized => f.write_str("Unauthorized"),
+            Error::Unavailable(name) => write!(f, "{} unavailable", name),
+            Error::ReadOnly => f.write_str("Read only"),
+            Error::Cache(err) => write!(f, "Diff cache error : {}", err),
+            Error::InternalServer => f.write_str("Internal server error"),
+        }
+    }
+}
+
+/// Missing files are not found, other IO errors are logged as internal errors
+impl From<io::Error> for Error {
+    fn from(err: io::Error) -> Self {
+        if err.kind() == io::ErrorKind::NotFound {
+            Error::NotFound("File")
+        } else {
+            log_internal_error(format!("{}\n{:?}", err, err));
+            Error::InternalServer
+        }
+    }
+}
+
+impl From<cacache::Error> for Error {
+    fn from(err: cacache::Error) -> Self {
+        Error::Cache(err)
+    }
+}
+
 impl From<Error> for Response {
     fn from(e: Error) -> Self {
-        match e {
-            Error::NotFound(name) => Response::text(format!("{} not found", name)).with_status_code(404),
-            Error::InvalidRequest => Response::text("Invalid request").with_status_code(400),
-            Error::InternalServer => Response::text("Internal server error").with_status_code(500),
+        let explanation = match e {
+            Error::NotFound(_) => "There is nothing here, the link may be wrong or the page may not have been tracked.",
+            Error::InvalidRequest | Error::InvalidParam(_) => {
+                "Something about the request wasn't understood, check the link or the filters."
+            }
+            Error::Unauthorized => "This needs an admin token.",
+            Error::Unavailable(_) => "This isn't available at the moment, try again later.",
+            Error::ReadOnly => "This server only serves what it has already tracked, it can't make any changes.",
+            Error::Cache(_) | Error::InternalServer => {
+                "Something went wrong while making this page, the details have been logged."
+            }
+        };
+        let message = match e {
+            // the details of internal errors are for the log, not the page
+            Error::Cache(_) => Error::InternalServer.to_string(),
+            _ => e.to_string(),
+        };
+        Response::html(format!(
+            include_str!("error.html"),
+            message = message,
+            explanation = explanation,
+            request_id = current_request_id().unwrap_or_default(),
+        ))
+        .with_status_code(e.status_code())
+    }
+}
+
+thread_local! {
+    /// The id of the request being handled on this thread and the details of internal errors logged while handling it
+    static CURRENT_REQUEST: RefCell<Option<(String, Vec<String>)>> = RefCell::new(None);
+}
+
+/// Handle a request on this thread, returning the details of any internal errors logged while handling it
+pub fn with_request_id<R>(request_id: &str, handle: impl FnOnce() -> R) -> (R, Vec<String>) {
+    CURRENT_REQUEST.with(|current| *current.borrow_mut() = Some((request_id.to_owned(), vec![])));
+    let result = handle();
+    let errors = CURRENT_REQUEST
+        .with(|current| current.borrow_mut().take())
+        .map(|(_, errors)| errors)
+        .unwrap_or_default();
+    (result, errors)
+}
+
+fn current_request_id() -> Option<String> {
+    CURRENT_REQUEST.with(|current| current.borrow().as_ref().map(|(request_id, _)| request_id.clone()))
+}
+
+/// Log the details of an internal error against the request being handled
+pub fn log_internal_error(details: String) {
+    CURRENT_REQUEST.with(|current| match &mut *current.borrow_mut() {
+        Some((request_id, errors)) => {
+            eprintln!("[{}] Internal server error : {}", request_id, details);
+            errors.push(details);
+        }
+        None => eprintln!("Internal server error : {}", details),
+    })
+}
+
+/// The details of internal errors of recent requests, so that they can be looked up by the request id from a bug report
+#[derive(Default)]
+pub struct ErrorLog(Mutex<VecDeque<(String, Vec<String>)>>);
+
+impl ErrorLog {
+    const CAPACITY: usize = 1000;
+
+    pub fn record(&self, request_id: String, errors: Vec<String>) {
+        let mut log = self.0.lock().unwrap();
+        if log.len() == Self::CAPACITY {
+            log.pop_front();
         }
+        log.push_back((request_id, errors));
+    }
+
+    pub fn get(&self, request_id: &str) -> Option<Vec<String>> {
+        let log = self.0.lock().unwrap();
+        log.iter().find(|(id, _)| id == request_id).map(|(_, errors)| errors.clone())
     }
 }
 
@@ -28,13 +153,9 @@ impl<T> CouldFind for Result<T, io::Error> {
     type Success = T;
 
     fn could_find(self, name: &'static str) -> Result<Self::Success, Error> {
-        self.map_err(|err| {
-            if err.kind() == io::ErrorKind::NotFound {
-                Error::NotFound(name)
-            } else {
-                eprintln!("Internal server error : {}\n{:?}", err, err);
-                Error::InternalServer
-            }
+        self.map_err(|err| match Error::from(err) {
+            Error::NotFound(_) => Error::NotFound(name),
+            err => err,
         })
     }
 }
@@ -46,3 +167,21 @@ impl<T> CouldFind for Option<T> {
         self.ok_or(Error::NotFound(name))
     }
 }
+
+#[test]
+fn test_errors_are_logged_against_request() {
+    let error_log = ErrorLog::default();
+    let (response, errors) = with_request_id("abc-1", || {
+        Err::<(), _>(io::Error::new(io::ErrorKind::Other, "disk on fire"))
+            .could_find("Update")
+            .unwrap_err()
+    });
+    assert!(matches!(response, Error::InternalServer));
+    assert_eq!(errors.len(), 1);
+    assert!(errors[0].starts_with("disk on fire"));
+    error_log.record("abc-1".to_owned(), errors);
+
+    assert!(error_log.get("abc-1").is_some());
+    assert!(error_log.get("abc-2").is_none());
+    assert_eq!(current_request_id(), None);
+}
@@ -0,0 +1,155 @@
+//! A newline delimited JSON export of updates, so that mirrors can sync incrementally without walking the html pages
+
+use std::{
+    fmt::{self, Write},
+    str::FromStr,
+};
+
+use chrono::{DateTime, FixedOffset};
+use update_repo::{update::Update, Url};
+
+/// Where an export resumes, after the update at `timestamp` on `url` with `seq`, or at `timestamp` if there is no url.
+/// It is written like the path of an update page, `{timestamp}/{host}{path}`, or just the timestamp, and the timestamp
+/// is followed by `~{seq}` when it isn't the first update to the url at the timestamp.
+#[derive(Debug, PartialEq, Eq)]
+pub struct Cursor {
+    pub timestamp: DateTime<FixedOffset>,
+    pub url: Option<Url>,
+    pub seq: u32,
+}
+
+impl Cursor {
+    pub fn after(update: &Update) -> Self {
+        Self {
+            timestamp: *update.timestamp(),
+            url: Some(update.url().clone()),
+            seq: update.update_ref().seq,
+        }
+    }
+}
+
+impl FromStr for Cursor {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let (timestamp, url) = match s.split_once('/') {
+            Some((timestamp, url)) => (timestamp, Some(url)),
+            None => (s, None),
+        };
+        let (timestamp, seq) = timestamp.split_once('~').unwrap_or((timestamp, "0"));
+        Ok(Self {
+            timestamp: timestamp.parse().map_err(|_| ())?,
+            seq: seq.parse().map_err(|_| ())?,
+            url: url
+                .map(|url| format!("https://{}", url).parse())
+                .transpose()
+                .map_err(|_| ())?,
+        })
+    }
+}
+
+impl fmt::Display for Cursor {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.timestamp.to_rfc3339())?;
+        if self.seq != 0 {
+            write!(f, "~{}", self.seq)?;
+        }
+        if let Some(url) = &self.url {
+            write!(f, "/{}", url.as_str().trim_start_matches("https://"))?;
+        }
+        Ok(())
+    }
+}
+
+/// Write an update as a line of JSON, with its tags and the timestamps of its doc versions if they are given
+pub fn write_update_line<'a>(
+    f: &mut String,
+    update: &Update,
+    tags: Option<impl Iterator<Item = &'a str>>,
+    doc_versions: Option<impl Iterator<Item = DateTime<FixedOffset>>>,
+) -> fmt::Result {
+    f.push_str(r#"{"url":"#);
+    write_json_string(f, update.url().as_str())?;
+    write!(f, r#","timestamp":"{}","#, update.timestamp().to_rfc3339())?;
+    // only updates after the first to their url at their timestamp have a seq
+    if update.update_ref().seq != 0 {
+        write!(f, r#""seq":{},"#, update.update_ref().seq)?;
+    }
+    write!(f, r#""id":"{}","change":"#, update.update_ref().short_id())?;
+    write_json_string(f, update.change())?;
+    if let Some(tags) = tags {
+        f.push_str(r#","tags":["#);
+        for (i, tag) in tags.enumerate() {
+            if i > 0 {
+                f.push(',');
+            }
+            write_json_string(f, tag)?;
+        }
+        f.push(']');
+    }
+    if let Some(doc_versions) = doc_versions {
+        f.push_str(r#","doc_versions":["#);
+        for (i, timestamp) in doc_versions.enumerate() {
+            if i > 0 {
+                f.push(',');
+            }
+            write!(f, r#""{}""#, timestamp.to_rfc3339())?;
+        }
+        f.push(']');
+    }
+    f.push_str("}\n");
+    Ok(())
+}
+
+fn write_json_string(f: &mut String, s: &str) -> fmt::Result {
+    f.push('"');
+    for c in s.chars() {
+        match c {
+            '"' => f.push_str(r#"\""#),
+            '\\' => f.push_str(r"\\"),
+            '\n' => f.push_str(r"\n"),
+            '\r' => f.push_str(r"\r"),
+            '\t' => f.push_str(r"\t"),
+            c if (c as u32) < 0x20 => write!(f, r"\u{:04x}", c as u32)?,
+            c => f.push(c),
+        }
+    }
+    f.push('"');
+    Ok(())
+}
+
+#[test]
+fn test_cursor() {
+    let cursor: Cursor = "2022-02-17T09:53:57+00:00/www.gov.uk/guidance/travel-abroad"
+        .parse()
+        .unwrap();
+    assert_eq!(
+        cursor,
+        Cursor {
+            timestamp: "2022-02-17T09:53:57+00:00".parse().unwrap(),
+            url: Some("https://www.gov.uk/guidance/travel-abroad".parse().unwrap()),
+            seq: 0,
+        }
+    );
+    assert_eq!(
+        cursor.to_string(),
+        "2022-02-17T09:53:57+00:00/www.gov.uk/guidance/travel-abroad"
+    );
+    let cursor: Cursor = "2022-02-17T09:53:57+00:00~1/www.gov.uk/guidance/travel-abroad"
+        .parse()
+        .unwrap();
+    assert_eq!(cursor.seq, 1);
+    assert_eq!(
+        cursor.to_string(),
+        "2022-02-17T09:53:57+00:00~1/www.gov.uk/guidance/travel-abroad"
+    );
+    assert_eq!("2022-02-17T09:53:57+00:00".parse::<Cursor>().unwrap().url, None);
+    assert!("yesterday".parse::<Cursor>().is_err());
+}
+
+#[test]
+fn test_json_string() {
+    let mut f = String::new();
+    write_json_string(&mut f, "Added \"advice\"\n\\ \u{1}").unwrap();
+    assert_eq!(f, r#""Added \"advice\"\n\\ \u0001""#);
+}
@@ -0,0 +1,193 @@
+//! The formats the update pages can be served in, chosen with the `Accept` header. Handlers gather the same data for
+//! every format and render it with the page templates for html or with the renderers here.
+
+use std::fmt::{self, Write};
+
+use chrono::{DateTime, FixedOffset};
+use rouille::Request;
+use serde_json::{json, Value};
+use update_repo::{doc::FirstVersion, update::Update};
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum Format {
+    Html,
+    Json,
+    Atom,
+}
+
+impl Format {
+    pub fn media_type(self) -> &'static str {
+        match self {
+            Format::Html => "text/html; charset=utf-8",
+            Format::Json => "application/json",
+            Format::Atom => "application/atom+xml",
+        }
+    }
+
+    /// The offered format which the request's `Accept` header prefers, the first offered format is used when the header
+    /// is missing, has no preference between them or accepts none of them
+    pub fn negotiate(request: &Request, offered: &[Format]) -> Format {
+        let accept = match request.header("Accept") {
+            Some(accept) => accept,
+            None => return offered[0],
+        };
+        let mut best = (0.0, offered[0]);
+        for range in accept.split(',') {
+            let mut params = range.split(';');
+            let media_range = params.next().unwrap_or_default().trim();
+            let quality = params
+                .find_map(|param| param.trim().strip_prefix("q="))
+                .and_then(|quality| quality.parse::<f32>().ok())
+                .unwrap_or(1.0);
+            for &format in offered {
+                if quality > best.0 && format.matches(media_range) {
+                    best = (quality, format);
+                }
+            }
+        }
+        best.1
+    }
+
+    fn matches(self, media_range: &str) -> bool {
+        let media_type = self.media_type().split(';').next().unwrap_or_default();
+        match media_range.strip_suffix("/*") {
+            Some("*") => true,
+            Some(range_type) => media_type.split('/').next() == Some(range_type),
+            None => media_type.eq_ignore_ascii_case(media_range),
+        }
+    }
+}
+
+/// An update as a JSON object, with absolute links to its page and permalink
+pub fn update_json<'a>(update: &Update, tags: impl Iterator<Item = &'a str>, href: String, permalink: String) -> Value {
+    json!({
+        "url": update.url().as_str(),
+        "timestamp": update.timestamp().to_rfc3339(),
+        "id": update.update_ref().short_id(),
+        "change": update.change(),
+        "tags": sorted(tags),
+        "href": href,
+        "permalink": permalink,
+    })
+}
+
+/// Tags are kept in sets, they are sorted so that the output is stable
+pub fn sorted<'a>(tags: impl Iterator<Item = &'a str>) -> Vec<&'a str> {
+    let mut tags: Vec<_> = tags.collect();
+    tags.sort_unstable();
+    tags
+}
+
+/// An entry in an Atom feed
+pub struct AtomEntry<'a> {
+    pub update: &'a Update,
+    /// absolute link to the update's page, also used as the entry's id
+    pub href: String,
+    pub tags: Vec<&'a str>,
+    /// how the doc was first captured, if this is its first update
+    pub first: Option<FirstVersion>,
+}
+
+/// Write an Atom feed of updates, `href` is the absolute link to the feed itself
+pub fn write_atom_feed<'a>(
+    f: &mut String,
+    title: &str,
+    href: &str,
+    updated: DateTime<FixedOffset>,
+    entries: impl Iterator<Item = AtomEntry<'a>>,
+) -> fmt::Result {
+    write_feed_head(f, title, href, updated)?;
+    for entry in entries {
+        writeln!(f, "<entry>")?;
+        writeln!(f, "<title>{}</title>", escape_xml(entry.update.url().path()))?;
+        writeln!(f, "<id>{}</id>", escape_xml(&entry.href))?;
+        writeln!(f, r#"<link href="{}"/>"#, escape_xml(&entry.href))?;
+        writeln!(f, "<updated>{}</updated>", entry.update.timestamp().to_rfc3339())?;
+        writeln!(f, "<summary>{}</summary>", escape_xml(entry.update.change()))?;
+        for tag in entry.tags {
+            writeln!(f, r#"<category term="{}"/>"#, escape_xml(tag))?;
+        }
+        if let Some(first) = entry.first {
+            writeln!(f, r#"<category term="first-{}" label="{}"/>"#, first, first.label())?;
+        }
+        writeln!(f, "</entry>")?;
+    }
+    writeln!(f, "</feed>")
+}
+
+/// An entry in an Atom feed of the stored versions of a doc
+pub struct AtomVersionEntry {
+    pub timestamp: DateTime<FixedOffset>,
+    /// absolute link to the diff of the version against the version before it, also used as the entry's id
+    pub href: String,
+    /// whether this is the doc's first stored version, which is diffed against nothing
+    pub first: bool,
+}
+
+/// Write an Atom feed of the stored versions of a doc, whose entries are titled `doc_title`. `href` is the absolute link
+/// to the feed itself
+pub fn write_versions_feed(
+    f: &mut String,
+    title: &str,
+    href: &str,
+    doc_title: &str,
+    updated: DateTime<FixedOffset>,
+    entries: impl Iterator<Item = AtomVersionEntry>,
+) -> fmt::Result {
+    write_feed_head(f, title, href, updated)?;
+    for entry in entries {
+        writeln!(f, "<entry>")?;
+        writeln!(f, "<title>{}</title>", escape_xml(doc_title))?;
+        writeln!(f, "<id>{}</id>", escape_xml(&entry.href))?;
+        writeln!(f, r#"<link href="{}"/>"#, escape_xml(&entry.href))?;
+        writeln!(f, "<updated>{}</updated>", entry.timestamp.to_rfc3339())?;
+        let summary = if entry.first {
+            "First version stored"
+        } else {
+            "New version stored"
+        };
+        writeln!(f, "<summary>{}</summary>", summary)?;
+        writeln!(f, "</entry>")?;
+    }
+    writeln!(f, "</feed>")
+}
+
+fn write_feed_head(f: &mut String, title: &str, href: &str, updated: DateTime<FixedOffset>) -> fmt::Result {
+    writeln!(f, r#"<?xml version="1.0" encoding="utf-8"?>"#)?;
+    writeln!(f, r#"<feed xmlns="http://www.w3.org/2005/Atom">"#)?;
+    writeln!(f, "<title>{}</title>", escape_xml(title))?;
+    writeln!(f, "<id>{}</id>", escape_xml(href))?;
+    writeln!(f, r#"<link rel="self" href="{}"/>"#, escape_xml(href))?;
+    writeln!(f, "<updated>{}</updated>", updated.to_rfc3339())
+}
+
+fn escape_xml(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+#[test]
+fn test_negotiate() {
+    let request = |accept: &str| {
+        Request::fake_http("GET", "/updates", vec![("Accept".to_owned(), accept.to_owned())], vec![])
+    };
+    let offered = [Format::Html, Format::Json, Format::Atom];
+    assert_eq!(Format::negotiate(&Request::fake_http("GET", "/updates", vec![], vec![]), &offered), Format::Html);
+    assert_eq!(
+        Format::negotiate(&request("text/html,application/xhtml+xml,*/*;q=0.8"), &offered),
+        Format::Html
+    );
+    assert_eq!(Format::negotiate(&request("application/json"), &offered), Format::Json);
+    assert_eq!(Format::negotiate(&request("application/atom+xml, */*;q=0.1"), &offered), Format::Atom);
+    assert_eq!(Format::negotiate(&request("*/*"), &offered), Format::Html);
+    assert_eq!(Format::negotiate(&request("application/*"), &offered), Format::Json);
+    assert_eq!(
+        Format::negotiate(&request("application/json;q=0.5, text/html;q=0.9"), &offered),
+        Format::Html
+    );
+    assert_eq!(Format::negotiate(&request("image/png"), &offered), Format::Html);
+    assert_eq!(Format::negotiate(&request("application/atom+xml"), &offered[..2]), Format::Html);
+}
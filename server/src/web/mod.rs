@@ -1,102 +1,646 @@
 use std::{
     borrow::{Borrow, Cow},
-    env,
+    cmp::Reverse,
+    collections::HashMap,
     fmt::{self, Write},
-    mem,
+    fs, io, mem,
     ops::Deref,
+    path::{Path, PathBuf},
     str::FromStr,
-    sync::{Arc, RwLock, RwLockWriteGuard},
-    time::Instant,
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        mpsc::{self, Sender},
+        Arc, Mutex, RwLock, RwLockWriteGuard,
+    },
+    time::{Duration, Instant},
 };
 
-use chrono::{format::StrftimeItems, DateTime, FixedOffset};
-use rouille::{find_route, Request, Response};
-use update_repo::{doc::DocumentVersion, tag::Tag, update::Update, Url};
+use chrono::{DateTime, FixedOffset, NaiveDate, Offset, TimeZone};
+use chrono_tz::Tz;
+use rouille::{find_route, Request, Response, ResponseBody};
+use update_repo::{
+    doc::DocumentVersion,
+    filter::Filter,
+    tag::Tag,
+    update::{Update, UpdateRef},
+    Url,
+};
 
 #[macro_use]
 mod web_macros;
+mod access_log;
+mod collapse;
+mod diff_cache;
+mod differ;
 mod error;
+mod export;
+mod format;
 mod page;
+pub mod precompute;
+mod share;
 
-use crate::data::Data;
-
-use error::{CouldFind, Error};
+use crate::{
+    data::{self, Data, DocBody, SharedData},
+    display_rules::DisplayRules,
+    ingress::{disk_space, journal::Journal, watchdog, AdminRequest, Retraction},
+    read_only,
+    search::Query,
+    webhooks::{self, Webhooks},
+};
 
-pub fn listen(addr: &str, data: Arc<RwLock<Data>>) {
-    println!("Loading data");
+use access_log::AccessLog;
+pub use diff_cache::DIFF_VERSION;
+use differ::Differ;
+use error::{log_internal_error, CouldFind, Error, ErrorLog};
+use format::{AtomEntry, AtomVersionEntry, Format};
+use share::{DiffMode, DiffPin};
 
+pub fn listen(addr: &str, app: Arc<App>) {
     println!("Listen on http://{}", addr);
 
-    let default_page_fast_cache = FastCache::default();
+    rouille::start_server_with_pool(addr, None, move |request| app.handle(request));
+}
+
+/// The web app, routes requests to their handlers with the state and config they need
+pub struct App {
+    data: Arc<SharedData>,
+    /// refetches and retractions are queued for ingress, which makes all of the writes
+    admin_queue: Mutex<Sender<AdminRequest>>,
+    /// replaced on reload, requests keep the config they started with
+    config: RwLock<Arc<Config>>,
+    default_page_fast_cache: FastCache,
+    error_log: ErrorLog,
+    access_log: Option<AccessLog>,
+    /// request ids are the server start time and a count of requests, unless the proxy has provided one
+    started: i64,
+    request_count: AtomicU64,
+    /// requests which could change anything are refused
+    read_only: bool,
+}
+
+impl App {
+    pub fn new(data: Arc<SharedData>, admin_queue: Mutex<Sender<AdminRequest>>) -> Self {
+        let display_rules = DisplayRules::from_env();
+        data.write(|data| data.set_display_rules(display_rules.clone()));
+        Self {
+            data,
+            admin_queue,
+            config: RwLock::new(Arc::new(Config::from_env())),
+            default_page_fast_cache: FastCache::default(),
+            error_log: ErrorLog::default(),
+            access_log: AccessLog::from_env(),
+            started: chrono::Utc::now().timestamp(),
+            request_count: AtomicU64::new(0),
+            read_only: read_only(),
+        }
+    }
+
+    /// Re-read the config from the environment
+    pub fn reload_config(&self) {
+        *self.config.write().unwrap() = Arc::new(Config::from_env());
+        // the display rules are used by the data's listings
+        let display_rules = DisplayRules::from_env();
+        self.data.write(|data| data.set_display_rules(display_rules.clone()));
+        // the cached default page is of the old default page size
+        self.default_page_fast_cache.clear();
+    }
 
-    rouille::start_server_with_pool(addr, None, move |request| {
+    pub fn handle(&self, request: &Request) -> Response {
         let start = Instant::now();
-        let response = find_route!(
-            rouille::match_assets(request, "./static"),
-            handle_root(request),
-            handle_updates(request, &data.read().unwrap(), &default_page_fast_cache),
-            handle_update(request, &data.read().unwrap()),
-            handle_doc_diff_page(request, &data.read().unwrap())
-        );
-        eprintln!(
-            "> {ts} {remote_ip:15} < {status_code:3} ({took:3.0}ms) <- {method:4} {url} [Referer: {referrer:?} User-agent: {user_agent:?}]",
-            ts = chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
-            method = request.method(),
-            url = request.url(),
-            status_code = response.status_code,
-            remote_ip = request
+        access_log::start_request();
+        let config = self.config.read().unwrap().clone();
+        let request_id = request
+            .header("X-Request-Id")
+            .filter(|id| id.len() <= 64 && id.chars().all(|c| c.is_ascii_alphanumeric() || c == '-'))
+            .map(str::to_owned)
+            .unwrap_or_else(|| format!("{:x}-{:x}", self.started, self.request_count.fetch_add(1, Ordering::Relaxed)));
+        let (response, errors) = error::with_request_id(&request_id, || {
+            if self.read_only && !matches!(request.method(), "GET" | "HEAD") {
+                return Error::ReadOnly.into();
+            }
+            find_route!(
+                rouille::match_assets(request, "./static"),
+                handle_dashboard(request, &self.data.snapshot(), config.display_tz),
+                handle_stats(request, &self.data.snapshot()),
+                handle_status(request),
+                handle_stale(request, &self.data.snapshot(), config.display_tz),
+                handle_updates(request, &self.data.snapshot(), &self.default_page_fast_cache, config.display_tz, &config.base_url, config.page_sizes),
+                handle_feed(request, &self.data.snapshot(), &config.base_url, config.page_sizes),
+                handle_update(request, &self.data.snapshot(), config.display_tz, &config.base_url, &config.differ, config.missing_doc_status),
+                handle_doc_diff_page(request, &self.data.snapshot(), config.display_tz, &config.base_url, &config.differ),
+                handle_short_link(request, &self.data.snapshot()),
+                handle_share_link(request, &self.data.snapshot()),
+                handle_history_feed(request, &self.data.snapshot(), &config.base_url),
+                handle_versions_feed(request, &self.data.snapshot(), &config.base_url),
+                handle_subscribe(request, &self.data.snapshot()),
+                handle_unsubscribe(request, &self.data.snapshot()),
+                handle_export(request, &self.data.snapshot()),
+                handle_doc_version(request, &self.data.snapshot()),
+                handle_browse(request, &self.data.snapshot(), config.display_tz),
+                handle_admin_refetch(request, &self.admin_queue),
+                handle_admin_retract(request, &self.admin_queue),
+                handle_admin_hide(request, &self.data),
+                handle_admin_unhide(request, &self.data),
+                handle_admin_error(request, &self.error_log),
+                handle_admin_email(request, &self.data.snapshot(), &config.outbox),
+                handle_admin_ingress(request, &config.ingress_journal, config.display_tz)
+            )
+        });
+        if !errors.is_empty() {
+            self.error_log.record(request_id.clone(), errors);
+        }
+        let entry = access_log::Entry {
+            timestamp: &chrono::Utc::now().to_rfc3339_opts(chrono::SecondsFormat::Secs, true),
+            request_id: &request_id,
+            ip: &request
                 .header("X-Forwarded-For")
                 .map(Cow::from)
                 .unwrap_or_else(|| request.remote_addr().ip().to_string().into()),
-            referrer = request.header("Referer").unwrap_or_default(),
-            user_agent = request.header("User-Agent").unwrap_or_default(),
-            took = Instant::now().duration_since(start).as_millis(),
-        );
-        response
-    });
+            method: request.method(),
+            url: &request.url(),
+            status: response.status_code,
+            duration_ms: Instant::now().duration_since(start).as_millis() as u64,
+            referrer: request.header("Referer").unwrap_or_default(),
+            user_agent: request.header("User-Agent").unwrap_or_default(),
+        };
+        match &self.access_log {
+            Some(access_log) => {
+                if let Err(err) = access_log.log(&entry) {
+                    eprintln!("Failed writing access log : {}", err);
+                }
+            }
+            None => eprintln!(
+                "> {ts} [{request_id}] {remote_ip:15} < {status_code:3} ({took:3.0}ms) <- {method:4} {url} [Referer: {referrer:?} User-agent: {user_agent:?}]",
+                ts = entry.timestamp,
+                request_id = entry.request_id,
+                method = entry.method,
+                url = entry.url,
+                status_code = entry.status,
+                remote_ip = entry.ip,
+                referrer = entry.referrer,
+                user_agent = entry.user_agent,
+                took = entry.duration_ms,
+            ),
+        }
+        response.with_additional_header("X-Request-Id", request_id)
+    }
 }
 
 route! {
     (GET /)
-    handle_root(request: &Request) {
-        Ok(Response::redirect_302("/updates"))
+    handle_dashboard(request: &Request, data: &Data, display_tz: Tz) {
+        let display_tz = requested_tz(request)?.unwrap_or(display_tz);
+        let now = chrono::Utc::now();
+        let week_ago = now - chrono::Duration::days(DASHBOARD_DAYS);
+
+        let mut sections: HashMap<&str, usize> = HashMap::new();
+        let mut today_count = 0;
+        for (day, activity) in data.daily_activity(week_ago.naive_utc().date()) {
+            if *day == now.naive_utc().date() {
+                today_count = activity.updates;
+            }
+            for (section, count) in &activity.sections {
+                *sections.entry(section.as_str()).or_default() += count;
+            }
+        }
+        let mut sections: Vec<_> = sections.into_iter().collect();
+        sections.sort_by_key(|(section, count)| (Reverse(*count), *section));
+
+        Ok(Response::html(format!(
+            include_str!("dashboard.html"),
+            today_count = today_count,
+            days = DASHBOARD_DAYS,
+            sections = sections.iter().take(DASHBOARD_LIST_LENGTH).map(|(section, count)| {
+                format!(
+                    r#"<li><a href="/updates?url_prefix={prefix}">{section}</a> {count} updates</li>"#,
+                    prefix = query_value(&format!("www.gov.uk{}/", section)),
+                    section = section,
+                    count = count,
+                )
+            }).collect::<String>(),
+            most_updated = data.most_updated_since(week_ago.into()).into_iter().take(DASHBOARD_LIST_LENGTH).map(|(update, count)| {
+                format!(
+                    r#"<li><a href="{href}">{path}</a> {timestamp}, {count} updates tracked</li>"#,
+                    href = update_href(update.update_ref()),
+                    path = update.url().path(),
+                    timestamp = time_element(update.timestamp(), display_tz, DISPLAY_FORMAT),
+                    count = count,
+                )
+            }).collect::<String>(),
+            latest_update = data.latest_update().map_or("none yet".to_owned(), |update| time_element(update.timestamp(), display_tz, DISPLAY_FORMAT)),
+            update_count = data.update_count(),
+            url_count = data.url_count(),
+            tag_links = data.all_tags().map(|tag| {
+                format!(r#"<li><a href="/updates?tag={}">{}</a></li>"#, query_value(tag), tag)
+            }).collect::<String>(),
+        )))
+    }
+}
+
+route! {
+    (GET /stats)
+    handle_stats(request: &Request, data: &Data) {
+        let format = Format::negotiate(request, &[Format::Html, Format::Json]);
+        let stats = data.write_stats().map_err(|_| Error::InternalServer)?;
+        let largest_docs = data.largest_docs().map_err(|_| Error::InternalServer)?;
+        let recent_since = (chrono::Utc::now() - chrono::Duration::days(COVERAGE_DAYS)).naive_utc().date();
+        let coverage = data.coverage(recent_since);
+        let response = if format == Format::Json {
+            let lock_stats = data::write_lock_stats();
+            let json = serde_json::json!({
+                "writes": stats.writes,
+                "deduplicated": stats.deduplicated,
+                "bytes_stored": stats.bytes_stored,
+                "bytes_avoided": stats.bytes_avoided,
+                "largest_docs": largest_docs.iter().map(|(url, bytes)| serde_json::json!({
+                    "url": url.as_str(),
+                    "bytes": bytes,
+                })).collect::<Vec<_>>(),
+                "ingress_stalled": watchdog::is_stalled(),
+                "low_disk_space": disk_space::is_low(),
+                "free_bytes": disk_space::last_free_bytes(),
+                "data_write_lock": {
+                    "writes": lock_stats.writes,
+                    "wait_micros": lock_stats.wait_micros,
+                    "max_wait_micros": lock_stats.max_wait_micros,
+                    "held_micros": lock_stats.held_micros,
+                    "max_held_micros": lock_stats.max_held_micros,
+                },
+                "coverage_days": COVERAGE_DAYS,
+                "coverage": coverage.iter().map(|section| serde_json::json!({
+                    "section": section.section,
+                    "urls": section.urls,
+                    "updates": section.updates,
+                    "recent_updates": section.recent_updates,
+                    "oldest": section.oldest.to_rfc3339(),
+                })).collect::<Vec<_>>(),
+            });
+            Response::from_data(format.media_type(), json.to_string())
+        } else {
+            Response::html(format!(
+                include_str!("stats.html"),
+                writes = stats.writes,
+                deduplicated = stats.deduplicated,
+                deduplicated_percent = percentage(stats.deduplicated, stats.writes),
+                bytes_stored = format_bytes(stats.bytes_stored),
+                bytes_avoided = format_bytes(stats.bytes_avoided),
+                avoided_percent = percentage(stats.bytes_avoided, stats.bytes_stored + stats.bytes_avoided),
+                largest_docs = largest_docs.iter().map(|(url, bytes)| {
+                    format!(r#"<li><a href="{0}">{0}</a> {1}</li>"#, url.as_str(), format_bytes(*bytes))
+                }).collect::<String>(),
+                coverage_days = COVERAGE_DAYS,
+                coverage = coverage.iter().map(|section| {
+                    format!(
+                        r#"<tr><td><a href="/updates?section={query}">{section}</a></td><td>{urls}</td><td>{updates}</td><td>{recent_updates}</td><td>{oldest}</td></tr>"#,
+                        query = query_value(section.section),
+                        section = section.section,
+                        urls = section.urls,
+                        updates = section.updates,
+                        recent_updates = section.recent_updates,
+                        oldest = section.oldest.format("%Y-%m-%d"),
+                    )
+                }).collect::<String>(),
+            ))
+        };
+        Ok(response.with_additional_header("Vary", "Accept"))
+    }
+}
+
+query! {
+    /// The threshold of the stale docs list
+    struct StaleQuery {
+        /// docs whose latest version is older than this many days are listed
+        days: i64 = 30,
+    }
+}
+
+route! {
+    (GET /stale)
+    handle_stale(request: &Request, data: &Data, display_tz: Tz) {
+        let format = Format::negotiate(request, &[Format::Html, Format::Json]);
+        let display_tz = requested_tz(request)?.unwrap_or(display_tz);
+        let StaleQuery { days } = StaleQuery::from_request(request)?;
+        // up to a century, so that the threshold can't overflow
+        if !(1..=36500).contains(&days) {
+            return Err(Error::InvalidParam("days"));
+        }
+        let before = chrono::Utc::now() - chrono::Duration::days(days);
+        // every doc listed has updates, so it has a latest one
+        let stale: Vec<_> = data
+            .stale_docs(before.into())
+            .into_iter()
+            .filter_map(|(url, last_version)| {
+                let (latest_update, _tags) = data.get_updates(url)?.values().next_back()?;
+                Some((url, last_version, latest_update))
+            })
+            .collect();
+        let response = if format == Format::Json {
+            let json = serde_json::json!({
+                "days": days,
+                "docs": stale.iter().map(|(url, last_version, latest_update)| serde_json::json!({
+                    "url": url.as_str(),
+                    "last_version": last_version.to_rfc3339(),
+                    "latest_update": update_href(latest_update.update_ref()),
+                })).collect::<Vec<_>>(),
+            });
+            Response::from_data(format.media_type(), json.to_string())
+        } else {
+            Response::html(format!(
+                include_str!("stale.html"),
+                days = days,
+                count = stale.len(),
+                docs = stale.iter().map(|(url, last_version, latest_update)| {
+                    format!(
+                        r#"<li><a href="{href}">{path}</a> last captured {timestamp}</li>"#,
+                        href = update_href(latest_update.update_ref()),
+                        path = url.path(),
+                        timestamp = time_element(last_version, display_tz, DISPLAY_FORMAT),
+                    )
+                }).collect::<String>(),
+            ))
+        };
+        Ok(response.with_additional_header("Vary", "Accept"))
+    }
+}
+
+route! {
+    (GET /status)
+    handle_status(request: &Request) {
+        let stalled = watchdog::is_stalled();
+        let low_disk_space = disk_space::is_low();
+        let json = serde_json::json!({
+            "ok": !stalled && !low_disk_space,
+            "ingress_stalled": stalled,
+            "low_disk_space": low_disk_space,
+            "free_bytes": disk_space::last_free_bytes(),
+        });
+        // monitors can check the status code
+        let status = if stalled || low_disk_space { 503 } else { 200 };
+        Ok(Response::from_data(Format::Json.media_type(), json.to_string()).with_status_code(status))
     }
 }
 
+fn percentage(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.
+    } else {
+        part as f64 * 100. / whole as f64
+    }
+}
+
+/// A number of bytes in the largest unit it has at least one of
+fn format_bytes(bytes: u64) -> String {
+    const UNITS: &[&str] = &["KiB", "MiB", "GiB", "TiB"];
+    let mut size = bytes as f64;
+    let mut unit = "bytes";
+    for next_unit in UNITS {
+        if size < 1024. {
+            break;
+        }
+        size /= 1024.;
+        unit = next_unit;
+    }
+    if unit == "bytes" {
+        format!("{} bytes", bytes)
+    } else {
+        format!("{:.1} {}", size, unit)
+    }
+}
+
+/// The dashboard summarises this many days of activity
+const DASHBOARD_DAYS: i64 = 7;
+/// The coverage on `/stats` counts the updates over this many days
+const COVERAGE_DAYS: i64 = 30;
+/// The length of each list on the dashboard
+const DASHBOARD_LIST_LENGTH: usize = 10;
+/// The number of paths under a url prefix linked from the updates list
+const PREFIX_PATHS_LENGTH: usize = 20;
+
+query! {
+    /// The filters of the updates list
+    struct UpdatesQuery {
+        url_prefix: HttpsStrippedUrl = "www.gov.uk/".parse().unwrap(),
+        tag: String,
+        /// a section of the site, as in the path of its urls, eg. `/guidance` or `/government/publications`
+        section: String,
+        change: Query,
+        /// words in the text of the latest version of the updated doc, when the doc bodies are indexed
+        body: Query,
+        /// filter terms, the same as for the command line tools, eg. `#brexit 2022-01..`
+        filter: Filter,
+        /// an age range, eg. `7d..` for the last week, the same as an age filter term
+        age: String,
+    }
+}
+
+/// The age ranges offered as quick picks on the updates list, and their labels
+const AGE_PICKS: &[(&str, &str)] = &[("24h..", "Last 24 hours"), ("7d..", "Last 7 days"), ("30d..", "Last 30 days")];
+
 route! {
     (GET /updates)
-    handle_updates(request: &Request, data: &Data, fast_cache: &FastCache) {
+    handle_updates(request: &Request, data: &Data, fast_cache: &FastCache, display_tz: Tz, base_url: &BaseUrl, page_sizes: page::PageSizes) {
+        let format = Format::negotiate(request, &[Format::Html, Format::Json, Format::Atom]);
+        let display_tz = requested_tz(request)?.unwrap_or(display_tz);
         let data_updated_at = data.updated_at();
         let cache_guard =
-        if request.raw_query_string().is_empty() { // default query, use fast cache
+        // age ranges are only ever in the query, so the results of the cached page don't change without the data changing
+        if format == Format::Html && request.raw_query_string().is_empty() { // default query, use fast cache
             match fast_cache.try_cache(data_updated_at) {
-                Ok((html, etag)) => return Ok(Response::html(html).with_etag(request, etag)),
+                Ok((html, etag)) => {
+                    access_log::mark_cache_hit();
+                    return Ok(Response::html(html).with_etag(request, etag).with_additional_header("Vary", "Accept"));
+                }
                 Err(cache_guard) => Some(cache_guard),
             }
         } else {
             None
         };
 
-        let url_prefix = request.get_param("url_prefix").as_deref().unwrap_or("www.gov.uk/").parse::<HttpsStrippedUrl>().map_err(|_| Error::InvalidRequest)?.0;
-        let tag = request.get_param("tag").filter(|t| !t.is_empty()).map(Tag::new);
+        let query = UpdatesQuery::from_request(request)?;
+        let (filter, url_prefix) = query.to_filter()?;
+        let change = query.change.as_ref().filter(|change| !change.is_empty());
+        let body = query.body(data)?;
+
+        let updates = data.list_updates(&filter, query.section.as_deref(), change, body);
+
+        let response = match format {
+            Format::Html => {
+                let (html, mut etag) = updates_page_response(updates, request, data, display_tz, &url_prefix, &filter.tags, change, page_sizes)?;
+                if filter.is_relative() {
+                    // updates age out of the results without the data changing, so the etag is only good for a minute
+                    etag = format!("{} {}", etag, chrono::Utc::now().timestamp() / 60);
+                }
+                if let Some(mut cache_guard) = cache_guard {
+                    *cache_guard = Some((data_updated_at, Arc::new((html.clone(), etag.clone()))));
+                    drop(cache_guard)
+                }
+                Response::html(html).with_etag(request, etag)
+            }
+            Format::Json => {
+                let updates: Vec<_> = page::Page::new(request, updates, page_sizes)?
+                    .map(|update| update_json(update, request, data, base_url))
+                    .collect();
+                Response::from_data(format.media_type(), serde_json::Value::from(updates).to_string())
+            }
+            Format::Atom => updates_feed_response(updates, "GOV.UK updates", request, data, base_url, page_sizes)?,
+        };
+        Ok(response.with_additional_header("Vary", "Accept"))
+    }
+}
+
+route! {
+    (GET /feed)
+    handle_feed(request: &Request, data: &Data, base_url: &BaseUrl, page_sizes: page::PageSizes) {
+        let query = UpdatesQuery::from_request(request)?;
+        let (filter, url_prefix) = query.to_filter()?;
+        let change = query.change.as_ref().filter(|change| !change.is_empty());
+        let updates = data.list_updates(&filter, query.section.as_deref(), change, query.body(data)?);
 
-        let updates = data.list_updates(&url_prefix, tag);
+        let mut title = "GOV.UK updates".to_owned();
+        if url_prefix.path() != "/" {
+            write!(title, " under {}{}", url_prefix.host_str().unwrap_or_default(), url_prefix.path()).unwrap();
+        }
+        if let Some(section) = &query.section {
+            write!(title, " in {}", section).unwrap();
+        }
+        if !filter.tags.is_empty() {
+            let tags: Vec<_> = filter.tags.iter().map(|tag| tag.name()).collect();
+            write!(title, " tagged {}", tags.join(", ")).unwrap();
+        }
+        updates_feed_response(updates, &title, request, data, base_url, page_sizes)
+    }
+}
 
-        let (html, etag) = updates_page_response(updates,request,data);
-        if let Some(mut cache_guard) = cache_guard {
-            *cache_guard = Some((data_updated_at, Arc::new((html.clone(), etag.clone()))));
-            drop(cache_guard)
+impl UpdatesQuery {
+    /// The filter of the updates list and its url prefix, the tag, url prefix and age params are filter terms too
+    fn to_filter(&self) -> Result<(Filter, Url), Error> {
+        let mut filter = self.filter.clone().unwrap_or_default();
+        filter.tags.extend(self.tag.clone().map(Tag::new));
+        let url_prefix = filter.url_prefix.get_or_insert_with(|| self.url_prefix.0.clone()).clone();
+        if let Some(age) = &self.age {
+            filter.set_age_range(age).map_err(|_| Error::InvalidParam("age"))?;
+        }
+        Ok((filter, url_prefix))
+    }
+
+    /// The query of the doc bodies, which can only be searched when they are indexed
+    fn body(&self, data: &Data) -> Result<Option<&Query>, Error> {
+        match self.body.as_ref().filter(|body| !body.is_empty()) {
+            Some(_) if !data.has_body_index() => Err(Error::InvalidParam("body")),
+            body => Ok(body),
         }
-        Ok(Response::html(html).with_etag(request, etag))
     }
 }
 
+/// A page of updates as an Atom feed
+fn updates_feed_response<'a>(
+    updates: impl Iterator<Item = &'a Update>,
+    title: &str,
+    request: &Request,
+    data: &Data,
+    base_url: &BaseUrl,
+    page_sizes: page::PageSizes,
+) -> Result<Response, Error> {
+    let mut updates = page::Page::new(request, updates, page_sizes)?.peekable();
+    let updated = updates.peek().map_or_else(|| chrono::Utc::now().into(), |update| *update.timestamp());
+    let mut feed = String::new();
+    format::write_atom_feed(
+        &mut feed,
+        title,
+        &base_url.absolute(request, &request.raw_url()),
+        updated,
+        updates.map(|update| AtomEntry {
+            update,
+            href: base_url.absolute(request, &update_href(update.update_ref())),
+            tags: format::sorted(data.get_tags(update.update_ref()).iter().map(|tag| tag.name())),
+            first: data.first_version(update),
+        }),
+    )
+    .map_err(|_| Error::InternalServer)?;
+    Ok(Response::from_data(Format::Atom.media_type(), feed))
+}
+
+query! {
+    struct UpdateQuery {
+        /// which of the updates to the url at the timestamp, the first unless there were several
+        seq: u32 = 0,
+        /// filter terms carried from the updates list the update was linked from, the previous and next updates are
+        /// those which match them
+        filter: Filter,
+    }
+}
+
+/// The path of an update's page, the update's seq is only given when there were several updates to its url at its
+/// timestamp so that the links to all other updates are as they always were
+fn update_href(update_ref: &UpdateRef) -> String {
+    match update_ref.seq {
+        0 => update_path(&update_ref.timestamp, &update_ref.url),
+        seq => format!("{}?seq={}", update_path(&update_ref.timestamp, &update_ref.url), seq),
+    }
+}
+
+/// The path of an update's page carrying the filter terms of the list it is linked from, if the list is filtered
+fn filtered_update_href(update_ref: &UpdateRef, filter_terms: Option<&str>) -> String {
+    let href = update_href(update_ref);
+    match filter_terms.filter(|terms| !terms.trim().is_empty()) {
+        Some(terms) => {
+            let separator = if href.contains('?') { '&' } else { '?' };
+            format!("{}{}filter={}", href, separator, query_value(terms))
+        }
+        None => href,
+    }
+}
+
+/// The filters of an updates list as filter terms, for the update pages linked from it to carry. A list's section and
+/// searches can't be written as filter terms, so they aren't carried
+fn carried_filter_terms(request: &Request) -> Option<String> {
+    let mut terms: Vec<String> = request.get_param("filter").into_iter().collect();
+    if let Some(tag) = request.get_param("tag").filter(|tag| !tag.is_empty()) {
+        terms.push(format!(r#"#"{}""#, tag));
+    }
+    if let Some(Ok(url_prefix)) = request.get_param("url_prefix").map(|prefix| prefix.parse::<HttpsStrippedUrl>()) {
+        if url_prefix.path() != "/" {
+            terms.push(url_prefix.as_str().to_owned());
+        }
+    }
+    // an age range is written with `...` as a filter term, as `..` is a date range
+    if let Some(age) = request.get_param("age").filter(|age| !age.is_empty()) {
+        terms.push(if age.contains("...") { age } else { age.replacen("..", "...", 1) });
+    }
+    let terms = terms.join(" ");
+    (!terms.trim().is_empty()).then_some(terms)
+}
+
+/// Links to the updates before and after an update in time, keeping the filter terms the update page was given
+fn update_nav(previous: Option<&Update>, next: Option<&Update>, filter_terms: Option<&str>) -> String {
+    let links: Vec<String> = [(previous, "prev", "Previous update"), (next, "next", "Next update")]
+        .iter()
+        .filter_map(|&(update, rel, label)| {
+            let href = filtered_update_href(update?.update_ref(), filter_terms);
+            Some(format!(r#"<a rel="{}" href="{}">{}</a>"#, rel, escape_attribute(&href), label))
+        })
+        .collect();
+    if links.is_empty() {
+        return String::new();
+    }
+    format!(r#"<p class="update-nav">{}</p>"#, links.join(" | "))
+}
+
 route! {
-    (GET /update/{timestamp: DateTime<FixedOffset>}/{url: HttpsStrippedUrl})
-    handle_update(request: &Request, data: &Data) {
+    (GET /update/{timestamp: DateTime<FixedOffset>}/{url: HttpsStrippedUrl}) as update_path
+    handle_update(request: &Request, data: &Data, display_tz: Tz, base_url: &BaseUrl, differ: &Differ, missing_doc_status: u16) {
+        let format = Format::negotiate(request, &[Format::Html, Format::Json]);
+        let display_tz = requested_tz(request)?.unwrap_or(display_tz);
+        let UpdateQuery { seq, filter } = UpdateQuery::from_request(request)?;
+        if let Some(redirect) = redirect_trailing_slash(request, &url) {
+            return Ok(redirect);
+        }
         // get update
         let updates = data.get_updates(&url).could_find("Update")?;
-        let update = &updates.get(&timestamp).could_find("Update")?.0;
+        let update = &updates.get(&(timestamp, seq)).could_find("Update")?.0;
+        let filter = filter.unwrap_or_default();
+        let previous_update = data.previous_update(update.update_ref(), &filter);
+        let next_update = data.next_update(update.update_ref(), &filter);
 
         // get doc version before & after update
         let current_doc = data.iter_doc_versions(&url).and_then(|iter| {
@@ -108,71 +652,694 @@ route! {
                 .max_by_key(|v| *v.timestamp())
         });
 
+        if format == Format::Json {
+            let mut json = update_json(update, request, data, base_url);
+            let from_ts = previous_doc.as_ref().map(|v| *v.timestamp());
+            let to_ts = current_doc.as_ref().map(|v| *v.timestamp());
+            json["doc_from"] = from_ts.map(|v| v.to_rfc3339()).into();
+            json["doc_to"] = to_ts.map(|v| v.to_rfc3339()).into();
+            json["diff_href"] = base_url.absolute(request, &diff_path(&from_ts, &to_ts, &url)).into();
+            json["removed_at"] = data.removed_at(&url).map(|at| at.to_rfc3339()).into();
+            json["withdrawn_at"] = data.withdrawn_at(&url).map(|at| at.to_rfc3339()).into();
+            return Ok(Response::from_data(format.media_type(), json.to_string()).with_additional_header("Vary", "Accept"));
+        }
+
         // do the diff
-        let (diff_url, from_ts, to_ts, body) = diff_fields(&url, previous_doc.as_ref(), current_doc.as_ref(), data);
+        let DiffFields { diff_url, from_ts, to_ts, mut body, pending } = diff_fields(&url, previous_doc.as_ref(), current_doc.as_ref(), data, differ)?;
+        let missing = from_ts.is_none() && to_ts.is_none();
+        if missing {
+            body = missing_versions_notice(&url);
+        }
 
-        Ok(Response::html(format!(
+        let canonical_url = base_url.absolute(request, &update_href(update.update_ref()));
+        // the doc's title, the page may have been retitled by the update so the later version is preferred
+        let title = current_doc
+            .as_ref()
+            .or(previous_doc.as_ref())
+            .and_then(|doc| data.doc_title(doc))
+            .unwrap_or_else(|| format!("{}{}", url.host_str().unwrap_or_default(), url.path()));
+        let card = share::card(&title, update.change(), &canonical_url, Some(update.timestamp()));
+        let feed_url = history_path(&*url);
+
+        let response = Response::html(format!(
             include_str!("update.html"),
-            orig_url = &*url,
-            timestamp = update.timestamp().naive_local(),
+            breadcrumbs = breadcrumbs(&url, data),
+            canonical_url = canonical_url,
+            card = card,
+            feed_url = feed_url,
+            versions_feed_url = versions_feed_path(&*url),
+            permalink = base_url.absolute(request, &short_link_path(&update.update_ref().short_id())),
+            update_nav = update_nav(previous_update, next_update, request.get_param("filter").as_deref()),
+            orig_link = match data.removed_at(&url) {
+                Some(_) => format!(r#"<del><a href="{0}">{0}</a></del>"#, &*url),
+                None => format!(r#"<a href="{0}">{0}</a>"#, &*url),
+            },
+            status_notice = status_notice(&url, data, display_tz),
+            timestamp = time_element(update.timestamp(), display_tz, DISPLAY_FORMAT),
             change = update.change(),
             tags = data.get_tags(update.update_ref()).iter().map(|u| u.name()).collect::<String>(),
             diff_url = diff_url,
-            doc_from = from_ts.map_or(String::new(), |v| v.to_string()),
-            doc_to = to_ts.map_or(String::new(), |v| v.to_string()),
+            doc_from = from_ts.map_or(String::new(), |v| time_element(&v, display_tz, DISPLAY_FORMAT)),
+            doc_to = to_ts.map_or(String::new(), |v| time_element(&v, display_tz, DISPLAY_FORMAT)),
             body = body,
             history = updates.iter().rev().map(|(_, (update, _tags))| {
-                format!(r#"<a href="/update/{}/{}{}"><p class="update-description">{}<br />{}</p></a>"#, update.timestamp().to_rfc3339(), update.url().host_str().unwrap(), update.url().path(), update.timestamp().format("%F %H:%M"), update.change())
+                let first = data.first_version(update).map_or(String::new(), |first| format!(r#" <span class="first-version">{}</span>"#, first.label()));
+                format!(r#"<a href="{}"><p class="update-description">{}{}<br />{}</p></a>"#, update_href(update.update_ref()), time_element(update.timestamp(), display_tz, DISPLAY_FORMAT), first, update.change())
             }).collect::<String>()
-        ))
-        .with_status_code(if from_ts.is_none() && to_ts.is_none() { 404 } else { 200 })
+        ));
+        if pending {
+            return Ok(pending_diff_response(response));
+        }
+        Ok(response
+        .with_status_code(if missing { missing_doc_status } else { 200 })
+        .with_additional_header("Vary", "Accept")
         .with_etag(
             request,
-            format!("{} {}", previous_doc.is_some(), current_doc.is_some()),
+            format!(
+                "{} {} {:?} {:?} {} {}",
+                previous_doc.is_some(),
+                current_doc.is_some(),
+                data.removed_at(&url),
+                data.withdrawn_at(&url),
+                previous_update.map_or(String::new(), |update| update.update_ref().short_id()),
+                next_update.map_or(String::new(), |update| update.update_ref().short_id()),
+            ),
         ))
     }
 }
 
 route! {
-    (GET /diff/{from: MaybeEmpty<DateTime<FixedOffset>>}/{to: MaybeEmpty<DateTime<FixedOffset>>}/{url: HttpsStrippedUrl})
-    handle_doc_diff_page(request: &Request, data: &Data) {
+    (GET /diff/{from: MaybeEmpty<DateTime<FixedOffset>>}/{to: MaybeEmpty<DateTime<FixedOffset>>}/{url: HttpsStrippedUrl}) as diff_path
+    handle_doc_diff_page(request: &Request, data: &Data, display_tz: Tz, base_url: &BaseUrl, differ: &Differ) {
+        let display_tz = requested_tz(request)?.unwrap_or(display_tz);
+        let DiffQuery { mode } = DiffQuery::from_request(request)?;
+        if let Some(redirect) = redirect_trailing_slash(request, &url) {
+            return Ok(redirect);
+        }
         // get doc version from
         let from_doc = from.0.and_then(|ts| data.get_doc_version(&url, ts).ok());
 
         // get doc version to
         let to_doc = to.0.and_then(|ts| data.get_doc_version(&url, ts).ok());
 
+        // the update page which shows this diff, if there is one, is the canonical page for it
+        let updates = data.get_updates(&url);
+        let update = to.0.and_then(|to_ts| {
+            let ((update_ts, _), (update, _)) = updates?.range(..(to_ts, 0)).next_back()?;
+            from.0.map_or(true, |from_ts| *update_ts >= from_ts).then(|| update)
+        });
+        let canonical = update.map(|update| base_url.absolute(request, &update_href(update.update_ref())));
+
         // do the diff
-        let (diff_url, from_ts, to_ts, body) = diff_fields(&url, from_doc.as_ref(), to_doc.as_ref(), data);
+        let DiffFields { diff_url, from_ts, to_ts, body, pending } = diff_fields(&url, from_doc.as_ref(), to_doc.as_ref(), data, differ)?;
 
-        Ok(Response::html(format!(
+        // share links refer to the url by the short id of one of its updates, so there are none for urls without updates
+        let share_link = update
+            .or_else(|| updates?.values().next_back().map(|(update, _)| update))
+            .map(|update| {
+                let pin = DiffPin::new(from_ts.as_ref(), to_ts.as_ref(), mode);
+                base_url.absolute(request, &share_path(&update.update_ref().short_id(), &pin))
+            });
+        let (insertions, deletions) = share::change_counts(&body);
+        let card = share::card(
+            &format!("Change of {}{}", url.host_str().unwrap_or_default(), url.path()),
+            &format!(
+                "{} ({} insertions, {} deletions)",
+                update.map_or("Changes between two versions", |update| update.change()),
+                insertions,
+                deletions
+            ),
+            share_link.as_deref().or(canonical.as_deref()).unwrap_or_default(),
+            None,
+        );
+        let modes = DiffMode::ALL
+            .iter()
+            .map(|&option| {
+                if option == mode {
+                    format!("<strong>{}</strong>", option.label())
+                } else {
+                    format!(r#"<a href="{}{}">{}</a>"#, diff_path(&from.0, &to.0, &url), option.query(), option.label())
+                }
+            })
+            .collect::<Vec<_>>()
+            .join(" | ");
+
+        let response = Response::html(format!(
             include_str!("diff.html"),
+            breadcrumbs = breadcrumbs(&url, data),
+            canonical = canonical.map_or(String::new(), |href| format!(r#"<link rel="canonical" href="{}">"#, href)),
+            card = card,
             orig_url = &*url,
             diff_url = diff_url,
-            doc_from = from_ts.map_or(String::new(), |v| v.to_string()),
-            doc_to = to_ts.map_or(String::new(), |v| v.to_string()),
+            doc_from = from_ts.map_or(String::new(), |v| time_element(&v, display_tz, DISPLAY_FORMAT)),
+            doc_to = to_ts.map_or(String::new(), |v| time_element(&v, display_tz, DISPLAY_FORMAT)),
+            modes = modes,
+            share = share_link.map_or(String::new(), |href| format!(r#"<p>Share : <a href="{0}">{0}</a></p>"#, href)),
+            mode = mode.name(),
             body = body,
-        ))
+        ));
+        if pending {
+            return Ok(pending_diff_response(response));
+        }
+        Ok(response
         .with_status_code(if from_ts.is_none() && to_ts.is_none() { 404 } else { 200 })
         .with_etag(request, format!("{} {}", from_doc.is_some(), to_doc.is_some())))
     }
 }
 
+route! {
+    (GET /u/{short_id}) as short_link_path
+    handle_short_link(request: &Request, data: &Data) {
+        let update_ref = data.get_short_id(short_id).could_find("Update")?;
+        Ok(Response::redirect_301(update_href(update_ref)))
+    }
+}
+
+route! {
+    (GET /s/{update_id}/{pin: DiffPin}) as share_path
+    handle_share_link(request: &Request, data: &Data) {
+        let url = &data.get_short_id(update_id).could_find("Update")?.url;
+        // versions are pinned to the second
+        let version = |timestamp: Option<i64>| {
+            timestamp
+                .map(|timestamp| {
+                    data.iter_doc_versions(url)
+                        .into_iter()
+                        .flatten()
+                        .map(|version| *version.timestamp())
+                        .find(|version| version.timestamp() == timestamp)
+                        .could_find("Doc version")
+                })
+                .transpose()
+        };
+        let path = format!("{}{}", diff_path(&version(pin.from)?, &version(pin.to)?, url), pin.mode.query());
+        Ok(Response::redirect_301(path))
+    }
+}
+
+route! {
+    (GET /history/{url: AtomFeedUrl}) as history_path
+    handle_history_feed(request: &Request, data: &Data, base_url: &BaseUrl) {
+        if !data.is_displayed(&url) {
+            return Err(Error::NotFound("Doc"));
+        }
+        let updates = data.get_updates(&url).could_find("Doc")?;
+        let (updated, _) = *updates.keys().next_back().could_find("Doc")?;
+        let mut feed = String::new();
+        format::write_atom_feed(
+            &mut feed,
+            &format!("Changes to {}{}", url.host_str().unwrap_or_default(), url.path()),
+            &base_url.absolute(request, &history_path(&*url)),
+            updated,
+            updates.values().rev().map(|(update, tags)| AtomEntry {
+                update,
+                href: base_url.absolute(request, &update_href(update.update_ref())),
+                tags: format::sorted(tags.iter().map(|tag| tag.name())),
+                first: data.first_version(update),
+            }),
+        ).map_err(|_| Error::InternalServer)?;
+        Ok(Response::from_data(Format::Atom.media_type(), feed).with_etag(request, updated.to_rfc3339()))
+    }
+}
+
+route! {
+    (GET /doc/{url: VersionsFeedUrl}) as versions_feed_path
+    handle_versions_feed(request: &Request, data: &Data, base_url: &BaseUrl) {
+        if !data.is_displayed(&url) {
+            return Err(Error::NotFound("Doc"));
+        }
+        // oldest first, so that each version is paired with the version before it
+        let mut versions: Vec<DateTime<FixedOffset>> = data.iter_doc_versions(&url).could_find("Doc")?.map(|version| *version.timestamp()).collect();
+        versions.reverse();
+        let updated = *versions.last().could_find("Doc")?;
+        let previous_versions = std::iter::once(None).chain(versions.iter().copied().map(Some));
+        let entries: Vec<AtomVersionEntry> = previous_versions.zip(versions.iter().copied()).map(|(previous, timestamp)| AtomVersionEntry {
+            timestamp,
+            href: base_url.absolute(request, &diff_path(&previous, &Some(timestamp), &*url)),
+            first: previous.is_none(),
+        }).collect();
+        let doc_title = format!("{}{}", url.host_str().unwrap_or_default(), url.path());
+        let mut feed = String::new();
+        format::write_versions_feed(
+            &mut feed,
+            &format!("Versions of {}", doc_title),
+            &base_url.absolute(request, &versions_feed_path(&*url)),
+            &doc_title,
+            updated,
+            entries.into_iter().rev(),
+        ).map_err(|_| Error::InternalServer)?;
+        // a version before the latest may be removed, such as by a compaction
+        let etag = format!("{} {}", updated.to_rfc3339(), versions.len());
+        Ok(Response::from_data(Format::Atom.media_type(), feed).with_etag(request, etag))
+    }
+}
+
+query! {
+    struct WebhookQuery {
+        callback: url::Url,
+    }
+}
+
+route! {
+    (POST /api/v1/webhooks/{url: HttpsStrippedUrl})
+    handle_subscribe(request: &Request, data: &Data) {
+        authorize_admin(request)?;
+        let callback = webhook_callback(request)?;
+        data.get_updates(&url).could_find("Doc")?;
+        if data.webhooks().count() >= Webhooks::MAX_SUBSCRIPTIONS {
+            return Err(Error::Unavailable("Webhooks"));
+        }
+        let subscribed = data.webhooks().subscribe(url.0, callback)?;
+        Ok(Response::text("Subscribed").with_status_code(if subscribed { 201 } else { 200 }))
+    }
+}
+
+route! {
+    (DELETE /api/v1/webhooks/{url: HttpsStrippedUrl})
+    handle_unsubscribe(request: &Request, data: &Data) {
+        authorize_admin(request)?;
+        let callback = webhook_callback(request)?;
+        if !data.webhooks().unsubscribe(&url, &callback)? {
+            return Err(Error::NotFound("Subscription"));
+        }
+        Ok(Response::empty_204())
+    }
+}
+
+/// The callback of a webhook subscription, only https so that deliveries can't be read or altered on the way, and only
+/// to public hosts so that the server can't be pointed at itself or its network
+fn webhook_callback(request: &Request) -> Result<url::Url, Error> {
+    WebhookQuery::from_request(request)?
+        .callback
+        .filter(|callback| callback.scheme() == "https" && webhooks::is_public_host(callback))
+        .ok_or(Error::InvalidParam("callback"))
+}
+
+query! {
+    struct DiffQuery {
+        mode: DiffMode = DiffMode::Inline,
+    }
+}
+
+query! {
+    struct ExportQuery {
+        since: export::Cursor,
+        limit: usize = EXPORT_LIMIT,
+        /// a comma separated list of the optional fields to include
+        include: String = String::new(),
+        filter: Filter,
+    }
+}
+
+route! {
+    (GET /api/v1/updates/export)
+    handle_export(request: &Request, data: &Data) {
+        let ExportQuery { since, limit, include, filter } = ExportQuery::from_request(request)?;
+        let limit = limit.min(EXPORT_LIMIT);
+        let include_tags = include.split(',').any(|include| include == "tags");
+        let include_doc_versions = include.split(',').any(|include| include == "doc_versions");
+
+        let updates = match &since {
+            Some(since) => data.updates_after(&since.timestamp, since.url.as_ref().map(|url| (url, since.seq))),
+            None => data.updates_after(&DateTime::<FixedOffset>::from(chrono::MIN_DATETIME), None),
+        };
+        let mut body = String::new();
+        let mut last = None;
+        let filter = filter.unwrap_or_default();
+        let listed = |update: &&Update| data.is_displayed(update.url()) && data.matches_filter(update, &filter);
+        for update in updates.filter(listed).take(limit) {
+            export::write_update_line(
+                &mut body,
+                update,
+                include_tags.then(|| data.get_tags(update.update_ref()).iter().map(|tag| tag.name())),
+                include_doc_versions.then(|| data.iter_doc_versions(update.url()).into_iter().flatten().map(|v| *v.timestamp())),
+            ).map_err(|_| Error::InternalServer)?;
+            last = Some(update);
+        }
+
+        let mut response = Response::from_data("application/x-ndjson", body);
+        // the cursor to resume from, or to poll with for updates added later
+        if let Some(next) = last.map(export::Cursor::after).or(since) {
+            response = response.with_additional_header("X-Next-Cursor", next.to_string());
+        }
+        Ok(response)
+    }
+}
+
+route! {
+    (GET /api/v1/docs/{timestamp: DateTime<FixedOffset>}/{url: HttpsStrippedUrl}) as doc_version_path
+    handle_doc_version(request: &Request, data: &Data) {
+        let doc = data.get_doc_version(&url, timestamp).could_find("Doc version")?;
+        let content = data.read_doc(&doc).could_find("Doc version")?;
+        // stored html is a sanitised fragment starting with `<main`
+        let content_type = if content.starts_with(b"<main") {
+            "text/html; charset=utf-8"
+        } else {
+            "application/octet-stream"
+        };
+        let len = content.len();
+        let response = Response {
+            status_code: 200,
+            headers: vec![("Content-Type".into(), content_type.into())],
+            // served from the content as read, which may be mapped rather than in memory, so that it isn't copied
+            data: ResponseBody::from_reader_and_size(io::Cursor::new(content), len),
+            upgrade: None,
+        };
+        // versions never change
+        Ok(response.with_public_cache(365 * 24 * 60 * 60))
+    }
+}
+
+route! {
+    (GET /browse/{date: NaiveDate}/{url: HttpsStrippedUrl}) as browse_path
+    handle_browse(request: &Request, data: &Data, display_tz: Tz) {
+        let display_tz = requested_tz(request)?.unwrap_or(display_tz);
+        // the tree as it was at the end of the day in the display time zone
+        let end_of_day = display_tz.from_local_datetime(&date.and_hms(23, 59, 59)).latest().could_find("Date")?;
+        let at = end_of_day.with_timezone(&end_of_day.offset().fix());
+        let (version, children) = data.tree_at(&url, at).could_find("Path")?;
+        let doc_link = |url: &Url, version: &Option<DocumentVersion>| {
+            version.as_ref().map_or(String::new(), |version| {
+                format!(r#"<a href="{}">{}</a>"#, doc_version_path(version.timestamp(), url), time_element(version.timestamp(), display_tz, DISPLAY_FORMAT))
+            })
+        };
+        Ok(Response::html(format!(
+            include_str!("browse.html"),
+            path = url.path(),
+            date = date,
+            // there is no day either side of the earliest and latest dates
+            day_links = [(date.pred_opt(), "Previous day"), (date.succ_opt(), "Next day")]
+                .iter()
+                .filter_map(|(day, label)| Some(format!(r#" <a href="{}">{}</a>"#, browse_path(&(*day)?, &*url), label)))
+                .collect::<String>(),
+            doc = match &version {
+                Some(_) => doc_link(&*url, &version),
+                None => "none".to_owned(),
+            },
+            updates_href = format!("/updates?url_prefix={}", query_value(&ToPathSegment::<HttpsStrippedUrl>::to_path_segment(&*url))),
+            children = children.iter().map(|(child, version)| {
+                format!(
+                    r#"<li><a href="{}">{}</a> {}</li>"#,
+                    browse_path(&date, child),
+                    child.path_segments().and_then(|mut segments| segments.next_back()).unwrap_or_default(),
+                    doc_link(child, version),
+                )
+            }).collect::<String>(),
+)))
+    }
+}
+
+/// The most updates returned by one export request
+const EXPORT_LIMIT: usize = 10_000;
+
+query! {
+    struct RefetchQuery {
+        url: url::Url,
+    }
+}
+
+route! {
+    (POST /admin/refetch)
+    handle_admin_refetch(request: &Request, admin_queue: &Mutex<Sender<AdminRequest>>) {
+        if request.header("Authorization").is_some() {
+            authorize_admin(request)?;
+        } else {
+            authorize_admin_form(request)?;
+        }
+        let url = RefetchQuery::from_request(request)?.url.ok_or(Error::InvalidParam("url"))?;
+        if url.host_str() != Some("www.gov.uk") {
+            return Err(Error::InvalidRequest);
+        }
+        admin_queue
+            .lock()
+            .map_err(|_| Error::InternalServer)?
+            .send(AdminRequest::Refetch(url.clone()))
+            .map_err(|_| Error::Unavailable("Ingress"))?;
+        Ok(Response::text(format!("Refetch of {} queued", url)).with_status_code(202))
+    }
+}
+
+/// How long a retraction waits for ingress, which may be in the middle of processing emails
+const RETRACT_TIMEOUT: Duration = Duration::from_secs(60);
+
+query! {
+    struct RetractQuery {
+        /// `update` or `doc`
+        kind: String,
+        url: Url,
+        timestamp: DateTime<FixedOffset>,
+        /// which of the updates to the url at the timestamp, the first unless there were several
+        seq: u32 = 0,
+    }
+}
+
+route! {
+    (POST /admin/retract)
+    handle_admin_retract(request: &Request, admin_queue: &Mutex<Sender<AdminRequest>>) {
+        authorize_admin(request)?;
+        let query = RetractQuery::from_request(request)?;
+        let url = query.url.ok_or(Error::InvalidParam("url"))?;
+        let timestamp = query.timestamp.ok_or(Error::InvalidParam("timestamp"))?;
+        let retraction = match query.kind.as_deref() {
+            Some("update") => Retraction::Update(UpdateRef { url, timestamp, seq: query.seq }),
+            Some("doc") => Retraction::DocVersion(url, timestamp),
+            _ => return Err(Error::InvalidParam("kind")),
+        };
+        // deleted by ingress so that it can't race with its writes, and its events keep the data consistent
+        let (reply, result) = mpsc::channel();
+        admin_queue
+            .lock()
+            .map_err(|_| Error::InternalServer)?
+            .send(AdminRequest::Retract(retraction, reply))
+            .map_err(|_| Error::Unavailable("Ingress"))?;
+        match result.recv_timeout(RETRACT_TIMEOUT) {
+            Ok(Ok(deleted)) => Ok(Response::text(deleted)),
+            Ok(Err(err)) => Ok(Response::text(format!("Retraction failed : {}", err)).with_status_code(500)),
+            Err(_) => Err(Error::Unavailable("Ingress")),
+        }
+    }
+}
+
+query! {
+    struct HideQuery {
+        url: Url,
+    }
+}
+
+route! {
+    (POST /admin/hide)
+    handle_admin_hide(request: &Request, data: &SharedData) {
+        authorize_admin(request)?;
+        let url = HideQuery::from_request(request)?.url.ok_or(Error::InvalidParam("url"))?;
+        let hidden = data
+            .write(|data| data.hide(url.clone()))
+            .map_err(|_| Error::InternalServer)?;
+        Ok(Response::text(if hidden {
+            format!("{} hidden", url)
+        } else {
+            format!("{} was already hidden", url)
+        }))
+    }
+}
+
+route! {
+    (POST /admin/unhide)
+    handle_admin_unhide(request: &Request, data: &SharedData) {
+        authorize_admin(request)?;
+        let url = HideQuery::from_request(request)?.url.ok_or(Error::InvalidParam("url"))?;
+        let shown = data.write(|data| data.unhide(&url)).map_err(|_| Error::InternalServer)?;
+        Ok(Response::text(if shown {
+            format!("{} shown again", url)
+        } else {
+            format!("{} wasn't hidden", url)
+        }))
+    }
+}
+
+route! {
+    (GET /admin/errors/{request_id})
+    handle_admin_error(request: &Request, error_log: &ErrorLog) {
+        authorize_admin(request)?;
+        let errors = error_log.get(request_id).could_find("Request")?;
+        Ok(Response::text(errors.join("\n\n")))
+    }
+}
+
+route! {
+    (GET /admin/email/{timestamp: DateTime<FixedOffset>}/{url: HttpsStrippedUrl})
+    handle_admin_email(request: &Request, data: &Data, outbox: &Path) {
+        authorize_admin(request)?;
+        let UpdateQuery { seq, .. } = UpdateQuery::from_request(request)?;
+        let source = data.update_source(&UpdateRef { url: url.0, timestamp, seq }).could_find("Email")?;
+        let email = fs::read(outbox.join(source)).could_find("Email")?;
+        Ok(Response::from_data("text/plain; charset=utf-8", email))
+    }
+}
+
+query! {
+    struct IngressQuery {
+        /// the number of emails listed
+        limit: usize = 50,
+    }
+}
+
+route! {
+    (GET /admin/ingress)
+    handle_admin_ingress(request: &Request, journal: &Journal, display_tz: Tz) {
+        authorize_admin(request)?;
+        let format = Format::negotiate(request, &[Format::Html, Format::Json]);
+        let limit = IngressQuery::from_request(request)?.limit;
+        let emails = journal.recent().map_err(|_| Error::InternalServer)?;
+        let emails = emails.iter().take(limit);
+        let response = if format == Format::Json {
+            let json: Vec<_> = emails.map(|email| email.to_json()).collect();
+            Response::from_data(format.media_type(), serde_json::Value::from(json).to_string())
+        } else {
+            Response::html(format!(
+                include_str!("ingress.html"),
+                emails = emails.map(|email| {
+                    let changes = email.changes.iter().map(|change| {
+                        let list = |items: &[String]| items.iter().map(|item| format!("<li>{}</li>", escape_attribute(item))).collect::<String>();
+                        format!(
+                            r#"<li><a href="{url}">{url}</a> {updated_at} ({duration_ms}ms)<br />{change}{error}<p>Fetched</p><ul>{fetched}</ul><p>Events</p><ul>{events}</ul></li>"#,
+                            url = escape_attribute(&change.url),
+                            updated_at = escape_attribute(&change.updated_at),
+                            duration_ms = change.duration_ms,
+                            change = escape_attribute(&change.change),
+                            error = change.error.as_ref().map_or(String::new(), |error| format!(r#"<pre class="ingress-error">{}</pre>"#, escape_attribute(error))),
+                            fetched = list(&change.fetched),
+                            events = list(&change.events),
+                        )
+                    }).collect::<String>();
+                    format!(
+                        r#"<details class="ingress-email"><summary>{processed_at} {email} : {count} changes in {duration_ms}ms{failed}</summary><ol>{changes}</ol></details>"#,
+                        processed_at = time_element(&email.processed_at.into(), display_tz, DISPLAY_FORMAT),
+                        email = escape_attribute(&email.email),
+                        count = email.changes.len(),
+                        duration_ms = email.duration_ms,
+                        failed = if email.ok { "" } else { r#" <span class="ingress-failed">failed</span>"# },
+                        changes = changes,
+                    )
+                }).collect::<String>(),
+            ))
+        };
+        Ok(response.with_additional_header("Vary", "Accept"))
+    }
+}
+
+/// Checks that the request has the bearer token set in `ADMIN_TOKEN`, admin routes are disabled when it isn't set
+fn authorize_admin(request: &Request) -> Result<(), Error> {
+    let token = dotenv::var("ADMIN_TOKEN").map_err(|_| Error::NotFound("Route"))?;
+    match request.header("Authorization").and_then(|header| header.strip_prefix("Bearer ")) {
+        Some(provided) if !token.is_empty() && provided == token => Ok(()),
+        _ => Err(Error::Unauthorized),
+    }
+}
+
+/// Checks that a form posted to an admin route has the token set in `ADMIN_TOKEN` in its `token` field, for the admin
+/// actions offered on pages, as a form can't set the `Authorization` header
+fn authorize_admin_form(request: &Request) -> Result<(), Error> {
+    let token = dotenv::var("ADMIN_TOKEN").map_err(|_| Error::NotFound("Route"))?;
+    let fields = rouille::input::post::raw_urlencoded_post_input(request).map_err(|_| Error::InvalidRequest)?;
+    match fields.iter().find(|(name, _)| name == "token") {
+        Some((_, provided)) if !token.is_empty() && *provided == token => Ok(()),
+        _ => Err(Error::Unauthorized),
+    }
+}
+
 fn updates_page_response<'a>(
     updates: impl Iterator<Item = &'a Update>,
     request: &Request,
     data: &Data,
-) -> (String, String) {
-    let mut results = UpdateList::new(updates, request, data);
+    display_tz: Tz,
+    url_prefix: &Url,
+    tags: &[Tag],
+    change: Option<&Query>,
+    page_sizes: page::PageSizes,
+) -> Result<(String, String), Error> {
+    let mut results = UpdateList::new(updates, request, data, display_tz, tags, change, page_sizes)?;
     let etag = results.etag();
     let mut result_string = String::new(); // ugh
     results.into_writer(&mut result_string).unwrap();
     let selected_tag = request.get_param("tag");
+    let selected_section = request.get_param("section");
+    let selected_age = request.get_param("age").unwrap_or_default();
+    // links to the same list over each quick pick age range
+    let age_href = |age: &str| {
+        let existing_pairs = request.raw_query_string().to_owned();
+        let mut href = url::form_urlencoded::Serializer::new(request.url() + "?");
+        for (name, value) in url::form_urlencoded::parse(existing_pairs.as_bytes()) {
+            if name != "age" && name != "offset" {
+                href.append_pair(&name, &value);
+            }
+        }
+        if !age.is_empty() {
+            href.append_pair("age", age);
+        }
+        href.finish()
+    };
+    let age_picks = std::iter::once(("", "Any time"))
+        .chain(AGE_PICKS.iter().copied())
+        .map(|(age, label)| {
+            if age == selected_age {
+                format!(r#"<strong>{}</strong>"#, label)
+            } else {
+                format!(r#"<a href="{}">{}</a>"#, escape_attribute(&age_href(age)), label)
+            }
+        })
+        .collect::<Vec<_>>()
+        .join(" | ");
+    // the feed of the same filters, from its first page
+    let feed_url = {
+        let existing_pairs = request.raw_query_string().to_owned();
+        let mut href = url::form_urlencoded::Serializer::new("/feed?".to_owned());
+        for (name, value) in url::form_urlencoded::parse(existing_pairs.as_bytes()) {
+            if name != "offset" && name != "limit" {
+                href.append_pair(&name, &value);
+            }
+        }
+        href.finish()
+    };
+    // when filtering by url prefix, the paths under it with the most updates, to narrow it down further
+    let prefix_paths = if request.get_param("url_prefix").map_or(false, |prefix| !prefix.is_empty()) {
+        let mut paths: Vec<_> = data.child_paths(url_prefix).collect();
+        paths.sort_by_key(|(_, count)| std::cmp::Reverse(*count));
+        let stripped_prefix = url_prefix.as_str().trim_start_matches("https://").trim_end_matches('/');
+        format!(
+            r#"<p class="prefix-paths">{count} updates under {prefix}{paths}</p>"#,
+            count = data.count_updates(url_prefix),
+            prefix = stripped_prefix,
+            paths = paths
+                .iter()
+                .take(PREFIX_PATHS_LENGTH)
+                .enumerate()
+                .map(|(index, (segment, count))| format!(
+                    r#"{separator}<a href="/updates?url_prefix={href}">{segment}</a> ({count})"#,
+                    separator = if index == 0 { " : " } else { ", " },
+                    href = query_value(&format!("{}/{}/", stripped_prefix, segment)),
+                    segment = segment,
+                    count = count,
+                ))
+                .collect::<String>(),
+        )
+    } else {
+        String::new()
+    };
     let html = format!(
         include_str!("updates.html"),
         result_string,
-        url_prefix_filter = request.get_param("url_prefix").as_deref().unwrap_or("www.gov.uk/"),
-        change_filter = request.get_param("change").as_deref().unwrap_or(""),
+        feed_url = escape_attribute(&feed_url),
+        url_prefix_filter = escape_attribute(request.get_param("url_prefix").as_deref().unwrap_or("www.gov.uk/")),
+        change_filter = escape_attribute(request.get_param("change").as_deref().unwrap_or("")),
+        body_filter = if data.has_body_index() {
+            format!(
+                r#"<input name="body" placeholder="Page text" value="{}" />"#,
+                escape_attribute(request.get_param("body").as_deref().unwrap_or(""))
+            )
+        } else {
+            String::new()
+        },
+        filter_terms = escape_attribute(request.get_param("filter").as_deref().unwrap_or("")),
         tag_options = data
             .all_tags()
             .map(|tag| format!(
@@ -182,9 +1349,32 @@ fn updates_page_response<'a>(
                     .then(|| "selected")
                     .unwrap_or_default()
             ))
+            .collect::<String>(),
+        prefix_paths = prefix_paths,
+        age_picks = age_picks,
+        section_options = data
+            .all_sections()
+            .map(|(section, count)| format!(
+                r#"<option value="{value}" {selected}>{section} ({count})</option>"#,
+                value = escape_attribute(section),
+                section = section,
+                count = count,
+                selected = (selected_section.as_deref() == Some(section))
+                    .then(|| "selected")
+                    .unwrap_or_default()
+            ))
             .collect::<String>()
     );
-    (html, etag)
+    Ok((html, etag))
+}
+
+struct DiffFields {
+    diff_url: String,
+    from_ts: Option<DateTime<FixedOffset>>,
+    to_ts: Option<DateTime<FixedOffset>>,
+    body: String,
+    /// the diff took too long and is still being computed, the body links to the versions instead
+    pending: bool,
 }
 
 fn diff_fields(
@@ -192,61 +1382,284 @@ fn diff_fields(
     from: Option<&DocumentVersion>,
     to: Option<&DocumentVersion>,
     data: &Data,
-) -> (
-    String,
-    Option<DateTime<FixedOffset>>,
-    Option<DateTime<FixedOffset>>,
-    String,
-) {
-    let diff_base = format!(
+    differ: &Differ,
+) -> Result<DiffFields, Error> {
+    let diff_base = diff_base(url, from, to);
+
+    // the last version of a removed page is diffed against nothing, rather than showing it as if it were still live
+    let is_removal = match (from, to) {
+        (Some(from), None) => {
+            data.removed_at(url).is_some()
+                && data.iter_doc_versions(url).and_then(|mut versions| versions.next()).as_ref() == Some(from)
+        }
+        _ => false,
+    };
+
+    let mut pending = false;
+    let body = match (from, to) {
+        (Some(from), None) if is_removal => differ
+            .diff(
+                false,
+                &diff_base,
+                data.read_doc_with_base_url(from, &diff_base)?,
+                DocBody::default(),
+            )
+            .unwrap_or_default(),
+        (Some(from), Some(to)) => {
+            let precomputed = data.precomputed_diff(from, to).unwrap_or_else(|err| {
+                log_internal_error(format!("Reading the precomputed diff {} failed : {}", diff_base, err));
+                None
+            });
+            let diff = match precomputed.or_else(|| differ.cached(&diff_base)) {
+                Some(diff) => {
+                    access_log::mark_cache_hit();
+                    Some(diff)
+                }
+                None => differ.diff(
+                    true,
+                    &diff_base,
+                    data.read_doc_with_base_url(from, &diff_base)?,
+                    data.read_doc_with_base_url(to, &diff_base)?,
+                ),
+            };
+            match diff {
+                // the diff is cached as it is, the unchanged parts are collapsed each time it is shown
+                Some(diff) => collapse::collapse_unchanged(&diff),
+                None => {
+                    pending = true;
+                    format!(
+                        r#"<p>This diff is taking a while to work out, it will be ready if you reload in a moment. Until then, you can see <a href="{from}">the earlier version</a> or <a href="{to}">the later version</a>.</p>"#,
+                        from = diff_path(&Some(*from.timestamp()), &None, url),
+                        to = diff_path(&None, &Some(*to.timestamp()), url),
+                    )
+                }
+            }
+        }
+        (Some(from), None) => data.read_doc_with_base_url(from, &diff_base)?.into_inner(),
+        (None, Some(to)) => data.read_doc_with_base_url(to, &diff_base)?.into_inner(),
+        _ => "No versions recorded for this update".to_owned(),
+    };
+
+    Ok(DiffFields {
+        diff_url: diff_path(&from.map(|v| *v.timestamp()), &to.map(|v| *v.timestamp()), url),
+        from_ts: from.map(DocumentVersion::timestamp).copied(),
+        to_ts: to.map(DocumentVersion::timestamp).copied(),
+        body,
+        pending,
+    })
+}
+
+/// The base url of the links in a diff between two versions of a doc, it is also the diff's key in the caches
+fn diff_base(url: &Url, from: Option<&DocumentVersion>, to: Option<&DocumentVersion>) -> String {
+    format!(
         "/diff/{}/{}/{}",
         from.map_or(String::new(), |v| v.timestamp().to_rfc3339()),
         to.map_or(String::new(), |v| v.timestamp().to_rfc3339()),
         url.host().unwrap(),
+    )
+}
+
+/// An update as JSON with absolute links
+fn update_json(update: &Update, request: &Request, data: &Data, base_url: &BaseUrl) -> serde_json::Value {
+    format::update_json(
+        update,
+        data.get_tags(update.update_ref()).iter().map(|tag| tag.name()),
+        base_url.absolute(request, &update_href(update.update_ref())),
+        base_url.absolute(request, &short_link_path(&update.update_ref().short_id())),
+    )
+}
+
+/// In place of the diff when no version of a doc was captured, explains why and links to the live page. When admin
+/// routes are enabled, it has a form to fetch the doc now
+fn missing_versions_notice(url: &Url) -> String {
+    let mut notice = format!(
+        r#"<div class="missing-versions"><p>No version of this page was captured, so there is no change to show. The page may have been updated before it was tracked, or fetching it failed when this update arrived.</p><p>See <a href="{url}">the page as it is now on GOV.UK</a>, or the other updates to it in the update history.</p>"#,
+        url = escape_attribute(url.as_str()),
     );
+    if dotenv::var("ADMIN_TOKEN").map_or(false, |token| !token.is_empty()) {
+        let _ = write!(
+            notice,
+            r#"<form method="post" action="/admin/refetch?url={url}"><label>Admin token <input type="password" name="token" required></label> <button>Fetch now</button></form>"#,
+            url = query_value(url.as_str()),
+        );
+    }
+    notice.push_str("</div>");
+    notice
+}
 
-    (
-        format!("{}{}", diff_base, url.path()),
-        from.map(DocumentVersion::timestamp).copied(),
-        to.map(DocumentVersion::timestamp).copied(),
-        match (from, to) {
-            (Some(from), Some(to)) => {
-                let cache = env::var("DIFFCACHE").ok();
-                let cached_diff = if let Some(cache) = &cache.as_deref() {
-                    match cacache::read_sync(cache, &diff_base) {
-                        Ok(from_cache) => String::from_utf8(from_cache).ok(),
-                        Err(cacache::Error::EntryNotFound(_, _)) => None,
-                        Err(err) => {
-                            println!("Error reading from cache : {:?}", err);
-                            if let Err(err) = cacache::remove_sync(cache, &diff_base) {
-                                println!("Error removing from cache : {:?}", err);
-                            }
-                            None
-                        }
-                    }
-                } else {
-                    None
-                };
-                cached_diff.unwrap_or_else(|| {
-                    let diff = data
-                        .read_doc_to_string(from)
-                        .with_base_url(&diff_base)
-                        .diff(&data.read_doc_to_string(to).with_base_url(&diff_base));
-                    if let Some(cache) = &cache {
-                        if let Err(err) = cacache::write_sync(cache, &diff_base, &diff) {
-                            println!("Error writing to cache : {:?}", err);
-                        }
-                    }
-                    diff
-                })
-            }
-            (Some(from), None) => data.read_doc_to_string(from).with_base_url(&diff_base).into_inner(),
-            (None, Some(to)) => data.read_doc_to_string(to).with_base_url(&diff_base).into_inner(),
-            _ => "No versions recorded for this update".to_owned(),
-        },
+/// Notices for a page which has been withdrawn or removed from the site, linking to the diff of its removal
+fn status_notice(url: &Url, data: &Data, display_tz: Tz) -> String {
+    let mut notice = String::new();
+    if let Some(withdrawn_at) = data.withdrawn_at(url) {
+        notice.push_str(&format!(
+            r#"<p class="withdrawn-on">Withdrawn on gov.uk on {}</p>"#,
+            time_element(&withdrawn_at, display_tz, DISPLAY_FORMAT),
+        ));
+    }
+    let last_version = data.iter_doc_versions(url).and_then(|mut versions| versions.next());
+    if let (Some(removed_at), Some(last_version)) = (data.removed_at(url), last_version) {
+        notice.push_str(&format!(
+            r#"<p class="removed-on">Removed from gov.uk on {removed_at} : <a href="{diff}">see what was removed</a></p>"#,
+            removed_at = time_element(&removed_at, display_tz, DISPLAY_FORMAT),
+            diff = diff_path(&Some(*last_version.timestamp()), &None, url),
+        ));
+    }
+    notice
+}
+
+/// A page with a diff which is still being computed shouldn't be cached, and can be retried shortly
+fn pending_diff_response(response: Response) -> Response {
+    response
+        .with_status_code(503)
+        .with_additional_header("Retry-After", "5")
+        .with_no_cache()
+}
+
+/// The parts of the app configured from the environment, which can be reloaded while it is running
+struct Config {
+    /// timestamps are stored in UTC, this is the zone they are shown in
+    display_tz: Tz,
+    base_url: BaseUrl,
+    differ: Differ,
+    /// where the emails are moved to once they are processed, admins can view the email an update came from
+    outbox: PathBuf,
+    /// the status of an update page when no version of its doc was captured, either 404 or 200 with the notice
+    missing_doc_status: u16,
+    page_sizes: page::PageSizes,
+    /// the journal of the recently processed emails written by ingress
+    ingress_journal: Journal,
+}
+
+impl Config {
+    fn from_env() -> Self {
+        Self {
+            display_tz: dotenv::var("DISPLAY_TZ")
+                .ok()
+                .and_then(|tz| tz.parse().ok())
+                .unwrap_or(chrono_tz::Europe::London),
+            base_url: BaseUrl::from_env(),
+            differ: Differ::from_env(),
+            outbox: dotenv::var("OUTBOX")
+                .map(PathBuf::from)
+                .unwrap_or_else(|_| Path::new(&dotenv::var("NEW_REPO").unwrap_or_default()).join("outbox")),
+            missing_doc_status: match dotenv::var("MISSING_DOC_STATUS").as_deref() {
+                Ok("200") => 200,
+                _ => 404,
+            },
+            page_sizes: page::PageSizes::from_env(),
+            ingress_journal: Journal::from_env(Path::new(&dotenv::var("NEW_REPO").unwrap_or_default())),
+        }
+    }
+}
+
+/// The public base url of the site, used for generating absolute links. Configured with `PUBLIC_URL`, otherwise taken from the request and the proxy headers on it
+struct BaseUrl(Option<String>);
+
+impl BaseUrl {
+    fn from_env() -> Self {
+        Self(
+            dotenv::var("PUBLIC_URL")
+                .ok()
+                .map(|url| url.trim_end_matches('/').to_owned())
+                .filter(|url| !url.is_empty()),
+        )
+    }
+
+    /// The absolute url for a path on this site
+    fn absolute(&self, request: &Request, path: &str) -> String {
+        if let Some(base) = &self.0 {
+            return format!("{}{}", base, path);
+        }
+        // behind a proxy the first value of each header is the one the client used
+        let first = |header: &str| request.header(header).and_then(|value| value.split(',').next()).map(str::trim);
+        let proto = first("X-Forwarded-Proto").unwrap_or(if request.is_secure() { "https" } else { "http" });
+        let host = first("X-Forwarded-Host")
+            .or_else(|| request.header("Host"))
+            .unwrap_or("localhost");
+        format!("{}://{}{}", proto, host, path)
+    }
+}
+
+/// Doc urls other than the root are never shown with a trailing slash, redirect if one was requested
+fn redirect_trailing_slash(request: &Request, url: &Url) -> Option<Response> {
+    let path = request.raw_url().split('?').next().unwrap_or_default();
+    let trimmed = path.trim_end_matches('/');
+    (url.path() != "/" && trimmed.len() < path.len()).then(|| {
+        let query = request.raw_query_string();
+        if query.is_empty() {
+            Response::redirect_301(trimmed.to_owned())
+        } else {
+            Response::redirect_301(format!("{}?{}", trimmed, query))
+        }
+    })
+}
+
+/// Format for timestamps shown on their own, includes the zone so that it is clear which one is being displayed
+const DISPLAY_FORMAT: &str = "%F %H:%M %Z";
+
+/// The display timezone requested with the `tz` param, which overrides the configured one
+fn requested_tz(request: &Request) -> Result<Option<Tz>, Error> {
+    query_param(request, "tz")
+}
+
+/// A query parameter parsed as `T`, `None` if it is missing or empty
+fn query_param<T: FromStr>(request: &Request, name: &'static str) -> Result<Option<T>, Error> {
+    request
+        .get_param(name)
+        .filter(|value| !value.is_empty())
+        .map(|value| value.parse().map_err(|_| Error::InvalidParam(name)))
+        .transpose()
+}
+
+/// A `<time>` element for a timestamp, displayed in `display_tz` using the strftime `format`
+fn time_element(timestamp: &DateTime<FixedOffset>, display_tz: Tz, format: &str) -> String {
+    format!(
+        r#"<time datetime="{}">{}</time>"#,
+        timestamp.to_rfc3339(),
+        timestamp.with_timezone(&display_tz).format(format)
     )
 }
 
+/// Breadcrumbs of the path segments of a url, each linking to the updates under its path, or to the section filter for the
+/// section of the site, and the parent pages which have updates also linking to their history feeds
+fn breadcrumbs(url: &Url, data: &Data) -> String {
+    let host = url.host_str().unwrap_or_default();
+    let section = data::section(url);
+    let segments: Vec<&str> = url.path_segments().into_iter().flatten().filter(|s| !s.is_empty()).collect();
+    let mut crumbs = format!(r#"<li><a href="/updates">{}</a></li>"#, host);
+    for (i, segment) in segments.iter().enumerate() {
+        let path = format!("/{}", segments[..=i].join("/"));
+        let href = if path == section {
+            format!("/updates?section={}", query_value(&path))
+        } else {
+            format!("/updates?url_prefix={}", query_value(&format!("{}{}", host, path)))
+        };
+        let parent: Option<Url> = format!("{}://{}{}", url.scheme(), host, path).parse().ok();
+        let history = match parent {
+            Some(parent) if i + 1 < segments.len() && data.get_updates(&parent).is_some() => {
+                format!(r#" <a class="breadcrumb-history" href="{}">history</a>"#, history_path(&parent))
+            }
+            _ => String::new(),
+        };
+        write!(crumbs, r#"<li><a href="{}">{}</a>{}</li>"#, href, segment, history).unwrap();
+    }
+    format!(r#"<nav class="breadcrumbs"><ol>{}</ol></nav>"#, crumbs)
+}
+
+/// Escape a value from the request to be echoed back in a double quoted html attribute
+fn escape_attribute(value: &str) -> String {
+    value
+        .replace('&', "&amp;")
+        .replace('"', "&quot;")
+        .replace('<', "&lt;")
+        .replace('>', "&gt;")
+}
+
+/// Encode a value for a link's query string
+fn query_value(value: &str) -> String {
+    url::form_urlencoded::byte_serialize(value.as_bytes()).collect()
+}
+
 /// Parse helper for deserialising things where an empty string means `None`
 struct MaybeEmpty<T>(Option<T>);
 
@@ -291,21 +1704,139 @@ impl Deref for HttpsStrippedUrl {
     }
 }
 
+/// Parse helper for the url of a doc's feed, which is its `HttpsStrippedUrl` with `.atom` appended
+struct AtomFeedUrl(Url);
+
+impl FromStr for AtomFeedUrl {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url: HttpsStrippedUrl = s.strip_suffix(".atom").ok_or(())?.parse().map_err(|_| ())?;
+        Ok(AtomFeedUrl(url.0))
+    }
+}
+
+impl Deref for AtomFeedUrl {
+    type Target = Url;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// Parse helper for the url of a doc's versions feed, which is its `HttpsStrippedUrl` with `/versions.atom` appended
+struct VersionsFeedUrl(Url);
+
+impl FromStr for VersionsFeedUrl {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let url: HttpsStrippedUrl = s.strip_suffix("/versions.atom").ok_or(())?.parse().map_err(|_| ())?;
+        Ok(VersionsFeedUrl(url.0))
+    }
+}
+
+impl Deref for VersionsFeedUrl {
+    type Target = Url;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+/// A value which can be a captured segment of a route's path, `T` is the type the route parses the segment as
+trait ToPathSegment<T: ?Sized> {
+    fn to_path_segment(&self) -> Cow<'_, str>;
+}
+
+impl ToPathSegment<str> for str {
+    fn to_path_segment(&self) -> Cow<'_, str> {
+        self.into()
+    }
+}
+
+impl ToPathSegment<str> for String {
+    fn to_path_segment(&self) -> Cow<'_, str> {
+        self.into()
+    }
+}
+
+impl ToPathSegment<DateTime<FixedOffset>> for DateTime<FixedOffset> {
+    fn to_path_segment(&self) -> Cow<'_, str> {
+        self.to_rfc3339().into()
+    }
+}
+
+impl ToPathSegment<NaiveDate> for NaiveDate {
+    fn to_path_segment(&self) -> Cow<'_, str> {
+        self.to_string().into()
+    }
+}
+
+impl ToPathSegment<HttpsStrippedUrl> for Url {
+    fn to_path_segment(&self) -> Cow<'_, str> {
+        format!("{}{}", self.host_str().unwrap_or_default(), self.path()).into()
+    }
+}
+
+impl ToPathSegment<HttpsStrippedUrl> for HttpsStrippedUrl {
+    fn to_path_segment(&self) -> Cow<'_, str> {
+        ToPathSegment::<HttpsStrippedUrl>::to_path_segment(&self.0)
+    }
+}
+
+impl ToPathSegment<AtomFeedUrl> for Url {
+    fn to_path_segment(&self) -> Cow<'_, str> {
+        format!("{}.atom", ToPathSegment::<HttpsStrippedUrl>::to_path_segment(self)).into()
+    }
+}
+
+impl ToPathSegment<VersionsFeedUrl> for Url {
+    fn to_path_segment(&self) -> Cow<'_, str> {
+        format!("{}/versions.atom", ToPathSegment::<HttpsStrippedUrl>::to_path_segment(self)).into()
+    }
+}
+
+impl<T: ToPathSegment<T>> ToPathSegment<MaybeEmpty<T>> for Option<T> {
+    fn to_path_segment(&self) -> Cow<'_, str> {
+        self.as_ref().map_or("".into(), T::to_path_segment)
+    }
+}
+
 /// A paginated list of updates which can be displayed as html
 struct UpdateList<'a, 'd, Us: Iterator<Item = &'a Update>> {
     data: &'d Data,
+    display_tz: Tz,
+    /// the tags filtered to, which are highlighted in the tags of each update
+    tags: &'d [Tag],
+    /// a change description search to highlight the results of
+    change: Option<&'d Query>,
+    /// the filters of the list, carried by the links to the updates
+    filter_terms: Option<String>,
     page: page::Page<std::iter::Peekable<Us>>,
     etag: String,
 }
 
 impl<'a, 'd, Us: Iterator<Item = &'a Update>> UpdateList<'a, 'd, Us> {
-    fn new(items: impl IntoIterator<IntoIter = Us>, request: &Request, data: &'d Data) -> Self {
+    fn new(
+        items: impl IntoIterator<IntoIter = Us>,
+        request: &Request,
+        data: &'d Data,
+        display_tz: Tz,
+        tags: &'d [Tag],
+        change: Option<&'d Query>,
+        page_sizes: page::PageSizes,
+    ) -> Result<Self, Error> {
         let mut items = items.into_iter().peekable();
-        Self {
+        Ok(Self {
             data,
+            display_tz,
+            tags,
+            change,
+            filter_terms: carried_filter_terms(request),
             etag: items.peek().map_or(String::new(), |u| format!("{}", u.timestamp())),
-            page: page::Page::new(request, items),
-        }
+            page: page::Page::new(request, items, page_sizes)?,
+        })
     }
 
     fn into_writer(mut self, f: &mut String) -> fmt::Result {
@@ -321,34 +1852,51 @@ impl<'a, 'd, Us: Iterator<Item = &'a Update>> UpdateList<'a, 'd, Us> {
 
         for update in &mut self.page {
             let update = update.borrow();
-            let update_date = update.timestamp().date();
+            let update_date = update.timestamp().with_timezone(&self.display_tz).date();
             if Some(update_date) != current_date {
                 current_date = Some(update_date);
-                writeln!(f, r#"<h3 class="date-seperator">{}</h3>"#, update_date.naive_local()).unwrap();
+                writeln!(
+                    f,
+                    r#"<h3 class="date-seperator"><time datetime="{date}">{date}</time></h3>"#,
+                    date = update_date.naive_local()
+                )?;
+            }
+            let href = escape_attribute(&filtered_update_href(update.update_ref(), self.filter_terms.as_deref()));
+            match self.data.removed_at(update.url()) {
+                Some(removed_at) => writeln!(
+                    f,
+                    r#"<a href="{}" class="update-url"><del>{}</del> <span class="removed-on">removed {}</span></a>"#,
+                    &href,
+                    update.url().path(),
+                    time_element(&removed_at, self.display_tz, "%e %b %Y"),
+                )?,
+                None => writeln!(
+                    f,
+                    r#"<a href="{}" class="update-url">{}{}</a>"#,
+                    &href,
+                    update.url().path(),
+                    if self.data.withdrawn_at(update.url()).is_some() {
+                        r#" <span class="withdrawn-on">withdrawn</span>"#
+                    } else {
+                        ""
+                    },
+                )?,
             }
-            let mut update_path = update.timestamp().to_rfc3339();
-            write!(
-                &mut update_path,
-                "/{}{}",
-                update.url().host_str().unwrap_or_default(),
-                update.url().path(),
-            )?;
-            writeln!(
-                f,
-                r#"<a href="/update/{}" class="update-url">{}</a>"#,
-                &update_path,
-                update.url().path(),
-            )?;
             writeln!(
                 f,
-                r#"<a href="/update/{}" class="update-description">{} {}</a>"#,
-                &update_path,
-                update.timestamp().time().format_with_items(StrftimeItems::new("%H:%M")),
-                update.change(),
+                r#"<a href="{}" class="update-description">{} {}</a>"#,
+                &href,
+                time_element(update.timestamp(), self.display_tz, "%H:%M %Z"),
+                self.change
+                    .map_or_else(|| update.change().into(), |change| Cow::from(change.highlight(update.change()))),
             )?;
-            writeln!(f, r#"<a href="/update/{}" class="update-tags">"#, &update_path)?;
+            writeln!(f, r#"<a href="{}" class="update-tags">"#, &href)?;
             for tag in self.data.get_tags(update.update_ref()) {
-                writeln!(f, "<div>{}</div>", tag.name())?;
+                if self.tags.contains(&**tag) {
+                    writeln!(f, r#"<div><strong class="active-tag">{}</strong></div>"#, tag.name())?;
+                } else {
+                    writeln!(f, "<div>{}</div>", tag.name())?;
+                }
             }
             writeln!(f, r#"</a>"#)?;
         }
@@ -376,6 +1924,12 @@ struct FastCache(Arc<RwLock<FastCacheInternal>>);
 type FastCacheInternal = Option<(Instant, Arc<(String, String)>)>;
 
 impl FastCache {
+    fn clear(&self) {
+        if let Ok(mut guard) = self.0.write() {
+            *guard = None;
+        }
+    }
+
     fn try_cache(&self, oldest_allowed: Instant) -> Result<(String, String), RwLockWriteGuard<FastCacheInternal>> {
         if let Ok(guard) = self.0.read() {
             if let Some((rendered_at, cached)) = &*guard {
@@ -2,8 +2,74 @@ use std::fmt::{self, Write};
 
 use rouille::Request;
 
+use super::error::Error;
+
+query! {
+    struct PageQuery {
+        offset: usize = 0,
+        limit: usize,
+    }
+}
+
+/// The page sizes offered to choose from, along with the configured default and max
+const PAGE_SIZE_CHOICES: &[usize] = &[50, 100, 200, 500, 1000];
+
+/// How many items are on a page, `PAGE_SIZE` by default (200) and at most `MAX_PAGE_SIZE` (1000). A requested limit is
+/// clamped to between 1 and the max
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct PageSizes {
+    pub default: usize,
+    pub max: usize,
+}
+
+impl Default for PageSizes {
+    fn default() -> Self {
+        Self {
+            default: 200,
+            max: 1000,
+        }
+    }
+}
+
+impl PageSizes {
+    pub fn from_env() -> Self {
+        let size = |name: &str| {
+            dotenv::var(name)
+                .ok()
+                .and_then(|size| size.parse::<usize>().ok())
+                .filter(|size| *size > 0)
+        };
+        let defaults = Self::default();
+        let default = size("PAGE_SIZE").unwrap_or(defaults.default);
+        Self {
+            default,
+            max: size("MAX_PAGE_SIZE").unwrap_or(defaults.max).max(default),
+        }
+    }
+
+    fn clamp(&self, limit: Option<usize>) -> usize {
+        limit.unwrap_or(self.default).clamp(1, self.max)
+    }
+
+    /// The sizes to offer, in order
+    fn choices(&self) -> Vec<usize> {
+        let mut choices: Vec<usize> = PAGE_SIZE_CHOICES
+            .iter()
+            .copied()
+            .filter(|size| *size < self.max)
+            .chain([self.default, self.max])
+            .collect();
+        choices.sort_unstable();
+        choices.dedup();
+        choices
+    }
+}
+
 pub struct Page<I> {
     href: String,
+    /// the link to the first page of another size is this followed by the limit
+    size_href: String,
+    sizes: PageSizes,
     offset: usize,
     limit: usize,
     emitted: usize,
@@ -11,34 +77,35 @@ pub struct Page<I> {
 }
 
 impl<T, I: Iterator<Item = T>> Page<I> {
-    pub fn new(request: &Request, items: I) -> Self {
-        let offset = request
-            .get_param("offset")
-            .and_then(|offset| offset.parse().ok())
-            .unwrap_or(0);
-        let limit = request
-            .get_param("limit")
-            .and_then(|limit| limit.parse().ok())
-            .unwrap_or(200);
+    pub fn new(request: &Request, items: I, sizes: PageSizes) -> Result<Self, Error> {
+        let PageQuery { offset, limit } = PageQuery::from_request(request)?;
+        let limit = sizes.clamp(limit);
 
         let existing_pairs = request.raw_query_string().to_owned();
         let mut href = form_urlencoded::Serializer::new(request.url() + "?");
+        let mut size_href = form_urlencoded::Serializer::new(request.url() + "?");
         for (name, value) in form_urlencoded::parse(existing_pairs.as_bytes()) {
             if name != "offset" {
                 href.append_pair(&name, &value);
+                if name != "limit" {
+                    size_href.append_pair(&name, &value);
+                }
             }
         }
         let href = href.finish();
+        let size_href = size_href.finish();
 
         let items = items.skip(offset);
 
-        Self {
+        Ok(Self {
             href,
+            size_href,
+            sizes,
             offset,
             limit,
             items,
             emitted: 0,
-        }
+        })
     }
 
     pub fn into_writer(self, f: &mut String) -> fmt::Result {
@@ -78,6 +145,20 @@ impl<T, I: Iterator<Item = T>> Page<I> {
                 next_offset = next_offset,
             )?;
         }
+        write!(f, r#"<span class="page-sizes">Per page :"#)?;
+        for size in self.sizes.choices() {
+            if size == limit {
+                write!(f, " <strong>{}</strong>", size)?;
+            } else {
+                write!(
+                    f,
+                    r#" <a href="{href}&limit={size}">{size}</a>"#,
+                    href = self.size_href,
+                    size = size,
+                )?;
+            }
+        }
+        writeln!(f, "</span>")?;
         writeln!(f, "</div>")?;
         Ok(())
     }
@@ -97,3 +178,25 @@ impl<T, I: Iterator<Item = T>> Iterator for Page<I> {
         r
     }
 }
+
+#[test]
+fn test_page_sizes() {
+    let sizes = PageSizes { default: 200, max: 500 };
+    let page = |url: &str| Page::new(&Request::fake_http("GET", url, vec![], vec![]), 0..1000, sizes);
+    assert_eq!(page("/updates").unwrap().count(), 200);
+    assert_eq!(page("/updates?limit=10").unwrap().count(), 10);
+    assert_eq!(page("/updates?limit=10000000").unwrap().count(), 500);
+    assert_eq!(page("/updates?limit=0").unwrap().count(), 1);
+    assert!(page("/updates?limit=lots").is_err());
+    assert_eq!(sizes.choices(), [50, 100, 200, 500]);
+
+    let mut html = String::new();
+    page("/updates?tag=brexit&limit=50&offset=100")
+        .unwrap()
+        .into_writer(&mut html)
+        .unwrap();
+    assert!(html.contains(r#"<a href="/updates?tag=brexit&limit=50&offset=50">prev</a>"#));
+    assert!(html.contains(
+        r#"Per page : <strong>50</strong> <a href="/updates?tag=brexit&limit=100">100</a> <a href="/updates?tag=brexit&limit=200">200</a>"#
+    ));
+}
@@ -0,0 +1,147 @@
+//! Precomputing the diffs between neighbouring versions of docs into the repo, so that the update pages of large docs
+//! don't wait for them to be diffed. Each pass diffs the docs whose latest version has changed since the pass before,
+//! which on start is every doc, newest first, skipping the diffs which are already stored
+
+use std::{
+    collections::HashMap,
+    io,
+    path::Path,
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use chrono::{DateTime, FixedOffset};
+use update_repo::{
+    doc::{DiffRepo, DocRepo, DocumentVersion},
+    Url,
+};
+
+use super::{diff_base, DIFF_VERSION};
+use crate::data::{DocBody, SharedData};
+
+pub struct Precomputer {
+    data: Arc<SharedData>,
+    doc_repo: DocRepo,
+    diff_repo: DiffRepo,
+    /// the latest version of each doc when its diffs were last precomputed
+    precomputed: HashMap<Url, DateTime<FixedOffset>>,
+    interval: Duration,
+}
+
+impl Precomputer {
+    /// Precomputes the diffs of the repo at `repo`, passing over the docs every `PRECOMPUTE_DIFFS_SECS` (default 60)
+    pub fn from_env(repo: &Path, data: Arc<SharedData>) -> io::Result<Self> {
+        let interval_secs = dotenv::var("PRECOMPUTE_DIFFS_SECS")
+            .ok()
+            .and_then(|secs| secs.parse().ok())
+            .unwrap_or(60);
+        Ok(Self {
+            data,
+            doc_repo: DocRepo::new(repo.join("url"))?,
+            diff_repo: DiffRepo::new(repo.join("url"), DIFF_VERSION)?,
+            precomputed: HashMap::new(),
+            interval: Duration::from_secs(interval_secs),
+        })
+    }
+
+    pub fn run(mut self) {
+        loop {
+            let started = Instant::now();
+            let count = self.pass();
+            if count > 0 {
+                println!("Precomputed {} diffs in {:?}", count, started.elapsed());
+            }
+            thread::sleep(self.interval);
+        }
+    }
+
+    /// Precompute the diffs of the docs with new versions, returns the number of diffs written
+    fn pass(&mut self) -> usize {
+        // the snapshot is only held while the docs are listed, as a write waits for it to be dropped
+        let mut changed: Vec<(Url, DateTime<FixedOffset>)> = self
+            .data
+            .snapshot()
+            .latest_versions()
+            .filter(|(url, latest)| self.precomputed.get(*url) != Some(*latest))
+            .map(|(url, latest)| (url.clone(), *latest))
+            .collect();
+        changed.sort_by(|(_, a), (_, b)| b.cmp(a));
+        let mut count = 0;
+        for (url, latest) in changed {
+            match self.precompute(&url) {
+                Ok(precomputed) => count += precomputed,
+                // such as a doc which isn't text, it isn't tried again until it has a new version
+                Err(err) => println!("Precomputing the diffs of {} failed : {}", url, err),
+            }
+            self.precomputed.insert(url, latest);
+        }
+        count
+    }
+
+    /// Diff each pair of neighbouring versions of a doc which isn't stored yet, the stored diffs which are no longer
+    /// between neighbours are removed. Returns the number of diffs written
+    fn precompute(&self, url: &Url) -> io::Result<usize> {
+        // newest first
+        let versions: Vec<DocumentVersion> = self.doc_repo.list_versions(url.clone())?.collect::<io::Result<_>>()?;
+        self.diff_repo.prune(url, &versions)?;
+        let mut count = 0;
+        for pair in versions.windows(2) {
+            let (from, to) = (&pair[1], &pair[0]);
+            if self.diff_repo.contains(from, to) {
+                continue;
+            }
+            // diffed as the update pages diff them, with the links made absolute in the same way
+            let base = diff_base(url, Some(from), Some(to));
+            let read = |doc: &DocumentVersion| {
+                DocBody::read_with_base_url(io::BufReader::new(self.doc_repo.open(doc)?), &base)
+            };
+            let diff = read(from)?.diff(&read(to)?);
+            self.diff_repo.write(from, to, &diff)?;
+            count += 1;
+        }
+        Ok(count)
+    }
+}
+
+#[test]
+fn test_precompute() {
+    use update_repo::{tag::TagRepo, update::UpdateRepo};
+
+    use crate::data::Data;
+
+    let base = Path::new("tmp/test_precompute");
+    let _ = std::fs::remove_dir_all(base);
+    TagRepo::new(base.join("tag")).unwrap();
+    let url: Url = "https://www.gov.uk/guidance/a".parse().unwrap();
+    let doc_repo = DocRepo::new(base.join("url")).unwrap();
+    let mut write_avoidance_buffer = Vec::new();
+    for (timestamp, body) in [
+        ("2022-02-17T09:00:00+00:00", "<p>You need a passport.</p>"),
+        ("2022-02-17T10:00:00+00:00", "<p>You need a passport and a visa.</p>"),
+    ] {
+        let mut write = doc_repo
+            .create(url.clone(), timestamp.parse().unwrap(), &mut write_avoidance_buffer)
+            .unwrap();
+        io::Write::write_all(&mut write, body.as_bytes()).unwrap();
+        write.done().unwrap();
+    }
+    UpdateRepo::new(base.join("url"))
+        .unwrap()
+        .create(url.clone(), "2022-02-17T10:00:00+00:00".parse().unwrap(), "Visas")
+        .unwrap();
+    let data = Arc::new(SharedData::new(Data::load(base)));
+
+    let mut precomputer = Precomputer::from_env(base, data.clone()).unwrap();
+    assert_eq!(precomputer.pass(), 1);
+    // the doc has no new version since
+    assert_eq!(precomputer.pass(), 0);
+    let versions: Vec<DocumentVersion> = doc_repo.list_versions(url).unwrap().map(Result::unwrap).collect();
+    assert_eq!(
+        data.snapshot().precomputed_diff(&versions[1], &versions[0]).unwrap(),
+        Some(update_diff::diff(
+            "<p>You need a passport.</p>",
+            "<p>You need a passport and a visa.</p>"
+        ))
+    );
+}
@@ -0,0 +1,189 @@
+//! Short links to a diff between two pinned doc versions, and the cards which chat tools and social media show when a
+//! link to a page is shared
+
+use std::{borrow::Cow, fmt, str::FromStr};
+
+use chrono::{DateTime, FixedOffset};
+
+use super::{escape_attribute, ToPathSegment};
+
+/// How the diff page renders a diff, all of them are the same diff with insertions or deletions hidden
+#[derive(Clone, Copy, Debug, PartialEq, Eq)]
+pub enum DiffMode {
+    /// insertions and deletions
+    Inline,
+    /// only the later version, with insertions highlighted
+    Added,
+    /// only the earlier version, with deletions highlighted
+    Removed,
+}
+
+impl DiffMode {
+    pub const ALL: [DiffMode; 3] = [DiffMode::Inline, DiffMode::Added, DiffMode::Removed];
+
+    pub fn name(self) -> &'static str {
+        match self {
+            DiffMode::Inline => "inline",
+            DiffMode::Added => "added",
+            DiffMode::Removed => "removed",
+        }
+    }
+
+    pub fn label(self) -> &'static str {
+        match self {
+            DiffMode::Inline => "Inline",
+            DiffMode::Added => "Additions",
+            DiffMode::Removed => "Removals",
+        }
+    }
+
+    /// The query string selecting this mode on the diff page, the default mode has none
+    pub fn query(self) -> Cow<'static, str> {
+        match self {
+            DiffMode::Inline => "".into(),
+            mode => format!("?mode={}", mode.name()).into(),
+        }
+    }
+}
+
+impl FromStr for DiffMode {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        DiffMode::ALL.iter().copied().find(|mode| mode.name() == s).ok_or(())
+    }
+}
+
+/// The doc versions and mode of a shared diff, as one path segment. Versions are pinned by their timestamp in whole
+/// seconds since the epoch, in base 36 to keep links short, and are empty when the diff is against nothing
+#[derive(Debug, PartialEq, Eq)]
+pub struct DiffPin {
+    pub from: Option<i64>,
+    pub to: Option<i64>,
+    pub mode: DiffMode,
+}
+
+impl DiffPin {
+    pub fn new(from: Option<&DateTime<FixedOffset>>, to: Option<&DateTime<FixedOffset>>, mode: DiffMode) -> Self {
+        Self {
+            from: from.map(DateTime::timestamp),
+            to: to.map(DateTime::timestamp),
+            mode,
+        }
+    }
+}
+
+impl FromStr for DiffPin {
+    type Err = ();
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut parts = s.split('.');
+        let mut timestamp = || match parts.next() {
+            Some("") => Ok(None),
+            Some(timestamp) => i64::from_str_radix(timestamp, 36).map(Some).map_err(|_| ()),
+            None => Err(()),
+        };
+        let (from, to) = (timestamp()?, timestamp()?);
+        let mode = parts.next().ok_or(())?.parse()?;
+        if parts.next().is_some() {
+            return Err(());
+        }
+        Ok(Self { from, to, mode })
+    }
+}
+
+impl fmt::Display for DiffPin {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        for timestamp in [self.from, self.to] {
+            if let Some(timestamp) = timestamp {
+                f.write_str(&to_base36(timestamp))?;
+            }
+            f.write_str(".")?;
+        }
+        f.write_str(self.mode.name())
+    }
+}
+
+impl ToPathSegment<DiffPin> for DiffPin {
+    fn to_path_segment(&self) -> Cow<'_, str> {
+        self.to_string().into()
+    }
+}
+
+fn to_base36(mut n: i64) -> String {
+    const DIGITS: &[u8; 36] = b"0123456789abcdefghijklmnopqrstuvwxyz";
+    let mut digits = vec![];
+    loop {
+        digits.push(DIGITS[(n % 36) as usize]);
+        n /= 36;
+        if n == 0 {
+            break;
+        }
+    }
+    digits.reverse();
+    String::from_utf8(digits).unwrap()
+}
+
+/// The number of insertions and deletions in a rendered diff
+pub fn change_counts(diff: &str) -> (usize, usize) {
+    let count = |kind: &str| {
+        [format!("<{}>", kind), format!("<{} ", kind), format!("data-diff-node=\"{}\"", kind)]
+            .iter()
+            .map(|pattern| diff.matches(pattern.as_str()).count())
+            .sum()
+    };
+    (count("ins"), count("del"))
+}
+
+/// OpenGraph and Twitter meta tags describing a page, so that links to it unfurl as a card. A page with a published
+/// time is described as an article
+pub fn card(title: &str, description: &str, url: &str, published: Option<&DateTime<FixedOffset>>) -> String {
+    let mut card = format!(
+        r#"<meta property="og:type" content="{kind}">
+    <meta property="og:site_name" content="GOV.UK change explorer">
+    <meta property="og:title" content="{title}">
+    <meta property="og:description" content="{description}">
+    <meta property="og:url" content="{url}">
+    <meta name="twitter:card" content="summary">
+    <meta name="twitter:title" content="{title}">
+    <meta name="twitter:description" content="{description}">"#,
+        kind = if published.is_some() { "article" } else { "website" },
+        title = escape_attribute(title),
+        description = escape_attribute(description),
+        url = escape_attribute(url),
+    );
+    if let Some(published) = published {
+        card.push_str(&format!(
+            r#"
+    <meta property="article:published_time" content="{}">"#,
+            published.to_rfc3339()
+        ));
+    }
+    card
+}
+
+#[test]
+fn test_diff_pins() {
+    let from: DateTime<FixedOffset> = "2022-02-17T09:00:00+00:00".parse().unwrap();
+    let to: DateTime<FixedOffset> = "2022-02-17T11:00:00.123+01:00".parse().unwrap();
+    let pin = DiffPin::new(Some(&from), Some(&to), DiffMode::Added);
+    assert_eq!(pin.to_string(), "r7fyc0.r7g140.added");
+    assert_eq!("r7fyc0.r7g140.added".parse(), Ok(pin));
+
+    let pin = DiffPin::new(None, Some(&to), DiffMode::Inline);
+    assert_eq!(pin.to_string(), ".r7g140.inline");
+    assert_eq!(".r7g140.inline".parse(), Ok(pin));
+
+    assert_eq!("r7fyc0.r7g140".parse::<DiffPin>(), Err(()));
+    assert_eq!("r7fyc0.r7g140.sideways".parse::<DiffPin>(), Err(()));
+    assert_eq!("r7fyc0.r7g!40.inline".parse::<DiffPin>(), Err(()));
+    assert_eq!("...inline".parse::<DiffPin>(), Err(()));
+}
+
+#[test]
+fn test_change_counts() {
+    assert_eq!(
+        change_counts(r#"<p>You need a passport <ins>and a visa </ins>to travel<del>.</del></p><p data-diff-node="ins">New</p><details>"#),
+        (2, 1)
+    );
+}
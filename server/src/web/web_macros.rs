@@ -42,6 +42,72 @@ macro_rules! path {
   };
 }
 
+/// The template of a route's path, with the captures named in braces, eg. `route_template!(/foo/{bar: usize})` is
+/// `"/foo/{bar}"`
+macro_rules! route_template {
+  (/{$seg:ident} $(/$tail:tt)*) => {
+      concat!("/{", stringify!($seg), "}", route_template!($(/$tail)*))
+  };
+  (/{$seg:ident: $sty:ty} $(/$tail:tt)*) => {
+      concat!("/{", stringify!($seg), "}", route_template!($(/$tail)*))
+  };
+  (/$seg:ident $(/$tail:tt)*) => {
+      concat!("/", stringify!($seg), route_template!($(/$tail)*))
+  };
+  (/) => {
+      "/"
+  };
+  () => {
+      ""
+  };
+}
+
+/// A function building the path of a route from the values of its captures, so that links can't drift from the routes
+/// they link to. `route_path!(foo_path /foo/{bar: usize}/{baz})` makes
+/// `fn foo_path(bar: &impl ToPathSegment<usize>, baz: &impl ToPathSegment<str>) -> String`
+macro_rules! route_path {
+  (@munch $name:ident $path:tt [$($params:tt)*] /{$seg:ident} $(/$tail:tt)*) => {
+      route_path!(@munch $name $path [$($params)* $seg: str;] $(/$tail)*);
+  };
+  (@munch $name:ident $path:tt [$($params:tt)*] /{$seg:ident: $sty:ty} $(/$tail:tt)*) => {
+      route_path!(@munch $name $path [$($params)* $seg: $sty;] $(/$tail)*);
+  };
+  (@munch $name:ident $path:tt [$($params:tt)*] /$seg:ident $(/$tail:tt)*) => {
+      route_path!(@munch $name $path [$($params)*] $(/$tail)*);
+  };
+  (@munch $name:ident $path:tt [$($params:tt)*] /) => {
+      route_path!(@munch $name $path [$($params)*]);
+  };
+  (@munch $name:ident ($($path:tt)*) [$($param:ident: $pty:ty;)*]) => {
+      fn $name($($param: &(impl $crate::web::ToPathSegment<$pty> + ?Sized)),*) -> String {
+          let mut path = String::new();
+          route_path!(@push path $($path)*);
+          path
+      }
+  };
+  (@push $p:ident /{$seg:ident} $(/$tail:tt)*) => {
+      $p.push('/');
+      $p.push_str(&$crate::web::ToPathSegment::<str>::to_path_segment($seg));
+      route_path!(@push $p $(/$tail)*);
+  };
+  (@push $p:ident /{$seg:ident: $sty:ty} $(/$tail:tt)*) => {
+      $p.push('/');
+      $p.push_str(&$crate::web::ToPathSegment::<$sty>::to_path_segment($seg));
+      route_path!(@push $p $(/$tail)*);
+  };
+  (@push $p:ident /$seg:ident $(/$tail:tt)*) => {
+      $p.push_str(concat!("/", stringify!($seg)));
+      route_path!(@push $p $(/$tail)*);
+  };
+  (@push $p:ident /) => {
+      $p.push('/');
+  };
+  (@push $p:ident) => {};
+  ($name:ident $($path:tt)*) => {
+      route_path!(@munch $name ($($path)*) [] $($path)*);
+  };
+}
+
 /// Create a http handler for a specific route.
 /// ```
 /// route! {
@@ -55,8 +121,16 @@ macro_rules! path {
 /// Will make a route function called `my_route` accepting the args `request: &Request, arg: &Arg`
 /// if the route matches, the second path segment will be parsed as a `usize` and extracted to the
 /// variable `bar` and the third and any following segments will be captured in the variable `baz`
-/// If the route doesn't match, the functions will return a 404
+/// If the route doesn't match, the functions will return a 404. The template of a matching route is recorded for the
+/// access log
+///
+/// Naming a path function after the route, with `(GET /foo/{bar: usize}/{baz}) as foo_path`, also makes a function
+/// building the route's path with [`route_path!`]
 macro_rules! route {
+  {( $method:ident $($path:tt)*) as $path_fn:ident $id:ident($request:ident: &Request $(, $arg:ident: $arg_ty:ty)*) $b:block} => {
+      route! {( $method $($path)*) $id($request: &Request $(, $arg: $arg_ty)*) $b}
+      route_path!($path_fn $($path)*);
+  };
   {( $method:ident $($path:tt)*) $id:ident($request:ident: &Request $(, $arg:ident: $arg_ty:ty)*) $b:block} => {
       fn $id ($request: &Request $(, $arg: $arg_ty)*) -> Response {
           let f = move || -> Result<Response, $crate::web::error::Error> {
@@ -64,6 +138,7 @@ macro_rules! route {
                   return Err($crate::web::error::Error::NotFound("Method"))
               }
               path!(let $($path)* = $request.url());
+              $crate::web::access_log::set_route(concat!(stringify!($method), " ", route_template!($($path)*)));
               $b
           };
           f().unwrap_or_else(Into::into)
@@ -71,6 +146,44 @@ macro_rules! route {
   };
 }
 
+/// Declare a struct of the query parameters of a route, with a `from_request` function parsing them with `FromStr`. A
+/// field is `None` when its parameter is missing or empty unless it has a default, an unparseable parameter is an
+/// `InvalidParam` error.
+/// ```ignore
+/// query! {
+///     struct MyQuery {
+///         tag: String,
+///         limit: usize = 100,
+///     }
+/// }
+/// ```
+///
+/// Will make `struct MyQuery { tag: Option<String>, limit: usize }`
+macro_rules! query {
+  {$(#[$meta:meta])* struct $name:ident { $($(#[$field_meta:meta])* $field:ident: $ty:ty $(= $default:expr)?),* $(,)? }} => {
+      $(#[$meta])*
+      struct $name {
+          $($(#[$field_meta])* $field: query!(@type $ty $(= $default)?)),*
+      }
+
+      impl $name {
+          fn from_request(request: &Request) -> Result<Self, $crate::web::error::Error> {
+              Ok(Self {
+                  $($field: query!(@value request, $field: $ty $(= $default)?)),*
+              })
+          }
+      }
+  };
+  (@type $ty:ty = $default:expr) => { $ty };
+  (@type $ty:ty) => { Option<$ty> };
+  (@value $request:ident, $field:ident: $ty:ty = $default:expr) => {
+      $crate::web::query_param::<$ty>($request, stringify!($field))?.unwrap_or_else(|| $default)
+  };
+  (@value $request:ident, $field:ident: $ty:ty) => {
+      $crate::web::query_param::<$ty>($request, stringify!($field))?
+  };
+}
+
 #[cfg(test)]
 macro_rules! assert_extract {
     (path($($args:tt)*); $($is:ident == $should:literal);*) => {
@@ -140,3 +253,22 @@ fn test_paths() {
 
     assert_extract!(path(let / = path););
 }
+
+#[test]
+fn test_route_paths() {
+    use chrono::{DateTime, FixedOffset};
+
+    route_path!(root_path /);
+    route_path!(example_path /example/{timestamp: DateTime<FixedOffset>}/{rest});
+    assert_eq!(root_path(), "/");
+    let timestamp: DateTime<FixedOffset> = "2022-02-17T09:53:57Z".parse().unwrap();
+    assert_eq!(example_path(&timestamp, "a/b"), "/example/2022-02-17T09:53:57+00:00/a/b");
+}
+
+#[test]
+fn test_route_templates() {
+    assert_eq!(route_template!(/), "/");
+    assert_eq!(route_template!(/foo/bar), "/foo/bar");
+    assert_eq!(route_template!(/foo/{bar}), "/foo/{bar}");
+    assert_eq!(route_template!(/{a: u8}/b/{c: Option<Vec<u8>>}), "/{a}/b/{c}");
+}
@@ -0,0 +1,240 @@
+//! Webhooks for watching single docs. A subscription is a doc url and a callback url, the callback is sent a JSON `POST`
+//! for each new update to that doc.
+
+use std::{
+    collections::HashMap,
+    fs, io,
+    net::{IpAddr, Ipv4Addr, Ipv6Addr},
+    path::PathBuf,
+    sync::{
+        mpsc::{self, Sender},
+        Mutex,
+    },
+    thread,
+    time::Duration,
+};
+
+use update_repo::{update::Update, Url};
+
+pub struct Webhooks {
+    /// the file the subscriptions are kept in, with a line per subscription of the doc url and the callback url
+    path: PathBuf,
+    subscriptions: Mutex<HashMap<Url, Vec<url::Url>>>,
+    /// sends deliveries to the thread making them, which is started by the first delivery
+    deliveries: Mutex<Option<Sender<(url::Url, String)>>>,
+}
+
+impl Webhooks {
+    /// The most subscriptions kept, so that a leaked admin token can't fill the disk with them
+    pub const MAX_SUBSCRIPTIONS: usize = 10_000;
+    const TIMEOUT: Duration = Duration::from_secs(10);
+
+    /// Load the subscriptions kept in the file at `path`, there are none if it doesn't exist
+    pub fn load(path: PathBuf) -> io::Result<Self> {
+        let mut subscriptions: HashMap<Url, Vec<url::Url>> = HashMap::new();
+        match fs::read_to_string(&path) {
+            Ok(contents) => {
+                for line in contents.lines() {
+                    let parsed = line
+                        .split_once(' ')
+                        .and_then(|(doc, callback)| Some((doc.parse().ok()?, callback.parse().ok()?)));
+                    match parsed {
+                        Some((doc, callback)) => subscriptions.entry(doc).or_default().push(callback),
+                        None => println!("Ignoring invalid webhook subscription {:?}", line),
+                    }
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        Ok(Self {
+            path,
+            subscriptions: Mutex::new(subscriptions),
+            deliveries: Mutex::default(),
+        })
+    }
+
+    pub fn count(&self) -> usize {
+        self.subscriptions.lock().unwrap().values().map(Vec::len).sum()
+    }
+
+    /// Subscribe a callback to the updates of a doc, returns false if it was already subscribed
+    pub fn subscribe(&self, doc: Url, callback: url::Url) -> io::Result<bool> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let callbacks = subscriptions.entry(doc).or_default();
+        if callbacks.contains(&callback) {
+            return Ok(false);
+        }
+        callbacks.push(callback);
+        self.save(&subscriptions)?;
+        Ok(true)
+    }
+
+    /// Unsubscribe a callback from the updates of a doc, returns false if it wasn't subscribed
+    pub fn unsubscribe(&self, doc: &Url, callback: &url::Url) -> io::Result<bool> {
+        let mut subscriptions = self.subscriptions.lock().unwrap();
+        let callbacks = match subscriptions.get_mut(doc) {
+            Some(callbacks) => callbacks,
+            None => return Ok(false),
+        };
+        let before = callbacks.len();
+        callbacks.retain(|subscribed| subscribed != callback);
+        if callbacks.len() == before {
+            return Ok(false);
+        }
+        if callbacks.is_empty() {
+            subscriptions.remove(doc);
+        }
+        self.save(&subscriptions)?;
+        Ok(true)
+    }
+
+    /// Send a new update to the callbacks subscribed to its doc, in the background
+    pub fn notify(&self, update: &Update) {
+        let callbacks = match self.subscriptions.lock().unwrap().get(update.url()) {
+            Some(callbacks) => callbacks.clone(),
+            None => return,
+        };
+        let body = serde_json::json!({
+            "url": update.url().as_str(),
+            "timestamp": update.timestamp().to_rfc3339(),
+            "id": update.update_ref().short_id(),
+            "change": update.change(),
+        })
+        .to_string();
+        let mut deliveries = self.deliveries.lock().unwrap();
+        let sender = deliveries.get_or_insert_with(start_delivery);
+        for callback in callbacks {
+            let _ = sender.send((callback, body.clone()));
+        }
+    }
+
+    fn save(&self, subscriptions: &HashMap<Url, Vec<url::Url>>) -> io::Result<()> {
+        let mut contents = String::new();
+        for (doc, callbacks) in subscriptions {
+            for callback in callbacks {
+                contents.push_str(&format!("{} {}\n", doc, callback));
+            }
+        }
+        let tmp_path = self.path.with_extension("saving");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(tmp_path, &self.path)
+    }
+}
+
+/// Start the thread which makes deliveries, one at a time so that a slow callback can't use up the server's threads
+fn start_delivery() -> Sender<(url::Url, String)> {
+    let (sender, receiver) = mpsc::channel::<(url::Url, String)>();
+    thread::spawn(move || {
+        for (callback, body) in receiver {
+            // checked again as it is delivered, as the callback's host may resolve somewhere else by now
+            if !resolves_publicly(&callback) {
+                println!("Webhook delivery to {} skipped, it doesn't resolve to a public address", callback);
+                continue;
+            }
+            let result = ureq::post(callback.as_str())
+                .timeout(Webhooks::TIMEOUT)
+                .set("Content-Type", "application/json")
+                .send_string(&body);
+            if let Err(err) = result {
+                println!("Webhook delivery to {} failed : {}", callback, err);
+            }
+        }
+    });
+    sender
+}
+
+/// Whether a callback's host is public, rather than a loopback, private or link-local address which the server shouldn't
+/// be made to send requests to. A domain is checked by [`resolves_publicly`] when a delivery is made
+pub fn is_public_host(callback: &url::Url) -> bool {
+    match callback.host() {
+        Some(url::Host::Domain(domain)) => {
+            let domain = domain.trim_end_matches('.').to_ascii_lowercase();
+            domain != "localhost" && !domain.ends_with(".localhost")
+        }
+        Some(url::Host::Ipv4(ip)) => is_public_ip(IpAddr::V4(ip)),
+        Some(url::Host::Ipv6(ip)) => is_public_ip(IpAddr::V6(ip)),
+        None => false,
+    }
+}
+
+/// Whether every address a callback's host resolves to is public
+fn resolves_publicly(callback: &url::Url) -> bool {
+    is_public_host(callback)
+        && match callback.socket_addrs(|| Some(443)) {
+            Ok(addrs) => !addrs.is_empty() && addrs.iter().all(|addr| is_public_ip(addr.ip())),
+            Err(_) => false,
+        }
+}
+
+fn is_public_ip(ip: IpAddr) -> bool {
+    match ip {
+        IpAddr::V4(ip) => is_public_ipv4(ip),
+        IpAddr::V6(ip) => match ip.to_ipv4_mapped() {
+            Some(ip) => is_public_ipv4(ip),
+            None => is_public_ipv6(ip),
+        },
+    }
+}
+
+fn is_public_ipv4(ip: Ipv4Addr) -> bool {
+    let [first, second, ..] = ip.octets();
+    !(ip.is_loopback()
+        || ip.is_private()
+        || ip.is_link_local()
+        || ip.is_unspecified()
+        || ip.is_broadcast()
+        || ip.is_multicast()
+        || ip.is_documentation()
+        // shared address space, used for carrier-grade NAT
+        || (first == 100 && (64..128).contains(&second))
+        || first == 0)
+}
+
+fn is_public_ipv6(ip: Ipv6Addr) -> bool {
+    let first = ip.segments()[0];
+    !(ip.is_loopback()
+        || ip.is_unspecified()
+        || ip.is_multicast()
+        // unique local
+        || (first & 0xfe00) == 0xfc00
+        // link-local
+        || (first & 0xffc0) == 0xfe80)
+}
+
+#[test]
+fn test_is_public_host() {
+    let public = |callback: &str| is_public_host(&callback.parse().unwrap());
+    assert!(public("https://example.org/hook"));
+    assert!(public("https://93.184.216.34/hook"));
+    assert!(!public("https://localhost/hook"));
+    assert!(!public("https://a.localhost./hook"));
+    assert!(!public("https://127.0.0.1/hook"));
+    assert!(!public("https://10.1.2.3/hook"));
+    assert!(!public("https://192.168.0.1/hook"));
+    assert!(!public("https://169.254.169.254/latest/meta-data"));
+    assert!(!public("https://[::1]/hook"));
+    assert!(!public("https://[fe80::1]/hook"));
+    assert!(!public("https://[fd00::1]/hook"));
+    assert!(!public("https://[::ffff:127.0.0.1]/hook"));
+}
+
+#[test]
+fn test_subscriptions_are_kept() {
+    let path = PathBuf::from("tmp/test_subscriptions_are_kept");
+    let _ = fs::remove_file(&path);
+    fs::create_dir_all(path.parent().unwrap()).unwrap();
+    let doc: Url = "https://www.gov.uk/foreign-travel-advice/france".parse().unwrap();
+    let callback: url::Url = "https://example.org/hook".parse().unwrap();
+
+    let webhooks = Webhooks::load(path.clone()).unwrap();
+    assert_eq!(webhooks.count(), 0);
+    assert!(webhooks.subscribe(doc.clone(), callback.clone()).unwrap());
+    assert!(!webhooks.subscribe(doc.clone(), callback.clone()).unwrap());
+
+    let webhooks = Webhooks::load(path.clone()).unwrap();
+    assert_eq!(webhooks.count(), 1);
+    assert!(webhooks.unsubscribe(&doc, &callback).unwrap());
+    assert!(!webhooks.unsubscribe(&doc, &callback).unwrap());
+    assert_eq!(Webhooks::load(path).unwrap().count(), 0);
+}
@@ -1,13 +1,18 @@
 use pretty_assertions::assert_eq;
 use update_repo::doc::content::*;
-use update_tracker::ingress::retrieve_doc;
+use update_tracker::ingress::fetch::{CassetteFetcher, Fetcher};
+
+/// Fetches are replayed from `tests/cassettes`, set `REFRESH_CASSETTES` to record them again from gov.uk
+fn retrieve_doc(url: &url::Url) -> anyhow::Result<Option<Doc>> {
+    CassetteFetcher::from_env("tests/cassettes").fetch(url)
+}
 
 macro_rules! assert_doc {
     ($doc:expr, $url:expr, $body:expr) => {
         let doc = $doc;
         let url = $url;
         assert_eq!(doc.url.as_str(), url);
-        if let DocContent::DiffableHtml(content, _, _) = &doc.content {
+        if let DocContent::DiffableHtml(content, ..) = &doc.content {
             let diff = html_diff::get_differences($body, content);
             assert!(
                 diff.is_empty(),
@@ -87,17 +92,19 @@ fn fetch_file() {
     assert_file(
         &doc,
         "https://assets.publishing.service.gov.uk/government/uploads/system/uploads/attachment_data/file/722576/bus-open-data-case-for-change.pdf",
-        include_bytes!(
-            "govuk/government/uploads/system/uploads/attachment_data/file/722576/bus-open-data-case-for-change.pdf"
-        ),
+        b"%PDF-",
+        1387247,
     );
     assert!(doc.content.attachments().is_none());
 }
 
-fn assert_file(doc: &Doc, url: &str, body: &[u8]) {
+/// The body of a file is kept as it was fetched, it is recorded in the cassette so it is only checked by its start and
+/// length here rather than keeping a second copy
+fn assert_file(doc: &Doc, url: &str, start: &[u8], len: usize) {
     assert_eq!(doc.url.as_str(), url,);
     if let DocContent::Other(content) = &doc.content {
-        assert_eq!(content.as_slice(), body);
+        assert!(content.starts_with(start));
+        assert_eq!(content.len(), len);
     } else {
         panic!("Fail")
     }
@@ -0,0 +1,318 @@
+//! Snapshot tests of the pages rendered by the web handlers, run against a small fixture repo. A missing snapshot fails
+//! the test, set `UPDATE_SNAPSHOTS` to record new snapshots and re-record them all after an intended change.
+
+use std::{
+    env, fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    sync::{mpsc, Arc, Mutex},
+};
+
+use pretty_assertions::assert_eq;
+use rouille::{Request, Response};
+use update_repo::{
+    doc::DocRepo,
+    tag::TagRepo,
+    update::{UpdateRef, UpdateRepo},
+};
+use update_tracker::{
+    data::{Data, SharedData},
+    web::App,
+};
+
+const DOC_URL: &str = "https://www.gov.uk/guidance/travel-abroad";
+
+fn fixture_app(name: &str) -> App {
+    app(&fixture_repo(name))
+}
+
+/// A repo with two versions of a doc and the update between them
+fn fixture_repo(name: &str) -> PathBuf {
+    let base = PathBuf::from(format!("tmp/web_snapshots/{}", name));
+    let _ = fs::remove_dir_all(&base);
+
+    let doc_repo = DocRepo::new(base.join("url")).unwrap();
+    let mut write_avoidance_buffer = Vec::new();
+    for (timestamp, body) in [
+        (
+            "2022-02-17T09:00:00+00:00",
+            "<main><h1>Travel abroad</h1><p>You need a passport to travel to France.</p></main>",
+        ),
+        (
+            "2022-02-17T10:00:00+00:00",
+            "<main><h1>Travel abroad</h1><p>You need a passport and a visa to travel to France.</p></main>",
+        ),
+    ] {
+        let mut write = doc_repo
+            .create(DOC_URL.parse().unwrap(), timestamp.parse().unwrap(), &mut write_avoidance_buffer)
+            .unwrap();
+        write.write_all(body.as_bytes()).unwrap();
+        write.done().unwrap();
+    }
+
+    let update = UpdateRepo::new(base.join("url"))
+        .unwrap()
+        .create(
+            DOC_URL.parse().unwrap(),
+            "2022-02-17T09:30:00+00:00".parse().unwrap(),
+            "Visas are needed for travel to France",
+        )
+        .unwrap()
+        .into_inner();
+    TagRepo::new(base.join("tag"))
+        .unwrap()
+        .tag_update("brexit".to_owned(), update.update_ref().clone())
+        .unwrap();
+    base
+}
+
+/// The admin token of every test's app, set for all of them alike as the environment is shared by the tests
+const ADMIN_TOKEN: &str = "snapshot-admin";
+
+fn app(base: &Path) -> App {
+    env::set_var("ADMIN_TOKEN", ADMIN_TOKEN);
+    let data = Data::load(base);
+    let (admin_sender, _) = mpsc::channel();
+    App::new(Arc::new(SharedData::new(data)), Mutex::new(admin_sender))
+}
+
+fn get(app: &App, url: &str) -> Response {
+    get_accepting(app, url, "text/html")
+}
+
+fn get_accepting(app: &App, url: &str, accept: &str) -> Response {
+    app.handle(&Request::fake_http(
+        "GET",
+        url,
+        vec![
+            ("Host".to_owned(), "govdiff.example".to_owned()),
+            ("X-Request-Id".to_owned(), "snapshot".to_owned()),
+            ("Accept".to_owned(), accept.to_owned()),
+        ],
+        vec![],
+    ))
+}
+
+fn assert_snapshot(name: &str, response: Response) {
+    let mut body = String::new();
+    response.data.into_reader_and_size().0.read_to_string(&mut body).unwrap();
+    let body = format!("{}\n{}", response.status_code, body);
+
+    let path = Path::new("tests/snapshots").join(format!("{}.html", name));
+    if env::var_os("UPDATE_SNAPSHOTS").is_some() {
+        fs::create_dir_all(path.parent().unwrap()).unwrap();
+        fs::write(&path, &body).unwrap();
+        println!("Recorded snapshot {:?}", path);
+        return;
+    }
+    let snapshot = fs::read_to_string(&path)
+        .unwrap_or_else(|err| panic!("snapshot {:?} can't be read, record it with UPDATE_SNAPSHOTS : {}", path, err));
+    assert_eq!(snapshot, body, "snapshot {:?}", path);
+}
+
+#[test]
+fn dashboard_page() {
+    // the fixture updates are too old to count as recent, so the snapshot doesn't depend on the day it is run
+    let app = fixture_app("dashboard");
+    assert_snapshot("dashboard", get(&app, "/"));
+}
+
+#[test]
+fn stats_page() {
+    let app = fixture_app("stats");
+    assert_snapshot("stats", get(&app, "/stats"));
+    assert_snapshot("stats_json", get_accepting(&app, "/stats", "application/json"));
+}
+
+#[test]
+fn updates_page() {
+    let app = fixture_app("updates");
+    assert_snapshot("updates", get(&app, "/updates"));
+    assert_snapshot("updates_filtered", get(&app, "/updates?tag=brexit&change=visa*"));
+    assert_snapshot("updates_section", get(&app, "/updates?section=/guidance"));
+    assert_snapshot("updates_prefix", get(&app, "/updates?url_prefix=www.gov.uk/guidance/"));
+    assert_snapshot(
+        "updates_filter_terms",
+        get(&app, "/updates?filter=%23brexit+https://www.gov.uk/guidance/+2022-02..2022-03"),
+    );
+    // the fixture updates are older than any quick pick, so none are listed
+    assert_snapshot("updates_age", get(&app, "/updates?tag=brexit&age=30d.."));
+}
+
+#[test]
+fn updates_formats() {
+    let app = fixture_app("updates_formats");
+    assert_snapshot("updates_json", get_accepting(&app, "/updates", "application/json"));
+    assert_snapshot("updates_atom", get_accepting(&app, "/updates?tag=brexit", "application/atom+xml"));
+    // the same feed for feed readers, whatever they accept
+    assert_snapshot("feed", get(&app, "/feed?tag=brexit&url_prefix=www.gov.uk/guidance/"));
+    assert_snapshot(
+        "update_json",
+        get_accepting(
+            &app,
+            "/update/2022-02-17T09:30:00+00:00/www.gov.uk/guidance/travel-abroad",
+            "application/json",
+        ),
+    );
+    assert_snapshot(
+        "history_atom",
+        get(&app, "/history/www.gov.uk/guidance/travel-abroad.atom"),
+    );
+    assert_snapshot(
+        "versions_atom",
+        get(&app, "/doc/www.gov.uk/guidance/travel-abroad/versions.atom"),
+    );
+}
+
+#[test]
+fn stale_page_days_are_checked() {
+    let app = fixture_app("stale_days");
+    assert_eq!(get(&app, "/stale?days=30").status_code, 200);
+    for days in ["0", "-1", "36501", "9223372036854775807"] {
+        assert_eq!(get(&app, &format!("/stale?days={}", days)).status_code, 400, "days={}", days);
+    }
+}
+
+#[test]
+fn webhook_subscriptions() {
+    let app = fixture_app("webhook_subscriptions");
+    let authorization = ("Authorization".to_owned(), format!("Bearer {}", ADMIN_TOKEN));
+    let request = |method: &str, url: &str| {
+        app.handle(&Request::fake_http(method, url, vec![authorization.clone()], vec![]))
+            .status_code
+    };
+    let hook = "/api/v1/webhooks/www.gov.uk/guidance/travel-abroad?callback=https://example.org/hook";
+    assert_eq!(request("POST", hook), 201);
+    assert_eq!(request("POST", hook), 200);
+    assert_eq!(
+        request("POST", "/api/v1/webhooks/www.gov.uk/guidance/travel-abroad?callback=http://example.org/hook"),
+        400
+    );
+    assert_eq!(
+        request("POST", "/api/v1/webhooks/www.gov.uk/guidance/travel-home?callback=https://example.org/hook"),
+        404
+    );
+    // the server isn't made to send requests to itself or its network
+    for callback in ["https://localhost/hook", "https://10.0.0.1/hook", "https://169.254.169.254/latest"] {
+        let url = format!("/api/v1/webhooks/www.gov.uk/guidance/travel-abroad?callback={}", callback);
+        assert_eq!(request("POST", &url), 400);
+    }
+    // only an admin can subscribe or unsubscribe
+    let unauthorized = app.handle(&Request::fake_http("DELETE", hook, vec![], vec![]));
+    assert_eq!(unauthorized.status_code, 401);
+    assert_eq!(request("DELETE", hook), 204);
+    assert_eq!(request("DELETE", hook), 404);
+}
+
+#[test]
+fn update_page() {
+    let app = fixture_app("update");
+    assert_snapshot(
+        "update",
+        get(&app, "/update/2022-02-17T09:30:00+00:00/www.gov.uk/guidance/travel-abroad"),
+    );
+}
+
+#[test]
+fn diff_page() {
+    let app = fixture_app("diff");
+    assert_snapshot(
+        "diff",
+        get(
+            &app,
+            "/diff/2022-02-17T09:00:00+00:00/2022-02-17T10:00:00+00:00/www.gov.uk/guidance/travel-abroad",
+        ),
+    );
+}
+
+#[test]
+fn diff_share_link() {
+    let app = fixture_app("diff_share_link");
+    let update_ref: UpdateRef = (
+        DOC_URL.parse().unwrap(),
+        "2022-02-17T09:30:00+00:00".parse().unwrap(),
+    )
+        .into();
+    // the versions at 09:00 and 10:00, as seconds in base 36
+    let response = get(&app, &format!("/s/{}/r7fyc0.r7g140.removed", update_ref.short_id()));
+    assert_eq!(response.status_code, 301);
+    let location = response
+        .headers
+        .iter()
+        .find(|(name, _)| name == "Location")
+        .unwrap()
+        .1
+        .to_string();
+    assert_eq!(
+        location,
+        "/diff/2022-02-17T09:00:00+00:00/2022-02-17T10:00:00+00:00/www.gov.uk/guidance/travel-abroad?mode=removed"
+    );
+    assert_snapshot("diff_removed", get(&app, &location));
+}
+
+#[test]
+fn not_found_page() {
+    let app = fixture_app("not_found");
+    assert_snapshot(
+        "not_found",
+        get(&app, "/update/2022-02-17T09:30:00+00:00/www.gov.uk/guidance/travel-home"),
+    );
+}
+
+#[test]
+fn missing_versions_page() {
+    let base = fixture_repo("missing_versions");
+    UpdateRepo::new(base.join("url"))
+        .unwrap()
+        .create(
+            "https://www.gov.uk/guidance/travel-home".parse().unwrap(),
+            "2022-02-17T09:30:00+00:00".parse().unwrap(),
+            "First published",
+        )
+        .unwrap();
+    let app = app(&base);
+    assert_snapshot(
+        "missing_versions",
+        get(&app, "/update/2022-02-17T09:30:00+00:00/www.gov.uk/guidance/travel-home"),
+    );
+}
+
+#[test]
+fn removed_page() {
+    let base = fixture_repo("removed");
+    DocRepo::new(base.join("url"))
+        .unwrap()
+        .mark_removed(DOC_URL.parse().unwrap(), "2022-02-18T09:00:00+00:00".parse().unwrap())
+        .unwrap();
+    let app = app(&base);
+    assert_snapshot("removed_updates", get(&app, "/updates"));
+    assert_snapshot(
+        "removed_update",
+        get(&app, "/update/2022-02-17T09:30:00+00:00/www.gov.uk/guidance/travel-abroad"),
+    );
+    assert_snapshot(
+        "removed_diff",
+        get(&app, "/diff/2022-02-17T10:00:00+00:00//www.gov.uk/guidance/travel-abroad"),
+    );
+}
+
+#[test]
+fn withdrawn_page() {
+    let base = fixture_repo("withdrawn");
+    let doc_repo = DocRepo::new(base.join("url")).unwrap();
+    let latest = doc_repo
+        .list_versions(DOC_URL.parse().unwrap())
+        .unwrap()
+        .next()
+        .unwrap()
+        .unwrap();
+    doc_repo
+        .mark_withdrawn(&latest, "2022-02-17T09:45:00+00:00".parse().unwrap())
+        .unwrap();
+    let app = app(&base);
+    assert_snapshot("withdrawn_updates", get(&app, "/updates"));
+    assert_snapshot(
+        "withdrawn_update",
+        get(&app, "/update/2022-02-17T09:30:00+00:00/www.gov.uk/guidance/travel-abroad"),
+    );
+}
@@ -0,0 +1,50 @@
+use std::{
+    collections::{HashMap, HashSet},
+    env, io,
+    path::PathBuf,
+};
+
+use update_repo::{
+    check::check_repo_or_exit,
+    tag::{TagRepo, TagRules},
+    update::{UpdateRef, UpdateRepo},
+};
+
+/// Retroactively applies tag rules to all the updates in a repo, updates which already have a tag aren't tagged again
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = env::args();
+    let _ = args.next().unwrap();
+    let repo_path = PathBuf::from(args.next().expect("no repo path"));
+    check_repo_or_exit(&repo_path);
+    let rules = TagRules::load(args.next().expect("no tag rules path"))?;
+
+    let update_repo = UpdateRepo::new(repo_path.join("url"))?;
+    let tag_repo = TagRepo::new(repo_path.join("tag"))?;
+    // the updates already in each tag, read once for each tag matched
+    let mut tag_updates: HashMap<String, HashSet<UpdateRef>> = HashMap::new();
+    let mut tagged = 0;
+    for update in update_repo.list_all(&"https://www.gov.uk/".parse()?)? {
+        let update = update?;
+        for tag in rules.tags_for(&update) {
+            if !tag_updates.contains_key(tag) {
+                tag_updates.insert(tag.to_owned(), read_tag(&tag_repo, tag)?);
+            }
+            if !tag_updates.get_mut(tag).unwrap().insert(update.update_ref().clone()) {
+                continue;
+            }
+            tag_repo.tag_update(tag.to_owned(), update.update_ref().clone())?;
+            println!("Tagged {} {} with {:?}", update.url(), update.timestamp(), tag);
+            tagged += 1;
+        }
+    }
+    println!("Tagged {} updates", tagged);
+    Ok(())
+}
+
+fn read_tag(tag_repo: &TagRepo, tag: &str) -> io::Result<HashSet<UpdateRef>> {
+    match tag_repo.list_updates_in_tag(tag) {
+        Ok(updates) => Ok(updates.filter_map(Result::ok).collect()),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(HashSet::new()),
+        Err(err) => Err(err),
+    }
+}
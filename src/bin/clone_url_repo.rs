@@ -1,43 +1,49 @@
 use std::env;
 
-use chrono::Utc;
-use update_repo::doc::{content::sanitise_doc, DocRepo};
+use update_repo::{
+    doc::{content::sanitise_doc, DocRepo},
+    progress::{Progress, Verbosity},
+    repository::Durability,
+};
 
+/// Copies the doc versions of a url repo to another, sanitising them. Takes the source and destination paths, and
+/// `--quiet` or `--verbose`
 fn main() -> Result<(), Box<dyn std::error::Error>> {
-    let mut args = env::args();
-    let _ = args.next().unwrap();
+    let (verbosity, args) = Verbosity::from_args(env::args().skip(1));
+    let mut args = args.into_iter();
     let source_path = args.next().expect("no source path");
     let dest_path = args.next().expect("no dest path");
 
     let source_doc_repo = DocRepo::new(source_path)?;
-    let dest_doc_repo = DocRepo::new(dest_path)?;
+    // a copy which is interrupted is made again, so it isn't worth syncing each write, and versions which sanitise the
+    // same are removed once at the end
+    let dest_doc_repo = DocRepo::new(dest_path)?
+        .with_durability(Durability::None)
+        .with_bulk_mode();
 
+    let progress = Progress::new(None, verbosity);
     let mut write_avoidance_buffer = Vec::new();
     let mut buf = Vec::new();
-    let mut last_wrote = 0;
-    let mut last_nl = 0;
     let mut count = 0;
     for res in source_doc_repo
         .list_all(&"https://www.gov.uk/".parse().unwrap())
         .unwrap()
     {
-        count += 1;
         let doc_ver = res?;
-        let ts = Utc::now().timestamp_millis();
-        if ts * 60 / 1000 > last_wrote {
-            print!("\rCopying #{} {}", count, doc_ver);
-            last_wrote = ts * 60 / 1000;
-        }
-        if ts / 10_000 > last_nl {
-            println!();
-            last_nl = ts / 10_000;
-        }
+        progress.log(format!("Copying {}", doc_ver));
 
         let mut read = source_doc_repo.open(&doc_ver)?;
         let mut write =
             dest_doc_repo.create(doc_ver.url().clone(), *doc_ver.timestamp(), &mut write_avoidance_buffer)?;
         sanitise_doc(&mut read, &mut write, &mut buf)?;
         let _ = write.done()?;
+        count += 1;
+        progress.inc(1);
     }
+    progress.set_message("removing duplicates");
+    let removed = dest_doc_repo.compact_all(&"https://www.gov.uk/".parse().unwrap())?;
+    let elapsed = progress.finish();
+    println!("Copied {} versions in {}s", count, elapsed.as_secs());
+    println!("Removed {} duplicate versions", removed.versions_removed);
     Ok(())
 }
@@ -0,0 +1,40 @@
+use std::{env, path::PathBuf, sync::Arc};
+
+use update_repo::{
+    check::check_repo_or_exit,
+    doc::{DocEvent, DocRepo, DocumentVersion},
+    progress::{Progress, Verbosity},
+    repository::EventSink,
+};
+
+/// Removes doc versions which are identical to the version before them, such as after a change to the sanitiser or for repos
+/// written in bulk mode where writes weren't deduplicated. Reports the space reclaimed, and each removed version with
+/// `--verbose`. Takes the repo path
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let (verbosity, args) = Verbosity::from_args(env::args().skip(1));
+    let repo_path = PathBuf::from(args.into_iter().next().expect("no repo path"));
+    check_repo_or_exit(&repo_path);
+
+    let progress = Arc::new(Progress::new(None, verbosity));
+    let doc_repo = DocRepo::new(repo_path.join("url"))?.with_event_sink(Arc::new(ReportDeleted(progress.clone())));
+    let stats = doc_repo.compact_all(&"https://www.gov.uk/".parse()?)?;
+    let elapsed = progress.finish();
+    println!(
+        "Removed {} duplicate versions in {}s",
+        stats.versions_removed,
+        elapsed.as_secs()
+    );
+    println!("Reclaimed {} bytes", stats.bytes_reclaimed);
+    Ok(())
+}
+
+struct ReportDeleted(Arc<Progress>);
+
+impl EventSink for ReportDeleted {
+    fn on_doc(&self, _doc: &DocumentVersion, event: &DocEvent) {
+        if let DocEvent::Deleted { url, timestamp } = event {
+            self.0.inc(1);
+            self.0.log(format!("Deleted {} {}", timestamp.to_rfc3339(), url));
+        }
+    }
+}
@@ -0,0 +1,33 @@
+use std::{env, path::PathBuf, process, time::Instant};
+
+use update_repo::{
+    check::{check_repo_or_exit, repo_version, upgrade_version},
+    doc::DocRepo,
+};
+
+/// Migrates a repo to version 3, compressing each doc version which isn't compressed already. Versions in either form are
+/// read, so the repo can be used while it is migrating, and it can be run again. The repo is marked as version 3 before
+/// any version is compressed, so that older versions, which would read the compressed versions as they are, refuse it.
+/// Takes the repo path
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = env::args();
+    let _ = args.next().unwrap();
+    let repo_path = PathBuf::from(args.next().expect("no repo path"));
+    check_repo_or_exit(&repo_path);
+    if repo_version(&repo_path)? < 2 {
+        eprintln!("The repo is version 1, migrate it with migrate_tag_refs first");
+        process::exit(1);
+    }
+
+    upgrade_version(&repo_path)?;
+    let started = Instant::now();
+    let doc_repo = DocRepo::new(repo_path.join("url"))?;
+    let stats = doc_repo.compress_all(&"https://www.gov.uk/".parse()?)?;
+    println!(
+        "Compressed {} versions in {}s",
+        stats.versions_compressed,
+        started.elapsed().as_secs()
+    );
+    println!("Reclaimed {} bytes", stats.bytes_reclaimed);
+    Ok(())
+}
@@ -0,0 +1,21 @@
+use std::{env, path::PathBuf, process};
+
+use update_repo::check::{check_repo_or_exit, check_tags};
+
+/// Checks a whole repo, beyond the sample checked at startup, exits with an error if anything is invalid
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = env::args();
+    let _ = args.next().unwrap();
+    let repo_path = PathBuf::from(args.next().expect("no repo path"));
+    check_repo_or_exit(&repo_path);
+
+    let invalid_tag_lines = check_tags(&repo_path)?;
+    for (tag, err) in &invalid_tag_lines {
+        println!("Tag {:?} : {}", tag, err);
+    }
+    println!("{} invalid lines in tag files", invalid_tag_lines.len());
+    if !invalid_tag_lines.is_empty() {
+        process::exit(1);
+    }
+    Ok(())
+}
@@ -0,0 +1,47 @@
+use std::{
+    env, fs,
+    path::{Path, PathBuf},
+};
+
+use update_repo::{
+    check::{check_repo_or_exit, upgrade_version},
+    update::UpdateRef,
+};
+
+/// Migrates a repo to version 2, rewriting the update refs in tag files from `{url}#{timestamp}` to
+/// `{timestamp}|{url}`. Both are read, so the repo can be used while it is migrating, and it can be run again
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = env::args();
+    let _ = args.next().unwrap();
+    let repo_path = PathBuf::from(args.next().expect("no repo path"));
+    check_repo_or_exit(&repo_path);
+
+    for entry in fs::read_dir(repo_path.join("tag"))? {
+        let path = entry?.path();
+        let rewritten = migrate_tag_file(&path)?;
+        println!("Rewrote {} lines in tag {:?}", rewritten, path.file_name().unwrap_or_default());
+    }
+    upgrade_version(&repo_path)?;
+    Ok(())
+}
+
+/// Rewrite all the update refs in a tag file in the current form, returns the number of lines changed
+fn migrate_tag_file(path: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut rewritten = 0;
+    let mut contents = String::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let migrated = line.parse::<UpdateRef>()?.to_string();
+        if migrated != line {
+            rewritten += 1;
+        }
+        contents.push_str(&migrated);
+        contents.push('\n');
+    }
+    if rewritten > 0 {
+        // written outside of the tag dir so that it can't be mistaken for a tag
+        let tmp_path = path.parent().unwrap().with_file_name("tag.migrating");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(tmp_path, path)?;
+    }
+    Ok(rewritten)
+}
@@ -0,0 +1,84 @@
+use std::{
+    env, fs, io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, FixedOffset};
+use update_repo::{check::check_repo_or_exit, repository::normalize_timestamp, update::UpdateRef};
+
+/// Migrates a repo written before timestamps were normalized, renaming leaves and rewriting tag files so that all timestamps are in UTC
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = env::args();
+    let _ = args.next().unwrap();
+    let repo_path = PathBuf::from(args.next().expect("no repo path"));
+    check_repo_or_exit(&repo_path);
+
+    let renamed = normalize_leaves(&repo_path.join("url"))?;
+    println!("Renamed {} leaves", renamed);
+
+    for entry in fs::read_dir(repo_path.join("tag"))? {
+        let path = entry?.path();
+        let rewritten = normalize_tag_file(&path)?;
+        println!("Rewrote {} lines in tag {:?}", rewritten, path.file_name().unwrap_or_default());
+    }
+    Ok(())
+}
+
+/// Recursively rename leaves named by a timestamp which isn't in UTC, returns the number renamed
+fn normalize_leaves(dir: &Path) -> io::Result<usize> {
+    let mut renamed = 0;
+    for entry in fs::read_dir(dir)? {
+        let entry = entry?;
+        if entry.file_type()?.is_dir() {
+            renamed += normalize_leaves(&entry.path())?;
+            continue;
+        }
+        let file_name = entry.file_name();
+        let (repo_key, name) = match file_name.to_str().and_then(|name| name.strip_prefix('<')?.split_once('>')) {
+            Some(leaf) => leaf,
+            None => continue,
+        };
+        let timestamp: DateTime<FixedOffset> = match name.parse() {
+            Ok(timestamp) => timestamp,
+            Err(_) => continue,
+        };
+        let normalized = format!("<{}>{}", repo_key, normalize_timestamp(timestamp).to_rfc3339());
+        if file_name.to_str() == Some(&normalized) {
+            continue;
+        }
+        let to = entry.path().with_file_name(&normalized);
+        if to.exists() {
+            println!("Not renaming {:?}, {} already exists", entry.path(), normalized);
+            continue;
+        }
+        fs::rename(entry.path(), to)?;
+        renamed += 1;
+    }
+    Ok(renamed)
+}
+
+/// Rewrite all the update refs in a tag file in UTC, returns the number of lines changed
+fn normalize_tag_file(path: &Path) -> Result<usize, Box<dyn std::error::Error>> {
+    let mut rewritten = 0;
+    let mut contents = String::new();
+    for line in fs::read_to_string(path)?.lines() {
+        let update_ref: UpdateRef = line.parse()?;
+        let normalized = UpdateRef {
+            timestamp: normalize_timestamp(update_ref.timestamp),
+            ..update_ref
+        }
+        .to_string();
+        if normalized != line {
+            rewritten += 1;
+        }
+        contents.push_str(&normalized);
+        contents.push('\n');
+    }
+    if rewritten > 0 {
+        // written outside of the tag dir so that it can't be mistaken for a tag
+        let tmp_path = path.parent().unwrap().with_file_name("tag.normalizing");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(tmp_path, path)?;
+    }
+    Ok(rewritten)
+}
@@ -0,0 +1,46 @@
+use std::{env, path::PathBuf, process};
+
+use chrono::{DateTime, FixedOffset};
+use update_repo::{check::check_repo_or_exit, tracker::Tracker, update::UpdateRef, Url};
+
+/// Deletes a bogus update or doc version from a repo, such as one written from a mis-parsed email. Takes the repo path,
+/// `update` or `doc`, the url and the timestamp, and for an update optionally its seq. A deleted update is also removed
+/// from its tags. This is for when the server is stopped, a running server makes the deletions with its
+/// `/admin/retract` route, as they aren't otherwise coordinated with its ingress and the server wouldn't see them
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = env::args();
+    let _ = args.next().unwrap();
+    let repo_path = PathBuf::from(args.next().expect("no repo path"));
+    let kind = args.next().expect("no kind, update or doc");
+    let url: Url = args.next().expect("no url").parse()?;
+    let timestamp: DateTime<FixedOffset> = args.next().expect("no timestamp").parse()?;
+    check_repo_or_exit(&repo_path);
+
+    let tracker = Tracker::new(&repo_path)?;
+    match kind.as_str() {
+        "update" => {
+            let seq = args.next().map(|seq| seq.parse()).transpose()?.unwrap_or(0);
+            let update = tracker.delete_update(&UpdateRef { url, timestamp, seq })?;
+            println!(
+                "Deleted update {} {} : {}",
+                update.url(),
+                update.timestamp().to_rfc3339(),
+                update.change()
+            );
+        }
+        "doc" => {
+            let version = tracker.doc_repo().ensure_version(url, timestamp)?;
+            tracker.doc_repo().delete_version(&version)?;
+            println!(
+                "Deleted doc version {} {}",
+                version.url(),
+                version.timestamp().to_rfc3339()
+            );
+        }
+        _ => {
+            eprintln!("Unknown kind {:?}, expected update or doc", kind);
+            process::exit(1);
+        }
+    }
+    Ok(())
+}
@@ -0,0 +1,43 @@
+use std::{collections::BTreeSet, env, fs, path::PathBuf};
+
+use update_repo::{check::check_repo_or_exit, tag::TagMapping};
+
+/// Retroactively applies a tag mapping to a repo, merging the updates of each tag into the tag it maps to
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = env::args();
+    let _ = args.next().unwrap();
+    let repo_path = PathBuf::from(args.next().expect("no repo path"));
+    check_repo_or_exit(&repo_path);
+    let mapping = TagMapping::load(args.next().expect("no tag mapping path"))?;
+
+    let tag_dir = repo_path.join("tag");
+    let mut names: Vec<String> = fs::read_dir(&tag_dir)?
+        .map(|entry| Ok(entry?.file_name().to_string_lossy().into_owned()))
+        .collect::<std::io::Result<_>>()?;
+    names.sort();
+    for name in names {
+        let tag = mapping.canonical(&name);
+        if tag == name {
+            continue;
+        }
+        let from = tag_dir.join(&name);
+        let to = tag_dir.join(tag);
+        let mut lines: BTreeSet<String> = fs::read_to_string(&from)?.lines().map(str::to_owned).collect();
+        let moved = lines.len();
+        if to.exists() {
+            lines.extend(fs::read_to_string(&to)?.lines().map(str::to_owned));
+        }
+        let mut contents = String::new();
+        for line in lines {
+            contents.push_str(&line);
+            contents.push('\n');
+        }
+        // written outside of the tag dir so that it can't be mistaken for a tag
+        let tmp_path = tag_dir.with_file_name("tag.rewriting");
+        fs::write(&tmp_path, contents)?;
+        fs::rename(tmp_path, &to)?;
+        fs::remove_file(&from)?;
+        println!("Moved {} updates from tag {:?} to {:?}", moved, name, tag);
+    }
+    Ok(())
+}
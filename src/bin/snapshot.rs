@@ -0,0 +1,17 @@
+use std::{env, path::PathBuf};
+
+use update_repo::{check::check_repo_or_exit, snapshot::Snapshot};
+
+/// Snapshots a repo for a backup to be taken from while the repo is still being written. Takes the repo path and the
+/// path of the snapshot, which must be on the same filesystem, and is left for the backup to remove
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = env::args();
+    let _ = args.next().unwrap();
+    let repo_path = PathBuf::from(args.next().expect("no repo path"));
+    let snapshot_path = PathBuf::from(args.next().expect("no snapshot path"));
+    check_repo_or_exit(&repo_path);
+
+    let path = Snapshot::create(&repo_path, &snapshot_path)?.keep();
+    println!("Snapshot of {:?} at {:?}", repo_path, path);
+    Ok(())
+}
@@ -0,0 +1,50 @@
+use std::{env, path::PathBuf};
+
+use update_repo::{check::check_repo_or_exit, doc::DocRepo};
+
+/// The number of urls listed when no number is given
+const DEFAULT_TOP: usize = 20;
+
+/// Reports how much storage deduplicating doc versions has saved, and the urls with the most stored, to tune the
+/// storage policies such as the coalesce window. Takes the repo path and optionally the number of urls to list. The repo
+/// is read in place, so the counts can be a few writes apart from the sizes while updates are being written
+fn main() -> Result<(), Box<dyn std::error::Error>> {
+    let mut args = env::args();
+    let _ = args.next().unwrap();
+    let repo_path = PathBuf::from(args.next().expect("no repo path"));
+    check_repo_or_exit(&repo_path);
+    let top = match args.next() {
+        Some(top) => top.parse()?,
+        None => DEFAULT_TOP,
+    };
+
+    let doc_repo = DocRepo::new(repo_path.join("url"))?;
+    let stats = doc_repo.write_stats()?;
+    println!("Doc versions written : {}", stats.writes);
+    println!(
+        "Deduplicated : {} ({:.1}%)",
+        stats.deduplicated,
+        percentage(stats.deduplicated, stats.writes)
+    );
+    println!("Bytes stored : {}", stats.bytes_stored);
+    println!(
+        "Bytes avoided : {} ({:.1}% of those written)",
+        stats.bytes_avoided,
+        percentage(stats.bytes_avoided, stats.bytes_stored + stats.bytes_avoided)
+    );
+
+    println!();
+    println!("Urls with the most stored :");
+    for (url, bytes) in doc_repo.largest_docs(&"https://www.gov.uk/".parse()?, top)? {
+        println!("{:>12} {}", bytes, url.as_str());
+    }
+    Ok(())
+}
+
+fn percentage(part: u64, whole: u64) -> f64 {
+    if whole == 0 {
+        0.
+    } else {
+        part as f64 * 100. / whole as f64
+    }
+}
@@ -0,0 +1,207 @@
+//! Checks that a path is a repo this version can use before anything reads from it or writes to it. The repos create
+//! their directories as they are needed, so without this a wrong path is only noticed later by an empty site or a panic.
+
+use std::{
+    error, fmt, fs, io,
+    path::{Path, PathBuf},
+    process,
+};
+
+use chrono::{DateTime, FixedOffset};
+
+use crate::tag::{TagLineError, TagRepo};
+
+/// The version of the repo layout written by this version, stored in the `repo-version` file at the root of a repo.
+/// Version 2 writes update refs in tag files as `{timestamp}|{url}` rather than `{url}#{timestamp}`, and version 3 may
+/// store doc versions gzip compressed
+pub const REPO_VERSION: u32 = 3;
+
+const VERSION_FILE: &str = "repo-version";
+
+/// Check the version and structure of the repo at `path` and read a sample leaf from it
+pub fn check_repo(path: impl AsRef<Path>) -> Result<(), CheckError> {
+    let path = path.as_ref();
+    if !path.is_dir() {
+        return Err(CheckError::NotADirectory(path.to_owned()));
+    }
+    let version = repo_version(path)?;
+    if version > REPO_VERSION {
+        return Err(CheckError::UnsupportedVersion(version));
+    }
+    for dir in ["url", "tag"] {
+        if !path.join(dir).is_dir() {
+            return Err(CheckError::MissingDir(path.join(dir)));
+        }
+    }
+    sample_leaf(&path.join("url"))?;
+    Ok(())
+}
+
+/// Check the repo at `path`, printing what is wrong and exiting if it can't be used, for use at startup
+pub fn check_repo_or_exit(path: impl AsRef<Path>) {
+    if let Err(err) = check_repo(&path) {
+        eprintln!("The repo at {:?} can't be used : {}", path.as_ref(), err);
+        process::exit(1);
+    }
+}
+
+/// Create the directories and version file of a new repo, an existing repo is left as it is
+pub fn init_repo(path: impl AsRef<Path>) -> io::Result<()> {
+    let path = path.as_ref();
+    fs::create_dir_all(path.join("url"))?;
+    fs::create_dir_all(path.join("tag"))?;
+    if !path.join(VERSION_FILE).exists() {
+        fs::write(path.join(VERSION_FILE), format!("{}\n", REPO_VERSION))?;
+    }
+    Ok(())
+}
+
+/// Check every line of every tag file in the repo at `path`, returning the invalid lines with the names of their tags.
+/// Invalid lines are skipped when tags are listed, so these are the updates which have lost their tags
+pub fn check_tags(path: impl AsRef<Path>) -> Result<Vec<(String, TagLineError)>, CheckError> {
+    let tag_dir = path.as_ref().join("tag");
+    let read_err = |err| CheckError::Read(tag_dir.clone(), err);
+    let tag_repo = TagRepo::new(&tag_dir).map_err(read_err)?;
+    let mut invalid = vec![];
+    for tag in tag_repo.list_tags().map_err(read_err)? {
+        for update_ref in tag_repo.list_updates_in_tag(tag.name()).map_err(read_err)? {
+            if let Err(err) = update_ref {
+                invalid.push((tag.name().to_owned(), err));
+            }
+        }
+    }
+    Ok(invalid)
+}
+
+/// Mark a repo as the version written by this version, once it has been migrated
+pub fn upgrade_version(path: impl AsRef<Path>) -> io::Result<()> {
+    fs::write(path.as_ref().join(VERSION_FILE), format!("{}\n", REPO_VERSION))
+}
+
+/// The version of the repo at `path`, repos written before there was a version file are version 1
+pub fn repo_version(path: impl AsRef<Path>) -> Result<u32, CheckError> {
+    let path = path.as_ref();
+    let version_path = path.join(VERSION_FILE);
+    match fs::read_to_string(&version_path) {
+        Ok(version) => version
+            .trim()
+            .parse()
+            .map_err(|_| CheckError::InvalidVersion(version.trim().to_owned())),
+        Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(1),
+        Err(err) => Err(CheckError::Read(version_path, err)),
+    }
+}
+
+/// Read the first leaf found in the url tree, checking its name if it is named by a timestamp. An empty tree is fine,
+/// it is a new repo
+fn sample_leaf(dir: &Path) -> Result<bool, CheckError> {
+    let read_err = |err| CheckError::Read(dir.to_owned(), err);
+    let mut entries = fs::read_dir(dir)
+        .and_then(|dir| dir.collect::<io::Result<Vec<_>>>())
+        .map_err(read_err)?;
+    entries.sort_by_key(fs::DirEntry::file_name);
+    for entry in entries {
+        let path = entry.path();
+        if entry.file_type().map_err(read_err)?.is_dir() {
+            if sample_leaf(&path)? {
+                return Ok(true);
+            }
+            continue;
+        }
+        let file_name = entry.file_name();
+        let (repo_key, name) = match file_name.to_str().and_then(|name| name.strip_prefix('<')?.split_once('>')) {
+            Some(leaf) => leaf,
+            None => continue,
+        };
+        let (timestamp, seq) = match repo_key {
+            // updates after the first to a url at a timestamp have a `~seq` suffix
+            "update" => name.split_once('~').unwrap_or((name, "0")),
+            _ => (name, "0"),
+        };
+        if matches!(repo_key, "update" | "docver")
+            && (timestamp.parse::<DateTime<FixedOffset>>().is_err() || seq.parse::<u32>().is_err())
+        {
+            return Err(CheckError::InvalidLeaf(path));
+        }
+        fs::read(&path).map_err(|err| CheckError::Read(path, err))?;
+        return Ok(true);
+    }
+    Ok(false)
+}
+
+#[derive(Debug)]
+pub enum CheckError {
+    NotADirectory(PathBuf),
+    /// One of the directories every repo has
+    MissingDir(PathBuf),
+    InvalidVersion(String),
+    /// Written by a newer version with a layout this version doesn't know
+    UnsupportedVersion(u32),
+    /// A leaf whose name can't be parsed
+    InvalidLeaf(PathBuf),
+    Read(PathBuf, io::Error),
+}
+
+impl fmt::Display for CheckError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            CheckError::NotADirectory(path) => write!(f, "{:?} is not a directory, check the repo path", path),
+            CheckError::MissingDir(path) => write!(
+                f,
+                "{:?} is missing, check the repo path or create the directory if this is a new repo",
+                path
+            ),
+            CheckError::InvalidVersion(version) => write!(f, "The repo version {:?} is not a number", version),
+            CheckError::UnsupportedVersion(version) => write!(
+                f,
+                "The repo is version {} but only up to version {} is supported, upgrade to use it",
+                version, REPO_VERSION
+            ),
+            CheckError::InvalidLeaf(path) => write!(f, "{:?} is not named by a timestamp, the repo may be corrupt", path),
+            CheckError::Read(path, err) => write!(f, "Failed reading {:?} : {}, check its permissions", path, err),
+        }
+    }
+}
+
+impl error::Error for CheckError {}
+
+#[cfg(test)]
+mod test {
+    use std::fs;
+
+    use super::*;
+
+    #[test]
+    fn repos_are_checked() {
+        let path = Path::new("tmp/check_repo");
+        let _ = fs::remove_dir_all(path);
+        assert!(matches!(check_repo(path), Err(CheckError::NotADirectory(_))));
+
+        fs::create_dir_all(path.join("url")).unwrap();
+        assert!(matches!(check_repo(path), Err(CheckError::MissingDir(_))));
+
+        assert_eq!(repo_version(path).unwrap(), 1);
+        init_repo(path).unwrap();
+        check_repo(path).unwrap();
+        assert_eq!(repo_version(path).unwrap(), REPO_VERSION);
+
+        let node = path.join("url/www.gov.uk/guidance");
+        fs::create_dir_all(&node).unwrap();
+        fs::write(node.join("<update>2022-02-17T09:53:57+00:00"), "Change").unwrap();
+        check_repo(path).unwrap();
+        fs::write(node.join("<docver>yesterday"), "").unwrap();
+        assert!(matches!(check_repo(path), Err(CheckError::InvalidLeaf(_))));
+        fs::remove_file(node.join("<docver>yesterday")).unwrap();
+
+        fs::write(
+            path.join("tag/brexit"),
+            "2022-02-17T09:53:57+00:00|https://www.gov.uk/guidance\nhttps://www.gov.uk/guidance\n",
+        )
+        .unwrap();
+        let invalid = check_tags(path).unwrap();
+        assert!(matches!(invalid.as_slice(), [(tag, TagLineError::Parse { line: 2, .. })] if tag == "brexit"));
+
+        fs::write(path.join(VERSION_FILE), format!("{}\n", REPO_VERSION + 1)).unwrap();
+        assert!(matches!(check_repo(path), Err(CheckError::UnsupportedVersion(version)) if version == REPO_VERSION + 1));
+    }
+}
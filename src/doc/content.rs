@@ -5,13 +5,15 @@ use html5ever::{
     local_name, ns,
     serialize::{SerializeOpts, TraversalScope},
     tendril::{StrTendril, TendrilSink},
-    Attribute, ParseOpts,
+    ParseOpts,
 };
 use html5streams::{
     css_select,
     selector::{ContextualSelector, Selector},
     HtmlContext, HtmlPathElement, HtmlSerializer, HtmlSink, RootFilter,
 };
+use update_diff::sanitise_fragment;
+pub use update_diff::HtmlSanitizer;
 use url::Url;
 
 #[derive(Debug, Eq, PartialEq)]
@@ -22,7 +24,8 @@ pub struct Doc {
 
 #[derive(Debug, Eq, PartialEq)]
 pub enum DocContent {
-    DiffableHtml(String, Vec<Url>, Vec<DocUpdate>),
+    /// The sanitised html, its attachments, its change history and when it was withdrawn if it has been
+    DiffableHtml(String, Vec<Url>, Vec<DocUpdate>, Option<DateTime<Utc>>),
     Other(Vec<u8>),
 }
 
@@ -36,19 +39,23 @@ impl DocContent {
             traversal_scope: TraversalScope::IncludeNode,
             create_missing_parent: false,
         };
-        // stream is main selection & sanitiser ( -> attachment extractor ) ( -> history selector -> history extractor ) -> serializer
+        // stream is main selection & sanitiser ( -> attachment extractor ) ( -> history selector -> history extractor ) ( -> withdrawn extractor ) -> serializer
         let attachment_extractor = AttachmentExtractor::default();
         let history_extractor =
             RootFilter::<_, _, _, Vec<_>>::wrap(HistoryExtractor::default(), css_select!((#"full-history") ("li")));
+        let withdrawn_extractor = WithdrawnExtractor::default();
         let mut buf = Vec::new();
         let mut html_serializer = HtmlSerializer::new(&mut buf, opts);
-        let sink = HtmlSanitizer::wrap(((attachment_extractor, history_extractor), &mut html_serializer));
+        let sink = HtmlSanitizer::wrap((
+            (attachment_extractor, (history_extractor, withdrawn_extractor)),
+            &mut html_serializer,
+        ));
 
         let mut parse_opts = ParseOpts::default();
         parse_opts.tree_builder.exact_errors = true;
         let parser = html5streams::parse_document(sink, parse_opts);
 
-        let ((attachments, history), ()) = parser.from_utf8().read_from(html)?.unwrap(); // TODO fail on non-utf-8 instead of ignoring and any failure here should lead to a non-html doc
+        let ((attachments, (history, withdrawn_at)), ()) = parser.from_utf8().read_from(html)?.unwrap(); // TODO fail on non-utf-8 instead of ignoring and any failure here should lead to a non-html doc
 
         let attachments = attachments.into_iter();
         let attachments: Vec<Url> = if let Some(url) = url {
@@ -65,33 +72,42 @@ impl DocContent {
             String::from_utf8(buf).unwrap(),
             attachments,
             history.into_iter().collect::<Result<_, _>>()?,
+            withdrawn_at,
         ))
     }
 
     pub fn is_html(&self) -> bool {
         match self {
-            Self::DiffableHtml(_, _, _) => true,
+            Self::DiffableHtml(..) => true,
             Self::Other(_) => false,
         }
     }
 
     pub fn as_bytes(&self) -> &[u8] {
         match self {
-            DocContent::DiffableHtml(string, _, _) => string.as_bytes(),
+            DocContent::DiffableHtml(string, ..) => string.as_bytes(),
             DocContent::Other(bytes) => bytes.as_slice(),
         }
     }
 
     pub fn history(&self) -> Option<&[DocUpdate]> {
         match self {
-            DocContent::DiffableHtml(_, _, history) => Some(history.as_slice()),
+            DocContent::DiffableHtml(_, _, history, _) => Some(history.as_slice()),
             DocContent::Other(_) => None,
         }
     }
 
     pub fn attachments(&self) -> Option<&[Url]> {
         match self {
-            DocContent::DiffableHtml(_, attachments, _) => Some(attachments.as_slice()),
+            DocContent::DiffableHtml(_, attachments, ..) => Some(attachments.as_slice()),
+            DocContent::Other(_) => None,
+        }
+    }
+
+    /// When the doc was withdrawn, if it has a withdrawn notice
+    pub fn withdrawn_at(&self) -> Option<DateTime<Utc>> {
+        match self {
+            DocContent::DiffableHtml(_, _, _, withdrawn_at) => *withdrawn_at,
             DocContent::Other(_) => None,
         }
     }
@@ -107,140 +123,13 @@ impl DocUpdate {
     pub fn new(date: DateTime<Utc>, summary: impl Into<String>) -> Self {
         Self(date, summary.into())
     }
-}
 
-pub struct HtmlSanitizer<InputHandle: Eq + Copy, S: HtmlSink<InputHandle>> {
-    inner: S,
-    skip_handle: Option<InputHandle>,
-    main_handle: Option<InputHandle>,
-}
-
-impl<InputHandle: Eq + Copy, S: HtmlSink<InputHandle>> HtmlSanitizer<InputHandle, S> {
-    pub fn wrap(sink: S) -> Self {
-        Self {
-            inner: sink,
-            skip_handle: None,
-            main_handle: None,
-        }
+    pub fn date(&self) -> &DateTime<Utc> {
+        &self.0
     }
-}
-
-impl<InputHandle: Eq + Copy, S: HtmlSink<InputHandle>> HtmlSink<InputHandle> for HtmlSanitizer<InputHandle, S> {
-    type Output = S::Output;
 
-    fn append_doctype_to_document(
-        &mut self,
-        _name: &html5ever::tendril::StrTendril,
-        _public_id: &html5ever::tendril::StrTendril,
-        _system_id: &html5ever::tendril::StrTendril,
-    ) {
-    }
-
-    fn append_element(
-        &mut self,
-        mut context: HtmlContext<'_, InputHandle>,
-        element: &HtmlPathElement<'_, InputHandle>,
-    ) {
-        // select
-        if let Some(select_handle) = self.main_handle {
-            if let Some(select_index) = context
-                .iter()
-                .enumerate()
-                .find_map(|(index, elem)| (elem.handle == select_handle).then(|| index))
-            {
-                context = &context[select_index..];
-            } else {
-                // select ends
-                self.main_handle = None;
-                return;
-            }
-        }
-        if self.main_handle.is_none() && css_select!("main").is_match(element) {
-            // select starts
-            context = &[];
-            let select_handle = element.handle;
-            self.main_handle = Some(select_handle);
-        } else if self.main_handle.is_none() {
-            return;
-        }
-
-        // skip
-        if let Some(skip_handle) = self.skip_handle {
-            if context.iter().any(|elem| elem.handle == skip_handle) {
-                return;
-            } else {
-                self.skip_handle = None
-            }
-        }
-        let mut attrs: Vec<_> = element
-            .attrs
-            .iter()
-            .filter(|Attribute { name, value: _ }| !["id", "aria-labelledby", "aria-hidden"].contains(&&*name.local))
-            .cloned() // TODO : avoid cloning when not necessary
-            .collect();
-        let skip = attrs.iter().any(|Attribute { name, value }| {
-            &name.local == "class"
-                && value
-                    .split_whitespace()
-                    .any(|class| class == "gem-c-contextual-sidebar")
-        });
-        if skip {
-            self.skip_handle = Some(element.handle);
-            return;
-        }
-        attrs.sort();
-        let mut element = element.clone();
-        element.attrs = attrs.into();
-        self.inner.append_element(context, &element)
-    }
-
-    fn append_text(&mut self, context: HtmlContext<InputHandle>, text: &str) {
-        if let Some(select_handle) = self.main_handle {
-            if let Some(select_index) = context
-                .iter()
-                .enumerate()
-                .find_map(|(index, elem)| (elem.handle == select_handle).then(|| index))
-            {
-                let context = &context[select_index..];
-                if let Some(skip_handle) = self.skip_handle {
-                    if context.iter().any(|elem| elem.handle == skip_handle) {
-                        return;
-                    } else {
-                        self.skip_handle = None
-                    }
-                }
-                self.inner.append_text(context, text)
-            } else {
-                self.main_handle = None
-            }
-        }
-    }
-
-    fn append_comment(&mut self, context: HtmlContext<InputHandle>, text: &str) {
-        if let Some(select_handle) = self.main_handle {
-            if let Some(select_index) = context
-                .iter()
-                .enumerate()
-                .find_map(|(index, elem)| (elem.handle == select_handle).then(|| index))
-            {
-                let context = &context[select_index..];
-                if let Some(skip_handle) = self.skip_handle {
-                    if context.iter().any(|elem| elem.handle == skip_handle) {
-                        return;
-                    } else {
-                        self.skip_handle = None
-                    }
-                }
-                self.inner.append_comment(context, text)
-            } else {
-                self.main_handle = None
-            }
-        }
-    }
-
-    fn reset(&mut self) -> Self::Output {
-        self.skip_handle = None;
-        self.inner.reset()
+    pub fn summary(&self) -> &str {
+        &self.1
     }
 }
 
@@ -334,10 +223,61 @@ impl HtmlSink<u32> for HistoryExtractor {
     }
 }
 
+/// Finds the time in a notice that the doc has been withdrawn, gov.uk keeps withdrawn docs up with a notice rather than removing them
+#[derive(Default)]
+struct WithdrawnExtractor {
+    /// the text of a notice title says the doc was withdrawn
+    in_withdrawn_notice: bool,
+    withdrawn_at: Option<DateTime<Utc>>,
+}
+
+impl HtmlSink<u32> for WithdrawnExtractor {
+    type Output = Option<DateTime<Utc>>;
+
+    fn append_doctype_to_document(
+        &mut self,
+        _name: &html5ever::tendril::StrTendril,
+        _public_id: &html5ever::tendril::StrTendril,
+        _system_id: &html5ever::tendril::StrTendril,
+    ) {
+    }
+
+    fn append_element(&mut self, context: HtmlContext<'_, u32>, element: &HtmlPathElement<'_, u32>) {
+        use html5ever::*;
+        const DATETIME: QualName = QualName {
+            prefix: None,
+            ns: ns!(),
+            local: local_name!("datetime"),
+        };
+
+        if self.in_withdrawn_notice && css_select!((."gem-c-notice__title") ("time")).context_match(context, element) {
+            self.withdrawn_at = element.attr(DATETIME).and_then(|datetime| datetime.parse().ok());
+            self.in_withdrawn_notice = false;
+        }
+    }
+
+    fn append_text(&mut self, context: HtmlContext<u32>, text: &str) {
+        if text.contains("withdrawn")
+            && context
+                .iter()
+                .any(|elem| css_select!((."gem-c-notice__title")).context_match(&[], elem))
+        {
+            self.in_withdrawn_notice = true;
+        }
+    }
+
+    fn append_comment(&mut self, _context: HtmlContext<u32>, _text: &str) {}
+
+    fn reset(&mut self) -> Self::Output {
+        self.in_withdrawn_notice = false;
+        self.withdrawn_at.take()
+    }
+}
+
 pub fn sanitise_doc(
     reader: &mut (impl io::Read + io::Seek),
     writer: &mut impl io::Write,
-    mut buf: &mut Vec<u8>,
+    buf: &mut Vec<u8>,
 ) -> io::Result<()> {
     let mut prefix = [0; 5];
     let is_html_main = reader.read_exact(&mut prefix).is_ok() && &prefix == b"<main";
@@ -346,36 +286,13 @@ pub fn sanitise_doc(
         return io::copy(reader, writer).map(|_| ());
     }
 
-    buf.clear();
-    let opts = SerializeOpts {
-        scripting_enabled: false,
-        traversal_scope: TraversalScope::IncludeNode,
-        create_missing_parent: false,
-    };
-    let mut html_serializer = HtmlSerializer::new(&mut buf, opts);
-    let sink = HtmlSanitizer::wrap(&mut html_serializer);
-
-    let mut parse_opts = ParseOpts::default();
-    parse_opts.tree_builder.exact_errors = true;
-    let parser = html5streams::parse_fragment(sink, parse_opts);
-
-    match parser.from_utf8().read_from(reader) {
+    match sanitise_fragment(reader, buf) {
         Err(err) => {
-            eprintln!("Error sanitising document {}, copying instead.", err);
+            eprintln!("{}, copying instead.", err);
             reader.rewind()?;
             io::copy(reader, writer).map(|_| ())
         }
-        Ok(Err(err)) => {
-            eprintln!("Parse error {}, copying instead.", err);
-            reader.rewind()?;
-            io::copy(reader, writer).map(|_| ())
-        }
-        _ if buf.is_empty() => {
-            eprintln!("Sanitisation left nothing behind, copying instead");
-            reader.rewind()?;
-            io::copy(reader, writer).map(|_| ())
-        }
-        _ => writer.write_all(&buf[..]),
+        Ok(()) => writer.write_all(&buf[..]),
     }
 }
 
@@ -445,4 +362,21 @@ mod test {
         assert_eq!(a.as_bytes().len(), 7660);
         assert_eq!(a.attachments(), Some(&[][..]));
     }
+
+    #[test]
+    fn withdrawn_notice() {
+        let html = r#"<html><body><main>
+            <div class="gem-c-notice"><h2 class="gem-c-notice__title">This publication was withdrawn on <time datetime="2021-05-07T09:45:00.000+01:00">7 May 2021</time></h2></div>
+            <p>Some guidance</p>
+        </main></body></html>"#;
+        let doc = DocContent::html(&mut html.as_bytes(), None).unwrap();
+        assert_eq!(doc.withdrawn_at(), Some("2021-05-07T08:45:00Z".parse().unwrap()));
+
+        let doc = DocContent::html(
+            &mut doc_html(),
+            Some(&"https://www.gov.uk/register-to-vote".parse().unwrap()),
+        )
+        .unwrap();
+        assert_eq!(doc.withdrawn_at(), None);
+    }
 }
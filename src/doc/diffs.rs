@@ -0,0 +1,144 @@
+//! Diffs between neighbouring versions of docs, stored beside the versions so that they can be computed ahead of being
+//! shown rather than while a request waits. The diffs are of a version, bumped whenever the diffing changes, the diffs
+//! of other versions are never read and are removed when a doc's diffs are pruned
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+use chrono::{DateTime, FixedOffset};
+
+use super::DocumentVersion;
+use crate::{repository::Durability, url::UrlRepo, Url};
+
+pub struct DiffRepo {
+    repo: UrlRepo,
+    /// the version of the diffs which are read and written, it is part of their leaf names
+    version: u32,
+    durability: Durability,
+}
+
+impl DiffRepo {
+    pub fn new(base: impl AsRef<Path>, version: u32) -> io::Result<Self> {
+        Ok(Self {
+            repo: UrlRepo::new("docdiff", base)?,
+            version,
+            durability: Durability::default(),
+        })
+    }
+
+    /// The diff between two versions of a doc, if it has been stored
+    pub fn read(&self, from: &DocumentVersion, to: &DocumentVersion) -> io::Result<Option<String>> {
+        match fs::read_to_string(self.diff_path(from, to)) {
+            Ok(diff) => Ok(Some(diff)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    pub fn contains(&self, from: &DocumentVersion, to: &DocumentVersion) -> bool {
+        self.diff_path(from, to).exists()
+    }
+
+    /// Store the diff between two versions of a doc. It is written beside its leaf and then moved into place, so that
+    /// a part written diff is never read
+    pub fn write(&self, from: &DocumentVersion, to: &DocumentVersion, diff: &str) -> io::Result<()> {
+        assert_eq!(from.url(), to.url(), "diffs are between versions of the same doc");
+        let path = self.diff_path(from, to);
+        fs::create_dir_all(self.repo.node_path(to.url()))?;
+        let tmp_path = path.with_extension("writing");
+        let mut file = fs::File::create(&tmp_path)?;
+        io::Write::write_all(&mut file, diff.as_bytes())?;
+        self.durability.sync(&file, &tmp_path)?;
+        fs::rename(&tmp_path, &path)
+    }
+
+    /// Remove the diffs of a doc which aren't between neighbours of `versions`, such as those of a version which has been
+    /// coalesced, and those of other versions of the diffing. Returns how many were removed
+    pub fn prune(&self, url: &Url, versions: &[DocumentVersion]) -> io::Result<usize> {
+        let mut timestamps: Vec<DateTime<FixedOffset>> = versions.iter().map(|version| *version.timestamp()).collect();
+        timestamps.sort_unstable();
+        let leaves = match self.repo.read_leaves_for_url(url) {
+            Ok(leaves) => leaves,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let mut removed = 0;
+        for leaf in leaves {
+            let (name, entry) = leaf?;
+            let neighbours = self
+                .parse_name(&name)
+                .and_then(|(from, to)| timestamps.windows(2).find(|pair| pair[0] == from && pair[1] == to));
+            if neighbours.is_none() {
+                fs::remove_file(entry.path())?;
+                removed += 1;
+            }
+        }
+        Ok(removed)
+    }
+
+    fn diff_path(&self, from: &DocumentVersion, to: &DocumentVersion) -> PathBuf {
+        let name = format!(
+            "{}_{}_{}",
+            self.version,
+            from.timestamp().to_rfc3339(),
+            to.timestamp().to_rfc3339()
+        );
+        self.repo.leaf_path(to.url(), &name)
+    }
+
+    /// The timestamps of the versions a diff is between, if it is of this version of the diffing
+    fn parse_name(&self, name: &str) -> Option<(DateTime<FixedOffset>, DateTime<FixedOffset>)> {
+        let mut parts = name.split('_');
+        if parts.next()? != self.version.to_string() {
+            return None;
+        }
+        let from = parts.next()?.parse().ok()?;
+        let to = parts.next()?.parse().ok()?;
+        parts.next().is_none().then_some((from, to))
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn diffs_are_pruned_once_their_versions_are_not_neighbours() {
+        let base = "tmp/diffs::diffs_are_pruned_once_their_versions_are_not_neighbours";
+        let _ = fs::remove_dir_all(base);
+        let repo = DiffRepo::new(base, 2).unwrap();
+        let url: Url = "https://www.gov.uk/guidance/a".parse().unwrap();
+        let version = |timestamp: &str| DocumentVersion {
+            url: url.clone(),
+            timestamp: timestamp.parse().unwrap(),
+        };
+        let (first, second, third) = (
+            version("2022-02-17T09:00:00+00:00"),
+            version("2022-02-18T09:00:00+00:00"),
+            version("2022-02-19T09:00:00+00:00"),
+        );
+        assert_eq!(repo.read(&first, &second).unwrap(), None);
+        repo.write(&first, &second, "<del>a</del><ins>b</ins>").unwrap();
+        repo.write(&second, &third, "<del>b</del><ins>c</ins>").unwrap();
+        assert_eq!(
+            repo.read(&first, &second).unwrap().as_deref(),
+            Some("<del>a</del><ins>b</ins>")
+        );
+        assert!(repo.contains(&second, &third));
+        // the diffs of another version of the diffing aren't read
+        DiffRepo::new(base, 1).unwrap().write(&first, &second, "old").unwrap();
+        assert_eq!(DiffRepo::new(base, 3).unwrap().read(&first, &second).unwrap(), None);
+
+        // the second version was coalesced
+        assert_eq!(repo.prune(&url, &[third.clone(), first.clone()]).unwrap(), 3);
+        assert!(!repo.contains(&first, &second));
+        assert!(!repo.contains(&second, &third));
+        repo.write(&first, &third, "<del>a</del><ins>c</ins>").unwrap();
+        assert_eq!(repo.prune(&url, &[first.clone(), third.clone()]).unwrap(), 0);
+        assert!(repo.contains(&first, &third));
+        let other: Url = "https://www.gov.uk/guidance/b".parse().unwrap();
+        assert_eq!(repo.prune(&other, &[]).unwrap(), 0);
+    }
+}
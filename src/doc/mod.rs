@@ -4,15 +4,17 @@ use crate::{repository::Entity, Url};
 use chrono::{DateTime, FixedOffset};
 
 pub mod content;
+mod diffs;
 mod repository;
-pub use repository::DocRepo;
+pub use diffs::DiffRepo;
+pub use repository::{CompactStats, CompressStats, DocRepo, WriteStats};
 
 #[derive(Debug, PartialEq, Eq)]
 pub struct Document {
     url: Url,
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, Clone, PartialEq, Eq)]
 pub struct DocumentVersion {
     url: Url,
     timestamp: DateTime<FixedOffset>,
@@ -28,6 +30,39 @@ impl DocumentVersion {
     }
 }
 
+/// The content of a [`DocumentVersion`], read into memory, or with the `mmap` feature, large content is mapped so that
+/// it isn't copied into memory until it is used
+pub enum StoredContent {
+    Read(Vec<u8>),
+    #[cfg(feature = "mmap")]
+    Mapped(memmap2::Mmap),
+}
+
+impl StoredContent {
+    /// The content as text, docs are html apart from some attachments
+    pub fn as_str(&self) -> Result<&str, std::str::Utf8Error> {
+        std::str::from_utf8(self)
+    }
+}
+
+impl std::ops::Deref for StoredContent {
+    type Target = [u8];
+
+    fn deref(&self) -> &Self::Target {
+        match self {
+            StoredContent::Read(content) => content,
+            #[cfg(feature = "mmap")]
+            StoredContent::Mapped(content) => content,
+        }
+    }
+}
+
+impl AsRef<[u8]> for StoredContent {
+    fn as_ref(&self) -> &[u8] {
+        self
+    }
+}
+
 impl Entity for DocumentVersion {
     type WriteEvent = DocEvent;
 }
@@ -50,6 +85,68 @@ pub enum DocEvent {
     Created { url: Url },
     Updated { url: Url, timestamp: DateTime<FixedOffset> },
     Deleted { url: Url, timestamp: DateTime<FixedOffset> },
+    /// The page at the url was removed from the site at `timestamp`, the event's doc is the last version before it was
+    Removed { url: Url, timestamp: DateTime<FixedOffset> },
+    /// The event's doc has a notice that it was withdrawn at `timestamp`, gov.uk keeps withdrawn pages up rather than removing them
+    Withdrawn { url: Url, timestamp: DateTime<FixedOffset> },
+    /// The event's doc is the first version of the doc in the repo, and was captured as it was `first`
+    First { url: Url, first: FirstVersion },
+}
+
+/// How the first version of a doc in the repo was captured, it is recorded when the version is written as it can't be
+/// told reliably from the versions afterwards
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum FirstVersion {
+    /// the doc was new, it was captured when it was first published
+    Published,
+    /// the doc was already published, it was captured when it was first tracked
+    Captured,
+}
+
+impl FirstVersion {
+    /// Classify the first version of a doc from the change history on the page, and the number of updates to the doc
+    /// up to the version for when there is no history. GOV.UK lists only "First published." in the history of a new
+    /// doc, a doc which has been updated before it is captured has more in its history
+    pub fn classify(history: Option<&[content::DocUpdate]>, updates: usize) -> Self {
+        let is_new = match history {
+            Some(history) if !history.is_empty() => history.len() == 1,
+            _ => updates == 1,
+        };
+        if is_new {
+            FirstVersion::Published
+        } else {
+            FirstVersion::Captured
+        }
+    }
+
+    /// How the first version is labelled in listings
+    pub fn label(self) -> &'static str {
+        match self {
+            FirstVersion::Published => "First published",
+            FirstVersion::Captured => "First capture",
+        }
+    }
+}
+
+impl fmt::Display for FirstVersion {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            FirstVersion::Published => "published",
+            FirstVersion::Captured => "captured",
+        })
+    }
+}
+
+impl std::str::FromStr for FirstVersion {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "published" => Ok(FirstVersion::Published),
+            "captured" => Ok(FirstVersion::Captured),
+            _ => Err(format!("Unknown first version {:?}", s)),
+        }
+    }
 }
 
 impl DocEvent {
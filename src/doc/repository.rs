@@ -1,26 +1,97 @@
 use super::*;
 use crate::{
-    repository::WriteResult,
+    repository::{normalize_timestamp, Durability, EventSink, WriteResult},
     url::{IterUrlRepoLeaves, UrlRepo},
 };
 
-use chrono::DateTime;
+use chrono::{DateTime, Duration};
 use core::panic;
+use flate2::{read::GzDecoder, write::GzEncoder, Compression};
 use std::{
     error::Error,
     fs,
-    io::{self, Read},
+    io::{self, Read, Seek},
     path::{Path, PathBuf},
+    sync::{Arc, Mutex},
 };
 
 pub struct DocRepo {
     repo: UrlRepo,
+    /// A tombstone per url which has been removed from the site, holding the timestamp it was found to be removed
+    removed_markers: UrlRepo,
+    /// A marker per version which has a withdrawn notice, holding the timestamp it was withdrawn
+    withdrawn_markers: UrlRepo,
+    /// A marker per doc named by how its first version was captured
+    first_markers: UrlRepo,
+    /// the persisted [`WriteStats`], writes are serialised so that concurrent ones aren't lost
+    write_stats: Mutex<PathBuf>,
+    event_sink: Option<Arc<dyn EventSink>>,
+    /// a new latest version of a url replaces the previous version if it was written within this long before
+    coalesce_window: Option<Duration>,
+    durability: Durability,
+    /// writes aren't compared with their neighbouring versions
+    bulk: bool,
+    /// new versions are stored compressed
+    compress: bool,
 }
 
 impl DocRepo {
+    /// Content larger than this is mapped rather than read with the `mmap` feature, smaller content is cheaper to read
+    pub const MMAP_THRESHOLD: u64 = 1024 * 1024;
+
     pub fn new(base: impl AsRef<Path>) -> io::Result<Self> {
-        let repo = UrlRepo::new("docver", base)?;
-        Ok(Self { repo })
+        let repo = UrlRepo::new("docver", &base)?;
+        let removed_markers = UrlRepo::new("docremoved", &base)?;
+        let withdrawn_markers = UrlRepo::new("docwithdrawn", &base)?;
+        let first_markers = UrlRepo::new("docfirst", &base)?;
+        // named like a leaf so that it isn't mistaken for a url's directory
+        let write_stats = Mutex::new(base.as_ref().join("<docstats>"));
+        Ok(Self {
+            repo,
+            removed_markers,
+            withdrawn_markers,
+            first_markers,
+            write_stats,
+            event_sink: None,
+            coalesce_window: None,
+            durability: Durability::default(),
+            bulk: false,
+            compress: false,
+        })
+    }
+
+    /// Send the events of all writes to a sink
+    pub fn with_event_sink(mut self, event_sink: Arc<dyn EventSink>) -> Self {
+        self.event_sink = Some(event_sink);
+        self
+    }
+
+    /// Replace the previous version of a url with a new, different, latest version if the previous one is less than `window` older, so that rapid successive fetches don't clutter the history
+    pub fn with_coalesce_window(mut self, window: Duration) -> Self {
+        self.coalesce_window = Some(window);
+        self
+    }
+
+    /// Replace how writes are made durable
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Write versions without comparing them with their neighbouring versions, for large imports where looking up the
+    /// versions of the url for each write dominates. Duplicates and versions in the coalesce window are then all kept,
+    /// [`Self::compact_all`] removes the duplicates afterwards
+    pub fn with_bulk_mode(mut self) -> Self {
+        self.bulk = true;
+        self
+    }
+
+    /// Store new versions gzip compressed, they are decompressed when they are read. Versions are read whether they were
+    /// stored compressed or not, so this can be turned on for a repo which has versions already, though only a repo of
+    /// version 3 or later may have compressed versions as earlier versions of the repo would read them as they are
+    pub fn with_compression(mut self) -> Self {
+        self.compress = true;
+        self
     }
 
     /// Create a [`DocumentVersion`] and return a writer to write the content
@@ -30,19 +101,43 @@ impl DocRepo {
         timestamp: DateTime<FixedOffset>,
         write_avoidance_buffer: &'r mut Vec<u8>,
     ) -> io::Result<DeduplicatingWriter<'r>> {
-        let doc = DocumentVersion { url, timestamp };
+        let doc = DocumentVersion {
+            url,
+            timestamp: normalize_timestamp(timestamp),
+        };
         write_avoidance_buffer.clear();
         DeduplicatingWriter::new(doc, self, write_avoidance_buffer)
     }
 
     /// Open a [`DocumentVersion`] for reading
     pub fn open(&self, doc_version: &DocumentVersion) -> io::Result<impl io::Read + io::Seek> {
-        fs::File::open(self.path_for_version(doc_version))
+        open_version(&self.path_for_version(doc_version))
+    }
+
+    /// Read the content of a [`DocumentVersion`], with the `mmap` feature content larger than [`Self::MMAP_THRESHOLD`] is
+    /// mapped rather than read
+    pub fn read(&self, doc_version: &DocumentVersion) -> io::Result<StoredContent> {
+        let mut file = fs::File::open(self.path_for_version(doc_version))?;
+        if is_compressed(&mut file)? {
+            return Ok(StoredContent::Read(decompress(file)?));
+        }
+        #[cfg(feature = "mmap")]
+        if file.metadata()?.len() > Self::MMAP_THRESHOLD {
+            // safety: versions are never written to once they are created, only replaced or deleted, which leaves the
+            // mapping of the old file valid
+            return Ok(StoredContent::Mapped(unsafe { memmap2::Mmap::map(&file)? }));
+        }
+        let mut content = vec![];
+        file.read_to_end(&mut content)?;
+        Ok(StoredContent::Read(content))
     }
 
     /// Ensure that a [`DocumentVersion`] exists for a given url and timestamp
     pub fn ensure_version(&self, url: Url, timestamp: DateTime<FixedOffset>) -> io::Result<DocumentVersion> {
-        let doc_version = DocumentVersion { url, timestamp };
+        let doc_version = DocumentVersion {
+            url,
+            timestamp: normalize_timestamp(timestamp),
+        };
         fs::File::open(self.path_for_version(&doc_version))?;
         Ok(doc_version)
     }
@@ -100,6 +195,36 @@ impl DocRepo {
         }))
     }
 
+    /// The latest version of a url at a time, if it had one by then
+    pub fn version_at(&self, url: &Url, at: DateTime<FixedOffset>) -> io::Result<Option<DocumentVersion>> {
+        for version in self.list_versions(url.clone())? {
+            let version = version?;
+            if version.timestamp <= at {
+                return Ok(Some(version));
+            }
+        }
+        Ok(None)
+    }
+
+    /// The urls one path segment under a url which have directories, in order. They may only have other leaves, such as
+    /// updates, or only urls under them with versions
+    pub fn child_urls(&self, url: &Url) -> io::Result<Vec<Url>> {
+        self.repo.child_urls(url)
+    }
+
+    /// Whether a url, or any url under it, had a version at a time. The search stops at the first version found
+    pub fn existed_at(&self, url: &Url, at: DateTime<FixedOffset>) -> io::Result<bool> {
+        if self.version_at(url, at)?.is_some() {
+            return Ok(true);
+        }
+        for child in self.child_urls(url)? {
+            if self.existed_at(&child, at)? {
+                return Ok(true);
+            }
+        }
+        Ok(false)
+    }
+
     /// Lists all updates
     pub fn list_all(&self, base_url: &Url) -> io::Result<IterUrlRepoLeaves<'_, DocumentVersion>> {
         self.repo.list_all(base_url.clone(), |url, name, _| {
@@ -119,11 +244,515 @@ impl DocRepo {
         }
     }
 
+    /// Record that the page at a url has been removed from the site. Keeps the earlier timestamp if it was already removed and hasn't been seen since
+    pub fn mark_removed(&self, url: Url, timestamp: DateTime<FixedOffset>) -> WriteResult<DocumentVersion, 1> {
+        let timestamp = normalize_timestamp(timestamp);
+        let last_version = self
+            .list_versions(url.clone())?
+            .next()
+            .unwrap_or_else(|| Err(io::ErrorKind::NotFound.into()))?;
+        if self.removed_after(&last_version)?.is_some() {
+            return last_version.with_events([None]);
+        }
+        self.durability
+            .write(self.removed_marker_path(&url), timestamp.to_rfc3339())?;
+        let written = last_version.with_events([Some(DocEvent::Removed { url, timestamp })]);
+        if let (Some(sink), Ok(written)) = (&self.event_sink, &written) {
+            written.for_each_event(|doc, event| sink.on_doc(doc, event));
+        }
+        written
+    }
+
+    /// When the page at a url was removed from the site, if it hasn't been seen again since
+    pub fn removed_at(&self, url: &Url) -> io::Result<Option<DateTime<FixedOffset>>> {
+        match self.list_versions(url.clone())?.next() {
+            Some(last_version) => self.removed_after(&last_version?),
+            None => Ok(None),
+        }
+    }
+
+    /// Lists the last version of each url under a url prefix which has been removed, along with when it was removed
+    pub fn list_removed(
+        &self,
+        base_url: &Url,
+    ) -> io::Result<impl Iterator<Item = io::Result<(DocumentVersion, DateTime<FixedOffset>)>> + '_> {
+        let markers = self.removed_markers.list_all(base_url.clone(), |url, _, _| url)?;
+        Ok(markers.filter_map(move |url| {
+            let removed = url.and_then(|url| {
+                let last_version = match self.list_versions(url)?.next() {
+                    Some(last_version) => last_version?,
+                    None => return Ok(None),
+                };
+                Ok(self
+                    .removed_after(&last_version)?
+                    .map(|removed_at| (last_version, removed_at)))
+            });
+            removed.transpose()
+        }))
+    }
+
+    /// The removal timestamp of a url, if it is later than its last version
+    fn removed_after(&self, last_version: &DocumentVersion) -> io::Result<Option<DateTime<FixedOffset>>> {
+        match fs::read_to_string(self.removed_marker_path(&last_version.url)) {
+            Ok(timestamp) => {
+                let timestamp: DateTime<FixedOffset> = timestamp
+                    .trim()
+                    .parse()
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                Ok((timestamp > last_version.timestamp).then_some(timestamp))
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    fn removed_marker_path(&self, url: &Url) -> PathBuf {
+        self.removed_markers.leaf_path(url, "")
+    }
+
+    /// Record that a version has a notice that the doc was withdrawn
+    pub fn mark_withdrawn(
+        &self,
+        doc_version: &DocumentVersion,
+        withdrawn_at: DateTime<FixedOffset>,
+    ) -> WriteResult<DocumentVersion, 1> {
+        let withdrawn_at = normalize_timestamp(withdrawn_at);
+        let doc_version = self.ensure_version(doc_version.url.clone(), doc_version.timestamp)?;
+        if self.withdrawn_at(&doc_version)? == Some(withdrawn_at) {
+            return doc_version.with_events([None]);
+        }
+        self.durability
+            .write(self.withdrawn_marker_path(&doc_version), withdrawn_at.to_rfc3339())?;
+        let event = DocEvent::Withdrawn {
+            url: doc_version.url.clone(),
+            timestamp: withdrawn_at,
+        };
+        let written = doc_version.with_events([Some(event)]);
+        if let (Some(sink), Ok(written)) = (&self.event_sink, &written) {
+            written.for_each_event(|doc, event| sink.on_doc(doc, event));
+        }
+        written
+    }
+
+    /// When the doc was withdrawn, if the version has a withdrawn notice
+    pub fn withdrawn_at(&self, doc_version: &DocumentVersion) -> io::Result<Option<DateTime<FixedOffset>>> {
+        match fs::read_to_string(self.withdrawn_marker_path(doc_version)) {
+            Ok(timestamp) => timestamp
+                .trim()
+                .parse()
+                .map(Some)
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => Ok(None),
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Lists the urls under a url prefix whose latest version has a withdrawn notice, along with when they were withdrawn
+    pub fn list_withdrawn(
+        &self,
+        base_url: &Url,
+    ) -> io::Result<impl Iterator<Item = io::Result<(DocumentVersion, DateTime<FixedOffset>)>> + '_> {
+        let markers = self.withdrawn_markers.list_all(base_url.clone(), |url, name, _| (url, name.to_owned()))?;
+        Ok(markers.filter_map(move |marker| {
+            let withdrawn = marker.and_then(|(url, name)| {
+                let timestamp: DateTime<FixedOffset> = name
+                    .parse()
+                    .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+                let latest = match self.list_versions(url)?.next() {
+                    Some(latest) => latest?,
+                    None => return Ok(None),
+                };
+                if latest.timestamp != timestamp {
+                    return Ok(None);
+                }
+                Ok(self.withdrawn_at(&latest)?.map(|withdrawn_at| (latest, withdrawn_at)))
+            });
+            withdrawn.transpose()
+        }))
+    }
+
+    /// Record how the first version of a doc was captured, a doc keeps the classification it was first marked with
+    pub fn mark_first_version(
+        &self,
+        doc_version: &DocumentVersion,
+        first: FirstVersion,
+    ) -> WriteResult<DocumentVersion, 1> {
+        let doc_version = self.ensure_version(doc_version.url.clone(), doc_version.timestamp)?;
+        if self.first_version(&doc_version.url)?.is_some() {
+            return doc_version.with_events([None]);
+        }
+        self.durability
+            .write(self.first_markers.leaf_path(&doc_version.url, &first.to_string()), "")?;
+        let event = DocEvent::First {
+            url: doc_version.url.clone(),
+            first,
+        };
+        let written = doc_version.with_events([Some(event)]);
+        if let (Some(sink), Ok(written)) = (&self.event_sink, &written) {
+            written.for_each_event(|doc, event| sink.on_doc(doc, event));
+        }
+        written
+    }
+
+    /// How the first version of the doc at a url was captured, if it was recorded
+    pub fn first_version(&self, url: &Url) -> io::Result<Option<FirstVersion>> {
+        let mut markers = match self.first_markers.read_leaves_for_url(url) {
+            Ok(markers) => markers,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(err) => return Err(err),
+        };
+        match markers.next() {
+            Some(marker) => Ok(Some(parse_first_version(&marker?.0)?)),
+            None => Ok(None),
+        }
+    }
+
+    /// Lists the urls under a url prefix with how the first version of their doc was captured, for those where it was
+    /// recorded
+    pub fn list_first_versions(
+        &self,
+        base_url: &Url,
+    ) -> io::Result<impl Iterator<Item = io::Result<(Url, FirstVersion)>> + '_> {
+        let markers = self.first_markers.list_all(base_url.clone(), |url, name, _| (url, name.to_owned()))?;
+        Ok(markers.map(|marker| {
+            let (url, name) = marker?;
+            Ok((url, parse_first_version(&name)?))
+        }))
+    }
+
+    /// The counts of the versions written to the repo since the counts were added
+    pub fn write_stats(&self) -> io::Result<WriteStats> {
+        let path = self.write_stats.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        WriteStats::read(&path)
+    }
+
+    /// Count a write of a version of `len` bytes, which wasn't stored if it was `deduplicated`. The counts are only for
+    /// reporting, so failing to record them is logged rather than failing a write which has already been made
+    fn record_write(&self, len: u64, deduplicated: bool) {
+        self.record_stats(|stats| {
+            stats.writes += 1;
+            if deduplicated {
+                stats.deduplicated += 1;
+                stats.bytes_avoided += len;
+            } else {
+                stats.bytes_stored += len;
+            }
+        })
+    }
+
+    /// Count a stored version of `len` bytes which was removed by [`Self::compact`] as a deduplicated write, the counts
+    /// can have started after it was written so the bytes stored don't go below zero
+    fn record_compacted(&self, len: u64) {
+        self.record_stats(|stats| {
+            stats.deduplicated += 1;
+            stats.bytes_stored = stats.bytes_stored.saturating_sub(len);
+            stats.bytes_avoided += len;
+        })
+    }
+
+    fn record_stats(&self, record: impl FnOnce(&mut WriteStats)) {
+        let path = self.write_stats.lock().unwrap_or_else(|poisoned| poisoned.into_inner());
+        let result = WriteStats::read(&path).and_then(|mut stats| {
+            record(&mut stats);
+            self.durability.write(&*path, stats.to_string())
+        });
+        if let Err(err) = result {
+            eprintln!("Recording the write in {:?} failed : {}", path, err);
+        }
+    }
+
+    /// Lists the urls under a url prefix with the total size of their stored versions, in url order
+    pub fn stored_bytes(&self, base_url: &Url) -> io::Result<impl Iterator<Item = io::Result<(Url, u64)>> + '_> {
+        let mut versions = self
+            .repo
+            .list_all(base_url.clone(), |url, _, dir_entry| (url, dir_entry.metadata()))?
+            .peekable();
+        Ok(std::iter::from_fn(move || {
+            let (url, metadata) = match versions.next()? {
+                Ok(version) => version,
+                Err(err) => return Some(Err(err)),
+            };
+            let mut total = match metadata {
+                Ok(metadata) => metadata.len(),
+                Err(err) => return Some(Err(err)),
+            };
+            // an url's versions are listed together
+            while let Some(Ok((next_url, _))) = versions.peek() {
+                if *next_url != url {
+                    break;
+                }
+                match versions.next()?.map(|(_, metadata)| metadata) {
+                    Ok(Ok(metadata)) => total += metadata.len(),
+                    Ok(Err(err)) | Err(err) => return Some(Err(err)),
+                }
+            }
+            Some(Ok((url, total)))
+        }))
+    }
+
+    /// The `n` urls under a url prefix with the most bytes stored, largest first
+    pub fn largest_docs(&self, base_url: &Url, n: usize) -> io::Result<Vec<(Url, u64)>> {
+        let mut largest: Vec<(Url, u64)> = self.stored_bytes(base_url)?.collect::<io::Result<_>>()?;
+        largest.sort_by(|(url_a, a), (url_b, b)| b.cmp(a).then_with(|| url_a.cmp(url_b)));
+        largest.truncate(n);
+        Ok(largest)
+    }
+
+    /// Remove the versions of a url which are identical to the version before them, as they would have been deduplicated
+    /// when they were written, along with their withdrawn markers, sending a deleted event for each to the sink. Each is
+    /// counted as a deduplicated write in the write stats
+    pub fn compact(&self, url: &Url) -> io::Result<CompactStats> {
+        let mut versions: Vec<DocumentVersion> = self.list_versions(url.clone())?.collect::<io::Result<_>>()?;
+        versions.reverse();
+        let mut stats = CompactStats::default();
+        let mut previous: Option<DocumentVersion> = None;
+        for version in versions {
+            if let Some(previous) = &previous {
+                let path = self.path_for_version(&version);
+                if same_content(&self.path_for_version(previous), &path)? {
+                    let len = fs::metadata(&path)?.len();
+                    // the stats count the content as it was written, before it was compressed
+                    let content_len = io::copy(&mut open_version(&path)?, &mut io::sink())?;
+                    fs::remove_file(&path)?;
+                    self.remove_withdrawn_marker(&version)?;
+                    self.record_compacted(content_len);
+                    stats.versions_removed += 1;
+                    stats.bytes_reclaimed += len;
+                    if let Some(sink) = &self.event_sink {
+                        sink.on_doc(&version, &DocEvent::deleted(&version));
+                    }
+                    continue;
+                }
+            }
+            previous = Some(version);
+        }
+        Ok(stats)
+    }
+
+    /// [`Self::compact`] each url under a url prefix
+    pub fn compact_all(&self, base_url: &Url) -> io::Result<CompactStats> {
+        let mut urls: Vec<Url> = vec![];
+        for url in self.repo.list_all(base_url.clone(), |url, _, _| url)? {
+            let url = url?;
+            // an url's versions are listed together
+            if urls.last() != Some(&url) {
+                urls.push(url);
+            }
+        }
+        let mut stats = CompactStats::default();
+        for url in urls {
+            stats += self.compact(&url)?;
+        }
+        Ok(stats)
+    }
+
+    /// Delete a version, such as one captured from the wrong page, along with its withdrawn marker. Returns error if
+    /// there is no such version
+    pub fn delete_version(&self, doc_version: &DocumentVersion) -> WriteResult<DocumentVersion, 1> {
+        let doc_version = self.ensure_version(doc_version.url.clone(), doc_version.timestamp)?;
+        fs::remove_file(self.path_for_version(&doc_version))?;
+        self.remove_withdrawn_marker(&doc_version)?;
+        let event = DocEvent::deleted(&doc_version);
+        let written = doc_version.with_events([Some(event)]);
+        if let (Some(sink), Ok(written)) = (&self.event_sink, &written) {
+            written.for_each_event(|doc, event| sink.on_doc(doc, event));
+        }
+        written
+    }
+
+    /// Compress a version which was stored uncompressed, it is written beside its leaf and then moved over it, so that the
+    /// version can be read throughout. Returns the bytes reclaimed, or `None` if it was compressed already
+    pub fn compress(&self, doc_version: &DocumentVersion) -> io::Result<Option<u64>> {
+        let path = self.path_for_version(doc_version);
+        let mut file = fs::File::open(&path)?;
+        if is_compressed(&mut file)? {
+            return Ok(None);
+        }
+        let len = file.metadata()?.len();
+        // not named like a leaf, so that it is never listed as a version
+        let tmp_path = path.with_file_name(format!(
+            "{}.compressing",
+            normalize_timestamp(doc_version.timestamp).to_rfc3339()
+        ));
+        let mut encoder = GzEncoder::new(fs::File::create(&tmp_path)?, Compression::default());
+        io::copy(&mut file, &mut encoder)?;
+        let compressed = encoder.finish()?;
+        self.durability.sync(&compressed, &tmp_path)?;
+        fs::rename(&tmp_path, &path)?;
+        Ok(Some(len.saturating_sub(compressed.metadata()?.len())))
+    }
+
+    /// [`Self::compress`] each version under a url prefix
+    pub fn compress_all(&self, base_url: &Url) -> io::Result<CompressStats> {
+        let mut stats = CompressStats::default();
+        for version in self.list_all(base_url)? {
+            if let Some(reclaimed) = self.compress(&version?)? {
+                stats.versions_compressed += 1;
+                stats.bytes_reclaimed += reclaimed;
+            }
+        }
+        Ok(stats)
+    }
+
+    /// Remove the withdrawn marker of a version which is being removed, if it has one
+    fn remove_withdrawn_marker(&self, doc_version: &DocumentVersion) -> io::Result<()> {
+        match fs::remove_file(self.withdrawn_marker_path(doc_version)) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => Err(err),
+            _ => Ok(()),
+        }
+    }
+
+    fn withdrawn_marker_path(&self, doc_version: &DocumentVersion) -> PathBuf {
+        self.withdrawn_markers
+            .leaf_path(&doc_version.url, &normalize_timestamp(doc_version.timestamp).to_rfc3339())
+    }
+
     fn path_for_version(&self, DocumentVersion { url, timestamp }: &DocumentVersion) -> PathBuf {
-        self.repo.leaf_path(url, &timestamp.to_rfc3339())
+        self.repo.leaf_path(url, &normalize_timestamp(*timestamp).to_rfc3339())
     }
 }
 
+/// Counts of the versions written to a doc repo, a write is deduplicated when it is the same as a version next to it,
+/// so it isn't stored
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct WriteStats {
+    pub writes: u64,
+    pub deduplicated: u64,
+    pub bytes_stored: u64,
+    pub bytes_avoided: u64,
+}
+
+impl WriteStats {
+    fn read(path: &Path) -> io::Result<Self> {
+        let stats = match fs::read_to_string(path) {
+            Ok(stats) => stats,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(Self::default()),
+            Err(err) => return Err(err),
+        };
+        let mut read = Self::default();
+        for line in stats.lines() {
+            let (name, count) = line.split_once(' ').unwrap_or((line, ""));
+            let count = count
+                .parse()
+                .map_err(|error| io::Error::new(io::ErrorKind::InvalidData, error))?;
+            match name {
+                "writes" => read.writes = count,
+                "deduplicated" => read.deduplicated = count,
+                "bytes_stored" => read.bytes_stored = count,
+                "bytes_avoided" => read.bytes_avoided = count,
+                _ => {}
+            }
+        }
+        Ok(read)
+    }
+}
+
+impl fmt::Display for WriteStats {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        writeln!(f, "writes {}", self.writes)?;
+        writeln!(f, "deduplicated {}", self.deduplicated)?;
+        writeln!(f, "bytes_stored {}", self.bytes_stored)?;
+        writeln!(f, "bytes_avoided {}", self.bytes_avoided)
+    }
+}
+
+/// What compacting a doc repo removed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompactStats {
+    pub versions_removed: u64,
+    pub bytes_reclaimed: u64,
+}
+
+impl std::ops::AddAssign for CompactStats {
+    fn add_assign(&mut self, other: Self) {
+        self.versions_removed += other.versions_removed;
+        self.bytes_reclaimed += other.bytes_reclaimed;
+    }
+}
+
+/// What compressing a doc repo compressed
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct CompressStats {
+    pub versions_compressed: u64,
+    pub bytes_reclaimed: u64,
+}
+
+/// The first bytes of a gzip stream, html and the attachments which are tracked never start with them, so compressed
+/// versions are told apart from those stored as they are by them
+const GZIP_MAGIC: [u8; 2] = [0x1f, 0x8b];
+
+/// Whether a version's file is compressed, leaving it at its start
+fn is_compressed(file: &mut fs::File) -> io::Result<bool> {
+    let mut magic = [0; 2];
+    let compressed = match file.read_exact(&mut magic) {
+        Ok(()) => magic == GZIP_MAGIC,
+        Err(err) if err.kind() == io::ErrorKind::UnexpectedEof => false,
+        Err(err) => return Err(err),
+    };
+    file.rewind()?;
+    Ok(compressed)
+}
+
+fn decompress(file: fs::File) -> io::Result<Vec<u8>> {
+    let mut content = vec![];
+    GzDecoder::new(file).read_to_end(&mut content)?;
+    Ok(content)
+}
+
+/// Open a version's file, a compressed version is decompressed into memory
+fn open_version(path: &Path) -> io::Result<VersionReader> {
+    let mut file = fs::File::open(path)?;
+    if is_compressed(&mut file)? {
+        Ok(VersionReader::Decompressed(io::Cursor::new(decompress(file)?)))
+    } else {
+        Ok(VersionReader::File(file))
+    }
+}
+
+/// The content of a version, whether it was stored compressed or not
+enum VersionReader {
+    File(fs::File),
+    Decompressed(io::Cursor<Vec<u8>>),
+}
+
+impl io::Read for VersionReader {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        match self {
+            VersionReader::File(file) => file.read(buf),
+            VersionReader::Decompressed(content) => content.read(buf),
+        }
+    }
+}
+
+impl io::Seek for VersionReader {
+    fn seek(&mut self, pos: io::SeekFrom) -> io::Result<u64> {
+        match self {
+            VersionReader::File(file) => file.seek(pos),
+            VersionReader::Decompressed(content) => content.seek(pos),
+        }
+    }
+}
+
+/// Whether two versions' files have the same content, either may be compressed
+fn same_content(a: &Path, b: &Path) -> io::Result<bool> {
+    let (mut file_a, mut file_b) = (fs::File::open(a)?, fs::File::open(b)?);
+    // versions compressed the same way are the same size if they have the same content
+    let same_compression = is_compressed(&mut file_a)? == is_compressed(&mut file_b)?;
+    if same_compression && file_a.metadata()?.len() != file_b.metadata()?.len() {
+        return Ok(false);
+    }
+    let read = |path| -> io::Result<Vec<u8>> {
+        let mut content = vec![];
+        open_version(path)?.read_to_end(&mut content)?;
+        Ok(content)
+    };
+    Ok(read(a)? == read(b)?)
+}
+
+fn parse_first_version(name: &str) -> io::Result<FirstVersion> {
+    name.parse().map_err(|error: String| io::Error::new(io::ErrorKind::InvalidData, error))
+}
+
 const DUPLICATE_CHECK_BUFFER_SIZE: usize = 1024;
 const WRITE_AVOIDANCE_BUFFER_LIMIT: usize = 32 * 1024;
 
@@ -133,9 +762,13 @@ pub struct DeduplicatingWriter<'r> {
     state: DeduplicatingWriterState<'r>,
     repo: &'r DocRepo,
     /// if `Some` this is a version that is timestamped directly before the one being written, as as far as the current doc has been written, both are identical
-    identical_before: Option<(DocumentVersion, fs::File)>,
+    identical_before: Option<(DocumentVersion, VersionReader)>,
     /// like `identical_before` but with a version timestamped directly after the one being written
-    identical_after: Option<(DocumentVersion, fs::File)>,
+    identical_after: Option<(DocumentVersion, VersionReader)>,
+    /// the previous version, which will be replaced if this one is different, as it is within the coalesce window
+    coalesce_with: Option<DocumentVersion>,
+    /// the number of bytes written so far
+    len: u64,
     buffer: [u8; DUPLICATE_CHECK_BUFFER_SIZE],
 }
 enum DeduplicatingWriterState<'b> {
@@ -154,13 +787,24 @@ impl<'r> DeduplicatingWriter<'r> {
             fs::create_dir_all(parent)?;
         }
         let open_neighbour = |dv| -> io::Result<_> {
-            let path = repo.path_for_version(&dv);
-            let file = fs::File::open(&path)?;
+            let file = open_version(&repo.path_for_version(&dv))?;
             Ok((dv, file))
         };
-        let (before, after) = repo
-            .neighbours(&doc)
-            .map_err(|e| NeighbourCheckError::io(e, &"Finding neighbours"))?;
+        let (before, after) = if repo.bulk {
+            (None, None)
+        } else {
+            repo.neighbours(&doc)
+                .map_err(|e| NeighbourCheckError::io(e, &"Finding neighbours"))?
+        };
+        let coalesce_with = match (&before, &after, repo.coalesce_window) {
+            (Some(before), None, Some(window)) if before.timestamp >= doc.timestamp - window => {
+                Some(DocumentVersion {
+                    url: before.url.clone(),
+                    timestamp: before.timestamp,
+                })
+            }
+            _ => None,
+        };
         let identical_before = before.map(open_neighbour).transpose()?;
         let identical_after = after.map(open_neighbour).transpose()?;
         Ok(Self {
@@ -176,6 +820,8 @@ impl<'r> DeduplicatingWriter<'r> {
             repo,
             identical_before,
             identical_after,
+            coalesce_with,
+            len: 0,
             buffer: [0; DUPLICATE_CHECK_BUFFER_SIZE],
         })
     }
@@ -188,14 +834,14 @@ impl<'r> DeduplicatingWriter<'r> {
                 ref mut file,
                 is_new_doc,
             } => {
-                file.flush()?;
+                self.repo.durability.sync(file, &self.repo.path_for_version(&self.doc))?;
                 (is_new_doc, file)
             }
             DeduplicatingWriterState::Buffering(ref buffer) => {
                 let path = self.repo.path_for_version(&self.doc);
                 let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
                 file.write_all(buffer.get_ref())?;
-                file.flush()?;
+                self.repo.durability.sync(&file, &path)?;
                 self.state = DeduplicatingWriterState::Writing {
                     file,
                     is_new_doc: false,
@@ -209,7 +855,16 @@ impl<'r> DeduplicatingWriter<'r> {
         })
     }
 
-    pub fn done(mut self) -> WriteResult<DocumentVersion, 2> {
+    pub fn done(self) -> WriteResult<DocumentVersion, 2> {
+        let repo = self.repo;
+        let written = self.finish()?;
+        if let Some(sink) = &repo.event_sink {
+            written.for_each_event(|doc, event| sink.on_doc(doc, event));
+        }
+        Ok(written)
+    }
+
+    fn finish(mut self) -> WriteResult<DocumentVersion, 2> {
         if let Some((_, file)) = &mut self.identical_before {
             if file.read(&mut [0]).is_err() {
                 // file is EOF, so finishes at this point too
@@ -226,14 +881,31 @@ impl<'r> DeduplicatingWriter<'r> {
             if let DeduplicatingWriterState::Writing { .. } = self.state {
                 fs::remove_file(self.repo.path_for_version(&self.doc))?;
             }
+            self.repo.record_write(self.len, true);
             return before.with_events([None, None]);
         }
         let (is_new_doc, _file) = self.really_flush()?;
+        // written as it is and compressed once it is complete, as it is compared with its neighbours as it is written
+        if self.repo.compress {
+            self.repo.compress(&self.doc)?;
+        }
+        if self.identical_after.is_none() {
+            self.repo.record_write(self.len, false);
+        }
         if let Some((after, _)) = self.identical_after {
             fs::remove_file(self.repo.path_for_version(&after))?;
+            self.repo.remove_withdrawn_marker(&after)?;
+            // the later version was the same, so the repo is no larger for the write
+            self.repo.record_write(self.len, true);
             let events = [Some(DocEvent::updated(&self.doc)), Some(DocEvent::deleted(&after))];
             return self.doc.with_events(events);
         }
+        if let Some(previous) = self.coalesce_with.take() {
+            fs::remove_file(self.repo.path_for_version(&previous))?;
+            self.repo.remove_withdrawn_marker(&previous)?;
+            let events = [Some(DocEvent::updated(&self.doc)), Some(DocEvent::deleted(&previous))];
+            return self.doc.with_events(events);
+        }
         let events = [
             Some(DocEvent::updated(&self.doc)),
             is_new_doc.then(|| DocEvent::created(&self.doc)),
@@ -298,6 +970,7 @@ impl io::Write for DeduplicatingWriter<'_> {
                 }
             }
         };
+        self.len += written as u64;
         for check in buf[0..written].chunks(DUPLICATE_CHECK_BUFFER_SIZE) {
             self.check_duplicate_neighbours(check)?;
         }
@@ -532,6 +1205,246 @@ mod test {
         );
     }
 
+    #[test]
+    fn rapid_versions_are_coalesced() {
+        let repo = test_repo("rapid_versions_are_coalesced").with_coalesce_window(chrono::Duration::minutes(10));
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let first_timestamp: DateTime<FixedOffset> = "2022-02-17T09:00:00+00:00".parse().unwrap();
+        let second_timestamp = first_timestamp + chrono::Duration::minutes(5);
+        let third_timestamp = second_timestamp + chrono::Duration::minutes(30);
+
+        let mut write_avoidance_buffer = Vec::new();
+        let mut write = |timestamp, content: &str| {
+            let mut write = repo
+                .create(url.clone(), timestamp, &mut write_avoidance_buffer)
+                .unwrap();
+            write.write_all(content.as_bytes()).unwrap();
+            write.done().unwrap().into_events().collect::<Vec<_>>()
+        };
+        write(first_timestamp, "first");
+        let previous = repo.ensure_version(url.clone(), first_timestamp).unwrap();
+        repo.mark_withdrawn(&previous, first_timestamp).unwrap();
+
+        assert_eq!(
+            write(second_timestamp, "second"),
+            [
+                DocEvent::Updated {
+                    url: url.clone(),
+                    timestamp: second_timestamp
+                },
+                DocEvent::Deleted {
+                    url: url.clone(),
+                    timestamp: first_timestamp
+                }
+            ]
+        );
+        assert_eq!(
+            write(third_timestamp, "third"),
+            [DocEvent::Updated {
+                url: url.clone(),
+                timestamp: third_timestamp
+            }]
+        );
+
+        let versions: Vec<_> = repo
+            .list_versions(url.clone())
+            .unwrap()
+            .map(|v| v.unwrap().timestamp)
+            .collect();
+        assert_eq!(versions, [third_timestamp, second_timestamp]);
+        // the coalesced version's withdrawn marker goes with it
+        assert!(!repo.withdrawn_marker_path(&previous).exists());
+    }
+
+    #[test]
+    fn removed_page_is_marked_until_seen_again() {
+        let repo = test_repo("doc::removed_page_is_marked_until_seen_again");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let ts = |ts: &str| -> DateTime<FixedOffset> { ts.parse().unwrap() };
+        let mut write_avoidance_buffer = Vec::new();
+        let mut write_version = |timestamp, content: &str| {
+            let mut write = repo.create(url.clone(), timestamp, &mut write_avoidance_buffer).unwrap();
+            write.write_all(content.as_bytes()).unwrap();
+            write.done().unwrap().into_inner()
+        };
+        let last_version = write_version(ts("2021-03-01T10:00:00+00:00"), "content");
+        assert_eq!(repo.removed_at(&url).unwrap(), None);
+
+        let removed = repo.mark_removed(url.clone(), ts("2021-03-02T10:00:00+00:00")).unwrap();
+        assert_eq!(*removed, last_version);
+        assert_eq!(
+            removed.into_events().collect::<Vec<_>>(),
+            [DocEvent::Removed {
+                url: url.clone(),
+                timestamp: ts("2021-03-02T10:00:00+00:00")
+            }]
+        );
+        // still gone the next time it's fetched
+        let removed = repo.mark_removed(url.clone(), ts("2021-03-03T10:00:00+00:00")).unwrap();
+        assert_eq!(removed.into_events().count(), 0);
+        assert_eq!(repo.removed_at(&url).unwrap(), Some(ts("2021-03-02T10:00:00+00:00")));
+        let listed: Vec<_> = repo
+            .list_removed(&"http://www.example.org/".parse().unwrap())
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(listed, [(last_version, ts("2021-03-02T10:00:00+00:00"))]);
+
+        write_version(ts("2021-03-04T10:00:00+00:00"), "restored");
+        assert_eq!(repo.removed_at(&url).unwrap(), None);
+        assert_eq!(
+            repo.list_removed(&"http://www.example.org/".parse().unwrap())
+                .unwrap()
+                .count(),
+            0
+        );
+    }
+
+    #[test]
+    fn withdrawn_versions_are_marked() {
+        let repo = test_repo("doc::withdrawn_versions_are_marked");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let ts = |ts: &str| -> DateTime<FixedOffset> { ts.parse().unwrap() };
+        let mut write_avoidance_buffer = Vec::new();
+        let mut write_version = |timestamp, content: &str| {
+            let mut write = repo.create(url.clone(), timestamp, &mut write_avoidance_buffer).unwrap();
+            write.write_all(content.as_bytes()).unwrap();
+            write.done().unwrap().into_inner()
+        };
+        let live = write_version(ts("2021-03-01T10:00:00+00:00"), "guidance");
+        let withdrawn = write_version(ts("2021-03-02T10:00:00+00:00"), "withdrawn guidance");
+
+        let written = repo.mark_withdrawn(&withdrawn, ts("2021-03-02T09:00:00+00:00")).unwrap();
+        assert_eq!(
+            written.into_events().collect::<Vec<_>>(),
+            [DocEvent::Withdrawn {
+                url: url.clone(),
+                timestamp: ts("2021-03-02T09:00:00+00:00")
+            }]
+        );
+        // marking again doesn't change anything
+        let written = repo.mark_withdrawn(&withdrawn, ts("2021-03-02T09:00:00+00:00")).unwrap();
+        assert_eq!(written.into_events().count(), 0);
+
+        assert_eq!(repo.withdrawn_at(&live).unwrap(), None);
+        assert_eq!(repo.withdrawn_at(&withdrawn).unwrap(), Some(ts("2021-03-02T09:00:00+00:00")));
+        // only the latest version counts, and the markers aren't listed as versions
+        let base_url = "http://www.example.org/".parse().unwrap();
+        let listed: Vec<_> = repo.list_withdrawn(&base_url).unwrap().map(Result::unwrap).collect();
+        assert_eq!(listed, [(withdrawn, ts("2021-03-02T09:00:00+00:00"))]);
+        assert_eq!(repo.list_versions(url.clone()).unwrap().count(), 2);
+
+        write_version(ts("2021-03-03T10:00:00+00:00"), "reinstated guidance");
+        assert_eq!(repo.list_withdrawn(&base_url).unwrap().count(), 0);
+    }
+
+    #[test]
+    fn deleted_versions_are_gone_with_their_markers() {
+        let repo = test_repo("doc::deleted_versions_are_gone_with_their_markers");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let ts = |ts: &str| -> DateTime<FixedOffset> { ts.parse().unwrap() };
+        let mut write_avoidance_buffer = Vec::new();
+        let mut write_version = |timestamp, content: &str| {
+            let mut write = repo.create(url.clone(), timestamp, &mut write_avoidance_buffer).unwrap();
+            write.write_all(content.as_bytes()).unwrap();
+            write.done().unwrap().into_inner()
+        };
+        let good = write_version(ts("2021-03-01T10:00:00+00:00"), "guidance");
+        let bogus = write_version(ts("2021-03-02T10:00:00+00:00"), "another page");
+        repo.mark_withdrawn(&bogus, ts("2021-03-02T09:00:00+00:00")).unwrap();
+
+        let deleted = repo.delete_version(&bogus).unwrap();
+        assert_eq!(
+            deleted.into_events().collect::<Vec<_>>(),
+            [DocEvent::Deleted {
+                url: url.clone(),
+                timestamp: bogus.timestamp
+            }]
+        );
+        let remaining: Vec<_> = repo.list_versions(url.clone()).unwrap().map(Result::unwrap).collect();
+        assert_eq!(remaining, [good.clone()]);
+        assert_eq!(repo.withdrawn_at(&bogus).unwrap(), None);
+        assert!(matches!(repo.delete_version(&bogus), Err(err) if err.kind() == io::ErrorKind::NotFound));
+        assert_eq!(repo.read(&good).unwrap().as_str().unwrap(), "guidance");
+    }
+
+    #[test]
+    fn first_versions_are_marked() {
+        let repo = test_repo("doc::first_versions_are_marked");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let mut write_avoidance_buffer = Vec::new();
+        let mut write = repo
+            .create(url.clone(), "2021-03-01T10:00:00+00:00".parse().unwrap(), &mut write_avoidance_buffer)
+            .unwrap();
+        write.write_all(b"new guidance").unwrap();
+        let first = write.done().unwrap().into_inner();
+        assert_eq!(repo.first_version(&url).unwrap(), None);
+
+        let written = repo.mark_first_version(&first, FirstVersion::Published).unwrap();
+        assert_eq!(
+            written.into_events().collect::<Vec<_>>(),
+            [DocEvent::First {
+                url: url.clone(),
+                first: FirstVersion::Published
+            }]
+        );
+        // the first classification is kept
+        let written = repo.mark_first_version(&first, FirstVersion::Captured).unwrap();
+        assert_eq!(written.into_events().count(), 0);
+        assert_eq!(repo.first_version(&url).unwrap(), Some(FirstVersion::Published));
+
+        let base_url = "http://www.example.org/".parse().unwrap();
+        let listed: Vec<_> = repo.list_first_versions(&base_url).unwrap().map(Result::unwrap).collect();
+        assert_eq!(listed, [(url.clone(), FirstVersion::Published)]);
+        assert_eq!(repo.list_versions(url).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn writes_are_kept_when_they_cant_be_counted() {
+        let repo = test_repo("doc::writes_are_kept_when_they_cant_be_counted");
+        // the counts can't be read or replaced while there is a dir in their place
+        fs::create_dir_all("tmp/doc::writes_are_kept_when_they_cant_be_counted/<docstats>/dir").unwrap();
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let mut write_avoidance_buffer = Vec::new();
+        let mut write = repo
+            .create(url.clone(), "2021-03-01T10:00:00+00:00".parse().unwrap(), &mut write_avoidance_buffer)
+            .unwrap();
+        write.write_all(b"guidance").unwrap();
+        write.done().unwrap();
+
+        assert_eq!(repo.list_versions(url).unwrap().count(), 1);
+    }
+
+    #[test]
+    fn deduplicated_writes_are_counted() {
+        let repo = test_repo("doc::deduplicated_writes_are_counted");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let mut write_avoidance_buffer = Vec::new();
+        let mut write_version = |timestamp: &str, content: &str| {
+            let mut write = repo
+                .create(url.clone(), timestamp.parse().unwrap(), &mut write_avoidance_buffer)
+                .unwrap();
+            write.write_all(content.as_bytes()).unwrap();
+            write.done().unwrap();
+        };
+        write_version("2021-03-01T10:00:00+00:00", "guidance");
+        write_version("2021-03-02T10:00:00+00:00", "guidance");
+        write_version("2021-03-03T10:00:00+00:00", "new guidance");
+
+        assert_eq!(
+            repo.write_stats().unwrap(),
+            WriteStats {
+                writes: 3,
+                deduplicated: 1,
+                bytes_stored: 20,
+                bytes_avoided: 8,
+            }
+        );
+        let base_url = "http://www.example.org/".parse().unwrap();
+        let stored: Vec<_> = repo.stored_bytes(&base_url).unwrap().map(Result::unwrap).collect();
+        assert_eq!(stored, [(url, 20)]);
+    }
+
     #[test]
     fn list_versions() {
         let repo = test_repo("doc::list_versions");
@@ -615,6 +1528,148 @@ mod test {
         assert_eq!(sliced, docs);
     }
 
+    #[test]
+    fn read_content() {
+        let repo = test_repo("read_content");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let mut write_avoidance_buffer = Vec::new();
+        for (minute, len) in [(0, 100), (1, DocRepo::MMAP_THRESHOLD as usize + 1)] {
+            let content = "a".repeat(len);
+            let timestamp = "2021-03-01T10:00:00+00:00".parse::<DateTime<FixedOffset>>().unwrap()
+                + chrono::Duration::minutes(minute);
+            let mut write = repo
+                .create(url.clone(), timestamp, &mut write_avoidance_buffer)
+                .unwrap();
+            write.write_all(content.as_bytes()).unwrap();
+            let doc = write.done().unwrap();
+            assert_eq!(repo.read(&doc).unwrap().as_str(), Ok(content.as_str()));
+        }
+    }
+
+    #[test]
+    fn the_tree_is_browsed_as_it_was() {
+        let repo = test_repo("the_tree_is_browsed_as_it_was");
+        let ts = |ts: &str| -> DateTime<FixedOffset> { ts.parse().unwrap() };
+        let mut write_avoidance_buffer = Vec::new();
+        let mut write_version = |url: &str, timestamp, content: &str| {
+            let mut write = repo.create(url.parse().unwrap(), timestamp, &mut write_avoidance_buffer).unwrap();
+            write.write_all(content.as_bytes()).unwrap();
+            write.done().unwrap().into_inner()
+        };
+        let first = write_version("http://www.example.org/guidance", ts("2021-03-01T10:00:00+00:00"), "guidance");
+        write_version("http://www.example.org/guidance", ts("2021-03-03T10:00:00+00:00"), "new guidance");
+        write_version("http://www.example.org/news/a/story", ts("2021-03-02T10:00:00+00:00"), "story");
+
+        let base: Url = "http://www.example.org/".parse().unwrap();
+        let guidance: Url = "http://www.example.org/guidance".parse().unwrap();
+        let news: Url = "http://www.example.org/news".parse().unwrap();
+        assert_eq!(repo.child_urls(&base).unwrap(), [guidance.clone(), news.clone()]);
+        assert_eq!(repo.version_at(&guidance, ts("2021-03-02T12:00:00+00:00")).unwrap(), Some(first));
+        assert_eq!(repo.version_at(&guidance, ts("2021-02-01T12:00:00+00:00")).unwrap(), None);
+        // news has no versions of its own, but it has a story under it
+        assert_eq!(repo.version_at(&news, ts("2021-03-02T12:00:00+00:00")).unwrap(), None);
+        assert!(repo.existed_at(&news, ts("2021-03-02T12:00:00+00:00")).unwrap());
+        assert!(!repo.existed_at(&news, ts("2021-03-01T12:00:00+00:00")).unwrap());
+    }
+
+    #[test]
+    fn bulk_writes_are_compacted_afterwards() {
+        #[derive(Default)]
+        struct Deletions(Mutex<Vec<DateTime<FixedOffset>>>);
+        impl EventSink for Deletions {
+            fn on_doc(&self, doc: &DocumentVersion, event: &DocEvent) {
+                if let DocEvent::Deleted { .. } = event {
+                    self.0.lock().unwrap().push(doc.timestamp);
+                }
+            }
+        }
+
+        let deletions = Arc::new(Deletions::default());
+        let repo = test_repo("bulk_writes_are_compacted_afterwards")
+            .with_bulk_mode()
+            .with_event_sink(deletions.clone());
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let now = Utc::now();
+        let at = |minutes_ago| normalize_timestamp((now - chrono::Duration::minutes(minutes_ago)).into());
+        let mut write_avoidance_buffer = Vec::new();
+        for (minutes_ago, content) in [(4, "first"), (3, "first"), (2, "second"), (1, "second"), (0, "first")] {
+            let mut write = repo.create(url.clone(), at(minutes_ago), &mut write_avoidance_buffer).unwrap();
+            write.write_all(content.as_bytes()).unwrap();
+            write.done().unwrap();
+        }
+        assert_eq!(repo.list_versions(url.clone()).unwrap().count(), 5);
+        let duplicate = repo.ensure_version(url.clone(), at(3)).unwrap();
+        repo.mark_withdrawn(&duplicate, at(3)).unwrap();
+
+        let stats = repo.compact_all(&"http://www.example.org/".parse().unwrap()).unwrap();
+        assert_eq!(
+            stats,
+            CompactStats {
+                versions_removed: 2,
+                bytes_reclaimed: 11,
+            }
+        );
+        let remaining: Vec<_> = repo
+            .list_versions(url.clone())
+            .unwrap()
+            .map(|version| version.unwrap().timestamp)
+            .collect();
+        assert_eq!(remaining, [at(0), at(2), at(4)]);
+        assert_eq!(*deletions.0.lock().unwrap(), [at(3), at(1)]);
+        assert!(!repo.withdrawn_marker_path(&duplicate).exists());
+        assert_eq!(
+            repo.write_stats().unwrap(),
+            WriteStats {
+                writes: 5,
+                deduplicated: 2,
+                bytes_stored: 16,
+                bytes_avoided: 11,
+            }
+        );
+        assert_eq!(repo.compact(&url).unwrap(), CompactStats::default());
+    }
+
+    #[test]
+    fn compressed_versions_are_read_as_they_were_written() {
+        let repo = test_repo("compressed_versions_are_read_as_they_were_written");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let at = |minute| {
+            "2021-03-01T10:00:00+00:00".parse::<DateTime<FixedOffset>>().unwrap() + chrono::Duration::minutes(minute)
+        };
+        let mut write_avoidance_buffer = Vec::new();
+        let mut write_version = |repo: &DocRepo, minute, content: &str| {
+            let mut write = repo.create(url.clone(), at(minute), &mut write_avoidance_buffer).unwrap();
+            write.write_all(content.as_bytes()).unwrap();
+            write.done().unwrap().into_inner()
+        };
+        let content = "<p>A page</p>".repeat(100);
+        let uncompressed = write_version(&repo, 0, &content);
+        let repo = repo.with_compression();
+        // compared with the uncompressed version before it
+        assert_eq!(write_version(&repo, 1, &content), uncompressed);
+        let compressed = write_version(&repo, 2, "<p>A new page</p>");
+        let path = repo.path_for_version(&compressed);
+        assert_eq!(fs::read(&path).unwrap()[..2], GZIP_MAGIC);
+        assert_eq!(repo.read(&compressed).unwrap().as_str(), Ok("<p>A new page</p>"));
+        let mut buf = String::new();
+        repo.open(&compressed).unwrap().read_to_string(&mut buf).unwrap();
+        assert_eq!(buf, "<p>A new page</p>");
+
+        let stats = repo.compress_all(&"http://www.example.org/".parse().unwrap()).unwrap();
+        assert_eq!(stats.versions_compressed, 1);
+        assert!(stats.bytes_reclaimed > 0);
+        assert_eq!(repo.read(&uncompressed).unwrap().as_str(), Ok(content.as_str()));
+        assert_eq!(repo.compress(&uncompressed).unwrap(), None);
+        // only the versions are left in the doc's directory
+        assert_eq!(fs::read_dir(path.parent().unwrap()).unwrap().count(), 2);
+        // an uncompressed version is still a duplicate of the compressed version before it
+        let bulk_repo = DocRepo::new("tmp/compressed_versions_are_read_as_they_were_written")
+            .unwrap()
+            .with_bulk_mode();
+        write_version(&bulk_repo, 3, "<p>A new page</p>");
+        assert_eq!(repo.compact(&url).unwrap().versions_removed, 1);
+    }
+
     fn test_repo(name: &str) -> DocRepo {
         let path = format!("tmp/{}", name);
         let _ = fs::remove_dir_all(&path);
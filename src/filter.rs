@@ -0,0 +1,400 @@
+use std::{
+    fmt,
+    ops::{Bound, RangeBounds},
+    str::FromStr,
+};
+
+use chrono::{DateTime, Datelike, Duration, FixedOffset, NaiveDate, NaiveDateTime, Utc};
+
+use crate::{tag::Tag, update::UpdateRef, Url};
+
+/// Filter terms which reduce a list of updates, the same syntax is used by the command line tools and the web.
+///
+/// Terms are separated by whitespace, an update has to match all of them. There are four kinds of term:
+/// * `#tag` matches updates with a tag, `#"tag name"` for a tag with spaces in its name
+/// * `https://www.gov.uk/...` matches updates to urls starting with it
+/// * `2022-01..2022-02-15` matches updates from the first date until the second, either can be left out and a date can
+///   be just a year or a year and month
+/// * `1w...1d` matches updates from an age ago until another, either can be left out, eg. `2d...` is the updates of the
+///   last 2 days. An age is a number of years, months, weeks, days or hours, eg. `1y` or `6months`, or several of them,
+///   eg. `1y2m`
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct Filter {
+    /// Filter to only updates with all of these tags
+    pub tags: Vec<Tag>,
+    /// Filter to only updates on urls starting with this url prefix
+    pub url_prefix: Option<Url>,
+    /// Filter to only updates published within a date range
+    date_range: (Bound<NaiveDateTime>, Bound<NaiveDateTime>),
+    /// Filter by age
+    age_range: (Bound<Duration>, Bound<Duration>),
+}
+
+impl Default for Filter {
+    fn default() -> Self {
+        Self {
+            tags: vec![],
+            url_prefix: None,
+            date_range: (Bound::Unbounded, Bound::Unbounded),
+            age_range: (Bound::Unbounded, Bound::Unbounded),
+        }
+    }
+}
+
+impl Filter {
+    /// Parse filter terms which have already been split, such as command line arguments
+    pub fn parse_terms(terms: impl IntoIterator<Item = impl AsRef<str>>) -> Result<Self, FilterError> {
+        let mut filter = Filter::default();
+        for term in terms {
+            filter.add_term(term.as_ref())?;
+        }
+        Ok(filter)
+    }
+
+    fn add_term(&mut self, term: &str) -> Result<(), FilterError> {
+        let err = |reason: &str| FilterError {
+            term: term.to_owned(),
+            reason: reason.to_owned(),
+        };
+        if let Some(tag) = term.strip_prefix("#\"") {
+            // tag until the closing double quote
+            let tag = tag
+                .strip_suffix('"')
+                .ok_or_else(|| err("missing matching double quote"))?;
+            self.tags.push(Tag::new(tag.to_owned()));
+        } else if let Some(tag) = term.strip_prefix('#') {
+            self.tags.push(Tag::new(tag.to_owned()));
+        } else if term.starts_with("https://www.gov.uk/") {
+            let url: url::Url = term.parse().map_err(|_| err("invalid url"))?;
+            if url.fragment().is_some() {
+                return Err(err("a url prefix can't have a fragment"));
+            }
+            self.url_prefix = Some(url.into());
+        } else if term.contains("...") {
+            self.set_age_range(term)?;
+        } else if let Some((from, to)) = term.split_once("..") {
+            self.date_range = (
+                parse_date_bound(from)
+                    .ok_or_else(|| err("invalid date"))?
+                    .map_or(Bound::Unbounded, Bound::Included),
+                parse_date_bound(to)
+                    .ok_or_else(|| err("invalid date"))?
+                    .map_or(Bound::Unbounded, Bound::Excluded),
+            );
+        } else {
+            return Err(err("unrecognised filter"));
+        }
+        Ok(())
+    }
+
+    /// Set the age range from `from..to` or `from...to`, eg. `7d..` for the updates of the last week, as the age
+    /// filter of the web takes it. Either age can be left out
+    pub fn set_age_range(&mut self, range: &str) -> Result<(), FilterError> {
+        let err = |reason: &str| FilterError {
+            term: range.to_owned(),
+            reason: reason.to_owned(),
+        };
+        let (from, to) = range
+            .split_once("...")
+            .or_else(|| range.split_once(".."))
+            .ok_or_else(|| err("expected an age range, eg. 7d.."))?;
+        self.age_range = (
+            parse_age_bound(to)
+                .map_err(|age_err| err(&age_err.to_string()))?
+                .map_or(Bound::Unbounded, Bound::Included),
+            parse_age_bound(from)
+                .map_err(|age_err| err(&age_err.to_string()))?
+                .map_or(Bound::Unbounded, Bound::Excluded),
+        );
+        Ok(())
+    }
+
+    /// Whether the filter has an age range, and so which updates it matches changes over time
+    pub fn is_relative(&self) -> bool {
+        self.age_range != (Bound::Unbounded, Bound::Unbounded)
+    }
+
+    /// Whether the filter has no terms, and so matches every update
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Whether an update matches the url prefix, dates and ages of the filter, the tags of an update aren't known here so
+    /// they are left to the caller
+    pub fn filter_update_ref(&self, update_ref: &UpdateRef) -> bool {
+        if let Some(url_prefix) = &self.url_prefix {
+            if !update_ref.url.as_str().starts_with(url_prefix.as_str()) {
+                return false;
+            }
+        }
+        self.date_range.contains(&update_ref.timestamp.naive_local())
+            && self
+                .age_range
+                .contains(&(DateTime::<FixedOffset>::from(Utc::now()) - update_ref.timestamp))
+    }
+}
+
+/// Splits filter terms on whitespace which isn't in double quotes
+impl FromStr for Filter {
+    type Err = FilterError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut terms = vec![];
+        let mut term_start = None;
+        let mut in_quotes = false;
+        for (index, c) in s.char_indices() {
+            if c.is_whitespace() && !in_quotes {
+                if let Some(start) = term_start.take() {
+                    terms.push(&s[start..index]);
+                }
+                continue;
+            }
+            if c == '"' {
+                in_quotes = !in_quotes;
+            }
+            term_start.get_or_insert(index);
+        }
+        terms.extend(term_start.map(|start| &s[start..]));
+        Self::parse_terms(terms)
+    }
+}
+
+/// A date of a year, a year and month, or a year, month and day, as `2022-02-17`. `None` if it is invalid
+fn parse_date_bound(s: &str) -> Option<Option<NaiveDateTime>> {
+    if s.is_empty() {
+        return Some(None);
+    }
+    let mut date = NaiveDate::from_ymd_opt(0, 1, 1)?;
+    let mut date_parts = s.split('-');
+    date = date.with_year(date_parts.next()?.parse().ok()?)?;
+    if let Some(m) = date_parts.next() {
+        date = date.with_month(m.parse().ok()?)?;
+    }
+    if let Some(d) = date_parts.next() {
+        date = date.with_day(d.parse().ok()?)?;
+    }
+    if date_parts.next().is_some() {
+        return None;
+    }
+    Some(Some(date.and_hms_opt(0, 0, 0)?))
+}
+
+/// An age, `None` if it is empty
+fn parse_age_bound(s: &str) -> Result<Option<Duration>, AgeError> {
+    if s.is_empty() {
+        Ok(None)
+    } else {
+        parse_age(s).map(Some)
+    }
+}
+
+/// Parse an age of one or more numbers of a unit, eg. `6months` or `1y2m`. The units are years (`y`, `year` or
+/// `years`), months (`m`, `month`, `months`), weeks, days and hours in the same way, a year is taken to be 53 weeks and
+/// a month 30 days
+pub fn parse_age(s: &str) -> Result<Duration, AgeError> {
+    if s.is_empty() {
+        return Err(AgeError::Empty);
+    }
+    let mut seconds: i64 = 0;
+    let mut rest = s;
+    while !rest.is_empty() {
+        let number_end = rest.find(|c: char| !c.is_ascii_digit()).unwrap_or(rest.len());
+        let (number, after_number) = rest.split_at(number_end);
+        let unit_end = after_number
+            .find(|c: char| !c.is_alphabetic())
+            .unwrap_or(after_number.len());
+        let (unit, after_unit) = after_number.split_at(unit_end);
+        if number.is_empty() {
+            return Err(match after_number.chars().next() {
+                Some(c) if !c.is_alphabetic() => AgeError::UnexpectedChar(c),
+                _ => AgeError::MissingNumber,
+            });
+        }
+        if unit.is_empty() {
+            return Err(match after_number.chars().next() {
+                Some(c) => AgeError::UnexpectedChar(c),
+                None => AgeError::MissingUnit,
+            });
+        }
+        let unit_seconds = match unit.to_lowercase().as_str() {
+            "y" | "year" | "years" => 53 * 7 * 24 * 60 * 60,
+            "m" | "month" | "months" => 30 * 24 * 60 * 60,
+            "w" | "week" | "weeks" => 7 * 24 * 60 * 60,
+            "d" | "day" | "days" => 24 * 60 * 60,
+            "h" | "hour" | "hours" => 60 * 60,
+            _ => return Err(AgeError::UnknownUnit(unit.to_owned())),
+        };
+        seconds = number
+            .parse::<i64>()
+            .ok()
+            .and_then(|number| number.checked_mul(unit_seconds))
+            .and_then(|part| seconds.checked_add(part))
+            .filter(|seconds| *seconds <= Duration::max_value().num_seconds())
+            .ok_or(AgeError::TooLong)?;
+        rest = after_unit;
+    }
+    Ok(Duration::seconds(seconds))
+}
+
+/// Why an age couldn't be parsed
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum AgeError {
+    Empty,
+    /// a unit without a number before it, eg. `y`
+    MissingNumber,
+    /// a number without a unit after it, eg. `1y2`
+    MissingUnit,
+    UnknownUnit(String),
+    /// a character which isn't part of a number or a unit, eg. `1.5d`
+    UnexpectedChar(char),
+    /// too long to be represented
+    TooLong,
+}
+
+impl fmt::Display for AgeError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            AgeError::Empty => write!(f, "empty age"),
+            AgeError::MissingNumber => write!(f, "missing number before age unit"),
+            AgeError::MissingUnit => write!(f, "missing age unit after number"),
+            AgeError::UnknownUnit(unit) => write!(f, "unknown age unit {:?}", unit),
+            AgeError::UnexpectedChar(c) => write!(f, "unexpected {:?} in age", c),
+            AgeError::TooLong => write!(f, "age is too long"),
+        }
+    }
+}
+
+impl std::error::Error for AgeError {}
+
+#[derive(Debug)]
+pub struct FilterError {
+    term: String,
+    reason: String,
+}
+
+impl fmt::Display for FilterError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid filter {:?} : {}", self.term, self.reason)
+    }
+}
+
+impl std::error::Error for FilterError {}
+
+#[cfg(test)]
+mod test {
+    use chrono::Duration;
+
+    use super::{parse_age, AgeError, Filter};
+    use crate::{tag::Tag, update::UpdateRef};
+
+    #[test]
+    fn filter_terms() {
+        let filter: Filter = r#" #brexit  #"Guidance and regulation" https://www.gov.uk/guidance 2022-01..2022-02-15 "#
+            .parse()
+            .unwrap();
+        assert_eq!(
+            filter.tags,
+            [
+                Tag::new("brexit".to_owned()),
+                Tag::new("Guidance and regulation".to_owned())
+            ]
+        );
+        assert_eq!(
+            filter.url_prefix.as_ref().unwrap().as_str(),
+            "https://www.gov.uk/guidance"
+        );
+        let update_ref =
+            |url: &str, timestamp: &str| -> UpdateRef { (url.parse().unwrap(), timestamp.parse().unwrap()).into() };
+        assert!(filter.filter_update_ref(&update_ref(
+            "https://www.gov.uk/guidance/travel-abroad",
+            "2022-02-14T09:00:00+00:00"
+        )));
+        assert!(!filter.filter_update_ref(&update_ref(
+            "https://www.gov.uk/guidance/travel-abroad",
+            "2022-02-15T09:00:00+00:00"
+        )));
+        assert!(!filter.filter_update_ref(&update_ref(
+            "https://www.gov.uk/government/travel-abroad",
+            "2022-02-14T09:00:00+00:00"
+        )));
+
+        // ages are relative to now
+        let recent: Filter = "2d...".parse().unwrap();
+        let now = chrono::Utc::now();
+        let ago = |days| (now - chrono::Duration::days(days)).to_rfc3339();
+        assert!(recent.filter_update_ref(&update_ref("https://www.gov.uk/guidance", &ago(1))));
+        assert!(!recent.filter_update_ref(&update_ref("https://www.gov.uk/guidance", &ago(3))));
+        assert!(recent.is_relative() && !filter.is_relative());
+
+        // the web's age parameter takes either range
+        let mut day = Filter::default();
+        day.set_age_range("24h..").unwrap();
+        assert!(day.filter_update_ref(&update_ref("https://www.gov.uk/guidance", &ago(0))));
+        assert!(!day.filter_update_ref(&update_ref("https://www.gov.uk/guidance", &ago(2))));
+        day.set_age_range("2d...1d").unwrap();
+        assert!(!day.filter_update_ref(&update_ref("https://www.gov.uk/guidance", &ago(0))));
+        assert_eq!(
+            day.set_age_range("7d").unwrap_err().to_string(),
+            "Invalid filter \"7d\" : expected an age range, eg. 7d.."
+        );
+
+        assert!("".parse::<Filter>().unwrap().is_empty());
+        assert_eq!(
+            "visa".parse::<Filter>().unwrap_err().to_string(),
+            "Invalid filter \"visa\" : unrecognised filter"
+        );
+        assert!(r#"#"Guidance and"#.parse::<Filter>().is_err());
+        assert!("2022-13..".parse::<Filter>().is_err());
+        assert!("https://www.gov.uk/guidance#top".parse::<Filter>().is_err());
+    }
+
+    #[test]
+    fn age_grammar() {
+        let day = Duration::days(1);
+        for (age, expected) in [
+            ("1y", Duration::weeks(53)),
+            ("2years", Duration::weeks(106)),
+            ("1year", Duration::weeks(53)),
+            ("6m", day * 180),
+            ("1month", day * 30),
+            ("3W", Duration::weeks(3)),
+            ("10days", day * 10),
+            ("0d", Duration::zero()),
+            ("24h", day),
+            ("1hour", Duration::hours(1)),
+            ("1y2m", Duration::weeks(53) + day * 60),
+            ("1w2d", day * 9),
+            ("1d1d", day * 2),
+            ("007d", day * 7),
+        ] {
+            assert_eq!(parse_age(age), Ok(expected), "{:?}", age);
+        }
+
+        for (age, expected) in [
+            ("", AgeError::Empty),
+            ("d", AgeError::MissingNumber),
+            ("1w d", AgeError::UnexpectedChar(' ')),
+            ("7", AgeError::MissingUnit),
+            ("1y2", AgeError::MissingUnit),
+            ("2fortnights", AgeError::UnknownUnit("fortnights".to_owned())),
+            ("1.5d", AgeError::UnexpectedChar('.')),
+            ("-1d", AgeError::UnexpectedChar('-')),
+            ("1d-", AgeError::UnexpectedChar('-')),
+            ("٣d", AgeError::UnexpectedChar('٣')),
+            ("1dé", AgeError::UnknownUnit("dé".to_owned())),
+            ("3日", AgeError::UnknownUnit("日".to_owned())),
+            ("1ｄ", AgeError::UnknownUnit("ｄ".to_owned())),
+            ("99999999999999999999d", AgeError::TooLong),
+            ("9999999999999y", AgeError::TooLong),
+        ] {
+            assert_eq!(parse_age(age), Err(expected), "{:?}", age);
+        }
+
+        // in a filter term the error says which term it is
+        assert_eq!(
+            "1x...".parse::<Filter>().unwrap_err().to_string(),
+            "Invalid filter \"1x...\" : unknown age unit \"x\""
+        );
+        assert!("...".parse::<Filter>().unwrap().is_empty());
+    }
+}
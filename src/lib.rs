@@ -1,6 +1,11 @@
+pub mod check;
 pub mod doc;
+pub mod filter;
+pub mod progress;
 pub mod repository;
+pub mod snapshot;
 pub mod tag;
+pub mod tracker;
 pub mod update;
 mod url;
 
@@ -0,0 +1,93 @@
+//! Progress output shared by the command line tools. Long runs show a progress bar rather than printing a status line
+//! per item, `--quiet` hides the bar and the per-item messages leaving only warnings and the final summary, and
+//! `--verbose` prints the per-item messages above the bar
+
+use std::{borrow::Cow, time::Duration};
+
+use indicatif::{ProgressBar, ProgressStyle};
+
+/// How much a command line tool prints
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Verbosity {
+    Quiet,
+    Normal,
+    Verbose,
+}
+
+impl Verbosity {
+    /// Takes the `--quiet` and `--verbose` flags out of the args, returning the verbosity and the other args
+    pub fn from_args(args: impl IntoIterator<Item = String>) -> (Self, Vec<String>) {
+        let mut verbosity = Verbosity::Normal;
+        let mut rest = vec![];
+        for arg in args {
+            match arg.as_str() {
+                "-q" | "--quiet" => verbosity = Verbosity::Quiet,
+                "-v" | "--verbose" => verbosity = Verbosity::Verbose,
+                _ => rest.push(arg),
+            }
+        }
+        (verbosity, rest)
+    }
+}
+
+/// A progress bar, or a spinner when the number of items isn't known
+pub struct Progress {
+    bar: ProgressBar,
+    verbosity: Verbosity,
+}
+
+impl Progress {
+    /// The bar is redrawn at most this often, so that drawing doesn't slow down the run
+    const TICK: Duration = Duration::from_millis(200);
+
+    pub fn new(len: Option<u64>, verbosity: Verbosity) -> Self {
+        let bar = match (verbosity, len) {
+            (Verbosity::Quiet, _) => ProgressBar::hidden(),
+            (_, Some(len)) => {
+                let bar = ProgressBar::new(len);
+                bar.set_style(
+                    ProgressStyle::default_bar().template("{elapsed_precise} [{wide_bar}] {pos}/{len} {msg}"),
+                );
+                bar
+            }
+            (_, None) => {
+                let bar = ProgressBar::new_spinner();
+                bar.set_style(ProgressStyle::default_spinner().template("{elapsed_precise} {spinner} {pos} {msg}"));
+                bar
+            }
+        };
+        bar.enable_steady_tick(Self::TICK.as_millis() as u64);
+        Self { bar, verbosity }
+    }
+
+    /// Count `n` more items done
+    pub fn inc(&self, n: u64) {
+        self.bar.inc(n);
+    }
+
+    /// Replace the message shown after the count
+    pub fn set_message(&self, message: impl Into<Cow<'static, str>>) {
+        self.bar.set_message(message);
+    }
+
+    /// Print a per-item message, only with `--verbose`
+    pub fn log(&self, message: impl AsRef<str>) {
+        if self.verbosity == Verbosity::Verbose {
+            self.bar.println(message);
+        }
+    }
+
+    /// Print a message which shouldn't be missed, even with `--quiet`, when the bar is hidden it is printed to stderr
+    pub fn warn(&self, message: impl AsRef<str>) {
+        match self.verbosity {
+            Verbosity::Quiet => eprintln!("{}", message.as_ref()),
+            _ => self.bar.println(message),
+        }
+    }
+
+    /// Remove the bar, returning how long it ran for so that it can be included in the summary
+    pub fn finish(&self) -> Duration {
+        self.bar.finish_and_clear();
+        self.bar.elapsed()
+    }
+}
@@ -1,4 +1,19 @@
-use std::{io, ops::Deref};
+use chrono::{DateTime, FixedOffset};
+use std::{
+    fmt, fs,
+    io::{self, Write},
+    ops::Deref,
+    path::{Path, PathBuf},
+    process,
+    str::FromStr,
+    sync::atomic::{AtomicUsize, Ordering},
+};
+
+use crate::{
+    doc::{DocEvent, DocumentVersion},
+    tag::{Tag, TagEvent},
+    update::{Update, UpdateEvent},
+};
 
 /// Something that can be stored in a respository
 pub trait Entity: Sized {
@@ -28,6 +43,13 @@ impl<T: Entity, const N: usize> WithEvents<T, N> {
     pub fn into_inner(self) -> T {
         self.entity
     }
+
+    /// Call `f` with the entity and each of the events
+    pub(crate) fn for_each_event(&self, mut f: impl FnMut(&T, &T::WriteEvent)) {
+        for event in self.events.0.iter().flatten() {
+            f(&self.entity, event)
+        }
+    }
 }
 
 impl<T: Entity, const N: usize> Deref for WithEvents<T, N> {
@@ -51,3 +73,111 @@ impl<Ev, const N: usize> Iterator for Events<Ev, N> {
 
 /// The result of a write operation on a database, on success contains up to `N` entity events representing what changed
 pub type WriteResult<T, const N: usize> = io::Result<WithEvents<T, N>>;
+
+/// Receives the events of every write made through the repositories it is set on, so that an embedder can keep its own state up to date in one place instead of handling the events returned from each write
+pub trait EventSink: Send + Sync {
+    fn on_update(&self, _update: &Update, _event: &UpdateEvent) {}
+    fn on_doc(&self, _doc: &DocumentVersion, _event: &DocEvent) {}
+    fn on_tag(&self, _tag: &Tag, _event: &TagEvent) {}
+}
+
+/// Timestamps are stored in UTC so that an instant always has the same name, whichever offset it was parsed with
+pub fn normalize_timestamp(timestamp: DateTime<FixedOffset>) -> DateTime<FixedOffset> {
+    timestamp.with_timezone(&FixedOffset::east(0))
+}
+
+/// How the repos make their writes durable. Without syncing, a power loss can leave leaves empty or part written, which
+/// is worth avoiding for a server writing updates as they arrive, but not for a bulk import which can be run again
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum Durability {
+    /// Leave writes for the OS to write back when it chooses
+    None,
+    /// Sync the content of each leaf before the write returns
+    FsyncLeaf,
+    /// Also sync the directory of each leaf, so that the entry of a new leaf is as durable as its content
+    FsyncDir,
+}
+
+impl Default for Durability {
+    fn default() -> Self {
+        Durability::FsyncLeaf
+    }
+}
+
+impl Durability {
+    /// Make a file written at `path` durable
+    pub(crate) fn sync(self, file: &fs::File, path: &Path) -> io::Result<()> {
+        if self == Durability::None {
+            return Ok(());
+        }
+        file.sync_data()?;
+        if self == Durability::FsyncDir {
+            if let Some(parent) = path.parent() {
+                fs::File::open(parent)?.sync_all()?;
+            }
+        }
+        Ok(())
+    }
+
+    /// Write a whole file as [`fs::write`] does, and make it durable. It is written aside and moved over the path, so
+    /// that a crash leaves either the file as it was or as it is written rather than part written
+    pub(crate) fn write(self, path: impl AsRef<Path>, contents: impl AsRef<[u8]>) -> io::Result<()> {
+        let path = path.as_ref();
+        let tmp_path = tmp_path_for(path);
+        let result = (|| {
+            let mut file = fs::File::create(&tmp_path)?;
+            file.write_all(contents.as_ref())?;
+            // synced before the rename, or the rename could be durable before the content
+            if self != Durability::None {
+                file.sync_data()?;
+            }
+            fs::rename(&tmp_path, path)?;
+            if self == Durability::FsyncDir {
+                if let Some(parent) = path.parent() {
+                    fs::File::open(parent)?.sync_all()?;
+                }
+            }
+            Ok(())
+        })();
+        if result.is_err() {
+            let _ = fs::remove_file(&tmp_path);
+        }
+        result
+    }
+}
+
+/// A path beside `path` to write it aside at, unique to the write so that concurrent writes of the same file don't write
+/// into each other. It isn't named like a leaf so that one left by a crash isn't listed
+fn tmp_path_for(path: &Path) -> PathBuf {
+    static WRITES: AtomicUsize = AtomicUsize::new(0);
+    let file_name = path.file_name().map_or_else(Default::default, |name| name.to_string_lossy());
+    path.with_file_name(format!(
+        ".{}.{}-{}.writing",
+        file_name,
+        process::id(),
+        WRITES.fetch_add(1, Ordering::Relaxed)
+    ))
+}
+
+impl FromStr for Durability {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "none" => Ok(Durability::None),
+            "fsync-leaf" => Ok(Durability::FsyncLeaf),
+            "fsync-dir" => Ok(Durability::FsyncDir),
+            _ => Err(format!("Unknown durability {:?}, expected none, fsync-leaf or fsync-dir", s)),
+        }
+    }
+}
+
+impl fmt::Display for Durability {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(match self {
+            Durability::None => "none",
+            Durability::FsyncLeaf => "fsync-leaf",
+            Durability::FsyncDir => "fsync-dir",
+        })
+    }
+}
@@ -0,0 +1,147 @@
+//! Read only snapshots of a repo, so that a long read such as an export, a report or a backup sees the repo as it was
+//! when it started rather than a mix of before and after the writes made while it runs. A snapshot is a copy of the
+//! repo's directories in which the doc versions and updates, which are never changed once written, are hard links to
+//! the repo's files, so it is quick to make and takes little space. Everything else, such as the tag files which are
+//! appended to and the markers which are replaced, is copied. A snapshot must be on the same filesystem as its repo
+
+use std::{
+    fs, io,
+    path::{Path, PathBuf},
+};
+
+/// The leaves which are only ever created and removed, never written to again, so can be linked rather than copied
+const LINKED_LEAVES: [&str; 3] = ["<docver>", "<update>", "<docdiff>"];
+
+/// A snapshot of a repo, which is removed when it is dropped unless it is kept. Open the repos on its path to read it
+/// as any other repo, it is never written to
+#[derive(Debug)]
+pub struct Snapshot {
+    path: PathBuf,
+    keep: bool,
+}
+
+impl Snapshot {
+    /// Snapshot the repo at `repo` into `path`, which mustn't exist yet. The tags are copied before the urls, so that
+    /// every update in a tag of the snapshot is also in its urls
+    pub fn create(repo: impl AsRef<Path>, path: impl AsRef<Path>) -> io::Result<Self> {
+        let (repo, path) = (repo.as_ref(), path.as_ref());
+        fs::create_dir(path)?;
+        let snapshot = Self {
+            path: path.to_owned(),
+            keep: false,
+        };
+        for entry in fs::read_dir(repo)? {
+            let entry = entry?;
+            if entry.file_type()?.is_file() {
+                fs::copy(entry.path(), path.join(entry.file_name()))?;
+            }
+        }
+        for dir in ["tag", "url"] {
+            if repo.join(dir).is_dir() {
+                snapshot_dir(&repo.join(dir), &path.join(dir))?;
+            }
+        }
+        Ok(snapshot)
+    }
+
+    pub fn path(&self) -> &Path {
+        &self.path
+    }
+
+    /// Keep the snapshot after it is dropped, such as for a backup to be taken from it later. Returns its path
+    pub fn keep(mut self) -> PathBuf {
+        self.keep = true;
+        self.path.clone()
+    }
+}
+
+impl Drop for Snapshot {
+    fn drop(&mut self) {
+        if !self.keep {
+            let _ = fs::remove_dir_all(&self.path);
+        }
+    }
+}
+
+fn snapshot_dir(from: &Path, to: &Path) -> io::Result<()> {
+    fs::create_dir_all(to)?;
+    for entry in fs::read_dir(from)? {
+        let entry = entry?;
+        let (from, to) = (entry.path(), to.join(entry.file_name()));
+        let result = if entry.file_type()?.is_dir() {
+            snapshot_dir(&from, &to)
+        } else if LINKED_LEAVES
+            .iter()
+            .any(|prefix| entry.file_name().to_string_lossy().starts_with(prefix))
+        {
+            fs::hard_link(&from, &to)
+        } else {
+            fs::copy(&from, &to).map(|_| ())
+        };
+        match result {
+            // removed since it was listed, such as a coalesced doc version, so it isn't in the snapshot either
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            result => result?,
+        }
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod test {
+    use chrono::DateTime;
+
+    use super::*;
+    use crate::{
+        check::init_repo,
+        doc::content::DocContent,
+        tag::TagRepo,
+        tracker::Tracker,
+        update::{UpdateRef, UpdateRepo},
+        Url,
+    };
+
+    #[test]
+    fn snapshot_is_unchanged_by_later_writes() {
+        let repo_path = Path::new("tmp/snapshot::snapshot_is_unchanged_by_later_writes");
+        let snapshot_path = repo_path.with_extension("snapshot");
+        let _ = fs::remove_dir_all(repo_path);
+        let _ = fs::remove_dir_all(&snapshot_path);
+        init_repo(repo_path).unwrap();
+        let tracker = Tracker::new(repo_path).unwrap();
+        let url: Url = "https://www.gov.uk/a".parse().unwrap();
+        let first = DateTime::parse_from_rfc3339("2022-02-17T09:53:57+00:00").unwrap();
+        let second = DateTime::parse_from_rfc3339("2022-02-18T09:53:57+00:00").unwrap();
+        let content = DocContent::Other(b"First".to_vec());
+        tracker
+            .capture(url.clone(), first, Some(&content), Some("First"), ["brexit"])
+            .unwrap();
+
+        let snapshot = Snapshot::create(repo_path, &snapshot_path).unwrap();
+        let content = DocContent::Other(b"Second".to_vec());
+        tracker
+            .capture(url.clone(), second, Some(&content), Some("Second"), ["brexit"])
+            .unwrap();
+
+        let updates = UpdateRepo::new(snapshot.path().join("url")).unwrap();
+        let listed: Vec<_> = updates.list_updates(url.clone()).unwrap().collect();
+        assert_eq!(listed.len(), 1);
+        assert_eq!(listed[0].as_ref().unwrap().change(), "First");
+        let tags = TagRepo::new(snapshot.path().join("tag")).unwrap();
+        let tagged: Vec<UpdateRef> = tags
+            .list_updates_in_tag("brexit")
+            .unwrap()
+            .map(Result::unwrap)
+            .collect();
+        assert_eq!(tagged, [UpdateRef::from((url.clone(), first))]);
+        // the repo itself has both
+        let updates = UpdateRepo::new(repo_path.join("url")).unwrap();
+        assert_eq!(updates.list_updates(url).unwrap().count(), 2);
+
+        drop(snapshot);
+        assert!(!snapshot_path.exists());
+        let kept = Snapshot::create(repo_path, &snapshot_path).unwrap().keep();
+        assert!(kept.join("repo-version").exists());
+        assert!(Snapshot::create(repo_path, &snapshot_path).is_err());
+    }
+}
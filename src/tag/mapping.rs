@@ -0,0 +1,89 @@
+use std::{collections::HashMap, fmt, fs, io, path::Path, str::FromStr};
+
+/// Maps the categories of update emails, whose wording has varied over the years, onto a canonical set of tags.
+///
+/// The table is a text file with a mapping per line, `raw category => tag`. Blank lines and lines starting with `#` are
+/// ignored. Raw categories are matched ignoring case and repeated whitespace, a category without a mapping is used as
+/// the tag as it is.
+#[derive(Debug, Default)]
+pub struct TagMapping {
+    tags: HashMap<String, String>,
+}
+
+impl TagMapping {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// The tag for a raw category
+    pub fn canonical<'a>(&'a self, category: &'a str) -> &'a str {
+        self.tags.get(&match_key(category)).map_or(category, String::as_str)
+    }
+}
+
+impl FromStr for TagMapping {
+    type Err = TagMappingError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut tags = HashMap::new();
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            match line.split_once("=>") {
+                Some((category, tag)) if !category.trim().is_empty() && !tag.trim().is_empty() => {
+                    tags.insert(match_key(category), tag.trim().to_owned());
+                }
+                _ => return Err(TagMappingError { line: index + 1 }),
+            }
+        }
+        Ok(Self { tags })
+    }
+}
+
+fn match_key(category: &str) -> String {
+    category.split_whitespace().collect::<Vec<_>>().join(" ").to_lowercase()
+}
+
+#[derive(Debug)]
+pub struct TagMappingError {
+    line: usize,
+}
+
+impl fmt::Display for TagMappingError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Expected `category => tag` on line {} of tag mapping", self.line)
+    }
+}
+
+impl std::error::Error for TagMappingError {}
+
+#[cfg(test)]
+mod test {
+    use super::TagMapping;
+
+    #[test]
+    fn categories_are_mapped_to_tags() {
+        let mapping: TagMapping = "
+            # Brexit was renamed
+            Brexit guidance => Brexit
+            Transition period => Brexit
+
+            Coronavirus (COVID-19) => COVID-19
+        "
+        .parse()
+        .unwrap();
+        assert_eq!(mapping.canonical("Brexit guidance"), "Brexit");
+        assert_eq!(mapping.canonical("transition  Period"), "Brexit");
+        assert_eq!(mapping.canonical("Coronavirus (COVID-19)"), "COVID-19");
+        assert_eq!(mapping.canonical("Foreign travel advice"), "Foreign travel advice");
+
+        assert_eq!(
+            "Brexit\n".parse::<TagMapping>().unwrap_err().to_string(),
+            "Expected `category => tag` on line 1 of tag mapping"
+        );
+    }
+}
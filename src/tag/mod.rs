@@ -1,7 +1,11 @@
 use std::{fmt, ops::Deref};
 
+mod mapping;
 mod repository;
-pub use repository::TagRepo;
+mod rules;
+pub use mapping::{TagMapping, TagMappingError};
+pub use repository::{TagLineError, TagRepo};
+pub use rules::{TagRules, TagRulesError};
 
 use crate::{repository::Entity, update::UpdateRef};
 
@@ -44,6 +48,8 @@ pub enum TagEvent {
     UpdateTagged { tag: Tag, update_ref: UpdateRef },
     /// A new tag is added
     TagCreated { tag: Tag },
+    /// An update is removed from a tag, such as when it is deleted
+    UpdateUntagged { tag: Tag, update_ref: UpdateRef },
 }
 impl TagEvent {
     pub(crate) fn tag_created(tag: Tag) -> Self {
@@ -56,4 +62,11 @@ impl TagEvent {
             update_ref: update_ref.clone(),
         }
     }
+
+    pub(crate) fn update_untagged(tag: Tag, update_ref: &UpdateRef) -> Self {
+        Self::UpdateUntagged {
+            tag,
+            update_ref: update_ref.clone(),
+        }
+    }
 }
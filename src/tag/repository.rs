@@ -1,27 +1,72 @@
 use super::*;
-use crate::repository::WriteResult;
+use crate::{
+    repository::{normalize_timestamp, Durability, EventSink, WriteResult},
+    update::UpdateRefParseError,
+};
 
+use fs2::FileExt;
 use std::{
+    error,
     fs::{self},
-    io::{self, BufRead, BufReader, Write},
+    io::{self, BufRead, BufReader, Read, Seek, SeekFrom, Write},
     path::{Path, PathBuf},
-    str::FromStr,
+    sync::Arc,
 };
 
 pub struct TagRepo {
     base: PathBuf,
+    durability: Durability,
+    event_sink: Option<Arc<dyn EventSink>>,
 }
 
 impl TagRepo {
     pub fn new(base: impl AsRef<Path>) -> io::Result<Self> {
         let base = base.as_ref().to_path_buf();
         fs::create_dir_all(&base)?;
-        Ok(Self { base })
+        Ok(Self {
+            base,
+            durability: Durability::default(),
+            event_sink: None,
+        })
+    }
+
+    /// Replace how writes are made durable
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Send the events of all writes to a sink
+    pub fn with_event_sink(mut self, event_sink: Arc<dyn EventSink>) -> Self {
+        self.event_sink = Some(event_sink);
+        self
+    }
+
+    /// Lock the tags against writes from other repos and processes until the lock is dropped, appends take it as well
+    /// as rewrites so that a rewrite can't lose an update tagged while it was being made. It is a file outside of the tag
+    /// dir so that it can't be mistaken for a tag
+    fn lock(&self) -> io::Result<fs::File> {
+        let file = fs::OpenOptions::new()
+            .write(true)
+            .create(true)
+            .truncate(false)
+            .open(self.base.with_file_name("tag.lock"))?;
+        file.lock_exclusive()?;
+        Ok(file)
     }
 
     /// Tag a url in the repo
     pub fn tag_update(&self, tag_name: String, update_ref: UpdateRef) -> WriteResult<Tag, 2> {
+        let _lock = self.lock()?;
+        self.append_to_tag(tag_name, update_ref)
+    }
+
+    fn append_to_tag(&self, tag_name: String, update_ref: UpdateRef) -> WriteResult<Tag, 2> {
         let tag = Tag { name: tag_name };
+        let update_ref = UpdateRef {
+            timestamp: normalize_timestamp(update_ref.timestamp),
+            ..update_ref
+        };
         let path = self.path_for(&tag);
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
@@ -35,21 +80,88 @@ impl TagRepo {
                 if err.kind() == io::ErrorKind::AlreadyExists {
                     is_new_tag = false;
                 }
-                fs::OpenOptions::new().append(true).open(&path)
+                fs::OpenOptions::new().read(true).append(true).open(&path)
             })?;
-        file.write_all(format!("{}\n", update_ref).as_bytes())?;
-        file.flush()?;
+        // the line is written in one write, and synced unless durability is off, so that a crash is unlikely to leave
+        // part of it, but if an earlier write was torn its partial line is ended so that it doesn't run into this one
+        let mut line = String::with_capacity(update_ref.url.as_str().len() + 32);
+        if !is_new_tag && !ends_with_newline(&mut file)? {
+            line.push('\n');
+        }
+        line.push_str(&update_ref.to_string());
+        line.push('\n');
+        file.write_all(line.as_bytes())?;
+        self.durability.sync(&file, &path)?;
 
         let events = [
             Some(TagEvent::update_tagged(tag.clone(), &update_ref)),
             is_new_tag.then(|| TagEvent::tag_created(tag.clone())),
         ];
-        tag.with_events(events)
+        let written = tag.with_events(events)?;
+        if let Some(sink) = &self.event_sink {
+            written.for_each_event(|tag, event| sink.on_tag(tag, event));
+        }
+        Ok(written)
+    }
+
+    /// Tag a url in the repo unless it is already in the tag, for writes which may be repeated
+    pub fn ensure_tagged(&self, tag_name: String, update_ref: UpdateRef) -> WriteResult<Tag, 2> {
+        let update_ref = UpdateRef {
+            timestamp: normalize_timestamp(update_ref.timestamp),
+            ..update_ref
+        };
+        let _lock = self.lock()?;
+        match self.list_updates_in_tag(&tag_name) {
+            Ok(mut tagged) => {
+                if tagged.any(|tagged| matches!(tagged, Ok(tagged) if tagged == update_ref)) {
+                    return Tag { name: tag_name }.with_events([None, None]);
+                }
+            }
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {}
+            Err(err) => return Err(err),
+        }
+        self.append_to_tag(tag_name, update_ref)
+    }
+
+    /// Remove an update from a tag, such as when the update is deleted. The tag file is rewritten and moved over it under
+    /// the tags' lock, so an update tagged at the same time is kept. Returns error if there is no tag, and no event if the
+    /// update wasn't in it. Lines which can't be parsed are kept
+    pub fn untag_update(&self, tag_name: String, update_ref: &UpdateRef) -> WriteResult<Tag, 1> {
+        let tag = Tag { name: tag_name };
+        let update_ref = UpdateRef {
+            timestamp: normalize_timestamp(update_ref.timestamp),
+            ..update_ref.clone()
+        };
+        let path = self.path_for(&tag);
+        let _lock = self.lock()?;
+        let contents = fs::read_to_string(&path)?;
+        let mut kept = String::with_capacity(contents.len());
+        let mut untagged = false;
+        for line in contents.lines() {
+            if matches!(line.parse::<UpdateRef>(), Ok(tagged) if tagged == update_ref) {
+                untagged = true;
+            } else if !line.is_empty() {
+                kept.push_str(line);
+                kept.push('\n');
+            }
+        }
+        if !untagged {
+            return tag.with_events([None]);
+        }
+        self.durability.write(&path, kept)?;
+
+        let written = tag.clone().with_events([Some(TagEvent::update_untagged(tag, &update_ref))])?;
+        if let Some(sink) = &self.event_sink {
+            written.for_each_event(|tag, event| sink.on_tag(tag, event));
+        }
+        Ok(written)
     }
 
     /// Lists all tags, sorted by name
     pub fn list_tags(&self) -> io::Result<impl Iterator<Item = Tag>> {
         let mut dir: Vec<fs::DirEntry> = fs::read_dir(&self.base)?.collect::<io::Result<_>>()?;
+        // a tag being rewritten is written aside as a hidden file
+        dir.retain(|dir_entry| !dir_entry.file_name().to_string_lossy().starts_with('.'));
         dir.sort_by_key(fs::DirEntry::file_name);
 
         Ok(dir.into_iter().map(move |dir_entry| Tag {
@@ -57,15 +169,46 @@ impl TagRepo {
         }))
     }
 
-    /// Returns error if there is no tag
-    pub fn list_updates_in_tag(
+    /// Returns error if there is no tag. Each line which can't be parsed is an error, and the lines after it are still
+    /// listed, empty lines are skipped. Nothing more is listed after an error reading the file
+    pub fn list_updates_in_tag(&self, tag: &str) -> io::Result<impl Iterator<Item = Result<UpdateRef, TagLineError>>> {
+        let reader = BufReader::new(fs::File::open(&self.path_for(tag))?);
+        Ok(reader
+            .lines()
+            .enumerate()
+            .scan(false, |failed, (index, line)| {
+                if *failed {
+                    return None;
+                }
+                Some(match line {
+                    Ok(text) if text.is_empty() => None,
+                    Ok(text) => Some(text.parse().map_err(|error| TagLineError::Parse {
+                        line: index + 1,
+                        text,
+                        error,
+                    })),
+                    Err(err) => {
+                        *failed = true;
+                        Some(Err(TagLineError::Read(err)))
+                    }
+                })
+            })
+            .flatten())
+    }
+
+    /// Returns error if there is no tag. Lines which can't be parsed are skipped after they are passed to `on_error`, so
+    /// that one bad line doesn't lose the rest of the tag
+    pub fn list_valid_updates_in_tag<'e>(
         &self,
         tag: &str,
-    ) -> io::Result<impl Iterator<Item = Result<UpdateRef, <UpdateRef as FromStr>::Err>>> {
-        let reader = BufReader::new(fs::File::open(&self.path_for(tag))?);
-        Ok(reader.lines().map(|line| {
-            let s = line.unwrap();
-            s.parse()
+        mut on_error: impl FnMut(TagLineError) + 'e,
+    ) -> io::Result<impl Iterator<Item = UpdateRef> + 'e> {
+        Ok(self.list_updates_in_tag(tag)?.filter_map(move |update_ref| match update_ref {
+            Ok(update_ref) => Some(update_ref),
+            Err(err) => {
+                on_error(err);
+                None
+            }
         }))
     }
 
@@ -73,3 +216,137 @@ impl TagRepo {
         self.base.join(tag)
     }
 }
+
+/// Whether a file is empty or its last byte is a newline
+fn ends_with_newline(file: &mut fs::File) -> io::Result<bool> {
+    if file.metadata()?.len() == 0 {
+        return Ok(true);
+    }
+    let mut last = [0];
+    file.seek(SeekFrom::End(-1))?;
+    file.read_exact(&mut last)?;
+    Ok(last[0] == b'\n')
+}
+
+/// A line of a tag file which couldn't be listed
+#[derive(Debug)]
+pub enum TagLineError {
+    /// The file couldn't be read, the rest of it isn't listed
+    Read(io::Error),
+    /// A line, numbered from 1, which isn't an update ref, such as one torn by a crash while it was written
+    Parse {
+        line: usize,
+        text: String,
+        error: UpdateRefParseError,
+    },
+}
+
+impl error::Error for TagLineError {
+    fn source(&self) -> Option<&(dyn error::Error + 'static)> {
+        match self {
+            TagLineError::Read(err) => Some(err),
+            TagLineError::Parse { error, .. } => Some(error),
+        }
+    }
+}
+
+impl fmt::Display for TagLineError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            TagLineError::Read(err) => write!(f, "Failed reading tag file : {}", err),
+            TagLineError::Parse { line, text, error } => write!(f, "Line {} {:?} is invalid : {}", line, text, error),
+        }
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::*;
+
+    #[test]
+    fn invalid_lines_are_reported_and_skipped() {
+        let path = Path::new("tmp/tag::invalid_lines_are_reported_and_skipped");
+        let _ = fs::remove_dir_all(path);
+        let repo = TagRepo::new(path).unwrap();
+        let first: UpdateRef = "2022-02-17T09:53:57+00:00|https://www.gov.uk/a".parse().unwrap();
+        let second: UpdateRef = "2022-02-17T09:55:47+00:00|https://www.gov.uk/b".parse().unwrap();
+        let _ = repo.tag_update("brexit".to_owned(), first.clone()).unwrap();
+        // as left by a crash part way through writing a line
+        fs::OpenOptions::new()
+            .append(true)
+            .open(path.join("brexit"))
+            .unwrap()
+            .write_all(b"2022-02-17T09:5")
+            .unwrap();
+        let _ = repo.tag_update("brexit".to_owned(), second.clone()).unwrap();
+
+        let listed: Vec<_> = repo.list_updates_in_tag("brexit").unwrap().collect();
+        assert_eq!(listed.len(), 3);
+        assert!(matches!(&listed[1], Err(TagLineError::Parse { line: 2, text, .. }) if text == "2022-02-17T09:5"));
+
+        let mut errors = vec![];
+        let listed: Vec<_> = repo
+            .list_valid_updates_in_tag("brexit", |err| errors.push(err))
+            .unwrap()
+            .collect();
+        assert_eq!(listed, [first, second]);
+        assert_eq!(errors.len(), 1);
+    }
+
+    #[test]
+    fn untagged_updates_are_no_longer_listed() {
+        let path = Path::new("tmp/tag::untagged_updates_are_no_longer_listed");
+        let _ = fs::remove_dir_all(path);
+        let repo = TagRepo::new(path).unwrap();
+        let first: UpdateRef = "2022-02-17T09:53:57+00:00|https://www.gov.uk/a".parse().unwrap();
+        let second: UpdateRef = "2022-02-17T09:55:47+00:00|https://www.gov.uk/b".parse().unwrap();
+        let _ = repo.tag_update("brexit".to_owned(), first.clone()).unwrap();
+        let _ = repo.tag_update("brexit".to_owned(), second.clone()).unwrap();
+
+        let written = repo.untag_update("brexit".to_owned(), &first).unwrap();
+        assert_eq!(
+            written.into_events().collect::<Vec<_>>(),
+            [TagEvent::UpdateUntagged {
+                tag: Tag {
+                    name: "brexit".to_owned()
+                },
+                update_ref: first.clone()
+            }]
+        );
+        let listed: Vec<_> = repo.list_updates_in_tag("brexit").unwrap().map(Result::unwrap).collect();
+        assert_eq!(listed, [second]);
+        // untagging again doesn't change anything
+        let written = repo.untag_update("brexit".to_owned(), &first).unwrap();
+        assert_eq!(written.into_events().count(), 0);
+        assert_eq!(repo.list_tags().unwrap().count(), 1);
+    }
+
+    #[test]
+    fn updates_tagged_while_untagging_are_kept() {
+        let path = Path::new("tmp/tag::updates_tagged_while_untagging_are_kept");
+        let _ = fs::remove_dir_all(path);
+        let retracted: UpdateRef = "2022-02-17T09:53:57+00:00|https://www.gov.uk/a".parse().unwrap();
+        let tagging = std::thread::spawn(move || {
+            // another repo at the same path, as ingress and a retraction would have
+            let repo = TagRepo::new(path).unwrap();
+            (0..50)
+                .map(|i| {
+                    let tagged: UpdateRef = format!("2022-02-17T10:{:02}:00+00:00|https://www.gov.uk/b", i)
+                        .parse()
+                        .unwrap();
+                    let _ = repo.tag_update("brexit".to_owned(), tagged.clone()).unwrap();
+                    tagged
+                })
+                .collect::<Vec<_>>()
+        });
+        let repo = TagRepo::new(path).unwrap();
+        for _ in 0..50 {
+            let _ = repo.tag_update("brexit".to_owned(), retracted.clone()).unwrap();
+            let _ = repo.untag_update("brexit".to_owned(), &retracted).unwrap();
+        }
+        let tagged = tagging.join().unwrap();
+
+        let listed: Vec<_> = repo.list_updates_in_tag("brexit").unwrap().map(Result::unwrap).collect();
+        assert_eq!(listed, tagged);
+    }
+}
@@ -0,0 +1,161 @@
+use std::{collections::HashSet, fmt, fs, io, path::Path, str::FromStr};
+
+use regex::Regex;
+
+use crate::update::Update;
+
+/// Rules for tagging updates with tags of our own, beyond the categories in the update emails.
+///
+/// The rules are a text file with a rule per line, `kind pattern => tag`. Blank lines and lines starting with `#` are
+/// ignored. There are three kinds of rule:
+/// * `prefix` matches updates to urls starting with the pattern
+/// * `url` matches updates to urls matching the pattern as a regex
+/// * `change` matches updates whose change description matches the pattern as a regex
+///
+/// An update is tagged with the tag of every rule it matches.
+#[derive(Debug, Default)]
+pub struct TagRules {
+    rules: Vec<(Matcher, String)>,
+}
+
+#[derive(Debug)]
+enum Matcher {
+    Prefix(String),
+    Url(Regex),
+    Change(Regex),
+}
+
+impl TagRules {
+    pub fn load(path: impl AsRef<Path>) -> io::Result<Self> {
+        fs::read_to_string(path)?
+            .parse()
+            .map_err(|err| io::Error::new(io::ErrorKind::InvalidData, err))
+    }
+
+    /// The tags of the rules matching a change to a url, each tag once even if several of its rules match
+    pub fn tags<'a>(&'a self, url: &'a str, change: &'a str) -> impl Iterator<Item = &'a str> + 'a {
+        let mut seen = HashSet::new();
+        self.rules
+            .iter()
+            .filter(move |(matcher, _)| match matcher {
+                Matcher::Prefix(prefix) => url.starts_with(prefix.as_str()),
+                Matcher::Url(regex) => regex.is_match(url),
+                Matcher::Change(regex) => regex.is_match(change),
+            })
+            .map(|(_, tag)| tag.as_str())
+            .filter(move |tag| seen.insert(*tag))
+    }
+
+    /// The tags of the rules matching an update
+    pub fn tags_for<'a>(&'a self, update: &'a Update) -> impl Iterator<Item = &'a str> + 'a {
+        self.tags(update.url().as_str(), update.change())
+    }
+}
+
+impl FromStr for TagRules {
+    type Err = TagRulesError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut rules = Vec::new();
+        for (index, line) in s.lines().enumerate() {
+            let line = line.trim();
+            if line.is_empty() || line.starts_with('#') {
+                continue;
+            }
+            let err = |reason| TagRulesError {
+                line: index + 1,
+                reason,
+            };
+            // split on the last arrow so that a regex can contain one
+            let (rule, tag) = match line.rsplit_once("=>") {
+                Some((rule, tag)) if !tag.trim().is_empty() => (rule.trim(), tag.trim()),
+                _ => return Err(err("expected `kind pattern => tag`".to_owned())),
+            };
+            let (kind, pattern) = match rule.split_once(char::is_whitespace) {
+                Some((kind, pattern)) if !pattern.trim().is_empty() => (kind, pattern.trim()),
+                _ => return Err(err("expected `kind pattern => tag`".to_owned())),
+            };
+            let regex = || Regex::new(pattern).map_err(|regex_err| err(regex_err.to_string()));
+            let matcher = match kind {
+                "prefix" => Matcher::Prefix(pattern.to_owned()),
+                "url" => Matcher::Url(regex()?),
+                "change" => Matcher::Change(regex()?),
+                _ => {
+                    return Err(err(format!(
+                        "unknown kind of rule {:?}, expected prefix, url or change",
+                        kind
+                    )))
+                }
+            };
+            rules.push((matcher, tag.to_owned()));
+        }
+        Ok(Self { rules })
+    }
+}
+
+#[derive(Debug)]
+pub struct TagRulesError {
+    line: usize,
+    reason: String,
+}
+
+impl fmt::Display for TagRulesError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "Invalid tag rule on line {} : {}", self.line, self.reason)
+    }
+}
+
+impl std::error::Error for TagRulesError {}
+
+#[cfg(test)]
+mod test {
+    use super::TagRules;
+
+    #[test]
+    fn rules_match_updates() {
+        let rules: TagRules = "
+            # our own taxonomy
+            prefix https://www.gov.uk/government/publications/immigration-rules => immigration
+            url ^https://www\\.gov\\.uk/guidance/.*visa => immigration
+            change (?i)\\b(income|corporation) tax\\b => tax
+        "
+        .parse()
+        .unwrap();
+        let tags = |url, change| rules.tags(url, change).collect::<Vec<_>>();
+        assert_eq!(
+            tags(
+                "https://www.gov.uk/government/publications/immigration-rules-statement",
+                "Added statement"
+            ),
+            vec!["immigration"]
+        );
+        assert_eq!(
+            tags(
+                "https://www.gov.uk/guidance/student-visa",
+                "Updated Income Tax thresholds"
+            ),
+            vec!["immigration", "tax"]
+        );
+        assert_eq!(
+            tags(
+                "https://www.gov.uk/government/publications/immigration-rules-visa",
+                "Corporation tax"
+            ),
+            vec!["immigration", "tax"]
+        );
+        assert!(tags("https://www.gov.uk/guidance/import-duty", "Updated rates").is_empty());
+
+        assert_eq!(
+            "prefix https://www.gov.uk/\n"
+                .parse::<TagRules>()
+                .unwrap_err()
+                .to_string(),
+            "Invalid tag rule on line 1 : expected `kind pattern => tag`"
+        );
+        assert_eq!(
+            "\nsuffix .pdf => pdf".parse::<TagRules>().unwrap_err().to_string(),
+            "Invalid tag rule on line 2 : unknown kind of rule \"suffix\", expected prefix, url or change"
+        );
+        assert!("change (unclosed => tax".parse::<TagRules>().is_err());
+    }
+}
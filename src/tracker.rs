@@ -0,0 +1,426 @@
+//! Capturing snapshots of pages to a repo in one call, for tools which add to a repo without handling the doc, update
+//! and tag repos and their events themselves
+
+use std::{
+    cell::RefCell,
+    io::{self, Write},
+    path::Path,
+    sync::Arc,
+};
+
+use chrono::{DateTime, Duration, FixedOffset};
+
+use crate::{
+    doc::{content::DocContent, DocEvent, DocRepo, DocumentVersion, FirstVersion},
+    repository::{Durability, EventSink},
+    tag::TagRepo,
+    update::{ChangeNormalizer, Update, UpdateEvent, UpdateFields, UpdateRef, UpdateRepo},
+    Url,
+};
+
+/// Writes captures to the doc, update and tag repos of a repo. Captures only ever add to the repo, content which is
+/// the same as a version either side of it isn't stored again, and an update which is already in the repo isn't written
+/// or tagged again
+pub struct Tracker {
+    update_repo: UpdateRepo,
+    doc_repo: DocRepo,
+    tag_repo: TagRepo,
+    write_avoidance_buffer: RefCell<Vec<u8>>,
+}
+
+/// What a capture wrote to the repo
+#[derive(Debug)]
+pub struct Capture {
+    /// the version with the captured content, an earlier version if the content was the same as it
+    pub doc: Option<DocumentVersion>,
+    /// the update, if there was one and it wasn't in the repo already
+    pub update: Option<Update>,
+}
+
+impl Tracker {
+    /// Capture to the repo at `base`, which is initialised with [`crate::check::init_repo`]
+    pub fn new(base: impl AsRef<Path>) -> io::Result<Self> {
+        let base = base.as_ref();
+        Ok(Self {
+            update_repo: UpdateRepo::new(base.join("url"))?,
+            doc_repo: DocRepo::new(base.join("url"))?,
+            tag_repo: TagRepo::new(base.join("tag"))?,
+            write_avoidance_buffer: RefCell::new(Vec::new()),
+        })
+    }
+
+    /// Send the events of all the writes to a sink
+    pub fn with_event_sink(self, event_sink: Arc<dyn EventSink>) -> Self {
+        Self {
+            update_repo: self.update_repo.with_event_sink(event_sink.clone()),
+            doc_repo: self.doc_repo.with_event_sink(event_sink.clone()),
+            tag_repo: self.tag_repo.with_event_sink(event_sink),
+            ..self
+        }
+    }
+
+    /// See [`UpdateRepo::with_change_normalizer`]
+    pub fn with_change_normalizer(self, normalizer: ChangeNormalizer) -> Self {
+        Self {
+            update_repo: self.update_repo.with_change_normalizer(normalizer),
+            ..self
+        }
+    }
+
+    /// Replace how the writes to all the repos are made durable
+    pub fn with_durability(self, durability: Durability) -> Self {
+        Self {
+            update_repo: self.update_repo.with_durability(durability),
+            doc_repo: self.doc_repo.with_durability(durability),
+            tag_repo: self.tag_repo.with_durability(durability),
+            ..self
+        }
+    }
+
+    /// See [`DocRepo::with_coalesce_window`]
+    pub fn with_doc_coalesce_window(self, window: Duration) -> Self {
+        Self {
+            doc_repo: self.doc_repo.with_coalesce_window(window),
+            ..self
+        }
+    }
+
+    /// See [`DocRepo::with_compression`]
+    pub fn with_doc_compression(self) -> Self {
+        Self {
+            doc_repo: self.doc_repo.with_compression(),
+            ..self
+        }
+    }
+
+    /// Capture the content of the page at a url at a time and an update to it with tags, either can be left out. A
+    /// withdrawn notice in the content is marked on the version, and the first version of a doc is marked with whether
+    /// it was first published or only first captured then. The tags are only written with a new update
+    pub fn capture(
+        &self,
+        url: Url,
+        timestamp: DateTime<FixedOffset>,
+        content: Option<&DocContent>,
+        change: Option<&str>,
+        tags: impl IntoIterator<Item = impl Into<String>>,
+    ) -> io::Result<Capture> {
+        let change = change.map(|change| (change, UpdateFields::default()));
+        self.write(url, timestamp, content, change, tags, false)
+    }
+
+    /// Capture like [`Self::capture`], with an update which has structured fields
+    pub fn capture_with_fields(
+        &self,
+        url: Url,
+        timestamp: DateTime<FixedOffset>,
+        content: Option<&DocContent>,
+        change: &str,
+        fields: UpdateFields,
+        tags: impl IntoIterator<Item = impl Into<String>>,
+    ) -> io::Result<Capture> {
+        self.write(url, timestamp, content, Some((change, fields)), tags, false)
+    }
+
+    /// Capture like [`Self::capture`], for captures which may have been made before, such as when reprocessing an email.
+    /// An update already in the repo with a different change is an error rather than being left alone, and the tags are
+    /// written to an update which is already in the repo, unless it is in them already
+    pub fn ensure(
+        &self,
+        url: Url,
+        timestamp: DateTime<FixedOffset>,
+        content: Option<&DocContent>,
+        change: Option<&str>,
+        tags: impl IntoIterator<Item = impl Into<String>>,
+    ) -> io::Result<Capture> {
+        let change = change.map(|change| (change, UpdateFields::default()));
+        self.write(url, timestamp, content, change, tags, true)
+    }
+
+    /// Ensure like [`Self::ensure`], with an update which has structured fields. An update which is already in the repo
+    /// keeps the fields it was written with
+    pub fn ensure_with_fields(
+        &self,
+        url: Url,
+        timestamp: DateTime<FixedOffset>,
+        content: Option<&DocContent>,
+        change: &str,
+        fields: UpdateFields,
+        tags: impl IntoIterator<Item = impl Into<String>>,
+    ) -> io::Result<Capture> {
+        self.write(url, timestamp, content, Some((change, fields)), tags, true)
+    }
+
+    fn write(
+        &self,
+        url: Url,
+        timestamp: DateTime<FixedOffset>,
+        content: Option<&DocContent>,
+        change: Option<(&str, UpdateFields)>,
+        tags: impl IntoIterator<Item = impl Into<String>>,
+        ensure: bool,
+    ) -> io::Result<Capture> {
+        let (doc, is_first) = match content {
+            Some(content) => {
+                let (doc, is_first) = self.write_doc(url.clone(), timestamp, content, ensure)?;
+                (Some(doc), is_first)
+            }
+            None => (None, false),
+        };
+        let update = match change {
+            Some((change, fields)) if ensure => {
+                let written = self.update_repo.ensure_with_fields(url, timestamp, change, fields)?;
+                let mut is_new = false;
+                written.for_each_event(|_, event| is_new |= matches!(event, UpdateEvent::Added { .. }));
+                let update = written.into_inner();
+                for tag in tags {
+                    self.tag_repo.ensure_tagged(tag.into(), update.update_ref().clone())?;
+                }
+                is_new.then(|| update)
+            }
+            Some((change, fields)) => match self.update_repo.create_with_fields(url, timestamp, change, fields) {
+                Ok(update) => {
+                    let update = update.into_inner();
+                    for tag in tags {
+                        self.tag_repo.tag_update(tag.into(), update.update_ref().clone())?;
+                    }
+                    Some(update)
+                }
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => None,
+                Err(err) => return Err(err),
+            },
+            None => None,
+        };
+        // classified after the update is written, as the update can be the doc's first
+        if let (Some(doc), Some(content), true) = (&doc, content, is_first) {
+            let first = FirstVersion::classify(content.history(), self.updates_until(doc)?);
+            self.doc_repo.mark_first_version(doc, first)?;
+        }
+        Ok(Capture { doc, update })
+    }
+
+    /// Write a version of a doc, and whether it is the doc's first version. When ensuring, a version already at the
+    /// timestamp is kept if it has the same content and is a conflict otherwise
+    fn write_doc(
+        &self,
+        url: Url,
+        timestamp: DateTime<FixedOffset>,
+        content: &DocContent,
+        ensure: bool,
+    ) -> io::Result<(DocumentVersion, bool)> {
+        if ensure {
+            if let Ok(doc) = self.doc_repo.ensure_version(url.clone(), timestamp) {
+                if self.doc_repo.read(&doc)?.as_ref() != content.as_ref() {
+                    return Err(io::Error::new(
+                        io::ErrorKind::AlreadyExists,
+                        format!("A different version of {} is already at {}", url, timestamp.to_rfc3339()),
+                    ));
+                }
+                return Ok((doc, false));
+            }
+        }
+        let written = self
+            .doc_repo
+            .create(url, timestamp, &mut *self.write_avoidance_buffer.borrow_mut())
+            .and_then(|mut doc| doc.write_all(content.as_ref()).and_then(|_| doc.done()))?;
+        let mut is_first = false;
+        written.for_each_event(|_, event| is_first |= matches!(event, DocEvent::Created { .. }));
+        let doc = written.into_inner();
+        if let Some(withdrawn_at) = content.withdrawn_at() {
+            self.doc_repo.mark_withdrawn(&doc, withdrawn_at.into())?;
+        }
+        Ok((doc, is_first))
+    }
+
+    /// The number of updates to the doc up to a version of it
+    fn updates_until(&self, doc: &DocumentVersion) -> io::Result<usize> {
+        let updates = match self.update_repo.list_updates(doc.url().clone()) {
+            Ok(updates) => updates,
+            Err(err) if err.kind() == io::ErrorKind::NotFound => return Ok(0),
+            Err(err) => return Err(err),
+        };
+        let mut count = 0;
+        for update in updates {
+            if update?.timestamp() <= doc.timestamp() {
+                count += 1;
+            }
+        }
+        Ok(count)
+    }
+
+    /// Delete an update, such as one written from a mis-parsed email, and remove it from the tags it is in. Returns
+    /// error if there is no such update
+    pub fn delete_update(&self, update_ref: &UpdateRef) -> io::Result<Update> {
+        self.update_repo.get(update_ref)?;
+        // untagged first, so that the tags never refer to an update which isn't in the repo
+        for tag in self.tag_repo.list_tags()? {
+            self.tag_repo.untag_update(tag.name().to_owned(), update_ref)?;
+        }
+        Ok(self.update_repo.delete(update_ref)?.into_inner())
+    }
+
+    /// For the writes which aren't captures, such as marking a page as removed, and for reading
+    pub fn doc_repo(&self) -> &DocRepo {
+        &self.doc_repo
+    }
+
+    /// For the writes which aren't captures, such as setting the source of an update, and for reading
+    pub fn update_repo(&self) -> &UpdateRepo {
+        &self.update_repo
+    }
+
+    /// For the writes which aren't captures, such as tagging an existing update, and for reading
+    pub fn tag_repo(&self) -> &TagRepo {
+        &self.tag_repo
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use std::{
+        fs,
+        sync::{Arc, Mutex},
+    };
+
+    use super::Tracker;
+    use crate::{
+        doc::{content::DocContent, DocEvent, DocumentVersion, FirstVersion},
+        repository::EventSink,
+        tag::{Tag, TagEvent},
+        update::{Update, UpdateEvent, UpdateRef},
+    };
+
+    #[derive(Default)]
+    struct Events(Mutex<Vec<String>>);
+
+    impl EventSink for Events {
+        fn on_update(&self, update: &Update, event: &UpdateEvent) {
+            if let UpdateEvent::Added { .. } = event {
+                self.0.lock().unwrap().push(format!("update {}", update.change()));
+            }
+        }
+
+        fn on_doc(&self, _doc: &DocumentVersion, event: &DocEvent) {
+            if let DocEvent::Updated { .. } = event {
+                self.0.lock().unwrap().push("doc".to_owned());
+            }
+        }
+
+        fn on_tag(&self, tag: &Tag, event: &TagEvent) {
+            if let TagEvent::UpdateTagged { .. } = event {
+                self.0.lock().unwrap().push(format!("tag {}", tag.name()));
+            }
+        }
+    }
+
+    #[test]
+    fn test_capture() {
+        let base = "tmp/test_capture";
+        let _ = fs::remove_dir_all(base);
+        let events = Arc::new(Events::default());
+        let tracker = Tracker::new(base).unwrap().with_event_sink(events.clone());
+        let url: crate::Url = "https://www.gov.uk/guidance/travel-abroad".parse().unwrap();
+        let content = DocContent::Other(b"<main>Visas are needed</main>".to_vec());
+
+        let capture = tracker
+            .capture(
+                url.clone(),
+                "2022-02-17T09:30:00+00:00".parse().unwrap(),
+                Some(&content),
+                Some("Added visa requirements"),
+                ["brexit"],
+            )
+            .unwrap();
+        assert_eq!(capture.update.unwrap().change(), "Added visa requirements");
+        assert_eq!(
+            capture.doc.unwrap().timestamp().to_rfc3339(),
+            "2022-02-17T09:30:00+00:00"
+        );
+        assert_eq!(
+            *events.0.lock().unwrap(),
+            ["doc", "update Added visa requirements", "tag brexit"]
+        );
+
+        // unchanged content is the earlier version, and the same update again isn't written or tagged
+        let capture = tracker
+            .capture(
+                url.clone(),
+                "2022-02-17T10:00:00+00:00".parse().unwrap(),
+                Some(&content),
+                None,
+                ["brexit"],
+            )
+            .unwrap();
+        assert!(capture.update.is_none());
+        assert_eq!(
+            capture.doc.unwrap().timestamp().to_rfc3339(),
+            "2022-02-17T09:30:00+00:00"
+        );
+        let capture = tracker
+            .capture(
+                url.clone(),
+                "2022-02-17T09:30:00+00:00".parse().unwrap(),
+                None,
+                Some("Added visa requirements"),
+                ["brexit"],
+            )
+            .unwrap();
+        assert!(capture.doc.is_none() && capture.update.is_none());
+        assert_eq!(events.0.lock().unwrap().len(), 3);
+        // the doc was captured with its only update
+        assert_eq!(
+            tracker.doc_repo().first_version(&url).unwrap(),
+            Some(FirstVersion::Published)
+        );
+
+        // ensuring the capture again tags the update without writing it again
+        let capture = tracker
+            .ensure(
+                url.clone(),
+                "2022-02-17T09:30:00+00:00".parse().unwrap(),
+                Some(&content),
+                Some("Added visa requirements"),
+                ["brexit", "visas"],
+            )
+            .unwrap();
+        assert!(capture.update.is_none());
+        assert_eq!(events.0.lock().unwrap()[3..], ["tag visas"]);
+        // but a different change at the same time is a conflict
+        assert!(tracker
+            .ensure(
+                url.clone(),
+                "2022-02-17T09:30:00+00:00".parse().unwrap(),
+                None,
+                Some("Added passport requirements"),
+                None::<String>,
+            )
+            .is_err());
+
+        // a doc captured without an update was already published
+        let url: crate::Url = "https://www.gov.uk/guidance/living-abroad".parse().unwrap();
+        tracker
+            .capture(
+                url.clone(),
+                "2022-02-17T10:00:00+00:00".parse().unwrap(),
+                Some(&content),
+                None,
+                None::<String>,
+            )
+            .unwrap();
+        assert_eq!(
+            tracker.doc_repo().first_version(&url).unwrap(),
+            Some(FirstVersion::Captured)
+        );
+
+        // a deleted update is gone from its tags
+        let update_ref = UpdateRef {
+            url: "https://www.gov.uk/guidance/travel-abroad".parse().unwrap(),
+            timestamp: "2022-02-17T09:30:00+00:00".parse().unwrap(),
+            seq: 0,
+        };
+        tracker.delete_update(&update_ref).unwrap();
+        assert!(tracker.update_repo().get(&update_ref).is_err());
+        for tag in ["brexit", "visas"] {
+            assert_eq!(tracker.tag_repo().list_updates_in_tag(tag).unwrap().count(), 0);
+        }
+    }
+}
@@ -0,0 +1,161 @@
+//! The format of an update's leaf file. Updates were first stored as only their change text, an update with structured
+//! fields starts with a header line and the fields, one `name: value` per line, followed by an empty line and then the
+//! change text:
+//!
+//! ```text
+//! %update 2
+//! category: Guidance and regulation
+//! source: updates/2022-02-17T09:53:57.eml
+//!
+//! The FCDO no longer advises against all travel
+//! ```
+//!
+//! Fields which aren't known are skipped when reading, so fields can be added without breaking older readers, and updates
+//! without any fields are still written as only their change text
+
+use std::fmt::Write;
+
+use super::UpdateRef;
+
+/// The first line of an update with fields
+const HEADER: &str = "%update 2\n";
+
+/// Structured fields stored along with an update's change, all are optional
+#[derive(Debug, Default, PartialEq, Eq, Clone)]
+pub struct UpdateFields {
+    /// the category of the change from the update email, such as "Guidance and regulation"
+    pub category: Option<String>,
+    /// where the update came from, such as the path of the email in the outbox
+    pub source: Option<String>,
+    /// how the attachments of the doc changed with the update
+    pub attachments: Option<AttachmentChanges>,
+    /// the earlier update which this one amends, when a change was published again with a corrected description
+    pub amended_from: Option<UpdateRef>,
+}
+
+/// The numbers of attachments of a doc which changed with an update
+#[derive(Debug, Default, PartialEq, Eq, Clone, Copy)]
+pub struct AttachmentChanges {
+    pub added: u32,
+    pub changed: u32,
+    pub removed: u32,
+}
+
+impl UpdateFields {
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+}
+
+/// The contents of the leaf file of an update
+pub(crate) fn encode(change: &str, fields: &UpdateFields) -> String {
+    // a change which looks like a header needs one to be read back
+    if fields.is_empty() && !change.starts_with(HEADER) {
+        return change.to_owned();
+    }
+    let mut encoded = HEADER.to_owned();
+    let mut field = |name: &str, value: &dyn std::fmt::Display| {
+        // values are on one line
+        let value = value.to_string().replace('\n', " ");
+        writeln!(encoded, "{}: {}", name, value).unwrap();
+    };
+    if let Some(category) = &fields.category {
+        field("category", category);
+    }
+    if let Some(source) = &fields.source {
+        field("source", source);
+    }
+    if let Some(attachments) = &fields.attachments {
+        field("attachments-added", &attachments.added);
+        field("attachments-changed", &attachments.changed);
+        field("attachments-removed", &attachments.removed);
+    }
+    if let Some(amended_from) = &fields.amended_from {
+        field("amended-from", amended_from);
+    }
+    encoded.push('\n');
+    encoded.push_str(change);
+    encoded
+}
+
+/// The change and fields from the contents of the leaf file of an update, in either format. Values which can't be
+/// parsed are skipped like unknown fields
+pub(crate) fn decode(contents: String) -> (String, UpdateFields) {
+    let rest = match contents.strip_prefix(HEADER) {
+        Some(rest) => rest,
+        None => return (contents, UpdateFields::default()),
+    };
+    let (header, change) = match rest.strip_prefix('\n') {
+        Some(change) => ("", change),
+        None => rest.split_once("\n\n").unwrap_or((rest, "")),
+    };
+    let mut fields = UpdateFields::default();
+    let mut attachments = AttachmentChanges::default();
+    let mut has_attachments = false;
+    for line in header.lines() {
+        let (name, value) = match line.split_once(": ") {
+            Some(field) => field,
+            None => continue,
+        };
+        match name {
+            "category" => fields.category = Some(value.to_owned()),
+            "source" => fields.source = Some(value.to_owned()),
+            "attachments-added" | "attachments-changed" | "attachments-removed" => {
+                if let Ok(count) = value.parse() {
+                    has_attachments = true;
+                    match name {
+                        "attachments-added" => attachments.added = count,
+                        "attachments-changed" => attachments.changed = count,
+                        _ => attachments.removed = count,
+                    }
+                }
+            }
+            "amended-from" => fields.amended_from = value.parse().ok(),
+            _ => {}
+        }
+    }
+    fields.attachments = has_attachments.then(|| attachments);
+    (change.to_owned(), fields)
+}
+
+#[cfg(test)]
+mod test {
+    use super::{decode, encode, AttachmentChanges, UpdateFields};
+
+    #[test]
+    fn fields_are_read_back() {
+        let fields = UpdateFields {
+            category: Some("Guidance and regulation".to_owned()),
+            source: Some("updates/2022-02-17T09:53:57.eml".to_owned()),
+            attachments: Some(AttachmentChanges {
+                added: 1,
+                changed: 2,
+                removed: 0,
+            }),
+            amended_from: Some(
+                "https://www.gov.uk/foreign-travel-advice/burundi#2022-02-17T09:30:00+00:00"
+                    .parse()
+                    .unwrap(),
+            ),
+        };
+        let change = "Updated entry requirements\n\nwith more lines";
+        let encoded = encode(change, &fields);
+        assert!(encoded.starts_with("%update 2\ncategory: Guidance and regulation\n"));
+        assert_eq!(decode(encoded), (change.to_owned(), fields));
+
+        // updates without fields are only their change, as they always were
+        assert_eq!(encode(change, &UpdateFields::default()), change);
+        assert_eq!(decode(change.to_owned()), (change.to_owned(), UpdateFields::default()));
+
+        // unknown fields are skipped
+        let encoded = "%update 2\nsource: email\nreviewed-by: someone\n\nA change".to_owned();
+        let (change, fields) = decode(encoded);
+        assert_eq!(change, "A change");
+        assert_eq!(fields.source.as_deref(), Some("email"));
+        assert_eq!(fields.category, None);
+
+        // a change which looks like a header is still read back
+        let change = "%update 2\nnot: a field";
+        assert_eq!(decode(encode(change, &UpdateFields::default())).0, change);
+    }
+}
@@ -1,25 +1,47 @@
-use std::{borrow::Borrow, fmt, str::FromStr};
-
-use chrono::{DateTime, FixedOffset};
-
-use crate::{repository::Entity, Url};
+use std::{
+    borrow::Borrow,
+    fmt::{self, Write},
+    str::FromStr,
+};
+
+use chrono::{DateTime, Duration, FixedOffset, Utc};
+
+use crate::{
+    repository::{normalize_timestamp, Entity},
+    Url,
+};
+mod fields;
+mod normalize;
 mod repository;
+pub use fields::{AttachmentChanges, UpdateFields};
+pub use normalize::ChangeNormalizer;
 pub use repository::UpdateRepo;
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub struct Update {
     update_ref: UpdateRef,
     change: String,
+    fields: UpdateFields,
 }
 
 impl Update {
     pub(crate) fn new(url: Url, timestamp: DateTime<FixedOffset>, change: String) -> Self {
         Self {
-            update_ref: UpdateRef { url, timestamp },
+            update_ref: UpdateRef { url, timestamp, seq: 0 },
             change,
+            fields: UpdateFields::default(),
         }
     }
 
+    pub(crate) fn with_fields(self, fields: UpdateFields) -> Self {
+        Self { fields, ..self }
+    }
+
+    pub(crate) fn with_seq(mut self, seq: u32) -> Self {
+        self.update_ref.seq = seq;
+        self
+    }
+
     pub fn url(&self) -> &Url {
         &self.update_ref.url
     }
@@ -32,6 +54,11 @@ impl Update {
         &self.change
     }
 
+    /// The structured fields stored with the update, updates written before fields were stored have none
+    pub fn fields(&self) -> &UpdateFields {
+        &self.fields
+    }
+
     pub fn update_ref(&self) -> &UpdateRef {
         &self.update_ref
     }
@@ -60,21 +87,33 @@ impl fmt::Display for Update {
 pub struct UpdateRef {
     pub url: Url,
     pub timestamp: DateTime<FixedOffset>,
+    /// Distinguishes updates to a url at the same timestamp, 0 for the first and then counting up in the order they were
+    /// written
+    pub seq: u32,
 }
 
-impl fmt::Display for UpdateRef {
-    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
-        fmt::write(f, format_args!("{}#{}", self.url.as_str(), self.timestamp.to_rfc3339()))
+impl UpdateRef {
+    /// The name of the update's leaves, its timestamp in UTC followed by `~` and its `seq` unless it is the first update
+    /// at the timestamp, which is named as all updates were before updates could share a timestamp
+    pub(crate) fn leaf_name(&self) -> String {
+        let timestamp = normalize_timestamp(self.timestamp).to_rfc3339();
+        match self.seq {
+            0 => timestamp,
+            seq => format!("{}~{}", timestamp, seq),
+        }
     }
-}
 
-impl FromStr for UpdateRef {
-    type Err = UpdateRefParseError;
+    /// Parse the name of a leaf of an update to a url
+    pub(crate) fn from_leaf_name(url: Url, name: &str) -> Result<Self, UpdateRefParseError> {
+        let (timestamp, seq) = parse_timestamp_and_seq(name)?;
+        Ok(Self { url, timestamp, seq })
+    }
 
-    fn from_str(s: &str) -> Result<Self, Self::Err> {
+    /// Parse the `{url}#{timestamp}` form
+    fn from_legacy_str(s: &str) -> Result<Self, UpdateRefParseError> {
         let mut url: url::Url = s.parse()?;
-        let timestamp = if let Some(fragment) = url.fragment() {
-            fragment.parse()?
+        let (timestamp, seq) = if let Some(fragment) = url.fragment() {
+            parse_timestamp_and_seq(fragment)?
         } else {
             return Err(UpdateRefParseError::FragmentNotProvided(url));
         };
@@ -82,13 +121,89 @@ impl FromStr for UpdateRef {
         Ok(UpdateRef {
             url: url.into(),
             timestamp,
+            seq,
         })
     }
+
+    /// A short id for permalinks, a hash of the url and the timestamp in UTC so that it is the same wherever it is worked out
+    pub fn short_id(&self) -> String {
+        const ALPHABET: &[u8; 32] = b"0123456789abcdefghjkmnpqrstvwxyz";
+        // hashed in the form update refs were written in before they had their own, so that ids don't change
+        let mut normalized = format!(
+            "{}#{}",
+            self.url.as_str(),
+            normalize_timestamp(self.timestamp).to_rfc3339()
+        );
+        if self.seq != 0 {
+            write!(normalized, "~{}", self.seq).unwrap();
+        }
+        // 64 bit FNV-1a, unlike the std hasher it is specified not to change
+        let mut hash: u64 = 0xcbf2_9ce4_8422_2325;
+        for byte in normalized.bytes() {
+            hash ^= u64::from(byte);
+            hash = hash.wrapping_mul(0x0100_0000_01b3);
+        }
+        (0..SHORT_ID_LEN)
+            .map(|i| ALPHABET[(hash >> (i * 5)) as usize & 31] as char)
+            .collect()
+    }
+}
+
+/// 50 bits of the hash, enough that ids won't collide for as many updates as gov.uk will make
+const SHORT_ID_LEN: usize = 10;
+
+/// A timestamp, optionally followed by `~` and a seq
+fn parse_timestamp_and_seq(s: &str) -> Result<(DateTime<FixedOffset>, u32), UpdateRefParseError> {
+    Ok(match s.split_once('~') {
+        Some((timestamp, seq)) => (
+            timestamp.parse()?,
+            seq.parse()
+                .map_err(|_| UpdateRefParseError::InvalidSeq(seq.to_owned()))?,
+        ),
+        None => (s.parse()?, 0),
+    })
+}
+
+/// Written as `{timestamp}|{url}`, with `~{seq}` after the timestamp when the update isn't the first to the url at the
+/// timestamp, eg. `2022-02-17T09:53:57+00:00|https://www.gov.uk/foreign-travel-advice`. This is how update refs are
+/// written in tag files and fields
+impl fmt::Display for UpdateRef {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.write_str(&self.timestamp.to_rfc3339())?;
+        if self.seq != 0 {
+            write!(f, "~{}", self.seq)?;
+        }
+        write!(f, "|{}", self.url.as_str())
+    }
+}
+
+/// Parses the form written by `Display`, and the `{url}#{timestamp}` form update refs were written in before, which
+/// older tag files still have until they are migrated
+impl FromStr for UpdateRef {
+    type Err = UpdateRefParseError;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        // a url can have a `|`, but it can't start with a timestamp
+        if let Some((timestamp, url)) = s.split_once('|') {
+            if let Ok((timestamp, seq)) = parse_timestamp_and_seq(timestamp) {
+                let url: url::Url = url.parse()?;
+                if url.fragment().is_some() {
+                    return Err(UpdateRefParseError::UnexpectedFragment(url));
+                }
+                return Ok(UpdateRef {
+                    url: url.into(),
+                    timestamp,
+                    seq,
+                });
+            }
+        }
+        Self::from_legacy_str(s)
+    }
 }
 
 impl From<(Url, DateTime<FixedOffset>)> for UpdateRef {
     fn from((url, timestamp): (Url, DateTime<FixedOffset>)) -> Self {
-        Self { url, timestamp }
+        Self { url, timestamp, seq: 0 }
     }
 }
 
@@ -97,6 +212,8 @@ pub enum UpdateRefParseError {
     ChronoParseError(chrono::ParseError),
     UrlParseError(url::ParseError),
     FragmentNotProvided(url::Url),
+    UnexpectedFragment(url::Url),
+    InvalidSeq(String),
 }
 
 impl From<chrono::ParseError> for UpdateRefParseError {
@@ -116,7 +233,9 @@ impl std::error::Error for UpdateRefParseError {
         match self {
             UpdateRefParseError::ChronoParseError(err) => Some(err),
             UpdateRefParseError::UrlParseError(err) => Some(err),
-            UpdateRefParseError::FragmentNotProvided(_) => None,
+            UpdateRefParseError::FragmentNotProvided(_)
+            | UpdateRefParseError::UnexpectedFragment(_)
+            | UpdateRefParseError::InvalidSeq(_) => None,
         }
     }
 }
@@ -127,10 +246,138 @@ impl fmt::Display for UpdateRefParseError {
             UpdateRefParseError::ChronoParseError(err) => write!(f, "Error parsing timestamp : {}", err),
             UpdateRefParseError::UrlParseError(err) => write!(f, "Error parsing url : {}", err),
             UpdateRefParseError::FragmentNotProvided(url) => write!(f, "Timestamp fragment not provided in {}", url),
+            UpdateRefParseError::UnexpectedFragment(url) => write!(f, "Unexpected fragment in {}", url),
+            UpdateRefParseError::InvalidSeq(seq) => write!(f, "Invalid seq {:?}", seq),
         }
     }
 }
 
+/// Checks made on updates before they are written to an [`UpdateRepo`]
+#[derive(Debug, Clone)]
+pub struct UpdateValidation {
+    /// Reject change descriptions which are empty or only whitespace
+    pub require_change: bool,
+    /// Reject change descriptions longer than this many bytes
+    pub max_change_len: Option<usize>,
+    /// Reject timestamps which are further than this ahead of the time of writing
+    pub max_future: Option<Duration>,
+}
+
+impl UpdateValidation {
+    /// No validation, for importing historic data which is already known to be odd
+    pub fn none() -> Self {
+        Self {
+            require_change: false,
+            max_change_len: None,
+            max_future: None,
+        }
+    }
+
+    pub(crate) fn validate(
+        &self,
+        timestamp: &DateTime<FixedOffset>,
+        change: &str,
+    ) -> Result<(), UpdateValidationError> {
+        if self.require_change && change.trim().is_empty() {
+            return Err(UpdateValidationError::EmptyChange);
+        }
+        if let Some(max) = self.max_change_len {
+            if change.len() > max {
+                return Err(UpdateValidationError::ChangeTooLong { len: change.len(), max });
+            }
+        }
+        if let Some(max_future) = self.max_future {
+            if *timestamp > Utc::now() + max_future {
+                return Err(UpdateValidationError::TimestampInFuture(*timestamp));
+            }
+        }
+        Ok(())
+    }
+}
+
+impl Default for UpdateValidation {
+    fn default() -> Self {
+        Self {
+            require_change: true,
+            max_change_len: Some(10 * 1024),
+            max_future: Some(Duration::hours(1)),
+        }
+    }
+}
+
+/// An update was rejected by [`UpdateValidation`], returned inside an [`std::io::Error`] of kind `InvalidInput`
+#[derive(Debug, PartialEq, Eq)]
+pub enum UpdateValidationError {
+    EmptyChange,
+    ChangeTooLong { len: usize, max: usize },
+    TimestampInFuture(DateTime<FixedOffset>),
+}
+
+impl std::error::Error for UpdateValidationError {}
+
+impl fmt::Display for UpdateValidationError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            UpdateValidationError::EmptyChange => write!(f, "Change description is empty"),
+            UpdateValidationError::ChangeTooLong { len, max } => {
+                write!(f, "Change description is {} bytes, more than the maximum {}", len, max)
+            }
+            UpdateValidationError::TimestampInFuture(timestamp) => {
+                write!(f, "Timestamp {} is in the future", timestamp.to_rfc3339())
+            }
+        }
+    }
+}
+
+impl From<UpdateValidationError> for std::io::Error {
+    fn from(error: UpdateValidationError) -> Self {
+        std::io::Error::new(std::io::ErrorKind::InvalidInput, error)
+    }
+}
+
+/// An update was already written at a url and timestamp with a different change, returned by [`UpdateRepo::ensure`]
+/// inside an [`std::io::Error`] of kind `AlreadyExists`
+#[derive(Debug, PartialEq, Eq)]
+pub struct UpdateConflict {
+    pub existing: Update,
+    pub attempted: Update,
+}
+
+impl UpdateConflict {
+    /// The conflict an error is, if it is one
+    pub fn from_error(error: &std::io::Error) -> Option<&Self> {
+        error.get_ref()?.downcast_ref()
+    }
+
+    /// The update with the longer change, which usually has the more complete description
+    pub fn longer(&self) -> &Update {
+        if self.attempted.change.len() > self.existing.change.len() {
+            &self.attempted
+        } else {
+            &self.existing
+        }
+    }
+}
+
+impl std::error::Error for UpdateConflict {}
+
+impl fmt::Display for UpdateConflict {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{} already has the change {:?}, not {:?}",
+            self.existing, self.existing.change, self.attempted.change
+        )
+    }
+}
+
+impl From<UpdateConflict> for std::io::Error {
+    fn from(conflict: UpdateConflict) -> Self {
+        std::io::Error::new(std::io::ErrorKind::AlreadyExists, conflict)
+    }
+}
+
+#[derive(Clone)]
 pub struct UpdateRefByUrl<U>(pub U);
 
 impl<U: Borrow<UpdateRef>> Eq for UpdateRefByUrl<U> {}
@@ -143,18 +390,20 @@ impl<L: Borrow<UpdateRef>, R: Borrow<UpdateRef>> PartialEq<UpdateRefByUrl<R>> fo
 
 impl<U: Borrow<UpdateRef>> Ord for UpdateRefByUrl<U> {
     fn cmp(&self, UpdateRefByUrl(other): &Self) -> std::cmp::Ordering {
-        let UpdateRef { url, timestamp } = self.0.borrow();
+        let UpdateRef { url, timestamp, seq } = self.0.borrow();
         url.cmp(&other.borrow().url)
             .then_with(|| timestamp.cmp(&other.borrow().timestamp))
+            .then_with(|| seq.cmp(&other.borrow().seq))
     }
 }
 
 impl<L: Borrow<UpdateRef>, R: Borrow<UpdateRef>> PartialOrd<UpdateRefByUrl<R>> for UpdateRefByUrl<L> {
     fn partial_cmp(&self, UpdateRefByUrl(other): &UpdateRefByUrl<R>) -> Option<std::cmp::Ordering> {
-        let UpdateRef { url, timestamp } = self.0.borrow();
+        let UpdateRef { url, timestamp, seq } = self.0.borrow();
         Some(
             url.cmp(&other.borrow().url)
-                .then_with(|| timestamp.cmp(&other.borrow().timestamp)),
+                .then_with(|| timestamp.cmp(&other.borrow().timestamp))
+                .then_with(|| seq.cmp(&other.borrow().seq)),
         )
     }
 }
@@ -171,13 +420,16 @@ impl From<UpdateRefByUrl<UpdateRef>> for UpdateRef {
     }
 }
 
-#[derive(PartialEq, Eq)]
+#[derive(Clone, PartialEq, Eq)]
 pub struct UpdateRefByTimestamp(pub UpdateRef);
 
 impl Ord for UpdateRefByTimestamp {
     fn cmp(&self, UpdateRefByTimestamp(other): &Self) -> std::cmp::Ordering {
-        let UpdateRefByTimestamp(UpdateRef { url, timestamp }) = self;
-        timestamp.cmp(&other.timestamp).then_with(|| url.cmp(&other.url))
+        let UpdateRefByTimestamp(UpdateRef { url, timestamp, seq }) = self;
+        timestamp
+            .cmp(&other.timestamp)
+            .then_with(|| url.cmp(&other.url))
+            .then_with(|| seq.cmp(&other.seq))
     }
 }
 
@@ -199,12 +451,14 @@ impl From<UpdateRefByTimestamp> for UpdateRef {
     }
 }
 
-#[derive(Debug, PartialEq, Eq)]
+#[derive(Debug, PartialEq, Eq, Clone)]
 pub enum UpdateEvent {
     /// Any update is added
     Added { url: Url, timestamp: DateTime<FixedOffset> },
     /// A new newest update for a document is added
     New { url: Url, timestamp: DateTime<FixedOffset> },
+    /// An update is deleted, such as one written from a mis-parsed email
+    Deleted { url: Url, timestamp: DateTime<FixedOffset> },
 }
 
 impl UpdateEvent {
@@ -221,4 +475,11 @@ impl UpdateEvent {
             timestamp: *update.timestamp(),
         }
     }
+
+    pub(crate) fn deleted(update: &Update) -> UpdateEvent {
+        Self::Deleted {
+            url: update.url().clone(),
+            timestamp: *update.timestamp(),
+        }
+    }
 }
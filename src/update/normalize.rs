@@ -0,0 +1,115 @@
+use std::borrow::Cow;
+
+/// Normalises change descriptions, so that descriptions of the same change which only differ in whitespace or
+/// punctuation, as when an email was reformatted or an import cleaned the text up, aren't conflicts in
+/// [`super::UpdateRepo::ensure`]. The default compares descriptions exactly and writes them as they are
+#[derive(Debug, Clone, Default)]
+pub struct ChangeNormalizer {
+    /// Collapse runs of whitespace into a space and trim the ends
+    pub whitespace: bool,
+    /// Replace typographic quotes, dashes and ellipses with their ASCII equivalents
+    pub typography: bool,
+    /// Ignore ASCII punctuation when comparing, it is never removed from written descriptions
+    pub punctuation: bool,
+    /// Write descriptions with the whitespace and typography normalisation applied, rather than as they were given
+    pub on_write: bool,
+}
+
+impl ChangeNormalizer {
+    /// Compare descriptions ignoring whitespace, typography and punctuation, and write them as they are given
+    pub fn lenient() -> Self {
+        Self {
+            whitespace: true,
+            typography: true,
+            punctuation: true,
+            on_write: false,
+        }
+    }
+
+    /// The description as it is written
+    pub(crate) fn for_write<'c>(&self, change: &'c str) -> Cow<'c, str> {
+        if self.on_write {
+            self.normalize(change)
+        } else {
+            Cow::Borrowed(change)
+        }
+    }
+
+    /// Whether two descriptions are of the same change
+    pub fn same(&self, a: &str, b: &str) -> bool {
+        a == b || self.comparable(a) == self.comparable(b)
+    }
+
+    /// The description with the whitespace and typography normalisation applied
+    pub fn normalize<'c>(&self, change: &'c str) -> Cow<'c, str> {
+        let mut change = Cow::Borrowed(change);
+        if self.typography {
+            let mut replaced = String::with_capacity(change.len());
+            for c in change.chars() {
+                match c {
+                    '\u{2018}' | '\u{2019}' | '\u{201b}' | '\u{2032}' => replaced.push('\''),
+                    '\u{201c}' | '\u{201d}' | '\u{201f}' | '\u{2033}' => replaced.push('"'),
+                    '\u{2010}' | '\u{2011}' | '\u{2012}' | '\u{2013}' | '\u{2014}' | '\u{2212}' => replaced.push('-'),
+                    '\u{2026}' => replaced.push_str("..."),
+                    '\u{a0}' | '\u{2009}' | '\u{202f}' => replaced.push(' '),
+                    c => replaced.push(c),
+                }
+            }
+            if replaced != change {
+                change = Cow::Owned(replaced);
+            }
+        }
+        if self.whitespace {
+            let collapsed = change.split_whitespace().collect::<Vec<_>>().join(" ");
+            if collapsed != change {
+                change = Cow::Owned(collapsed);
+            }
+        }
+        change
+    }
+
+    fn comparable(&self, change: &str) -> String {
+        let change = self.normalize(change);
+        if self.punctuation {
+            let without: String = change.chars().filter(|c| !c.is_ascii_punctuation()).collect();
+            // removing punctuation can leave doubled spaces
+            if self.whitespace {
+                return without.split_whitespace().collect::<Vec<_>>().join(" ");
+            }
+            return without;
+        }
+        change.into_owned()
+    }
+}
+
+#[cfg(test)]
+mod test {
+    use super::ChangeNormalizer;
+
+    #[test]
+    fn changes_differing_in_punctuation_are_the_same() {
+        let exact = ChangeNormalizer::default();
+        let lenient = ChangeNormalizer::lenient();
+        let change = "Updated ‘Entry requirements’ – tests are no longer needed.";
+        for other in [
+            "Updated 'Entry requirements' - tests are no longer needed.",
+            "Updated  ‘Entry requirements’ – tests are no longer needed\n",
+            "Updated Entry requirements - tests are no longer needed",
+        ] {
+            assert!(!exact.same(change, other));
+            assert!(lenient.same(change, other), "{:?}", other);
+        }
+        assert!(!lenient.same(change, "Updated 'Entry requirements' - tests are needed."));
+
+        // punctuation is only ignored in comparisons
+        let on_write = ChangeNormalizer {
+            on_write: true,
+            ..ChangeNormalizer::lenient()
+        };
+        assert_eq!(
+            on_write.for_write(change),
+            "Updated 'Entry requirements' - tests are no longer needed."
+        );
+        assert_eq!(lenient.for_write(change), change);
+    }
+}
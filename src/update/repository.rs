@@ -11,73 +11,234 @@ use std::{
     fs::{self},
     io::{self, Write},
     path::{Path, PathBuf},
+    sync::Arc,
 };
 
 pub struct UpdateRepo {
     repo: UrlRepo,
+    /// A marker per url holding the timestamp of its latest update, so that it doesn't need to be found by listing
+    latest_markers: UrlRepo,
+    /// The source of each update, such as the path of the email it came in
+    sources: UrlRepo,
+    validation: UpdateValidation,
+    normalizer: ChangeNormalizer,
+    durability: Durability,
+    event_sink: Option<Arc<dyn EventSink>>,
 }
 
 impl UpdateRepo {
     pub fn new(base: impl AsRef<Path>) -> io::Result<Self> {
-        let repo = UrlRepo::new("update", base)?;
-        Ok(Self { repo })
+        let repo = UrlRepo::new("update", &base)?;
+        let latest_markers = UrlRepo::new("update-latest", &base)?;
+        let sources = UrlRepo::new("update-source", &base)?;
+        Ok(Self {
+            repo,
+            latest_markers,
+            sources,
+            validation: UpdateValidation::default(),
+            normalizer: ChangeNormalizer::default(),
+            durability: Durability::default(),
+            event_sink: None,
+        })
+    }
+
+    /// Replace the checks made on updates before they are written
+    pub fn with_validation(mut self, validation: UpdateValidation) -> Self {
+        self.validation = validation;
+        self
+    }
+
+    /// Replace how changes are compared by `ensure`, and normalised as they are written
+    pub fn with_change_normalizer(mut self, normalizer: ChangeNormalizer) -> Self {
+        self.normalizer = normalizer;
+        self
+    }
+
+    /// Replace how writes are made durable
+    pub fn with_durability(mut self, durability: Durability) -> Self {
+        self.durability = durability;
+        self
+    }
+
+    /// Send the events of all writes to a sink
+    pub fn with_event_sink(mut self, event_sink: Arc<dyn EventSink>) -> Self {
+        self.event_sink = Some(event_sink);
+        self
     }
 
     /// Write an update
     pub fn create(&self, url: Url, timestamp: DateTime<FixedOffset>, change: &str) -> WriteResult<Update, 2> {
-        let path = self.path_for(&url, Some(&timestamp));
-        let update = Update::new(url, timestamp, change.to_owned());
-        if let Some(parent) = path.parent() {
-            fs::create_dir_all(parent)?;
-        }
-        let mut file = fs::OpenOptions::new().write(true).create_new(true).open(path)?;
-        file.write_all(update.change.as_bytes())?;
-        file.flush()?;
+        self.create_with_fields(url, timestamp, change, UpdateFields::default())
+    }
+
+    /// Write an update along with structured fields. A different change to the url already written at the timestamp is
+    /// kept and this one is written after it with the next `seq`, writing the same change again is an error
+    pub fn create_with_fields(
+        &self,
+        url: Url,
+        timestamp: DateTime<FixedOffset>,
+        change: &str,
+        fields: UpdateFields,
+    ) -> WriteResult<Update, 2> {
+        let timestamp = normalize_timestamp(timestamp);
+        let change = self.normalizer.for_write(change);
+        self.validation.validate(&timestamp, &change)?;
+        let mut update = Update::new(url, timestamp, change.into_owned()).with_fields(fields);
+        let (mut file, path) = loop {
+            let path = self.leaf_path(&update.update_ref);
+            if let Some(parent) = path.parent() {
+                fs::create_dir_all(parent)?;
+            }
+            match fs::OpenOptions::new().write(true).create_new(true).open(&path) {
+                Err(err) if err.kind() == io::ErrorKind::AlreadyExists => {
+                    let existing = self.get(&update.update_ref)?;
+                    if self.normalizer.same(&update.change, &existing.change) {
+                        return Err(err);
+                    }
+                    update.update_ref.seq += 1;
+                }
+                file => break (file?, path),
+            }
+        };
+        file.write_all(fields::encode(&update.change, &update.fields).as_bytes())?;
+        self.durability.sync(&file, &path)?;
 
-        let is_latest = self.latest(update.url())? == timestamp;
+        let is_latest = self.mark_if_latest(update.url(), timestamp)?;
         let events = [
             Some(UpdateEvent::added(&update)),
             is_latest.then(|| UpdateEvent::new(&update)),
         ];
-        update.with_events(events)
+        self.notify(update.with_events(events))
     }
 
     /// Write an update, or verify that the update is already written
     pub fn ensure(&self, url: Url, timestamp: DateTime<FixedOffset>, change: &str) -> WriteResult<Update, 2> {
-        let path = self.path_for(&url, Some(&timestamp));
-        let update = Update::new(url, timestamp, change.to_owned());
+        self.ensure_with_fields(url, timestamp, change, UpdateFields::default())
+    }
+
+    /// Write an update along with structured fields, or verify that the update is already written. An update already
+    /// written with the same change, as compared by the [`ChangeNormalizer`], at any `seq` of the timestamp is left as it
+    /// was written. When the url only has updates with different changes at the timestamp it is an [`UpdateConflict`]
+    /// with the first of them, as it can't be told whether the change was reworded or is another change
+    pub fn ensure_with_fields(
+        &self,
+        url: Url,
+        timestamp: DateTime<FixedOffset>,
+        change: &str,
+        fields: UpdateFields,
+    ) -> WriteResult<Update, 2> {
+        let timestamp = normalize_timestamp(timestamp);
+        let change = self.normalizer.for_write(change).into_owned();
+        let update = Update::new(url, timestamp, change).with_fields(fields);
+        let path = self.leaf_path(&update.update_ref);
         if let Some(parent) = path.parent() {
             fs::create_dir_all(parent)?;
         }
-        if let Ok(mut file) = fs::OpenOptions::new().read(true).open(&path) {
-            let mut contents = String::new();
-            file.read_to_string(&mut contents)?;
-            if change == contents {
-                return update.with_events(Default::default());
+        let mut first = None;
+        for seq in 0.. {
+            let update_ref = UpdateRef {
+                seq,
+                ..update.update_ref.clone()
+            };
+            let written = match self.get(&update_ref) {
+                Ok(written) => written,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => break,
+                Err(err) => return Err(err),
+            };
+            if self.normalizer.same(&update.change, &written.change) {
+                return written.with_events(Default::default());
+            }
+            first.get_or_insert(written);
+        }
+        if let Some(existing) = first {
+            return Err(UpdateConflict {
+                existing,
+                attempted: update,
             }
+            .into());
         }
+        self.validation.validate(&timestamp, &update.change)?;
 
         let mut file = fs::OpenOptions::new().write(true).create_new(true).open(&path)?;
-        file.write_all(update.change.as_bytes())?;
-        file.flush()?;
+        file.write_all(fields::encode(&update.change, &update.fields).as_bytes())?;
+        self.durability.sync(&file, &path)?;
 
-        let is_latest = self.latest(update.url())? == timestamp;
+        let is_latest = self.mark_if_latest(update.url(), timestamp)?;
         let events = [
             Some(UpdateEvent::added(&update)),
             is_latest.then(|| UpdateEvent::new(&update)),
         ];
-        update.with_events(events)
+        self.notify(update.with_events(events))
+    }
+
+    fn notify(&self, result: WriteResult<Update, 2>) -> WriteResult<Update, 2> {
+        if let (Ok(written), Some(sink)) = (&result, &self.event_sink) {
+            written.for_each_event(|update, event| sink.on_update(update, event));
+        }
+        result
+    }
+
+    /// Delete an update, such as one written from a mis-parsed email, along with its recorded source. Returns error if
+    /// there is no such update. The tags it is in aren't changed, [`crate::tracker::Tracker::delete_update`] removes
+    /// it from them too
+    pub fn delete(&self, update_ref: &UpdateRef) -> WriteResult<Update, 1> {
+        let update = self.get(update_ref)?;
+        fs::remove_file(self.leaf_path(update.update_ref()))?;
+        match fs::remove_file(self.sources.leaf_path(update.url(), &update.update_ref().leaf_name())) {
+            Err(err) if err.kind() != io::ErrorKind::NotFound => return Err(err),
+            _ => {}
+        }
+        // the marker is of the latest of those left, or is removed if none are
+        if matches!(self.latest(update.url()), Ok(latest) if latest == *update.timestamp()) {
+            let marker_path = self.latest_marker_path(update.url());
+            match self.find_latest(update.url()) {
+                Ok(latest) => self.durability.write(marker_path, latest.to_rfc3339())?,
+                Err(err) if err.kind() == io::ErrorKind::NotFound => fs::remove_file(marker_path)?,
+                Err(err) => return Err(err),
+            }
+        }
+        let event = UpdateEvent::deleted(&update);
+        let written = update.with_events([Some(event)])?;
+        if let Some(sink) = &self.event_sink {
+            written.for_each_event(|update, event| sink.on_update(update, event));
+        }
+        Ok(written)
     }
 
     /// Get the latest update under a url. Returns error if there is no update
     pub fn latest(&self, url: &Url) -> io::Result<DateTime<FixedOffset>> {
+        match fs::read_to_string(self.latest_marker_path(url)) {
+            Ok(marker) => marker
+                .parse()
+                .map_err(|error| io::Error::new(io::ErrorKind::Other, error)),
+            Err(err) if err.kind() == io::ErrorKind::NotFound => {
+                // no marker yet, possibly written before markers existed, so find it and leave a marker
+                let latest = self.find_latest(url)?;
+                self.durability.write(self.latest_marker_path(url), latest.to_rfc3339())?;
+                Ok(latest)
+            }
+            Err(err) => Err(err),
+        }
+    }
+
+    /// Update the latest marker for a url if the newly written timestamp is later, returns whether it was
+    fn mark_if_latest(&self, url: &Url, timestamp: DateTime<FixedOffset>) -> io::Result<bool> {
+        let is_latest = self.latest(url)? <= timestamp;
+        if is_latest {
+            self.durability.write(self.latest_marker_path(url), timestamp.to_rfc3339())?;
+        }
+        Ok(is_latest)
+    }
+
+    /// Find the latest update under a url by listing all of them
+    fn find_latest(&self, url: &Url) -> io::Result<DateTime<FixedOffset>> {
         let dir = self.repo.read_leaves_for_url(url)?;
         let mut latest = None;
         for entry in dir {
             let (name, _) = entry?;
-            let timestamp: DateTime<FixedOffset> = name
-                .parse()
-                .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
+            let timestamp = UpdateRef::from_leaf_name(url.clone(), &name)
+                .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?
+                .timestamp;
             if let Some(latest_i) = latest {
                 latest = Some(max(latest_i, timestamp));
             } else {
@@ -87,13 +248,39 @@ impl UpdateRepo {
         latest.ok_or_else(|| io::ErrorKind::NotFound.into())
     }
 
+    /// Get the first update to a url at a timestamp
     pub fn get_update(&self, url: Url, timestamp: DateTime<FixedOffset>) -> io::Result<Update> {
-        let mut file = fs::File::open(self.path_for(&url, Some(&timestamp)))?;
+        self.get(&UpdateRef::from((url, timestamp)))
+    }
+
+    pub fn get(&self, update_ref: &UpdateRef) -> io::Result<Update> {
+        let timestamp = normalize_timestamp(update_ref.timestamp);
+        let mut file = fs::File::open(self.leaf_path(update_ref))?;
         let mut change = vec![];
         file.read_to_end(&mut change)?;
         let change = String::from_utf8(change).map_err(|err| io::Error::new(io::ErrorKind::Other, err))?;
-        let doc_version = Update::new(url, timestamp, change);
-        Ok(doc_version)
+        let (change, fields) = fields::decode(change);
+        Ok(Update::new(update_ref.url.clone(), timestamp, change)
+            .with_seq(update_ref.seq)
+            .with_fields(fields))
+    }
+
+    /// Record where an update came from, replacing any source recorded before
+    pub fn set_source(&self, update_ref: &UpdateRef, source: &str) -> io::Result<()> {
+        let path = self.sources.leaf_path(&update_ref.url, &update_ref.leaf_name());
+        if let Some(parent) = path.parent() {
+            fs::create_dir_all(parent)?;
+        }
+        self.durability.write(path, source)
+    }
+
+    /// Where an update came from, the source set on it or otherwise the source it was written with. Returns error if no
+    /// source was recorded, as for updates written before sources were
+    pub fn get_source(&self, update_ref: &UpdateRef) -> io::Result<String> {
+        match fs::read_to_string(self.sources.leaf_path(&update_ref.url, &update_ref.leaf_name())) {
+            Err(err) if err.kind() == io::ErrorKind::NotFound => self.get(update_ref)?.fields.source.ok_or(err),
+            result => result,
+        }
     }
 
     /// Lists all updates on the specified url from newest to oldest
@@ -101,46 +288,108 @@ impl UpdateRepo {
         let files = self.repo.read_leaves_sorted_for_url(&url)?;
 
         Ok(files.rev().map(move |dir_entry| {
-            let timestamp = dir_entry
-                .0
-                .parse()
-                .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
-            let change = String::from_utf8(fs::read(&self.path_for(&url, Some(&timestamp)))?)
+            let update_ref = UpdateRef::from_leaf_name(url.clone(), &dir_entry.0)
                 .map_err(|error| io::Error::new(io::ErrorKind::Other, error))?;
-            Ok(Update::new(url.clone(), timestamp, change))
+            self.get(&update_ref)
         }))
     }
 
+    /// The urls one path segment under a url which may have updates, so that the updates under a url can be listed in
+    /// parts with `list_all`
+    pub fn child_urls(&self, url: &Url) -> io::Result<Vec<Url>> {
+        self.repo.child_urls(url)
+    }
+
     /// Lists all updates
     pub fn list_all(&self, base_url: &Url) -> io::Result<IterUrlRepoLeaves<'_, Update>> {
         self.repo.list_all(base_url.clone(), |url, name, dir_entry| {
-            let timestamp = name
-                .parse()
+            let update_ref = UpdateRef::from_leaf_name(url, name)
                 .map_err(|error| io::Error::new(io::ErrorKind::Other, error))
                 .unwrap();
-            let change = fs::read_to_string(dir_entry.path()).unwrap();
+            let (change, fields) = fields::decode(fs::read_to_string(dir_entry.path()).unwrap());
             Update {
-                update_ref: UpdateRef { url, timestamp },
+                update_ref,
                 change,
+                fields,
             }
         })
     }
 
-    fn path_for(&self, url: &Url, timestamp: Option<&DateTime<FixedOffset>>) -> PathBuf {
-        if let Some(timestamp) = timestamp {
-            self.repo.leaf_path(url, &timestamp.to_rfc3339())
-        } else {
-            self.repo.node_path(url)
-        }
+    fn latest_marker_path(&self, url: &Url) -> PathBuf {
+        self.latest_markers.leaf_path(url, "")
+    }
+
+    fn leaf_path(&self, update_ref: &UpdateRef) -> PathBuf {
+        self.repo.leaf_path(&update_ref.url, &update_ref.leaf_name())
     }
 }
 
 #[cfg(test)]
 mod test {
+    use std::sync::Mutex;
+
     use chrono::Utc;
 
     use super::*;
 
+    #[test]
+    fn writes_send_events_to_sink() {
+        #[derive(Default)]
+        struct RecordingSink(Mutex<Vec<(String, UpdateEvent)>>);
+        impl EventSink for RecordingSink {
+            fn on_update(&self, update: &Update, event: &UpdateEvent) {
+                self.0.lock().unwrap().push((update.change().to_owned(), event.clone()));
+            }
+        }
+
+        let sink = Arc::new(RecordingSink::default());
+        let repo = test_repo("update::writes_send_events_to_sink").with_event_sink(sink.clone());
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let timestamp: DateTime<FixedOffset> = "2022-02-17T09:53:00+00:00".parse().unwrap();
+
+        repo.create(url.clone(), timestamp, "a change").unwrap();
+        repo.ensure(url.clone(), timestamp, "a change").unwrap();
+
+        assert_eq!(
+            sink.0.lock().unwrap().as_slice(),
+            [
+                (
+                    "a change".to_owned(),
+                    UpdateEvent::Added {
+                        url: url.clone(),
+                        timestamp
+                    }
+                ),
+                ("a change".to_owned(), UpdateEvent::New { url, timestamp }),
+            ]
+        );
+    }
+
+    #[test]
+    fn sources_are_recorded() {
+        let repo = test_repo("update::sources_are_recorded");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let timestamp: DateTime<FixedOffset> = "2022-02-17T09:53:00+01:00".parse().unwrap();
+        let update = repo.create(url, timestamp, "a change").unwrap();
+
+        assert_eq!(
+            repo.get_source(update.update_ref()).unwrap_err().kind(),
+            io::ErrorKind::NotFound
+        );
+        repo.set_source(update.update_ref(), "govuk@example.org/1645091580.eml")
+            .unwrap();
+        assert_eq!(
+            repo.get_source(update.update_ref()).unwrap(),
+            "govuk@example.org/1645091580.eml"
+        );
+        assert_eq!(
+            repo.list_all(&"http://www.example.org/".parse().unwrap())
+                .unwrap()
+                .count(),
+            1
+        );
+    }
+
     #[test]
     fn old_update_creates_events_and_becomes_available() {
         let repo = test_repo("update::new_update_creates_events_and_becomes_available");
@@ -314,6 +563,261 @@ mod test {
         assert!(list.next().is_none());
     }
 
+    #[test]
+    fn conflicting_ensure_is_typed_conflict() {
+        let repo = test_repo("update::conflicting_ensure_is_typed_conflict");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let timestamp: DateTime<FixedOffset> = Utc::now().into();
+        let _ = repo.create(url.clone(), timestamp, "a change").unwrap();
+
+        let err = repo.ensure(url.clone(), timestamp, "a longer change").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::AlreadyExists);
+        let conflict = UpdateConflict::from_error(&err).unwrap();
+        assert_eq!(conflict.existing.change(), "a change");
+        assert_eq!(conflict.attempted.change(), "a longer change");
+        assert_eq!(conflict.longer().change(), "a longer change");
+        assert_eq!(repo.get_update(url, timestamp).unwrap().change(), "a change");
+    }
+
+    #[test]
+    fn different_changes_at_the_same_timestamp_are_kept() {
+        let repo = test_repo("update::different_changes_at_the_same_timestamp_are_kept");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let timestamp: DateTime<FixedOffset> = "2022-02-17T09:53:00+00:00".parse().unwrap();
+
+        let first = repo.create(url.clone(), timestamp, "a change").unwrap().into_inner();
+        let second = repo
+            .create(url.clone(), timestamp, "another change")
+            .unwrap()
+            .into_inner();
+        assert_eq!(first.update_ref().seq, 0);
+        assert_eq!(second.update_ref().seq, 1);
+        assert_eq!(
+            second.update_ref().to_string(),
+            "2022-02-17T09:53:00+00:00~1|http://www.example.org/test/doc"
+        );
+        assert_eq!(
+            second.update_ref().to_string().parse::<UpdateRef>().unwrap(),
+            *second.update_ref()
+        );
+        assert_eq!(
+            repo.create(url.clone(), timestamp, "a change").err().unwrap().kind(),
+            io::ErrorKind::AlreadyExists
+        );
+
+        assert_eq!(repo.get_update(url.clone(), timestamp).unwrap(), first);
+        assert_eq!(repo.get(second.update_ref()).unwrap(), second);
+        assert_eq!(
+            repo.list_updates(url.clone())
+                .unwrap()
+                .map(Result::unwrap)
+                .collect::<Vec<_>>(),
+            [second.clone(), first.clone()]
+        );
+        assert_eq!(
+            repo.list_all(&url).unwrap().map(Result::unwrap).collect::<Vec<_>>(),
+            [first, second]
+        );
+
+        // either change is already written
+        let update = repo.ensure(url.clone(), timestamp, "another change").unwrap();
+        assert_eq!(update.update_ref().seq, 1);
+        assert_eq!(update.into_events().count(), 0);
+        let err = repo.ensure(url, timestamp, "a third change").err().unwrap();
+        assert_eq!(UpdateConflict::from_error(&err).unwrap().existing.change(), "a change");
+    }
+
+    #[test]
+    fn ensure_compares_with_normalizer() {
+        let repo =
+            test_repo("update::ensure_compares_with_normalizer").with_change_normalizer(ChangeNormalizer::lenient());
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let timestamp: DateTime<FixedOffset> = Utc::now().into();
+        let _ = repo.create(url.clone(), timestamp, "Updated ‘Entry requirements’.").unwrap();
+
+        let update = repo
+            .ensure(url.clone(), timestamp, "Updated  'Entry requirements'")
+            .unwrap();
+        assert_eq!(update.change(), "Updated ‘Entry requirements’.");
+        assert_eq!(update.into_events().count(), 0);
+        assert!(repo.ensure(url, timestamp, "Updated 'Exit requirements'").is_err());
+    }
+
+    #[test]
+    fn fields_are_stored_with_update() {
+        let repo = test_repo("update::fields_are_stored_with_update");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let timestamp: DateTime<FixedOffset> = "2022-02-17T09:53:00+00:00".parse().unwrap();
+        let fields = UpdateFields {
+            category: Some("Guidance and regulation".to_owned()),
+            source: Some("updates/2022-02-17T09:53:57.eml".to_owned()),
+            ..UpdateFields::default()
+        };
+
+        let update = repo
+            .create_with_fields(url.clone(), timestamp, "a change", fields.clone())
+            .unwrap();
+        assert_eq!(update.fields(), &fields);
+        let update = repo.get_update(url.clone(), timestamp).unwrap();
+        assert_eq!((update.change(), update.fields()), ("a change", &fields));
+        let update = repo.list_all(&url).unwrap().next().unwrap().unwrap();
+        assert_eq!((update.change(), update.fields()), ("a change", &fields));
+        // the source it was written with is its source until another is set
+        assert_eq!(
+            repo.get_source(update.update_ref()).unwrap(),
+            "updates/2022-02-17T09:53:57.eml"
+        );
+        repo.set_source(update.update_ref(), "updates/other.eml").unwrap();
+        assert_eq!(repo.get_source(update.update_ref()).unwrap(), "updates/other.eml");
+
+        // ensuring the same change leaves the fields it was written with
+        let update = repo
+            .ensure_with_fields(url.clone(), timestamp, "a change", UpdateFields::default())
+            .unwrap();
+        assert_eq!(update.fields(), &fields);
+        assert_eq!(update.into_events().count(), 0);
+    }
+
+    #[test]
+    fn invalid_updates_are_rejected_unless_validation_disabled() {
+        let repo = test_repo("update::invalid_updates_are_rejected_unless_validation_disabled");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let future = DateTime::<FixedOffset>::from(Utc::now()) + chrono::Duration::days(2);
+
+        let err = repo.create(url.clone(), Utc::now().into(), " ").err().unwrap();
+        assert_eq!(err.kind(), io::ErrorKind::InvalidInput);
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref::<UpdateValidationError>(),
+            Some(&UpdateValidationError::EmptyChange)
+        );
+        let err = repo.ensure(url.clone(), future, "change").err().unwrap();
+        assert_eq!(
+            err.get_ref().unwrap().downcast_ref::<UpdateValidationError>(),
+            Some(&UpdateValidationError::TimestampInFuture(future))
+        );
+        assert!(repo.list_updates(url.clone()).unwrap().next().is_none());
+
+        let repo = repo.with_validation(UpdateValidation::none());
+        let _ = repo.create(url.clone(), future, "").unwrap();
+        assert_eq!(repo.latest(&url).unwrap(), future);
+    }
+
+    #[test]
+    fn latest_is_tracked_and_recovered_without_marker() {
+        let repo = test_repo("update::latest_is_tracked_and_recovered_without_marker");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+
+        for timestamp in [
+            "2021-03-01T11:00:00+00:00",
+            "2021-03-01T12:00:00+00:00",
+            "2021-03-01T10:00:00+00:00",
+        ] {
+            let _ = repo.create(url.clone(), timestamp.parse().unwrap(), "change").unwrap();
+        }
+        assert_eq!(repo.latest(&url).unwrap().to_rfc3339(), "2021-03-01T12:00:00+00:00");
+
+        fs::remove_file(repo.latest_marker_path(&url)).unwrap();
+        assert_eq!(repo.latest(&url).unwrap().to_rfc3339(), "2021-03-01T12:00:00+00:00");
+
+        let update = repo
+            .create(url.clone(), "2021-03-01T13:00:00+00:00".parse().unwrap(), "change")
+            .unwrap();
+        assert_eq!(update.into_events().count(), 2);
+        assert_eq!(repo.latest(&url).unwrap().to_rfc3339(), "2021-03-01T13:00:00+00:00");
+    }
+
+    #[test]
+    fn deleted_updates_are_gone_and_latest_is_found_again() {
+        let repo = test_repo("update::deleted_updates_are_gone_and_latest_is_found_again");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let earlier: UpdateRef = (url.clone(), "2021-03-01T11:00:00+00:00".parse().unwrap()).into();
+        let later: UpdateRef = (url.clone(), "2021-03-01T12:00:00+00:00".parse().unwrap()).into();
+        repo.create(url.clone(), earlier.timestamp, "change").unwrap();
+        repo.create(url.clone(), later.timestamp, "bogus").unwrap();
+        repo.set_source(&later, "inbox/bogus.eml").unwrap();
+
+        let deleted = repo.delete(&later).unwrap();
+        assert_eq!(
+            deleted.into_events().collect::<Vec<_>>(),
+            [UpdateEvent::Deleted {
+                url: url.clone(),
+                timestamp: later.timestamp
+            }]
+        );
+        assert_eq!(repo.get(&later).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(repo.get_source(&later).unwrap_err().kind(), io::ErrorKind::NotFound);
+        assert_eq!(repo.latest(&url).unwrap(), earlier.timestamp);
+        assert!(matches!(repo.delete(&later), Err(err) if err.kind() == io::ErrorKind::NotFound));
+
+        let other: Url = "http://www.example.org/guidance".parse().unwrap();
+        repo.create(other.clone(), earlier.timestamp, "change").unwrap();
+        repo.delete(&earlier).unwrap();
+        assert!(repo.list_updates(url.clone()).unwrap().next().is_none());
+        assert_eq!(repo.latest(&url).unwrap_err().kind(), io::ErrorKind::NotFound);
+        // the url's emptied dir is passed over when listing under a prefix above it
+        let listed = repo
+            .list_all(&"http://www.example.org/".parse().unwrap())
+            .unwrap()
+            .map(|update| update.unwrap().url().as_str().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(listed, [other.as_str()]);
+    }
+
+    #[test]
+    fn short_ids_are_stable() {
+        let url: Url = "https://www.gov.uk/foreign-travel-advice".parse().unwrap();
+        let update_ref = UpdateRef::from((url.clone(), "2022-02-17T09:53:57+00:00".parse().unwrap()));
+        let same_instant = UpdateRef::from((url.clone(), "2022-02-17T10:53:57+01:00".parse().unwrap()));
+        let other = UpdateRef::from((url, "2022-02-17T09:53:58+00:00".parse().unwrap()));
+
+        assert_eq!(update_ref.short_id(), "hmv2f1dfd8");
+        assert_eq!(update_ref.short_id(), same_instant.short_id());
+        assert_ne!(update_ref.short_id(), other.short_id());
+    }
+
+    #[test]
+    fn update_refs_are_read_in_either_form() {
+        let update_ref = UpdateRef {
+            url: "https://www.gov.uk/search?q=a|b".parse().unwrap(),
+            timestamp: "2022-02-17T09:53:57+00:00".parse().unwrap(),
+            seq: 0,
+        };
+        assert_eq!(
+            update_ref.to_string(),
+            "2022-02-17T09:53:57+00:00|https://www.gov.uk/search?q=a|b"
+        );
+        assert_eq!(update_ref.to_string().parse::<UpdateRef>().unwrap(), update_ref);
+        // as in tag files written before the current form
+        assert_eq!(
+            "https://www.gov.uk/search?q=a|b#2022-02-17T09:53:57+00:00"
+                .parse::<UpdateRef>()
+                .unwrap(),
+            update_ref
+        );
+        assert!(matches!(
+            "2022-02-17T09:53:57+00:00|https://www.gov.uk/search#results".parse::<UpdateRef>(),
+            Err(UpdateRefParseError::UnexpectedFragment(_))
+        ));
+    }
+
+    #[test]
+    fn timestamps_are_stored_in_utc() {
+        let repo = test_repo("update::timestamps_are_stored_in_utc");
+        let url: Url = "http://www.example.org/test/doc".parse().unwrap();
+        let summer_time: DateTime<FixedOffset> = "2021-07-01T10:00:00+01:00".parse().unwrap();
+
+        let update = repo.create(url.clone(), summer_time, "change").unwrap();
+        assert_eq!(update.timestamp().to_rfc3339(), "2021-07-01T09:00:00+00:00");
+
+        let update = repo.get_update(url.clone(), summer_time).unwrap();
+        assert_eq!(update.timestamp().to_rfc3339(), "2021-07-01T09:00:00+00:00");
+        let update = repo.list_updates(url.clone()).unwrap().next().unwrap().unwrap();
+        assert_eq!(update.timestamp().to_rfc3339(), "2021-07-01T09:00:00+00:00");
+
+        let update = repo.ensure(url.clone(), summer_time, "change").unwrap();
+        assert_eq!(update.into_events().count(), 0);
+    }
+
     #[test]
     fn list_updates() {
         let repo = test_repo("update::list_updates");
@@ -370,6 +874,32 @@ mod test {
         }
     }
 
+    #[test]
+    fn child_urls() {
+        let repo = test_repo("update::child_urls");
+        for url in [
+            "http://www.example.org/",
+            "http://www.example.org/test/doc1",
+            "http://www.example.org/guidance",
+        ] {
+            let _ = repo
+                .create(url.parse().unwrap(), "2021-03-01T10:00:00+00:00".parse().unwrap(), "1")
+                .unwrap();
+        }
+
+        let children = repo.child_urls(&"http://www.example.org/".parse().unwrap()).unwrap();
+        assert_eq!(
+            children.iter().map(Url::as_str).collect::<Vec<_>>(),
+            ["http://www.example.org/guidance", "http://www.example.org/test"]
+        );
+        let listed = children
+            .iter()
+            .flat_map(|child| repo.list_all(child).unwrap())
+            .map(|update| update.unwrap().url().as_str().to_owned())
+            .collect::<Vec<_>>();
+        assert_eq!(listed, ["http://www.example.org/guidance", "http://www.example.org/test/doc1"]);
+    }
+
     fn test_repo(name: &str) -> UpdateRepo {
         let path = format!("tmp/{}", name);
         let _ = fs::remove_dir_all(&path);
@@ -137,6 +137,19 @@ impl UrlRepo {
         Ok(leaves.into_iter())
     }
 
+    /// The urls one path segment under a url which have directories, in order
+    pub fn child_urls(&self, url: &Url) -> io::Result<Vec<Url>> {
+        let mut children = vec![];
+        for dir_entry in self.read_dir_sorted_for_url(url)? {
+            if let Some(name) = dir_entry.kind().as_node() {
+                let mut child = url.clone();
+                child.push_path_segment(name);
+                children.push(child);
+            }
+        }
+        Ok(children)
+    }
+
     /// Return an iterator over all the leaves of all urls under a url prefix
     pub fn list_all<Leaf>(
         &self,
@@ -238,8 +251,17 @@ impl<'r, Leaf> Iterator for IterUrlRepoLeaves<'r, Leaf> {
                         Ok(dir) => dir,
                         Err(err) => return Some(Err(err)),
                     };
-                    next_dir_entry = dir.next().expect("todo: handle empty dir");
-                    self.stack.push(dir);
+                    match dir.next() {
+                        Some(entry) => {
+                            next_dir_entry = entry;
+                            self.stack.push(dir);
+                        }
+                        // such as the dir of a url whose last entry was deleted
+                        None => {
+                            self.url.pop_path_segment();
+                            break;
+                        }
+                    }
                 } else if let Some((repo_key, name)) = kind.as_leaf() {
                     if repo_key == self.repo.repo_key {
                         let url = self.url.clone();